@@ -17,11 +17,14 @@ pub struct Entity {
     model_attrs: Vec<syn::Meta>,
     entity_attrs: Vec<syn::Meta>,
     updater_attrs: Vec<syn::Meta>,
+    subset_attrs: Vec<syn::Meta>,
 
     subsets: Vec<Subset>,
 
     all_fields: Vec<EntityField>,
     biz_id_field_positions: Vec<usize>,
+    /// `#[entity(history)]`, previous versions are kept in a history table
+    history: bool,
 }
 
 impl Parse for Entity {
@@ -72,6 +75,8 @@ impl Parse for Entity {
         let mut model_attrs = vec![];
         let mut entity_attrs = vec![];
         let mut updater_attrs = vec![];
+        let mut subset_attrs = vec![];
+        let mut history = false;
         let attrs = input.attrs.clone();
         for attr in attrs {
             let Some(attr_ident) = attr.path().get_ident() else {
@@ -95,6 +100,20 @@ impl Parse for Entity {
                     let derive = attr.parse_args::<syn::Meta>()?;
                     updater_attrs.push(derive);
                 }
+                "subset_attr" => {
+                    let derive = attr.parse_args::<syn::Meta>()?;
+                    subset_attrs.push(derive);
+                }
+                "entity" => {
+                    attr.parse_nested_meta(|meta| {
+                        if meta.path.is_ident("history") {
+                            history = true;
+                            Ok(())
+                        } else {
+                            Err(meta.error("unsupported entity option"))
+                        }
+                    })?;
+                }
                 _ => original_attrs.push(attr),
             }
         }
@@ -107,7 +126,9 @@ impl Parse for Entity {
             model_attrs,
             entity_attrs,
             updater_attrs,
+            subset_attrs,
             biz_id_field_positions: biz_id_positions,
+            history,
         };
 
         Ok(this)
@@ -284,6 +305,26 @@ impl Entity {
         Ident::new(&format!("{}Ident", entity_ident), entity_ident.span())
     }
 
+    fn ident_owned_struct_name(&self) -> Ident {
+        let entity_ident = &self.name;
+        Ident::new(&format!("{}IdentOwned", entity_ident), entity_ident.span())
+    }
+
+    /// Variants of the ident enum, the sys id comes first
+    fn ident_variants(&self) -> Vec<Ident> {
+        let id_field = self.id_field();
+        let mut variants = vec![Ident::new("SysId", id_field.ident().span())];
+        for field in self.all_fields.iter() {
+            if field.kind == FieldKind::BizId {
+                let ident = field.ident();
+                let variant = ident.to_string().to_case(Case::Pascal);
+                variants.push(Ident::new(&variant, ident.span()));
+            }
+        }
+
+        variants
+    }
+
     fn entity_biz_fields_enum_name(&self) -> Ident {
         let entity_ident = &self.name;
         Ident::new(
@@ -345,8 +386,10 @@ impl Entity {
             biz_field_names.push(origin_field_ident);
         }
 
+        let ident_owned_name = self.ident_owned_struct_name();
         let biz_ident_enums_name = self.entity_biz_fields_enum_name();
         let biz_variant_idents = variant_idents.clone().into_iter().skip(1);
+        let biz_all_variants = biz_variant_idents.clone();
         let mut filed_name_impl_arms = vec![];
         for (name, variant) in biz_field_names.iter().zip(variant_idents.iter().skip(1)) {
             let name = name.to_string();
@@ -361,6 +404,16 @@ impl Entity {
                 #(#variant_idents (std::borrow::Cow<'a, #variant_types>)),*
             }
 
+            /// An ident which borrows nothing
+            #[derive(PartialEq, Eq, Clone, Hash, Debug)]
+            pub struct #ident_owned_name(pub #ident_name<'static>);
+
+            impl<'a> From<#ident_owned_name> for #ident_name<'a> {
+                fn from(value: #ident_owned_name) -> Self {
+                    value.0
+                }
+            }
+
             #[derive(PartialEq, Eq, Clone, Hash, Debug, Copy)]
             pub enum #biz_ident_enums_name {
                 #(#biz_variant_idents),*
@@ -372,6 +425,10 @@ impl Entity {
                         #(#filed_name_impl_arms),*
                     }
                 }
+
+                fn all() -> &'static [Self] {
+                    &[#(Self:: #biz_all_variants),*]
+                }
             }
 
             #(
@@ -393,6 +450,93 @@ impl Entity {
         }
     }
 
+    fn entity_fields_enum_name(&self) -> Ident {
+        let entity_ident = &self.name;
+        Ident::new(&format!("{}FieldEnum", entity_ident), entity_ident.span())
+    }
+
+    fn entity_fields_enum_stream(&self) -> TokenStream {
+        let enum_name = self.entity_fields_enum_name();
+        let column_fields = self
+            .all_fields
+            .iter()
+            .filter(|field| field.kind.is_column())
+            .collect::<Vec<_>>();
+
+        let mut variant_idents = vec![];
+        let mut field_names = vec![];
+        for field in column_fields {
+            let ident = field.ident();
+            let variant = ident.to_string().to_case(Case::Pascal);
+            variant_idents.push(Ident::new(&variant, ident.span()));
+            field_names.push(ident.to_string());
+        }
+        let sys_id_variant = &variant_idents[0];
+        let entity_ident = &self.name;
+        let field_values = column_fields_values(&self.all_fields, |field| {
+            let ident = field.ident();
+            match field.kind {
+                FieldKind::SysId => quote! { bagua::repository::criteria::to_value(&self.#ident) },
+                _ => quote! {
+                    self.#ident
+                        .value_ref_opt()
+                        .map_or(bagua::repository::criteria::Value::Null, |v| {
+                            bagua::repository::criteria::to_value(v)
+                        })
+                },
+            }
+        });
+        let field_jsons = column_fields_values(&self.all_fields, |field| {
+            let ident = field.ident();
+            match field.kind {
+                FieldKind::SysId => quote! { bagua::repository::criteria::to_json(&self.#ident) },
+                _ => quote! {
+                    bagua::repository::criteria::to_json(&self.#ident.value_ref_opt())
+                },
+            }
+        });
+
+        quote! {
+            #[derive(PartialEq, Eq, Clone, Hash, Debug, Copy)]
+            pub enum #enum_name {
+                #(#variant_idents),*
+            }
+
+            impl bagua::entity::FieldEnum for #enum_name {
+                fn field_name(self) -> &'static str {
+                    match self {
+                        #(Self:: #variant_idents => #field_names),*
+                    }
+                }
+
+                fn sys_id() -> Self {
+                    Self:: #sys_id_variant
+                }
+
+                fn all() -> &'static [Self] {
+                    &[#(Self:: #variant_idents),*]
+                }
+            }
+
+            impl bagua::repository::criteria::FieldAccess<#enum_name> for #entity_ident {
+                fn field_value(&self, field: #enum_name) -> bagua::repository::criteria::Value {
+                    match field {
+                        #(#enum_name:: #variant_idents => #field_values),*
+                    }
+                }
+
+                fn field_json(
+                    &self,
+                    field: #enum_name,
+                ) -> bagua::anyhow::Result<bagua::serde_json::Value> {
+                    match field {
+                        #(#enum_name:: #variant_idents => #field_jsons),*
+                    }
+                }
+            }
+        }
+    }
+
     fn id_field(&self) -> &EntityField {
         self.all_fields.get(0).unwrap()
     }
@@ -414,6 +558,98 @@ impl Entity {
             quote! {#biz_enum}
         };
 
+        let field_enum_ident = self.entity_fields_enum_name();
+        let sys_id_ident = self.id_field().ident();
+        let (id_owned_ty, as_sys_id, id_to_owned) = if self.biz_id_field_positions.is_empty() {
+            (
+                quote! { #sys_id_ty },
+                quote! { Some(id) },
+                quote! { ::core::clone::Clone::clone(id) },
+            )
+        } else {
+            let id_owned = self.ident_owned_struct_name();
+            let variants = self.ident_variants();
+            (
+                quote! { #id_owned },
+                quote! {
+                    match id {
+                        #ident_struct_name::SysId(id) => Some(id.as_ref()),
+                        _ => None,
+                    }
+                },
+                quote! {
+                    #id_owned(match id {
+                        #(
+                            #ident_struct_name::#variants(v) => #ident_struct_name::#variants(
+                                ::std::borrow::Cow::Owned(::std::borrow::ToOwned::to_owned(&**v)),
+                            ),
+                        )*
+                    })
+                },
+            )
+        };
+
+        let ids = if self.biz_id_field_positions.is_empty() {
+            quote! { vec![::core::clone::Clone::clone(&self.#sys_id_ident)] }
+        } else {
+            let id_owned = self.ident_owned_struct_name();
+            let biz_pushes = self
+                .all_fields
+                .iter()
+                .filter(|f| f.kind == FieldKind::BizId)
+                .map(|f| {
+                    let ident = f.ident();
+                    let variant =
+                        Ident::new(&ident.to_string().to_case(Case::Pascal), ident.span());
+                    let pattern = match strip_optional(&f.origin.ty) {
+                        Cow::Owned(_) => quote! { Some(Some(v)) },
+                        Cow::Borrowed(_) => quote! { Some(v) },
+                    };
+                    quote! {
+                        if let #pattern = self.#ident.value_ref_opt() {
+                            ids.push(#id_owned(#ident_struct_name::#variant(
+                                ::std::borrow::Cow::Owned(::core::clone::Clone::clone(v)),
+                            )));
+                        }
+                    }
+                });
+            quote! {
+                let mut ids = vec![#id_owned(#ident_struct_name::SysId(
+                    ::std::borrow::Cow::Owned(::core::clone::Clone::clone(&self.#sys_id_ident)),
+                ))];
+                #(#biz_pushes)*
+                ids
+            }
+        };
+
+        let changed_pushes = self
+            .all_fields
+            .iter()
+            .filter(|f| f.kind.is_column() && f.kind != FieldKind::SysId)
+            .map(|f| {
+                let ident = f.ident();
+                let variant = Ident::new(&ident.to_string().to_case(Case::Pascal), ident.span());
+                quote! {
+                    if self.#ident.is_changed() {
+                        fields.push(#field_enum_ident::#variant);
+                    }
+                }
+            });
+
+        let nested_pushes = self
+            .all_fields
+            .iter()
+            .filter(|f| matches!(f.kind, FieldKind::Foreign | FieldKind::Group))
+            .filter_map(|f| {
+                let name = f.ident().to_string();
+                let check = f.change_check()?;
+                Some(quote! {
+                    if #check {
+                        fields.push(#name);
+                    }
+                })
+            });
+
         let entity_trait = quote! {
             const _: () = {
                 use bagua::entity::Entity;
@@ -423,6 +659,40 @@ impl Entity {
                     type SysId = #sys_id_ty;
 
                     type BizIdFieldEnum = #biz_enum_ident;
+
+                    type FieldEnum = #field_enum_ident;
+
+                    type IdOwned = #id_owned_ty;
+
+                    fn sys_id(&self) -> &Self::SysId {
+                        &self.#sys_id_ident
+                    }
+
+                    fn as_sys_id<'a>(id: &'a Self::Id<'_>) -> Option<&'a Self::SysId> {
+                        #as_sys_id
+                    }
+
+                    fn id_to_owned(id: &Self::Id<'_>) -> Self::IdOwned {
+                        #id_to_owned
+                    }
+
+                    fn ids(&self) -> Vec<Self::IdOwned> {
+                        #ids
+                    }
+
+                    fn changed_fields(&self) -> Vec<Self::FieldEnum> {
+                        #[allow(unused_mut)]
+                        let mut fields = vec![];
+                        #(#changed_pushes)*
+                        fields
+                    }
+
+                    fn changed_nested_fields(&self) -> Vec<&'static str> {
+                        #[allow(unused_mut)]
+                        let mut fields = vec![];
+                        #(#nested_pushes)*
+                        fields
+                    }
                 }
             };
         };
@@ -433,6 +703,7 @@ impl Entity {
         let entity_name = &self.name;
         let updater_ident = self.updater_name();
         let subset_full_ident = self.subset_full_ident();
+        let change_checks = self.all_fields.iter().filter_map(|f| f.change_check());
         let entity_trait = quote! {
             const _: () = {
                 impl bagua::entity::FieldGroup for #entity_name {
@@ -442,6 +713,10 @@ impl Entity {
                     fn update_fields(&mut self, updater: Self::Updater) {
                         self.update_fields(updater);
                     }
+
+                    fn has_changes(&self) -> bool {
+                        false #(|| #change_checks)*
+                    }
                 }
             };
         };
@@ -463,9 +738,13 @@ impl Entity {
         let read_only_struct = self.read_only_struct(fields.clone(), &entity_repr)?;
         let read_only_ident = &read_only_struct.name;
         let entity_ident_stream = self.entity_ident_stream();
+        let entity_fields_enum_stream = self.entity_fields_enum_stream();
         let impl_deref = self.impl_deref(read_only_ident);
         let impl_entity_trait = self.impl_entity_trait();
         let impl_field_group = self.impl_field_group();
+        let impl_history = self
+            .history
+            .then(|| quote! { impl ::bagua::entity::HistoryEntity for #entity_ident {} });
 
         let stream = quote_spanned! { self.name.span() =>
             #(#attrs)*
@@ -477,10 +756,14 @@ impl Entity {
 
             #entity_ident_stream
 
+            #entity_fields_enum_stream
+
             #impl_entity_trait
 
             #impl_field_group
 
+            #impl_history
+
             #impl_deref
 
             #read_only_struct
@@ -569,7 +852,47 @@ impl Entity {
                 })
                 .collect::<Vec<_>>();
 
+            let field_names = subset.fields.iter().map(|field| field.ident.to_string());
+            let field_access = (name == &self.subset_full_ident()).then(|| {
+                let enum_name = self.entity_fields_enum_name();
+                let variants = column_fields_values(&self.all_fields, |field| {
+                    let ident = field.ident();
+                    Ident::new(&ident.to_string().to_case(Case::Pascal), ident.span())
+                });
+                let values = column_fields_values(&self.all_fields, |field| {
+                    let ident = field.ident();
+                    quote! { bagua::repository::criteria::to_value(&self.#ident) }
+                });
+                let jsons = column_fields_values(&self.all_fields, |field| {
+                    let ident = field.ident();
+                    quote! { bagua::repository::criteria::to_json(&self.#ident) }
+                });
+                quote! {
+                    impl bagua::repository::criteria::FieldAccess<#enum_name> for #name {
+                        fn field_value(
+                            &self,
+                            field: #enum_name,
+                        ) -> bagua::repository::criteria::Value {
+                            match field {
+                                #(#enum_name:: #variants => #values),*
+                            }
+                        }
+
+                        fn field_json(
+                            &self,
+                            field: #enum_name,
+                        ) -> bagua::anyhow::Result<bagua::serde_json::Value> {
+                            match field {
+                                #(#enum_name:: #variants => #jsons),*
+                            }
+                        }
+                    }
+                }
+            });
+
+            let subset_attrs = &self.subset_attrs;
             let subset = quote! {
+                #(#[#subset_attrs])*
                 pub struct #name {
                     #subset_fields,
                 }
@@ -577,6 +900,10 @@ impl Entity {
                 impl ::bagua::entity::subset::Subset for #name {
                     type Entity = #entity_name;
 
+                    fn field_names() -> &'static [&'static str] {
+                        &[#(#field_names),*]
+                    }
+
                     fn to_entity(self) -> Self::Entity {
                         #entity_name {
                             #(#to_entity_fields)*
@@ -590,6 +917,8 @@ impl Entity {
                         ::bagua::entity::subset::Subset::to_entity(subset)
                     }
                 }
+
+                #field_access
             };
             subsets.push(subset);
         }
@@ -627,6 +956,20 @@ fn model_struct_name(entity_name: &Ident) -> Ident {
 }
 
 impl EntityField {
+    /// Expression checking whether the field has pending changes
+    fn change_check(&self) -> Option<TokenStream> {
+        let ident = self.ident();
+        match self.kind {
+            FieldKind::SysId => None,
+            FieldKind::Scalar | FieldKind::BizId | FieldKind::Foreign => {
+                Some(quote! { self.#ident.is_changed() })
+            }
+            FieldKind::Group => Some(quote! {
+                bagua::entity::FieldGroup::has_changes(&self.#ident)
+            }),
+        }
+    }
+
     fn to_guarded_field(&self) -> Field {
         let mut field = self.origin.clone();
         field.attrs.extend(self.entity_attrs.clone());
@@ -1160,6 +1503,15 @@ fn parse_subset_attr(
     Ok(())
 }
 
+/// `f` of each field stored as a column, in the order of the field enum
+fn column_fields_values<T>(fields: &[EntityField], f: impl Fn(&EntityField) -> T) -> Vec<T> {
+    fields
+        .iter()
+        .filter(|field| field.kind.is_column())
+        .map(f)
+        .collect()
+}
+
 fn serde_derive_attrs() -> Vec<syn::Attribute> {
     vec![
         syn::parse_quote! {
@@ -1179,4 +1531,12 @@ impl FieldKind {
     fn is_group(&self) -> bool {
         matches!(self, FieldKind::Group)
     }
+
+    /// Whether the field is stored as a column of the entity itself
+    fn is_column(&self) -> bool {
+        matches!(
+            self,
+            FieldKind::SysId | FieldKind::BizId | FieldKind::Scalar
+        )
+    }
 }
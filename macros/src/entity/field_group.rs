@@ -269,6 +269,7 @@ impl Entity {
         let entity_name = &self.name;
         let updater_ident = self.updater_name();
         let subset_full_ident = self.subset_full_ident();
+        let change_checks = self.all_fields.iter().filter_map(|f| f.change_check());
         let entity_trait = quote! {
             const _: () = {
                 impl bagua::entity::FieldGroup for #entity_name {
@@ -278,6 +279,10 @@ impl Entity {
                     fn update_fields(&mut self, updater: Self::Updater) {
                         self.update_fields(updater);
                     }
+
+                    fn has_changes(&self) -> bool {
+                        false #(|| #change_checks)*
+                    }
                 }
             };
         };
@@ -469,6 +474,17 @@ fn model_struct_name(entity_name: &Ident) -> Ident {
 }
 
 impl EntityField {
+    /// Expression checking whether the field has pending changes
+    fn change_check(&self) -> Option<TokenStream> {
+        let ident = self.ident();
+        match self.kind {
+            FieldKind::Scalar | FieldKind::Foreign => Some(quote! { self.#ident.is_changed() }),
+            FieldKind::Group => Some(quote! {
+                bagua::entity::FieldGroup::has_changes(&self.#ident)
+            }),
+        }
+    }
+
     fn to_guarded_field(&self) -> Field {
         let mut field = self.origin.clone();
         field.attrs.extend(self.entity_attrs.clone());
@@ -0,0 +1,228 @@
+use bagua::{
+    entity::SysId,
+    http::{HttpCredential, IdCredential},
+    provider::{Provider, ProviderContext},
+    repository::{
+        audit::{AuditAction, AuditActor, AuditedRepository, InMemoryAuditSink},
+        ConflictTarget, DeleteEffect, Repository, SaveEffect, SaveEffectOf, SubsetLoader,
+        UpdateEffect,
+    },
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct DocId(i64);
+
+impl SysId for DocId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+pub struct Doc {
+    id: DocId,
+    #[entity(biz_id)]
+    slug: String,
+    title: String,
+    pages: i64,
+    tags: Vec<String>,
+}
+
+fn doc(id: i64) -> Doc {
+    Doc::from(DocFull {
+        id: DocId(id),
+        slug: format!("doc-{id}"),
+        title: format!("doc {id}"),
+        pages: 1,
+        tags: vec![],
+    })
+}
+
+#[derive(Clone, Default)]
+struct Repo {
+    conflict: bool,
+}
+
+impl Provider for Repo {
+    fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+}
+
+impl Repository<Doc> for Repo {
+    async fn save(&mut self, _entity: &Doc) -> anyhow::Result<SaveEffectOf<Doc>> {
+        if self.conflict {
+            return Ok(SaveEffect::Conflict(ConflictTarget::SysId));
+        }
+        Ok(SaveEffect::Ok)
+    }
+
+    async fn update(&mut self, _entity: &Doc) -> anyhow::Result<UpdateEffect> {
+        Ok(UpdateEffect::Ok)
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> DocIdent<'a>: From<I>,
+    {
+        Ok(DeleteEffect::Ok)
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> DocIdent<'a>: From<I>,
+    {
+        Ok(true)
+    }
+
+    /// A native batch insert, even ids conflict
+    async fn save_batch(&mut self, entities: &[Doc]) -> anyhow::Result<Vec<SaveEffectOf<Doc>>> {
+        Ok(entities
+            .iter()
+            .map(|doc| match doc.id.0 % 2 {
+                0 => SaveEffect::Conflict(ConflictTarget::Unknown),
+                _ => SaveEffect::Ok,
+            })
+            .collect())
+    }
+}
+
+impl SubsetLoader<DocFull> for Repo {
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<DocFull>>
+    where
+        for<'a> DocIdent<'a>: From<I>,
+    {
+        let id = match DocIdent::from(id) {
+            DocIdent::SysId(id) => id.0,
+            DocIdent::Slug(slug) => slug.trim_start_matches("doc-").parse()?,
+        };
+        Ok(Some(DocFull {
+            id: DocId(id),
+            slug: format!("doc-{id}"),
+            title: format!("doc {id}"),
+            pages: 1,
+            tags: vec![],
+        }))
+    }
+}
+
+type AuditedDocRepo = AuditedRepository<Doc, Repo, InMemoryAuditSink>;
+
+#[tokio::test]
+async fn t_audit_records() -> anyhow::Result<()> {
+    let mut ctx = ProviderContext::new();
+    ctx.insert(AuditActor::from(IdCredential { id: 42 }));
+    let mut repo = AuditedDocRepo::build(&mut ctx)?;
+
+    let mut d = doc(1);
+    repo.save(&d).await?.ignore_effect();
+    d.pages.set(3);
+    repo.update(&d).await?.ignore_effect();
+    repo.delete(DocId(1)).await?.ignore_effect();
+
+    let records = repo.sink().records();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|r| r.entity == "Doc"));
+    assert!(records.iter().all(|r| r.entity_id == "1"));
+    assert!(records.iter().all(|r| r.actor.as_deref() == Some("42")));
+
+    assert_eq!(records[0].action, AuditAction::Create);
+    assert_eq!(records[0].changes.len(), 5);
+    assert_eq!(records[0].changes["title"], "doc 1");
+
+    assert_eq!(records[1].action, AuditAction::Update);
+    assert_eq!(records[1].changes.len(), 1);
+    assert_eq!(records[1].changes["pages"], 3);
+
+    assert_eq!(records[2].action, AuditAction::Delete);
+    assert!(records[2].changes.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_audit_skips_failed_writes() -> anyhow::Result<()> {
+    let sink = InMemoryAuditSink::new();
+    let mut repo = AuditedDocRepo::new(Repo { conflict: true }, sink.clone(), AuditActor::system());
+
+    assert!(!repo.save(&doc(1)).await?.is_ok());
+    assert!(sink.records().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_audit_batch() -> anyhow::Result<()> {
+    let sink = InMemoryAuditSink::new();
+    let mut repo = AuditedDocRepo::new(Repo::default(), sink.clone(), AuditActor::system());
+
+    let effects = repo.save_batch(&[doc(1), doc(2), doc(3)]).await?;
+    assert!(effects[0].is_ok());
+    assert!(!effects[1].is_ok());
+
+    let records = sink.records();
+    assert_eq!(records.len(), 2);
+    assert!(records.iter().all(|r| r.action == AuditAction::Create));
+    assert_eq!(records[1].entity_id, "3");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_audit_delete_by_biz_id() -> anyhow::Result<()> {
+    let sink = InMemoryAuditSink::new();
+    let mut repo = AuditedDocRepo::new(Repo::default(), sink.clone(), AuditActor::system());
+
+    repo.delete("doc-2".to_string()).await?.ignore_effect();
+    repo.delete_batch(vec!["doc-3".to_string()]).await?;
+
+    let records = sink.records();
+    assert_eq!(records[0].entity_id, "2");
+    assert_eq!(records[1].entity_id, "3");
+    assert!(records.iter().all(|r| r.actor.is_none()));
+
+    Ok(())
+}
+
+#[test]
+fn t_audit_requires_actor() {
+    let mut ctx = ProviderContext::new();
+    assert!(AuditedDocRepo::build(&mut ctx).is_err());
+
+    ctx.insert(AuditActor::system());
+    assert!(AuditedDocRepo::build(&mut ctx).is_ok());
+}
+
+#[tokio::test]
+async fn t_audit_json_values() -> anyhow::Result<()> {
+    let sink = InMemoryAuditSink::new();
+    let mut repo = AuditedDocRepo::new(Repo::default(), sink.clone(), AuditActor::system());
+
+    let mut d = doc(1);
+    d.tags.set(vec!["a".to_string(), "b".to_string()]);
+    repo.update(&d).await?.ignore_effect();
+
+    let records = sink.records();
+    assert_eq!(records[0].changes["tags"], serde_json::json!(["a", "b"]));
+
+    Ok(())
+}
+
+#[test]
+fn t_audit_actor_from_credential() {
+    struct Request;
+
+    impl HttpCredential<IdCredential<String>> for Request {
+        fn credential(&self) -> IdCredential<String> {
+            IdCredential {
+                id: "alice".to_string(),
+            }
+        }
+    }
+
+    let actor = AuditActor::from_credential(&Request);
+    assert_eq!(actor, AuditActor(Some("alice".to_string())));
+}
@@ -0,0 +1,55 @@
+use bagua::{
+    db::diesel::batch::group_by_changed,
+    entity::{Entity, SysId},
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct ItemId(i32);
+
+impl SysId for ItemId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+pub struct Item {
+    id: ItemId,
+    name: String,
+    price: u32,
+}
+
+fn item(id: i32) -> Item {
+    Item::from(ItemFull {
+        id: ItemId(id),
+        name: "item".to_string(),
+        price: 1,
+    })
+}
+
+#[test]
+fn t_group_by_changed() {
+    let mut a = item(1);
+    let mut b = item(2);
+    let mut c = item(3);
+    let d = item(4);
+    a.name.set("a".to_string());
+    b.price.set(2);
+    c.name.set("c".to_string());
+
+    assert_eq!(a.changed_fields(), vec![ItemFieldEnum::Name]);
+    assert!(d.changed_fields().is_empty());
+
+    let items = [a, b, c, d];
+    let groups = group_by_changed(&items);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].0, vec![ItemFieldEnum::Name]);
+    assert_eq!(
+        groups[0].1.iter().map(|i| *i.sys_id()).collect::<Vec<_>>(),
+        vec![ItemId(1), ItemId(3)]
+    );
+    assert_eq!(groups[1].0, vec![ItemFieldEnum::Price]);
+}
@@ -0,0 +1,316 @@
+use std::{cell::RefCell, future::Future, rc::Rc, time::Duration};
+
+use bagua::{
+    db::{AsyncTxCallback, BeforeCommitHook, TxCallback, TxnManager, TxnResult, TxnState},
+    entity::SysId,
+    provider::{Provider, ProviderContext},
+    repository::{
+        cache::{CacheBackend, CacheKey, CacheValue, CachedRepository, LruTtlCache},
+        DeleteEffect, Repository, SaveEffect, SaveEffectOf, SubsetReader, UpdateEffect,
+    },
+    result::BizResult,
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct PostId(i32);
+
+impl SysId for PostId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+#[subset_attr(derive(Clone))]
+pub struct Post {
+    id: PostId,
+    #[entity(biz_id)]
+    slug: String,
+    title: String,
+}
+
+#[derive(Clone, Default)]
+struct Repo {
+    reads: Rc<RefCell<usize>>,
+    title: Rc<RefCell<String>>,
+}
+
+impl SubsetReader<PostFull> for Repo {
+    async fn read<I>(&mut self, id: I) -> anyhow::Result<Option<PostFull>>
+    where
+        for<'a> PostIdent<'a>: From<I>,
+    {
+        *self.reads.borrow_mut() += 1;
+        let id = match PostIdent::from(id) {
+            PostIdent::SysId(id) => *id,
+            PostIdent::Slug(slug) => PostId(slug.len() as i32),
+        };
+        Ok(Some(PostFull {
+            id,
+            slug: "a".repeat(id.0 as usize),
+            title: self.title.borrow().clone(),
+        }))
+    }
+}
+
+impl Repository<Post> for Repo {
+    async fn save(&mut self, _entity: &Post) -> anyhow::Result<SaveEffectOf<Post>> {
+        Ok(SaveEffect::Ok)
+    }
+
+    async fn update(&mut self, entity: &Post) -> anyhow::Result<UpdateEffect> {
+        *self.title.borrow_mut() = entity.title.to_string();
+        Ok(UpdateEffect::Ok)
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> PostIdent<'a>: From<I>,
+    {
+        Ok(DeleteEffect::Ok)
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> PostIdent<'a>: From<I>,
+    {
+        Ok(true)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Txn {
+    state: Rc<RefCell<Option<TxnState>>>,
+    callbacks: Rc<RefCell<Vec<Box<dyn TxCallback>>>>,
+}
+
+impl Txn {
+    fn begin(&self) {
+        *self.state.borrow_mut() = Some(TxnState::Begun { depth: 1 });
+    }
+
+    fn end(&self, result: TxnResult) {
+        *self.state.borrow_mut() = None;
+        for cb in self.callbacks.take() {
+            cb.call(result);
+        }
+    }
+}
+
+impl TxnManager for Txn {
+    async fn do_transaction<F, T, E>(&mut self, tx: F) -> BizResult<T, E>
+    where
+        F: Future<Output = BizResult<T, E>>,
+    {
+        tx.await
+    }
+
+    fn register_callback<H>(&self, callback: H)
+    where
+        H: TxCallback,
+    {
+        self.callbacks.borrow_mut().push(Box::new(callback));
+    }
+
+    fn register_async_callback<H>(&self, _callback: H)
+    where
+        H: AsyncTxCallback,
+    {
+        unreachable!()
+    }
+
+    fn register_before_commit<H>(&self, _hook: H)
+    where
+        H: BeforeCommitHook,
+    {
+        unreachable!()
+    }
+
+    fn state(&self) -> TxnState {
+        self.state.borrow().unwrap_or(TxnState::NotInTransaction)
+    }
+}
+
+type Cache = LruTtlCache<CacheKey<PostId>, CacheValue>;
+
+fn cached_repo() -> (CachedRepository<Post, Repo, Txn, Cache>, Repo, Txn) {
+    let repo = Repo::default();
+    let txn = Txn::default();
+    let cache = Cache::new(100, Duration::from_secs(60));
+    let cached = CachedRepository::new(repo.clone(), txn.clone(), cache);
+    (cached, repo, txn)
+}
+
+#[tokio::test]
+async fn t_read_through() -> anyhow::Result<()> {
+    let (mut cached, repo, _txn) = cached_repo();
+
+    Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*repo.reads.borrow(), 1);
+
+    // Reads by biz id are not cached
+    Repository::read::<PostFull, _>(&mut cached, "a".to_string())
+        .await?
+        .unwrap();
+    assert_eq!(*repo.reads.borrow(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_invalidate_on_commit() -> anyhow::Result<()> {
+    let (mut cached, repo, txn) = cached_repo();
+
+    let mut post = Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    post.title.set("new".to_string());
+
+    txn.begin();
+    cached.update(&post).await?.ignore_effect();
+    // Uncommitted writes bypass the cache
+    let read = Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*read.title, "new");
+    assert_eq!(*repo.reads.borrow(), 2);
+    txn.end(TxnResult::Committed);
+
+    let read = Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*read.title, "new");
+    assert_eq!(*repo.reads.borrow(), 3);
+    Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*repo.reads.borrow(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_keep_on_rollback() -> anyhow::Result<()> {
+    let (mut cached, repo, txn) = cached_repo();
+
+    let mut post = Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    post.title.set("new".to_string());
+
+    txn.begin();
+    cached.update(&post).await?.ignore_effect();
+    *repo.title.borrow_mut() = String::new();
+    txn.end(TxnResult::RolledBack);
+
+    Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*repo.reads.borrow(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_invalidate_without_txn() -> anyhow::Result<()> {
+    let (mut cached, repo, _txn) = cached_repo();
+
+    Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    cached.delete(PostId(1)).await?.ignore_effect();
+    Repository::read::<PostFull, _>(&mut cached, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*repo.reads.borrow(), 2);
+
+    Ok(())
+}
+
+#[derive(Clone)]
+struct SharedCache(Cache);
+
+impl CacheBackend<CacheKey<PostId>, CacheValue> for SharedCache {
+    fn get(&self, key: &CacheKey<PostId>) -> Option<CacheValue> {
+        self.0.get(key)
+    }
+
+    fn put(&self, key: CacheKey<PostId>, value: CacheValue) {
+        self.0.put(key, value)
+    }
+
+    fn invalidate(&self, key: &CacheKey<PostId>) {
+        self.0.invalidate(key)
+    }
+
+    fn invalidate_if(&self, predicate: &dyn Fn(&CacheKey<PostId>) -> bool) {
+        self.0.invalidate_if(predicate)
+    }
+}
+
+macro_rules! provide_from_ctx {
+    ($($ty:ty),*) => {
+        $(
+            impl Provider for $ty {
+                fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+                    Ok(ctx.get::<Self>().unwrap().clone())
+                }
+            }
+        )*
+    };
+}
+
+provide_from_ctx!(Repo, Txn, SharedCache);
+
+#[tokio::test]
+async fn t_dirty_keys_shared_in_txn() -> anyhow::Result<()> {
+    let repo = Repo::default();
+    let txn = Txn::default();
+    let mut ctx = ProviderContext::new()
+        .with_instance(repo.clone())
+        .with_instance(txn.clone())
+        .with_instance(SharedCache(Cache::new(100, Duration::from_secs(60))));
+    let mut a = CachedRepository::<Post, Repo, Txn, SharedCache>::build(&mut ctx)?;
+    let mut b = CachedRepository::<Post, Repo, Txn, SharedCache>::build(&mut ctx)?;
+
+    let mut post = Repository::read::<PostFull, _>(&mut a, PostId(1))
+        .await?
+        .unwrap();
+    post.title.set("new".to_string());
+
+    txn.begin();
+    a.update(&post).await?.ignore_effect();
+    // The other decorator sees the uncommitted write and does not cache it
+    let read = Repository::read::<PostFull, _>(&mut b, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*read.title, "new");
+    assert_eq!(*repo.reads.borrow(), 2);
+    // Reads in a transaction never populate the cache
+    Repository::read::<PostFull, _>(&mut b, PostId(2))
+        .await?
+        .unwrap();
+    *repo.title.borrow_mut() = String::new();
+    txn.end(TxnResult::RolledBack);
+
+    // Served by the entry cached before the transaction
+    let read = Repository::read::<PostFull, _>(&mut b, PostId(1))
+        .await?
+        .unwrap();
+    assert_eq!(*read.title, "");
+    assert_eq!(*repo.reads.borrow(), 3);
+    Repository::read::<PostFull, _>(&mut b, PostId(2))
+        .await?
+        .unwrap();
+    assert_eq!(*repo.reads.borrow(), 4);
+
+    Ok(())
+}
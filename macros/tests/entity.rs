@@ -51,3 +51,40 @@ pub struct FileMeta {
     size: u64,
     is_link: bool,
 }
+
+#[test]
+fn t_field_enum() {
+    use bagua::entity::{Entity, FieldEnum};
+
+    assert_eq!(FileNodeFieldEnum::sys_id(), FileNodeFieldEnum::Id);
+    assert_eq!(FileNodeFieldEnum::Filename2.field_name(), "filename2");
+
+    let _: <FileNode as Entity>::FieldEnum = FileNodeFieldEnum::Filename;
+}
+
+#[test]
+fn t_changed_nested_fields() {
+    use bagua::entity::Entity;
+
+    let mut node = FileNode::from(FileNodeFull {
+        id: FileNodeId(1),
+        filename: "a".to_string(),
+        filename2: None,
+        foreign: HashSet::new(),
+        meta: FileMetaFull {
+            size: 0,
+            is_link: false,
+        },
+    });
+    assert!(node.changed_nested_fields().is_empty());
+
+    node.meta.size.set(1);
+    assert_eq!(node.changed_nested_fields(), ["meta"]);
+
+    node.foreign.add(FileNodeForeign {
+        id: FileNodeId(2),
+        _other_field: String::new(),
+    });
+    assert_eq!(node.changed_nested_fields(), ["foreign", "meta"]);
+    assert!(node.changed_fields().is_empty());
+}
@@ -0,0 +1,256 @@
+use anyhow::Context;
+use bagua::{
+    entity::SysId,
+    repository::{
+        event_sourced::{
+            AggregateEvent, AppendEffect, EventSourced, EventSourcedRepository, EventStore,
+            InMemoryEventStore,
+        },
+        ConflictTarget, DeleteEffect, Repository, SaveEffect, SubsetLoader, SubsetReader,
+    },
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct AccountId(i64);
+
+impl SysId for AccountId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+#[subset_attr(derive(Debug, PartialEq, serde::Serialize, serde::Deserialize))]
+pub struct Account {
+    id: AccountId,
+    owner: String,
+    balance: i64,
+    tags: Vec<String>,
+    address: Address,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Address {
+    city: String,
+    zip: Option<u32>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum AccountEvent {
+    Deposited(i64),
+    Withdrawn(i64),
+}
+
+impl EventSourced for Account {
+    type State = AccountFull;
+    type Event = AccountEvent;
+
+    fn apply(
+        state: Option<AccountFull>,
+        event: &AccountEvent,
+    ) -> anyhow::Result<Option<AccountFull>> {
+        let mut account = state.context("account does not exist")?;
+        match event {
+            AccountEvent::Deposited(amount) => account.balance += amount,
+            AccountEvent::Withdrawn(amount) => account.balance -= amount,
+        }
+
+        Ok(Some(account))
+    }
+}
+
+type Repo = EventSourcedRepository<Account, InMemoryEventStore>;
+
+fn account(id: i64) -> Account {
+    Account::from(AccountFull {
+        id: AccountId(id),
+        owner: "alice".to_string(),
+        balance: 0,
+        tags: vec![],
+        address: Address {
+            city: "Paris".to_string(),
+            zip: None,
+        },
+    })
+}
+
+#[tokio::test]
+async fn t_event_sourced() -> anyhow::Result<()> {
+    let store = InMemoryEventStore::new();
+    let mut repo = Repo::new(store.clone());
+
+    assert!(repo.save(&account(1)).await?.is_ok());
+    assert!(matches!(
+        repo.save(&account(1)).await?,
+        SaveEffect::Conflict(ConflictTarget::SysId)
+    ));
+
+    let effect = repo
+        .append_events(
+            &AccountId(1),
+            vec![AccountEvent::Deposited(100), AccountEvent::Withdrawn(30)],
+        )
+        .await?;
+    assert_eq!(effect, AppendEffect::Ok { version: 3 });
+
+    let mut account: Account = SubsetLoader::<AccountFull>::load(&mut repo, AccountId(1))
+        .await?
+        .unwrap()
+        .into();
+    assert_eq!(*account.balance, 70);
+    assert_eq!(repo.loaded_version(&AccountId(1)), Some(3));
+
+    account.owner.set("bob".to_string());
+    assert!(repo.update(&account).await?.is_ok());
+    let state = SubsetReader::<AccountFull>::read(&mut repo, AccountId(1))
+        .await?
+        .unwrap();
+    assert_eq!(state.owner, "bob");
+    assert_eq!(state.balance, 70);
+
+    // Another writer appends after the aggregate was loaded
+    let mut other = Repo::new(store.clone());
+    other
+        .append_events(&AccountId(1), vec![AccountEvent::Deposited(5)])
+        .await?
+        .ignore_effect();
+    let mut stale = Repo::new(store.clone());
+    let mut stale_account: Account = SubsetLoader::<AccountFull>::load(&mut stale, AccountId(1))
+        .await?
+        .unwrap()
+        .into();
+    other
+        .append_events(&AccountId(1), vec![AccountEvent::Deposited(5)])
+        .await?
+        .ignore_effect();
+    stale_account.balance.set(0);
+    assert!(stale.update(&stale_account).await?.is_conflict());
+
+    let history = repo.history(&AccountId(1)).await?;
+    assert_eq!(history.len(), 6);
+    assert!(matches!(history[0], (1, AggregateEvent::Created(_))));
+    assert!(matches!(history[3], (4, AggregateEvent::FieldsChanged(_))));
+    let replayed = repo.replay(&AccountId(1), 2).await?.unwrap();
+    assert_eq!(replayed.balance, 100);
+    assert_eq!(replayed.owner, "alice");
+
+    assert!(repo.exists(AccountId(1)).await?);
+    assert!(repo.delete(AccountId(1)).await?.is_ok());
+    assert!(!repo.exists(AccountId(1)).await?);
+    assert!(matches!(
+        repo.delete(AccountId(1)).await?,
+        DeleteEffect::NotFound
+    ));
+    assert!(repo.update(&account).await?.is_not_found());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_event_sourced_snapshot() -> anyhow::Result<()> {
+    let mut store = InMemoryEventStore::new();
+    let mut repo = Repo::new(store.clone()).with_snapshot_every(3);
+
+    repo.save(&account(1)).await?.ignore_effect();
+    repo.append_events(&AccountId(1), vec![AccountEvent::Deposited(10)])
+        .await?
+        .ignore_effect();
+    assert_eq!(store.load_snapshot("Account", "1").await?, None);
+
+    repo.append_events(
+        &AccountId(1),
+        vec![AccountEvent::Deposited(10), AccountEvent::Deposited(10)],
+    )
+    .await?
+    .ignore_effect();
+    let snapshot = store.load_snapshot("Account", "1").await?.unwrap();
+    assert_eq!(snapshot.version, 4);
+    assert_eq!(snapshot.state["balance"], 30);
+
+    // Loading folds the events after the snapshot
+    repo.append_events(&AccountId(1), vec![AccountEvent::Withdrawn(5)])
+        .await?
+        .ignore_effect();
+    let state = SubsetReader::<AccountFull>::read(&mut repo, AccountId(1))
+        .await?
+        .unwrap();
+    assert_eq!(state.balance, 25);
+    assert_eq!(
+        store.load_snapshot("Account", "1").await?.unwrap().version,
+        4
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_event_sourced_update_requires_load() -> anyhow::Result<()> {
+    let store = InMemoryEventStore::new();
+    let mut repo = Repo::new(store.clone());
+    repo.save(&account(1)).await?.ignore_effect();
+
+    let mut unloaded = Repo::new(store.clone());
+    let mut account = account(1);
+    account.balance.set(10);
+    assert!(unloaded.update(&account).await.is_err());
+
+    SubsetLoader::<AccountFull>::load(&mut unloaded, AccountId(1)).await?;
+    assert!(unloaded.update(&account).await?.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_event_sourced_unknown_field() -> anyhow::Result<()> {
+    let mut store = InMemoryEventStore::new();
+    let mut repo = Repo::new(store.clone());
+    repo.save(&account(1)).await?.ignore_effect();
+
+    // A field stored under a name the state does not serialize, e.g. after a serde rename
+    let renamed = AggregateEvent::<AccountEvent>::FieldsChanged(serde_json::Map::from_iter([(
+        "amount".to_string(),
+        serde_json::json!(10),
+    )]));
+    store
+        .append("Account", "1", 1, vec![serde_json::to_value(renamed)?])
+        .await?
+        .ignore_effect();
+    assert!(SubsetReader::<AccountFull>::read(&mut repo, AccountId(1))
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_event_sourced_json_fields() -> anyhow::Result<()> {
+    let mut repo = Repo::new(InMemoryEventStore::new());
+    let mut account = account(1);
+    account.tags.set(vec!["vip".to_string()]);
+    repo.save(&account).await?.ignore_effect();
+
+    let mut account: Account = SubsetLoader::<AccountFull>::load(&mut repo, AccountId(1))
+        .await?
+        .unwrap()
+        .into();
+    assert_eq!(*account.tags, vec!["vip".to_string()]);
+
+    account.tags.to_mut().push("new".to_string());
+    account.address.set(Address {
+        city: "Lyon".to_string(),
+        zip: Some(69001),
+    });
+    assert!(repo.update(&account).await?.is_ok());
+
+    let state = SubsetLoader::<AccountFull>::load(&mut repo, AccountId(1))
+        .await?
+        .unwrap();
+    assert_eq!(state.tags, vec!["vip".to_string(), "new".to_string()]);
+    assert_eq!(state.address.city, "Lyon");
+    assert_eq!(state.address.zip, Some(69001));
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use bagua::{
+    entity::SysId,
+    flake_id,
+    repository::{
+        criteria::{Criteria, FieldAccess, FieldExpr, Value},
+        page::{Keyset, PageRequest},
+    },
+    Entity,
+};
+
+flake_id!(PostId, @serde);
+
+#[Entity]
+#[subset_attr(derive(Clone))]
+pub struct Post {
+    id: PostId,
+    title: String,
+}
+
+fn posts() -> Vec<PostFull> {
+    [9, 10, 100, 11]
+        .into_iter()
+        .map(|id| PostFull {
+            id: PostId(id),
+            title: format!("post {id}"),
+        })
+        .collect()
+}
+
+#[test]
+fn t_flake_id_criteria() {
+    let post = &posts()[1];
+    assert_eq!(post.field_value(PostFieldEnum::Id), Value::Int(10));
+    assert!(PostFieldEnum::Id.eq(PostId(10)).matches(post));
+    assert!(PostFieldEnum::Id.gt(PostId(9)).matches(post));
+
+    // Still a string for JSON clients
+    assert_eq!(serde_json::to_string(&PostId(10)).unwrap(), r#""10""#);
+
+    let ids = Criteria::new()
+        .order_by_asc(PostFieldEnum::Id)
+        .apply(posts())
+        .into_iter()
+        .map(|p| p.id.0)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![9, 10, 11, 100]);
+}
+
+#[test]
+fn t_flake_id_keyset() -> anyhow::Result<()> {
+    let mut page = PageRequest::first(2);
+    let mut seen = vec![];
+    loop {
+        let keyset = Keyset::new(Criteria::new(), &page)?;
+        let result = keyset.page(keyset.criteria().apply(posts()))?;
+        seen.extend(result.items.iter().map(|p| p.id.0));
+
+        match result.next_cursor {
+            Some(cursor) => page = PageRequest::after(cursor, 2),
+            None => break,
+        }
+    }
+
+    assert_eq!(seen, vec![9, 10, 11, 100]);
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bagua::{
+    entity::{HistoryEntity, SysId},
+    repository::{
+        AsOfSubsetReader, DeleteEffect, Repository, SaveEffect, SaveEffectOf, UpdateEffect,
+    },
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct TicketId(i64);
+
+impl SysId for TicketId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+#[entity(history)]
+#[subset_attr(derive(Clone, Debug, PartialEq))]
+pub struct Ticket {
+    id: TicketId,
+    status: String,
+}
+
+fn assert_history<E: HistoryEntity>() {}
+
+fn at(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Versions of a ticket with the time they became current, `None` once deleted
+#[derive(Default)]
+struct Repo {
+    now: u64,
+    versions: HashMap<TicketId, Vec<(u64, Option<TicketFull>)>>,
+}
+
+impl AsOfSubsetReader<TicketFull> for Repo {
+    async fn read_as_of<I>(&mut self, id: I, at: SystemTime) -> anyhow::Result<Option<TicketFull>>
+    where
+        for<'a> TicketIdent: From<I>,
+    {
+        let at = at.duration_since(UNIX_EPOCH)?.as_secs();
+        let versions = self.versions.get(&TicketIdent::from(id));
+        Ok(versions
+            .and_then(|versions| versions.iter().rev().find(|(from, _)| *from <= at))
+            .and_then(|(_, version)| version.clone()))
+    }
+}
+
+impl Repository<Ticket> for Repo {
+    async fn save(&mut self, entity: &Ticket) -> anyhow::Result<SaveEffectOf<Ticket>> {
+        self.update(entity).await?.ignore_effect();
+        Ok(SaveEffect::Ok)
+    }
+
+    async fn update(&mut self, entity: &Ticket) -> anyhow::Result<UpdateEffect> {
+        let version = TicketFull {
+            id: entity.id,
+            status: entity.status.to_string(),
+        };
+        self.versions
+            .entry(entity.id)
+            .or_default()
+            .push((self.now, Some(version)));
+        Ok(UpdateEffect::Ok)
+    }
+
+    async fn delete<I>(&mut self, id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> TicketIdent: From<I>,
+    {
+        let id = TicketIdent::from(id);
+        self.versions.entry(id).or_default().push((self.now, None));
+        Ok(DeleteEffect::Ok)
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> TicketIdent: From<I>,
+    {
+        Ok(true)
+    }
+}
+
+#[tokio::test]
+async fn t_read_as_of() -> anyhow::Result<()> {
+    assert_history::<Ticket>();
+
+    let mut repo = Repo::default();
+    let mut ticket = Ticket::from(TicketFull {
+        id: TicketId(1),
+        status: "open".to_string(),
+    });
+    repo.now = 10;
+    repo.save(&ticket).await?.ignore_effect();
+    repo.now = 20;
+    ticket.status.set("closed".to_string());
+    repo.update(&ticket).await?.ignore_effect();
+    repo.now = 30;
+    repo.delete(TicketId(1)).await?.ignore_effect();
+
+    let ticket = Repository::read_as_of::<TicketFull, _>(&mut repo, TicketId(1), at(15)).await?;
+    assert_eq!(*ticket.unwrap().status, "open");
+    let ticket = Repository::read_as_of::<TicketFull, _>(&mut repo, TicketId(1), at(25)).await?;
+    assert_eq!(*ticket.unwrap().status, "closed");
+    let ticket = Repository::read_as_of::<TicketFull, _>(&mut repo, TicketId(1), at(35)).await?;
+    assert!(ticket.is_none());
+    let ticket = Repository::read_as_of::<TicketFull, _>(&mut repo, TicketId(1), at(5)).await?;
+    assert!(ticket.is_none());
+
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use std::sync::{Arc, Mutex};
+
+use bagua::{
+    entity::SysId,
+    repository::{
+        identity_map::{IdentityMap, WithIdentityMap},
+        unit_of_work::UnitOfWork,
+        DeleteEffect, Repository, SaveEffectOf, SubsetLoader, UpdateEffect,
+    },
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct UserId(i32);
+
+impl SysId for UserId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+#[subset(UserName {name})]
+pub struct User {
+    id: UserId,
+    #[entity(biz_id)]
+    name: String,
+    age: u32,
+}
+
+#[derive(Clone, Default)]
+struct Repo {
+    identity_map: IdentityMap,
+    loads: usize,
+    updates: Arc<Mutex<Vec<u32>>>,
+}
+
+impl WithIdentityMap for Repo {
+    fn identity_map(&self) -> &IdentityMap {
+        &self.identity_map
+    }
+}
+
+impl Repo {
+    fn load_id(&mut self, id: UserIdent) -> UserId {
+        self.loads += 1;
+        match id {
+            UserIdent::SysId(id) => *id,
+            UserIdent::Name(name) => UserId(name.len() as i32),
+        }
+    }
+}
+
+impl SubsetLoader<UserFull> for Repo {
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<UserFull>>
+    where
+        for<'a> UserIdent<'a>: From<I>,
+    {
+        let id = self.load_id(UserIdent::from(id));
+        Ok(Some(UserFull {
+            id,
+            name: "a".repeat(id.0 as usize),
+            age: 18,
+        }))
+    }
+}
+
+impl SubsetLoader<UserName> for Repo {
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<UserName>>
+    where
+        for<'a> UserIdent<'a>: From<I>,
+    {
+        let id = self.load_id(UserIdent::from(id));
+        Ok(Some(UserName {
+            id,
+            name: "a".repeat(id.0 as usize),
+        }))
+    }
+}
+
+impl Repository<User> for Repo {
+    async fn save(&mut self, _entity: &User) -> anyhow::Result<SaveEffectOf<User>> {
+        unreachable!()
+    }
+
+    async fn update(&mut self, entity: &User) -> anyhow::Result<UpdateEffect> {
+        self.updates.lock().unwrap().push(*entity.age);
+        Ok(UpdateEffect::Ok)
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> UserIdent<'a>: From<I>,
+    {
+        unreachable!()
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> UserIdent<'a>: From<I>,
+    {
+        unreachable!()
+    }
+}
+
+#[tokio::test]
+async fn t_same_instance_in_txn() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+    repo.identity_map.activate();
+
+    let a = repo.find_tracked::<UserFull, _>(UserId(3)).await?.unwrap();
+    let b = repo.find_tracked::<UserFull, _>(UserId(3)).await?.unwrap();
+    assert!(a.ptr_eq(&b));
+    assert_eq!(repo.loads, 1);
+
+    // Biz ids hit the database, but the tracked instance is returned
+    let c = repo
+        .find_tracked::<UserFull, _>("aaa".to_string())
+        .await?
+        .unwrap();
+    assert!(a.ptr_eq(&c));
+    assert_eq!(repo.loads, 2);
+
+    // A subset with fewer fields is served by the tracked instance
+    a.lock().await.age.set(20);
+    let d = repo.find_tracked::<UserName, _>(UserId(3)).await?.unwrap();
+    assert_eq!(*d.lock().await.age, 20);
+    assert_eq!(repo.loads, 2);
+
+    repo.identity_map.clear();
+    let e = repo.find_tracked::<UserFull, _>(UserId(3)).await?.unwrap();
+    assert!(!a.ptr_eq(&e));
+    assert_eq!(repo.loads, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_savepoint() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+    repo.identity_map.activate();
+
+    let a = repo.find_tracked::<UserFull, _>(UserId(1)).await?.unwrap();
+    repo.identity_map.begin_savepoint();
+    let b = repo.find_tracked::<UserFull, _>(UserId(2)).await?.unwrap();
+    repo.identity_map.begin_savepoint();
+    let c = repo.find_tracked::<UserFull, _>(UserId(3)).await?.unwrap();
+    repo.identity_map.release_savepoint();
+    assert_eq!(repo.loads, 3);
+
+    // Entities loaded since the outer savepoint are evicted, the earlier ones stay
+    repo.identity_map.rollback_to_savepoint();
+    let a2 = repo.find_tracked::<UserFull, _>(UserId(1)).await?.unwrap();
+    assert!(a.ptr_eq(&a2));
+    assert_eq!(repo.loads, 3);
+    let b2 = repo.find_tracked::<UserFull, _>(UserId(2)).await?.unwrap();
+    let c2 = repo.find_tracked::<UserFull, _>(UserId(3)).await?.unwrap();
+    assert!(!b.ptr_eq(&b2));
+    assert!(!c.ptr_eq(&c2));
+    assert_eq!(repo.loads, 5);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_missing_fields() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+    repo.identity_map.activate();
+
+    repo.find_tracked::<UserName, _>(UserId(3)).await?.unwrap();
+    let Err(err) = repo.find_tracked::<UserFull, _>(UserId(3)).await else {
+        panic!("expected an error");
+    };
+    assert!(err.to_string().contains("[\"age\"]"), "{err}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_inactive() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+
+    let a = repo.find_tracked::<UserFull, _>(UserId(3)).await?.unwrap();
+    let b = repo.find_tracked::<UserFull, _>(UserId(3)).await?.unwrap();
+    assert!(!a.ptr_eq(&b));
+    assert_eq!(repo.loads, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_unit_of_work() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+    repo.identity_map.activate();
+    let uow = UnitOfWork::new(());
+
+    let a = uow
+        .find_tracked::<UserFull, _, _>(&mut repo, UserId(3))
+        .await?
+        .unwrap();
+    let b = uow
+        .find_tracked::<UserName, _, _>(&mut repo, UserId(3))
+        .await?
+        .unwrap();
+    assert!(a.ptr_eq(&b));
+    assert_eq!(uow.len(), 1);
+
+    // A change made through any handle is flushed once
+    b.lock().await.age.set(20);
+    uow.flush().await?;
+    assert_eq!(*repo.updates.lock().unwrap(), vec![20]);
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use bagua::{
+    entity::SysId,
+    repository::{
+        DeleteEffect, LockEffect, LockOptions, LockWait, LockingSubsetLoader, Repository,
+        SaveEffect, SaveEffectOf, UpdateEffect,
+    },
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct WalletId(i64);
+
+impl SysId for WalletId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+pub struct Wallet {
+    id: WalletId,
+    balance: i64,
+}
+
+/// Balances, and whether the row is locked by another transaction
+#[derive(Default)]
+struct Repo {
+    rows: HashMap<WalletId, (i64, bool)>,
+}
+
+impl LockingSubsetLoader<WalletFull> for Repo {
+    async fn load_locked<I>(
+        &mut self,
+        id: I,
+        lock: LockOptions,
+    ) -> anyhow::Result<LockEffect<WalletFull>>
+    where
+        for<'a> WalletIdent: From<I>,
+    {
+        let id = WalletIdent::from(id);
+        let Some((balance, locked)) = self.rows.get(&id).copied() else {
+            return Ok(LockEffect::NotFound);
+        };
+
+        Ok(match (locked, lock.wait) {
+            (false, _) => LockEffect::Locked(WalletFull { id, balance }),
+            (true, LockWait::SkipLocked) => LockEffect::NotFound,
+            (true, _) => LockEffect::Timeout,
+        })
+    }
+}
+
+impl Repository<Wallet> for Repo {
+    async fn save(&mut self, _entity: &Wallet) -> anyhow::Result<SaveEffectOf<Wallet>> {
+        Ok(SaveEffect::Ok)
+    }
+
+    async fn update(&mut self, entity: &Wallet) -> anyhow::Result<UpdateEffect> {
+        self.rows.insert(entity.id, (*entity.balance, false));
+        Ok(UpdateEffect::Ok)
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> WalletIdent: From<I>,
+    {
+        Ok(DeleteEffect::Ok)
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> WalletIdent: From<I>,
+    {
+        Ok(true)
+    }
+}
+
+#[tokio::test]
+async fn t_find_for_update() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+    repo.rows.insert(WalletId(1), (100, false));
+    repo.rows.insert(WalletId(2), (50, true));
+
+    let mut wallet = repo
+        .find_for_update::<WalletFull, _>(WalletId(1), LockOptions::update())
+        .await?
+        .locked()
+        .unwrap();
+    wallet.balance.set(*wallet.balance - 30);
+    repo.update(&wallet).await?.ignore_effect();
+    assert_eq!(repo.rows[&WalletId(1)], (70, false));
+
+    let effect = repo
+        .find_for_update::<WalletFull, _>(WalletId(2), LockOptions::update().nowait())
+        .await?;
+    assert!(effect.is_timeout());
+
+    let effect = repo
+        .find_for_update::<WalletFull, _>(WalletId(2), LockOptions::update().skip_locked())
+        .await?;
+    assert!(effect.is_not_found());
+
+    let effect = repo
+        .find_for_update::<WalletFull, _>(WalletId(3), LockOptions::no_key_update())
+        .await?;
+    assert!(effect.is_not_found());
+
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use std::{cell::RefCell, collections::HashSet, future::Future, rc::Rc};
+
+use bagua::{
+    db::{AsyncTxCallback, BeforeCommitHook, TxCallback, TxnManager, TxnResult, TxnState},
+    entity::{Entity, SysId},
+    futures,
+    repository::{
+        membership::{BloomFilter, FilteredRepository, MembershipFilter},
+        DeleteEffect, FastExists, Repository, SaveEffect, SaveEffectOf, UpdateEffect,
+    },
+    result::BizResult,
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct MemberId(i32);
+
+impl SysId for MemberId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+pub struct Member {
+    id: MemberId,
+    #[entity(biz_id)]
+    email: String,
+    #[entity(biz_id)]
+    phone: Option<u64>,
+}
+
+fn member(id: i32, email: &str, phone: Option<u64>) -> Member {
+    Member::from(MemberFull {
+        id: MemberId(id),
+        email: email.to_string(),
+        phone,
+    })
+}
+
+#[derive(Clone, Default)]
+struct Repo {
+    exists_calls: Rc<RefCell<usize>>,
+}
+
+impl Repository<Member> for Repo {
+    async fn save(&mut self, _entity: &Member) -> anyhow::Result<SaveEffectOf<Member>> {
+        Ok(SaveEffect::Ok)
+    }
+
+    async fn update(&mut self, _entity: &Member) -> anyhow::Result<UpdateEffect> {
+        Ok(UpdateEffect::Ok)
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> MemberIdent<'a>: From<I>,
+    {
+        Ok(DeleteEffect::Ok)
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> MemberIdent<'a>: From<I>,
+    {
+        *self.exists_calls.borrow_mut() += 1;
+        Ok(false)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Txn {
+    state: Rc<RefCell<Option<TxnState>>>,
+    callbacks: Rc<RefCell<Vec<Box<dyn TxCallback>>>>,
+}
+
+impl Txn {
+    fn begin(&self) {
+        *self.state.borrow_mut() = Some(TxnState::Begun { depth: 1 });
+    }
+
+    fn end(&self, result: TxnResult) {
+        *self.state.borrow_mut() = None;
+        for cb in self.callbacks.take() {
+            cb.call(result);
+        }
+    }
+}
+
+impl TxnManager for Txn {
+    async fn do_transaction<F, T, E>(&mut self, tx: F) -> BizResult<T, E>
+    where
+        F: Future<Output = BizResult<T, E>>,
+    {
+        tx.await
+    }
+
+    fn register_callback<H>(&self, callback: H)
+    where
+        H: TxCallback,
+    {
+        self.callbacks.borrow_mut().push(Box::new(callback));
+    }
+
+    fn register_async_callback<H>(&self, _callback: H)
+    where
+        H: AsyncTxCallback,
+    {
+        unreachable!()
+    }
+
+    fn register_before_commit<H>(&self, _hook: H)
+    where
+        H: BeforeCommitHook,
+    {
+        unreachable!()
+    }
+
+    fn state(&self) -> TxnState {
+        self.state.borrow().unwrap_or(TxnState::NotInTransaction)
+    }
+}
+
+type Filter = BloomFilter<MemberIdentOwned>;
+
+#[test]
+fn t_entity_ids() {
+    let ids = member(1, "a@x.com", None).ids();
+    assert_eq!(ids.len(), 2);
+
+    let ids = member(1, "a@x.com", Some(42))
+        .ids()
+        .into_iter()
+        .map(|id| id.0)
+        .collect::<HashSet<_>>();
+    assert!(ids.contains(&MemberIdent::from(MemberId(1))));
+    assert!(ids.contains(&MemberIdent::from("a@x.com".to_string())));
+    assert!(ids.contains(&MemberIdent::from(42u64)));
+}
+
+#[tokio::test]
+async fn t_fast_exists() -> anyhow::Result<()> {
+    let repo = Repo::default();
+    let txn = Txn::default();
+    let filter = Filter::new(100, 0.01);
+    let mut filtered = FilteredRepository::new(repo.clone(), txn.clone(), filter.clone());
+
+    // Not seeded yet, the inner repository answers
+    assert_eq!(filtered.fast_exists(MemberId(1)).await?, FastExists::No);
+    assert_eq!(*repo.exists_calls.borrow(), 1);
+
+    let seed = futures::stream::iter([Ok(member(1, "a@x.com", None))]);
+    filter.rebuild(seed).await?;
+    assert!(filter.is_ready());
+
+    assert_eq!(
+        filtered.fast_exists("a@x.com".to_string()).await?,
+        FastExists::YesButNotSure
+    );
+    assert_eq!(
+        filtered.fast_exists("b@x.com".to_string()).await?,
+        FastExists::NoButNotSure
+    );
+    assert_eq!(*repo.exists_calls.borrow(), 1);
+
+    // Saved keys are added once committed
+    txn.begin();
+    filtered
+        .save(&member(2, "b@x.com", Some(7)))
+        .await?
+        .ignore_effect();
+    assert_eq!(filtered.fast_exists(7u64).await?, FastExists::NoButNotSure);
+    txn.end(TxnResult::Committed);
+    assert_eq!(filtered.fast_exists(7u64).await?, FastExists::YesButNotSure);
+
+    txn.begin();
+    filtered
+        .save(&member(3, "c@x.com", None))
+        .await?
+        .ignore_effect();
+    txn.end(TxnResult::RolledBack);
+    assert_eq!(
+        filtered.fast_exists("c@x.com".to_string()).await?,
+        FastExists::NoButNotSure
+    );
+
+    // Deleted keys stay until the filter is rebuilt
+    filtered.delete(MemberId(2)).await?.ignore_effect();
+    assert_eq!(filter.stale(), 1);
+    assert_eq!(
+        filtered.fast_exists(MemberId(2)).await?,
+        FastExists::YesButNotSure
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_insert_during_rebuild() -> anyhow::Result<()> {
+    use futures::StreamExt;
+
+    let filter = Filter::new(100, 0.01);
+    let written = member(2, "b@x.com", None);
+
+    // The key is written after the stream read past it
+    let seed = futures::stream::iter([member(1, "a@x.com", None)]).map(|member| {
+        for id in written.ids() {
+            filter.insert(&id);
+        }
+        Ok(member)
+    });
+    filter.rebuild(seed).await?;
+
+    for id in written.ids() {
+        assert!(filter.might_contain(&id));
+    }
+
+    // Inserts are no more buffered once rebuilt
+    let seed = futures::stream::iter([Ok(member(1, "a@x.com", None))]);
+    filter.rebuild(seed).await?;
+    assert!(!filter.might_contain(&written.ids()[0]));
+
+    Ok(())
+}
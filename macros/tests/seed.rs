@@ -0,0 +1,165 @@
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc, Mutex,
+};
+
+use bagua::{
+    entity::SysId,
+    repository::{
+        seed::{SeedDocument, Seeder},
+        DeleteEffect, Repository, SaveEffect, SaveEffectOf, UpdateEffect,
+    },
+    Entity,
+};
+
+static NEXT_ID: AtomicI64 = AtomicI64::new(1);
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct UserId(i64);
+
+impl SysId for UserId {
+    fn generate() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct PostId(i64);
+
+impl SysId for PostId {
+    fn generate() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[Entity]
+pub struct User {
+    id: UserId,
+    name: String,
+}
+
+#[Entity]
+pub struct Post {
+    id: PostId,
+    title: String,
+    author: UserId,
+    reviewer: Option<UserId>,
+}
+
+/// Saved rows, in the order of saving
+#[derive(Clone, Default)]
+struct Store(Arc<Mutex<Vec<String>>>);
+
+impl Store {
+    fn push(&self, row: String) {
+        self.0.lock().unwrap().push(row);
+    }
+}
+
+impl Repository<User> for Store {
+    async fn save(&mut self, entity: &User) -> anyhow::Result<SaveEffectOf<User>> {
+        self.push(format!("user {} {}", entity.id.0, *entity.name));
+        Ok(SaveEffect::Ok)
+    }
+
+    async fn update(&mut self, _entity: &User) -> anyhow::Result<UpdateEffect> {
+        unreachable!()
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> UserIdent: From<I>,
+    {
+        unreachable!()
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> UserIdent: From<I>,
+    {
+        unreachable!()
+    }
+}
+
+impl Repository<Post> for Store {
+    async fn save(&mut self, entity: &Post) -> anyhow::Result<SaveEffectOf<Post>> {
+        self.push(format!(
+            "post {} by {} reviewed by {:?}",
+            *entity.title,
+            entity.author.0,
+            entity.reviewer.map(|id| id.0)
+        ));
+        Ok(SaveEffect::Ok)
+    }
+
+    async fn update(&mut self, _entity: &Post) -> anyhow::Result<UpdateEffect> {
+        unreachable!()
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> PostIdent: From<I>,
+    {
+        unreachable!()
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        for<'a> PostIdent: From<I>,
+    {
+        unreachable!()
+    }
+}
+
+#[tokio::test]
+async fn t_seed() -> anyhow::Result<()> {
+    let store = Store::default();
+    let mut seeder = Seeder::new()
+        .register::<UserModel, _>("user", store.clone())
+        .register::<PostModel, _>("post", store.clone());
+
+    // Posts come first in the document but refer to users
+    let document = SeedDocument::from_json(
+        r#"{
+            "post": {
+                "hello": { "title": "@@hello", "author": "@user.alice", "reviewer": "@user.bob" }
+            },
+            "user": {
+                "alice": { "name": "Alice" },
+                "bob": { "name": "Bob" }
+            }
+        }"#,
+    )?;
+    let refs = seeder.seed(&document).await?;
+    let alice: UserId = refs.get("user.alice")?;
+    let bob: UserId = refs.get("user.bob")?;
+    assert_eq!(
+        *store.0.lock().unwrap(),
+        vec![
+            format!("user {} Alice", alice.0),
+            format!("user {} Bob", bob.0),
+            format!("post @hello by {} reviewed by Some({})", alice.0, bob.0),
+        ]
+    );
+
+    // Later documents refer to records seeded before
+    let document = SeedDocument::from_json(
+        r#"{ "post": { "again": { "title": "Again", "author": "@user.bob", "reviewer": null } } }"#,
+    )?;
+    seeder.seed(&document).await?;
+    assert_eq!(store.0.lock().unwrap().len(), 4);
+
+    let document = SeedDocument::from_json(
+        r#"{ "post": { "lost": { "title": "Lost", "author": "@user.carol" } } }"#,
+    )?;
+    assert!(seeder.seed(&document).await.is_err());
+
+    let document = SeedDocument::from_json(r#"{ "comment": { "first": {} } }"#)?;
+    assert!(seeder.seed(&document).await.is_err());
+
+    Ok(())
+}
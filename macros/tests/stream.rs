@@ -0,0 +1,151 @@
+use std::pin::pin;
+
+use bagua::futures::{StreamExt, TryStreamExt};
+use bagua::{
+    entity::SysId,
+    repository::{
+        criteria::{Criteria, FieldAccess, FieldExpr, Value},
+        stream::read_batch_stream,
+        BatchSubsetReader, DeleteEffect, Repository, SaveEffectOf, UpdateEffect,
+    },
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct RowId(i64);
+
+impl SysId for RowId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+#[subset_attr(derive(Clone))]
+pub struct Row {
+    id: RowId,
+    score: i64,
+}
+
+struct Repo {
+    rows: Vec<RowFull>,
+    reads: usize,
+}
+
+impl Repo {
+    fn new(count: i64) -> Self {
+        Self {
+            rows: (1..=count)
+                .map(|id| RowFull {
+                    id: RowId(id),
+                    score: id % 4,
+                })
+                .collect(),
+            reads: 0,
+        }
+    }
+}
+
+impl BatchSubsetReader<Criteria<RowFieldEnum>, RowFull> for Repo {
+    async fn read_batch(
+        &mut self,
+        condition: Criteria<RowFieldEnum>,
+    ) -> anyhow::Result<Vec<RowFull>> {
+        self.reads += 1;
+        Ok(condition.apply(self.rows.clone()))
+    }
+}
+
+impl Repository<Row> for Repo {
+    async fn save(&mut self, _entity: &Row) -> anyhow::Result<SaveEffectOf<Row>> {
+        unreachable!()
+    }
+
+    async fn update(&mut self, _entity: &Row) -> anyhow::Result<UpdateEffect> {
+        unreachable!()
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        RowIdent: From<I>,
+    {
+        unreachable!()
+    }
+
+    async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+    where
+        RowIdent: From<I>,
+    {
+        unreachable!()
+    }
+}
+
+#[tokio::test]
+async fn t_stream_in_chunks() -> anyhow::Result<()> {
+    let mut repo = Repo::new(10);
+    let criteria = Criteria::new().order_by_desc(RowFieldEnum::Score);
+
+    let ids = read_batch_stream(&mut repo, criteria, Some(3))
+        .map_ok(|row: RowFull| row.id.0)
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(ids, vec![3, 7, 2, 6, 10, 1, 5, 9, 4, 8]);
+    assert_eq!(repo.reads, 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_stream_is_lazy() -> anyhow::Result<()> {
+    let mut repo = Repo::new(10);
+    let criteria = Criteria::new().filter(RowFieldEnum::Score.ne(0));
+
+    {
+        let mut rows = pin!(repo.read_stream::<RowFull>(criteria, Some(2)));
+        let first = rows.next().await.unwrap()?;
+        assert_eq!(first.id, RowId(1));
+    }
+    assert_eq!(repo.reads, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_stream_limit() -> anyhow::Result<()> {
+    let mut repo = Repo::new(10);
+
+    let rows = repo
+        .read_stream::<RowFull>(Criteria::new().limit(5), Some(2))
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(rows.len(), 5);
+    assert_eq!(repo.reads, 3);
+
+    let rows = repo
+        .read_stream::<RowFull>(Criteria::new().offset(1), None)
+        .collect::<Vec<_>>()
+        .await;
+    assert!(rows.len() == 1 && rows[0].is_err());
+
+    Ok(())
+}
+
+#[test]
+fn t_generated_field_access() {
+    let full = RowFull {
+        id: RowId(7),
+        score: 3,
+    };
+    assert_eq!(full.field_value(RowFieldEnum::Id), Value::Int(7));
+    assert!(RowFieldEnum::Score.gt(2).matches(&full));
+
+    let row = Row::from(full.clone());
+    assert!(RowFieldEnum::Score.eq(3).matches(&row));
+
+    // Unloaded fields read as NULL
+    let mini = Row::from(RowMini { id: RowId(7) });
+    assert_eq!(mini.field_value(RowFieldEnum::Score), Value::Null);
+    assert!(!RowFieldEnum::Score.eq(3).not().matches(&mini));
+}
@@ -0,0 +1,345 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+use bagua::{
+    db::{AsyncTxCallback, BeforeCommitHook, TxCallback, TxnManager, TxnState},
+    entity::{ChildEntity, FieldGroup, SysId},
+    provider::{Provider, ProviderContext},
+    repository::{
+        unit_of_work::UnitOfWork, DeleteEffect, Repository, SaveEffectOf, SubsetLoader,
+        UpdateEffect,
+    },
+    result::BizResult,
+    usecase::{TxnUseCase, UseCase},
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct Id(i32);
+
+impl SysId for Id {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[Entity]
+pub struct Author {
+    id: Id,
+    name: String,
+}
+
+#[Entity]
+pub struct Book {
+    id: Id,
+    title: String,
+}
+
+impl ChildEntity for Book {
+    type Parent = Author;
+}
+
+#[derive(Clone, Default)]
+struct Repo {
+    updated: Rc<RefCell<Vec<String>>>,
+    conflict: bool,
+}
+
+impl SubsetLoader<AuthorFull> for Repo {
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<AuthorFull>>
+    where
+        for<'a> AuthorIdent: From<I>,
+    {
+        let id = AuthorIdent::from(id);
+        Ok(Some(AuthorFull {
+            id,
+            name: format!("author-{}", id.0),
+        }))
+    }
+}
+
+impl SubsetLoader<BookFull> for Repo {
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<BookFull>>
+    where
+        for<'a> BookIdent: From<I>,
+    {
+        let id = BookIdent::from(id);
+        Ok(Some(BookFull {
+            id,
+            title: format!("book-{}", id.0),
+        }))
+    }
+}
+
+macro_rules! impl_repo {
+    ($entity:ident, $describe:expr) => {
+        impl Repository<$entity> for Repo {
+            async fn save(&mut self, _entity: &$entity) -> anyhow::Result<SaveEffectOf<$entity>> {
+                unreachable!()
+            }
+
+            async fn update(&mut self, entity: &$entity) -> anyhow::Result<UpdateEffect> {
+                if self.conflict {
+                    return Ok(UpdateEffect::Conflict);
+                }
+                self.updated.borrow_mut().push($describe(entity));
+                Ok(UpdateEffect::Ok)
+            }
+
+            async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+            where
+                for<'a> Id: From<I>,
+            {
+                unreachable!()
+            }
+
+            async fn exists<I>(&mut self, _id: I) -> anyhow::Result<bool>
+            where
+                for<'a> Id: From<I>,
+            {
+                unreachable!()
+            }
+        }
+    };
+}
+
+impl_repo!(Author, |a: &Author| format!("author:{}", *a.name));
+impl_repo!(Book, |b: &Book| format!("book:{}", *b.title));
+
+impl Provider for Repo {
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(ctx.get::<Self>().cloned().unwrap_or_default())
+    }
+}
+
+/// Records the end states of the outermost transactions
+#[derive(Clone, Default)]
+struct Txn {
+    state: Arc<Mutex<Vec<TxnState>>>,
+    depth: Arc<Mutex<u32>>,
+}
+
+impl Provider for Txn {
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(ctx.get::<Self>().cloned().unwrap_or_default())
+    }
+}
+
+impl TxnManager for Txn {
+    async fn do_transaction<F, T, E>(&mut self, tx: F) -> BizResult<T, E>
+    where
+        F: Future<Output = BizResult<T, E>>,
+    {
+        *self.depth.lock().unwrap() += 1;
+        let res = tx.await;
+        let mut depth = self.depth.lock().unwrap();
+        *depth -= 1;
+        if *depth == 0 {
+            let state = match res {
+                Ok(Ok(_)) => TxnState::Committed,
+                _ => TxnState::RolledBack,
+            };
+            self.state.lock().unwrap().push(state);
+        }
+        res
+    }
+
+    fn register_callback<H>(&self, _callback: H)
+    where
+        H: TxCallback,
+    {
+    }
+
+    fn register_async_callback<H>(&self, _callback: H)
+    where
+        H: AsyncTxCallback,
+    {
+        unreachable!()
+    }
+
+    fn register_before_commit<H>(&self, _hook: H)
+    where
+        H: BeforeCommitHook,
+    {
+        unreachable!()
+    }
+
+    fn state(&self) -> TxnState {
+        match *self.depth.lock().unwrap() {
+            0 => TxnState::NotInTransaction,
+            depth => TxnState::Begun { depth },
+        }
+    }
+}
+
+#[tokio::test]
+async fn t_flush_on_commit() {
+    let mut repo = Repo::default();
+    let txn = Txn::default();
+    let mut uow = UnitOfWork::new(txn.clone());
+    uow.depends_on_parent::<Book>();
+
+    let uow2 = uow.clone();
+    let res: BizResult<(), ()> = uow
+        .do_transaction(async {
+            let book = uow2
+                .find::<BookFull, _, _>(&mut repo, Id(2))
+                .await?
+                .unwrap();
+            let author = uow2
+                .find::<AuthorFull, _, _>(&mut repo, Id(1))
+                .await?
+                .unwrap();
+            let _unchanged = uow2
+                .find::<AuthorFull, _, _>(&mut repo, Id(3))
+                .await?
+                .unwrap();
+
+            assert!(!book.lock().await.has_changes());
+            book.lock().await.title.set("new book".to_string());
+            author.lock().await.name.set("new author".to_string());
+            assert!(book.lock().await.has_changes());
+
+            Ok(Ok(()))
+        })
+        .await;
+
+    assert!(matches!(res, Ok(Ok(()))));
+    assert!(uow.is_empty());
+    assert_eq!(
+        *repo.updated.borrow(),
+        vec!["author:new author", "book:new book"]
+    );
+    assert_eq!(*txn.state.lock().unwrap(), vec![TxnState::Committed]);
+}
+
+#[tokio::test]
+async fn t_abort_on_conflict() {
+    let mut repo = Repo {
+        conflict: true,
+        ..Default::default()
+    };
+    let txn = Txn::default();
+    let mut uow = UnitOfWork::new(txn.clone());
+
+    let uow2 = uow.clone();
+    let res: BizResult<(), ()> = uow
+        .do_transaction(async {
+            let author = uow2
+                .find::<AuthorFull, _, _>(&mut repo, Id(1))
+                .await?
+                .unwrap();
+            author.lock().await.name.set("new author".to_string());
+
+            Ok(Ok(()))
+        })
+        .await;
+
+    assert!(res.is_err());
+    assert!(repo.updated.borrow().is_empty());
+    assert_eq!(*txn.state.lock().unwrap(), vec![TxnState::RolledBack]);
+}
+
+#[tokio::test]
+async fn t_no_flush_on_biz_error() {
+    let mut repo = Repo::default();
+    let mut uow = UnitOfWork::new(Txn::default());
+
+    let uow2 = uow.clone();
+    let res: BizResult<(), &str> = uow
+        .do_transaction(async {
+            let author = uow2
+                .find::<AuthorFull, _, _>(&mut repo, Id(1))
+                .await?
+                .unwrap();
+            author.lock().await.name.set("new author".to_string());
+
+            Ok(Err("biz"))
+        })
+        .await;
+
+    assert!(matches!(res, Ok(Err("biz"))));
+    assert!(uow.is_empty());
+    assert!(repo.updated.borrow().is_empty());
+}
+
+/// Renames a book, in the transaction of the calling use case
+struct RenameBook {
+    uow: UnitOfWork<Txn>,
+    repo: Repo,
+}
+
+impl Provider for RenameBook {
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self {
+            uow: UnitOfWork::build(ctx)?,
+            repo: Repo::build(ctx)?,
+        })
+    }
+}
+
+impl UseCase for RenameBook {
+    type Input = (i32, &'static str);
+    type Output = ();
+    type Error = &'static str;
+
+    async fn execute(&mut self, (id, title): Self::Input) -> BizResult<(), &'static str> {
+        let book = self
+            .uow
+            .find::<BookFull, _, _>(&mut self.repo, Id(id))
+            .await?
+            .unwrap();
+        book.lock().await.title.set(title.to_string());
+
+        if title.is_empty() {
+            return Ok(Err("empty title"));
+        }
+        Ok(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn t_nested_use_case() {
+    let repo = Repo::default();
+    let txn = Txn::default();
+    let mut uow = UnitOfWork::new(txn.clone());
+    let mut rename_book = TxnUseCase::<UnitOfWork<Txn>, RenameBook>::provide_with(|ctx| {
+        ctx.insert(repo.clone());
+        ctx.insert(uow.clone());
+    })
+    .unwrap();
+
+    let uow2 = uow.clone();
+    let mut repo2 = repo.clone();
+    let res: BizResult<(), &str> = uow
+        .do_transaction(async {
+            let author = uow2
+                .find::<AuthorFull, _, _>(&mut repo2, Id(1))
+                .await?
+                .unwrap();
+
+            rename_book.execute((2, "new book")).await?.unwrap();
+            // Rolled back, the book is no more tracked
+            let res = rename_book.execute((3, "")).await?;
+            assert_eq!(res, Err("empty title"));
+            assert_eq!(uow2.len(), 2);
+
+            // Changed after the nested use case, still flushed
+            author.lock().await.name.set("new author".to_string());
+            Ok(Ok(()))
+        })
+        .await;
+
+    assert!(matches!(res, Ok(Ok(()))));
+    assert!(uow.is_empty());
+    let mut updated = repo.updated.borrow().clone();
+    updated.sort();
+    assert_eq!(updated, vec!["author:new author", "book:new book"]);
+    assert_eq!(*txn.state.lock().unwrap(), vec![TxnState::Committed]);
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use bagua::{
+    entity::SysId,
+    repository::{
+        ConflictTarget, DeleteEffect, OnConflict, Repository, SaveEffect, SaveEffectOf,
+        UpdateEffect, UpsertEffect,
+    },
+    Entity,
+};
+
+#[derive(
+    PartialEq, Eq, Clone, Default, Copy, Hash, Debug, serde::Deserialize, serde::Serialize,
+)]
+pub struct AccountId(i32);
+
+impl SysId for AccountId {
+    fn generate() -> Self {
+        unreachable!()
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Default, Hash, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Username(String);
+
+#[Entity]
+pub struct Account {
+    id: AccountId,
+    #[entity(biz_id)]
+    email: String,
+    #[entity(biz_id)]
+    username: Username,
+    age: u32,
+}
+
+#[derive(Default)]
+struct Repo {
+    rows: HashMap<AccountId, (String, String, u32)>,
+    // A failed insert aborts a postgres transaction
+    failed_inserts: u32,
+}
+
+impl Repository<Account> for Repo {
+    async fn save(&mut self, entity: &Account) -> anyhow::Result<SaveEffectOf<Account>> {
+        let constraint = if self.rows.contains_key(&entity.id) {
+            "accounts_pkey"
+        } else if self.rows.values().any(|row| row.0 == *entity.email) {
+            "accounts_email_key"
+        } else if self.rows.values().any(|row| row.1 == entity.username.0) {
+            "accounts_username_key"
+        } else {
+            self.rows.insert(
+                entity.id,
+                (
+                    entity.email.to_string(),
+                    entity.username.0.clone(),
+                    *entity.age,
+                ),
+            );
+            return Ok(SaveEffect::Ok);
+        };
+        self.failed_inserts += 1;
+
+        Ok(SaveEffect::Conflict(ConflictTarget::from_constraint(
+            "accounts", constraint,
+        )))
+    }
+
+    async fn update(&mut self, entity: &Account) -> anyhow::Result<UpdateEffect> {
+        match self.rows.get_mut(&entity.id) {
+            Some(row) => {
+                row.2 = *entity.age;
+                Ok(UpdateEffect::Ok)
+            }
+            None => Ok(UpdateEffect::NotFound),
+        }
+    }
+
+    async fn delete<I>(&mut self, _id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> AccountIdent<'a>: From<I>,
+    {
+        unreachable!()
+    }
+
+    async fn exists<I>(&mut self, id: I) -> anyhow::Result<bool>
+    where
+        for<'a> AccountIdent<'a>: From<I>,
+    {
+        match AccountIdent::from(id) {
+            AccountIdent::SysId(id) => Ok(self.rows.contains_key(&*id)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn account(id: i32, email: &str, username: &str, age: u32) -> Account {
+    Account::from(AccountFull {
+        id: AccountId(id),
+        email: email.to_string(),
+        username: Username(username.to_string()),
+        age,
+    })
+}
+
+#[test]
+fn t_conflict_target_from_constraint() {
+    assert_eq!(
+        ConflictTarget::<AccountBizFieldEnum>::from_constraint("accounts", "accounts_pkey"),
+        ConflictTarget::SysId
+    );
+    assert_eq!(
+        ConflictTarget::from_constraint("accounts", "accounts_username_key"),
+        ConflictTarget::BizId(AccountBizFieldEnum::Username)
+    );
+    assert_eq!(
+        ConflictTarget::<AccountBizFieldEnum>::from_constraint("accounts", "accounts_phone_key"),
+        ConflictTarget::Unknown
+    );
+    assert_eq!(
+        ConflictTarget::from_constraint_map(
+            "accounts",
+            "uq_accounts_login",
+            &[("uq_accounts_login", AccountBizFieldEnum::Username)]
+        ),
+        ConflictTarget::BizId(AccountBizFieldEnum::Username)
+    );
+    assert_eq!(
+        ConflictTarget::from_constraint_map(
+            "accounts",
+            "accounts_email_key",
+            &[("uq_accounts_login", AccountBizFieldEnum::Username)]
+        ),
+        ConflictTarget::BizId(AccountBizFieldEnum::Email)
+    );
+}
+
+#[tokio::test]
+async fn t_save_conflict_on_biz_id() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+    repo.save(&account(1, "a@x.com", "a", 18))
+        .await?
+        .ignore_effect();
+
+    let effect = repo.save(&account(2, "a@x.com", "b", 18)).await?;
+    assert_eq!(
+        effect.conflict_target().and_then(|target| target.biz_id()),
+        Some(AccountBizFieldEnum::Email)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn t_upsert() -> anyhow::Result<()> {
+    let mut repo = Repo::default();
+
+    let effect = repo
+        .upsert(&account(1, "a@x.com", "a", 18), OnConflict::UpdateChanged)
+        .await?;
+    assert!(effect.is_inserted());
+
+    let effect = repo
+        .upsert(&account(1, "a@x.com", "a", 20), OnConflict::DoNothing)
+        .await?;
+    assert!(matches!(effect, UpsertEffect::Ignored));
+    assert_eq!(repo.rows[&AccountId(1)].2, 18);
+
+    let effect = repo
+        .upsert(&account(1, "a@x.com", "a", 20), OnConflict::UpdateChanged)
+        .await?;
+    assert!(effect.is_updated());
+    assert_eq!(repo.rows[&AccountId(1)].2, 20);
+
+    let effect = repo
+        .upsert(&account(2, "b@x.com", "a", 20), OnConflict::UpdateChanged)
+        .await?;
+    assert!(matches!(
+        effect,
+        UpsertEffect::Conflict(ConflictTarget::BizId(AccountBizFieldEnum::Username))
+    ));
+
+    let effect = repo
+        .upsert(&account(1, "a@x.com", "a", 30), OnConflict::Fail)
+        .await?;
+    assert!(matches!(
+        effect,
+        UpsertEffect::Conflict(ConflictTarget::SysId)
+    ));
+    // Only the conflict on the username tried to insert
+    assert_eq!(repo.failed_inserts, 1);
+
+    Ok(())
+}
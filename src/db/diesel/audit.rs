@@ -0,0 +1,96 @@
+//! Postgres audit sink
+//!
+//! The table is created by [`AUDIT_TABLE_SQL`], or a table of the same shape with another
+//! name given to [`AuditSinkDiesel::with_table`].
+
+use std::time::UNIX_EPOCH;
+
+use diesel::{
+    pg::Pg,
+    sql_types::{Double, Jsonb, Nullable, Text},
+};
+
+use crate::{
+    provider::{Provider, ProviderContext},
+    repository::audit::{AuditAction, AuditRecord, AuditSink},
+};
+
+use super::DieselSqlRunner;
+
+pub const DEFAULT_AUDIT_TABLE: &str = "audit_log";
+
+/// Schema of the default audit table
+pub const AUDIT_TABLE_SQL: &str = r#"
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    entity TEXT NOT NULL,
+    entity_id TEXT NOT NULL,
+    action TEXT NOT NULL,
+    actor TEXT,
+    changes JSONB NOT NULL,
+    at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_log_entity_idx ON audit_log (entity, entity_id);
+"#;
+
+/// Audit sink on a diesel adapter, records are written on the adapter's connection and
+/// hence inside its transaction
+#[derive(Clone)]
+pub struct AuditSinkDiesel<A> {
+    adapter: A,
+    table: &'static str,
+}
+
+impl<A> Provider for AuditSinkDiesel<A>
+where
+    A: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(A::build(ctx)?))
+    }
+}
+
+impl<A> AuditSinkDiesel<A> {
+    pub fn new(adapter: A) -> Self {
+        Self {
+            adapter,
+            table: DEFAULT_AUDIT_TABLE,
+        }
+    }
+
+    /// Use another table, the name is put into the SQL as is
+    pub fn with_table(mut self, table: &'static str) -> Self {
+        self.table = table;
+        self
+    }
+}
+
+impl<A> AuditSink for AuditSinkDiesel<A>
+where
+    A: DieselSqlRunner<Pg>,
+{
+    async fn record(&mut self, record: AuditRecord) -> anyhow::Result<()> {
+        let action = match record.action {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+        };
+        let at = record.at.duration_since(UNIX_EPOCH)?.as_secs_f64();
+
+        let sql = format!(
+            "INSERT INTO {} (entity, entity_id, action, actor, changes, at) \
+             VALUES ($1, $2, $3, $4, $5, to_timestamp($6))",
+            self.table
+        );
+        let query = diesel::sql_query(sql)
+            .bind::<Text, _>(record.entity)
+            .bind::<Text, _>(record.entity_id)
+            .bind::<Text, _>(action)
+            .bind::<Nullable<Text>, _>(record.actor)
+            .bind::<Jsonb, _>(serde_json::Value::Object(record.changes))
+            .bind::<Double, _>(at);
+        self.adapter.sql_execute(query).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,588 @@
+//! Helpers for batch writes
+//!
+//! - Multi-row `INSERT`: diesel inserts a slice of rows in one statement with
+//!   `insert_into(table).values(&rows)`. Split the rows by [`chunk_size`] and map the ids
+//!   returned by `on_conflict_do_nothing().returning(id)` with [`save_effects`].
+//! - Grouped `UPDATE`: group entities by [`group_by_changed`], split each group by
+//!   [`update_chunk_size`] and send one [`BatchUpdate`] per chunk, then map the returned ids
+//!   with [`update_effects`].
+//!   Entities with foreign or nested changes are updated one by one, since those are not
+//!   columns of the table, and unchanged entities only have to exist.
+//! - `DELETE ... WHERE id = ANY($1)`: postgres binds all the ids as one array by
+//!   [`BatchDeleteAny`], so they need no chunks. Other backends send one [`BatchDelete`]
+//!   (`WHERE id IN (...)`) per [`chunk_size`]`(1)` ids. Map the returned ids with
+//!   [`delete_effects`].
+//!
+//! A statement with more bind parameters than [`PG_MAX_BIND_PARAMS`] fails to build.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! async fn save_batch(&mut self, entities: &[User]) -> anyhow::Result<Vec<SaveEffectOf<User>>> {
+//!     let mut inserted = vec![];
+//!     for chunk in entities.chunks(chunk_size(3)) {
+//!         let rows = chunk.iter().map(UserRow::from).collect::<Vec<_>>();
+//!         let query = diesel::insert_into(users::table)
+//!             .values(rows)
+//!             .on_conflict_do_nothing()
+//!             .returning(users::id);
+//!         inserted.extend(self.adapter.sql_results::<i64, _>(query).await?);
+//!     }
+//!
+//!     Ok(save_effects(entities.iter().map(|u| u.id), inserted))
+//! }
+//!
+//! async fn update_batch(&mut self, entities: &[User]) -> anyhow::Result<Vec<UpdateEffect>> {
+//!     let mut updated = vec![];
+//!     let (nested, flat): (Vec<&User>, Vec<&User>) = entities
+//!         .iter()
+//!         .partition(|u| !u.changed_nested_fields().is_empty());
+//!     for user in nested {
+//!         if self.update(user).await?.is_ok() {
+//!             updated.push(user.id);
+//!         }
+//!     }
+//!
+//!     for (fields, group) in group_by_changed(flat.iter().copied()) {
+//!         for chunk in group.chunks(update_chunk_size(fields.len())) {
+//!             let ids = chunk.iter().map(|u| u.id).collect();
+//!             let mut update = BatchUpdate::new(users::table, users::id, ids);
+//!             for field in &fields {
+//!                 update = match field {
+//!                     UserFieldEnum::Name => update
+//!                         .set(users::name, chunk.iter().map(|u| u.name.to_string()).collect()),
+//!                     UserFieldEnum::Age => {
+//!                         update.set(users::age, chunk.iter().map(|u| *u.age).collect())
+//!                     }
+//!                     UserFieldEnum::Id => unreachable!(),
+//!                 };
+//!             }
+//!             updated.extend(self.adapter.sql_results::<i64, _>(update).await?);
+//!         }
+//!     }
+//!
+//!     let unchanged = flat
+//!         .iter()
+//!         .filter(|u| u.changed_fields().is_empty())
+//!         .map(|u| u.id)
+//!         .collect::<Vec<_>>();
+//!     for chunk in unchanged.chunks(chunk_size(1)) {
+//!         let query = users::table.select(users::id).filter(users::id.eq_any(chunk));
+//!         updated.extend(self.adapter.sql_results::<i64, _>(query).await?);
+//!     }
+//!
+//!     Ok(update_effects(entities.iter().map(|u| u.id), updated))
+//! }
+//!
+//! async fn delete_batch<I>(&mut self, ids: Vec<I>) -> anyhow::Result<Vec<DeleteEffect>> {
+//!     let ids = ids.into_iter().map(|id| UserId::from(id)).collect::<Vec<_>>();
+//!     let delete = BatchDeleteAny::new(users::table, users::id, ids.clone());
+//!     let deleted = self.adapter.sql_results::<i64, _>(delete).await?;
+//!
+//!     Ok(delete_effects(ids, deleted))
+//! }
+//! ```
+
+use std::{collections::HashSet, hash::Hash, marker::PhantomData};
+
+use diesel::{
+    backend::Backend,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
+    serialize::ToSql,
+    Column, QueryResult, RunQueryDsl, Table,
+};
+use indexmap::IndexMap;
+
+use crate::{
+    entity::Entity,
+    repository::{ConflictTarget, DeleteEffect, SaveEffect, UpdateEffect},
+};
+
+/// Maximum number of bind parameters of a postgres statement
+pub const PG_MAX_BIND_PARAMS: usize = 65535;
+
+/// Number of rows per statement so that a multi-row statement fits in the bind parameter limit
+pub fn chunk_size(binds_per_row: usize) -> usize {
+    (PG_MAX_BIND_PARAMS / binds_per_row.max(1)).max(1)
+}
+
+/// Number of rows per [`BatchUpdate`] setting `columns` columns
+pub fn update_chunk_size(columns: usize) -> usize {
+    chunk_size(update_binds_per_row(columns))
+}
+
+/// An id and a value per column, and the id in the `WHERE` clause
+fn update_binds_per_row(columns: usize) -> usize {
+    2 * columns + 1
+}
+
+fn check_bind_params(rows: usize, binds_per_row: usize) -> QueryResult<()> {
+    if rows * binds_per_row > PG_MAX_BIND_PARAMS {
+        return Err(diesel::result::Error::QueryBuilderError(
+            format!(
+                "{rows} rows take more than {PG_MAX_BIND_PARAMS} bind parameters, \
+                 split them by {} rows",
+                chunk_size(binds_per_row)
+            )
+            .into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Group entities by the set of their changed columns, in the order of first appearance.
+///
+/// Entities without changed columns are left out, foreign and nested changes are ignored.
+pub fn group_by_changed<'a, E>(
+    entities: impl IntoIterator<Item = &'a E>,
+) -> Vec<(Vec<E::FieldEnum>, Vec<&'a E>)>
+where
+    E: Entity + 'a,
+{
+    let mut groups: IndexMap<Vec<E::FieldEnum>, Vec<&E>> = IndexMap::new();
+    for entity in entities {
+        let fields = entity.changed_fields();
+        if fields.is_empty() {
+            continue;
+        }
+        groups.entry(fields).or_default().push(entity);
+    }
+
+    groups.into_iter().collect()
+}
+
+/// Map the ids returned by an `INSERT ... ON CONFLICT DO NOTHING RETURNING id`
+///
+/// `DO NOTHING` does not tell which unique key collided, so conflicts are
+/// [`ConflictTarget::Unknown`].
+pub fn save_effects<Id, B>(
+    ids: impl IntoIterator<Item = Id>,
+    inserted: impl IntoIterator<Item = Id>,
+) -> Vec<SaveEffect<B>>
+where
+    Id: Eq + Hash,
+{
+    effects_by_returning(
+        ids,
+        inserted,
+        || SaveEffect::Ok,
+        || SaveEffect::Conflict(ConflictTarget::Unknown),
+    )
+}
+
+/// Map the ids returned by an `UPDATE ... RETURNING id`
+pub fn update_effects<Id>(
+    ids: impl IntoIterator<Item = Id>,
+    updated: impl IntoIterator<Item = Id>,
+) -> Vec<UpdateEffect>
+where
+    Id: Eq + Hash,
+{
+    effects_by_returning(ids, updated, || UpdateEffect::Ok, || UpdateEffect::NotFound)
+}
+
+/// Map the ids returned by a `DELETE ... RETURNING id`
+pub fn delete_effects<Id>(
+    ids: impl IntoIterator<Item = Id>,
+    deleted: impl IntoIterator<Item = Id>,
+) -> Vec<DeleteEffect>
+where
+    Id: Eq + Hash,
+{
+    effects_by_returning(ids, deleted, || DeleteEffect::Ok, || DeleteEffect::NotFound)
+}
+
+fn effects_by_returning<Id, T>(
+    ids: impl IntoIterator<Item = Id>,
+    returned: impl IntoIterator<Item = Id>,
+    hit: impl Fn() -> T,
+    miss: impl Fn() -> T,
+) -> Vec<T>
+where
+    Id: Eq + Hash,
+{
+    let returned = returned.into_iter().collect::<HashSet<_>>();
+    ids.into_iter()
+        .map(|id| {
+            if returned.contains(&id) {
+                hit()
+            } else {
+                miss()
+            }
+        })
+        .collect()
+}
+
+/// Update many rows with different values in one statement
+///
+/// ```sql
+/// UPDATE "users" SET "name" = CASE "id" WHEN $1 THEN $2 WHEN $3 THEN $4 END
+/// WHERE "id" IN ($5, $6) RETURNING "id"
+/// ```
+pub struct BatchUpdate<T, IdCol, Id, DB> {
+    table: T,
+    ids: Vec<Id>,
+    assignments: Vec<Box<dyn QueryFragment<DB> + Send>>,
+    _id_column: PhantomData<IdCol>,
+}
+
+/// `"column" = CASE "id" WHEN $id THEN $value ... END`
+struct CaseAssignment<IdCol, Col, Id, V> {
+    ids: Vec<Id>,
+    values: Vec<V>,
+    _columns: PhantomData<(IdCol, Col)>,
+}
+
+impl<T, IdCol, Id, DB> BatchUpdate<T, IdCol, Id, DB>
+where
+    T: Table,
+    IdCol: Column<Table = T>,
+    Id: Clone + Send + 'static,
+    DB: Backend,
+{
+    pub fn new(table: T, _id_column: IdCol, ids: Vec<Id>) -> Self {
+        Self {
+            table,
+            ids,
+            assignments: vec![],
+            _id_column: PhantomData,
+        }
+    }
+
+    /// Set a column, `values` are in the same order as the ids
+    ///
+    /// # Panics
+    /// If the number of values differs from the number of ids.
+    pub fn set<Col, V>(mut self, _column: Col, values: Vec<V>) -> Self
+    where
+        Col: Column<Table = T> + Send + 'static,
+        V: ToSql<Col::SqlType, DB> + Send + 'static,
+        Id: ToSql<IdCol::SqlType, DB>,
+        IdCol: Send + 'static,
+        DB: diesel::sql_types::HasSqlType<Col::SqlType>
+            + diesel::sql_types::HasSqlType<IdCol::SqlType>
+            + 'static,
+    {
+        assert_eq!(
+            self.ids.len(),
+            values.len(),
+            "the number of values of column `{}` differs from the number of ids",
+            Col::NAME
+        );
+
+        self.assignments
+            .push(Box::new(CaseAssignment::<IdCol, Col, _, _> {
+                ids: self.ids.clone(),
+                values,
+                _columns: PhantomData,
+            }));
+        self
+    }
+}
+
+impl<IdCol, Col, Id, V, DB> QueryFragment<DB> for CaseAssignment<IdCol, Col, Id, V>
+where
+    IdCol: Column,
+    Col: Column,
+    Id: ToSql<IdCol::SqlType, DB>,
+    V: ToSql<Col::SqlType, DB>,
+    DB: Backend
+        + diesel::sql_types::HasSqlType<Col::SqlType>
+        + diesel::sql_types::HasSqlType<IdCol::SqlType>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        out.push_identifier(Col::NAME)?;
+        out.push_sql(" = CASE ");
+        out.push_identifier(IdCol::NAME)?;
+        for (id, value) in self.ids.iter().zip(self.values.iter()) {
+            out.push_sql(" WHEN ");
+            out.push_bind_param::<IdCol::SqlType, Id>(id)?;
+            out.push_sql(" THEN ");
+            out.push_bind_param::<Col::SqlType, V>(value)?;
+        }
+        out.push_sql(" END");
+
+        Ok(())
+    }
+}
+
+impl<T, IdCol, Id, DB> QueryFragment<DB> for BatchUpdate<T, IdCol, Id, DB>
+where
+    T: Table + QueryFragment<DB>,
+    IdCol: Column<Table = T>,
+    Id: ToSql<IdCol::SqlType, DB>,
+    DB: Backend + diesel::sql_types::HasSqlType<IdCol::SqlType>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        if self.assignments.is_empty() {
+            return Err(diesel::result::Error::QueryBuilderError(
+                "batch update without any column to set".into(),
+            ));
+        }
+
+        check_bind_params(self.ids.len(), update_binds_per_row(self.assignments.len()))?;
+
+        out.unsafe_to_cache_prepared();
+        out.push_sql("UPDATE ");
+        self.table.walk_ast(out.reborrow())?;
+        out.push_sql(" SET ");
+        for (idx, assignment) in self.assignments.iter().enumerate() {
+            if idx != 0 {
+                out.push_sql(", ");
+            }
+            assignment.walk_ast(out.reborrow())?;
+        }
+
+        walk_ids_returning::<IdCol, _, _>(&self.ids, out)
+    }
+}
+
+/// ` WHERE "id" IN (...) RETURNING "id"`
+fn walk_ids_returning<'b, IdCol, Id, DB>(
+    ids: &'b [Id],
+    mut out: AstPass<'_, 'b, DB>,
+) -> QueryResult<()>
+where
+    IdCol: Column,
+    Id: ToSql<IdCol::SqlType, DB>,
+    DB: Backend + diesel::sql_types::HasSqlType<IdCol::SqlType>,
+{
+    out.push_sql(" WHERE ");
+    if ids.is_empty() {
+        out.push_sql("1 = 0");
+    } else {
+        out.push_identifier(IdCol::NAME)?;
+        out.push_sql(" IN (");
+        for (idx, id) in ids.iter().enumerate() {
+            if idx != 0 {
+                out.push_sql(", ");
+            }
+            out.push_bind_param::<IdCol::SqlType, Id>(id)?;
+        }
+        out.push_sql(")");
+    }
+
+    out.push_sql(" RETURNING ");
+    out.push_identifier(IdCol::NAME)?;
+
+    Ok(())
+}
+
+impl<T, IdCol, Id, DB> QueryId for BatchUpdate<T, IdCol, Id, DB> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, IdCol, Id, DB> Query for BatchUpdate<T, IdCol, Id, DB>
+where
+    IdCol: Column,
+{
+    type SqlType = IdCol::SqlType;
+}
+
+impl<T, IdCol, Id, DB, Conn> RunQueryDsl<Conn> for BatchUpdate<T, IdCol, Id, DB> {}
+
+/// Delete many rows by id in one statement
+///
+/// ```sql
+/// DELETE FROM "users" WHERE "id" IN ($1, $2) RETURNING "id"
+/// ```
+pub struct BatchDelete<T, IdCol, Id> {
+    table: T,
+    ids: Vec<Id>,
+    _id_column: PhantomData<IdCol>,
+}
+
+impl<T, IdCol, Id> BatchDelete<T, IdCol, Id>
+where
+    T: Table,
+    IdCol: Column<Table = T>,
+{
+    pub fn new(table: T, _id_column: IdCol, ids: Vec<Id>) -> Self {
+        Self {
+            table,
+            ids,
+            _id_column: PhantomData,
+        }
+    }
+}
+
+impl<T, IdCol, Id, DB> QueryFragment<DB> for BatchDelete<T, IdCol, Id>
+where
+    T: Table + QueryFragment<DB>,
+    IdCol: Column<Table = T>,
+    Id: ToSql<IdCol::SqlType, DB>,
+    DB: Backend + diesel::sql_types::HasSqlType<IdCol::SqlType>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        check_bind_params(self.ids.len(), 1)?;
+
+        out.unsafe_to_cache_prepared();
+        out.push_sql("DELETE FROM ");
+        self.table.walk_ast(out.reborrow())?;
+        walk_ids_returning::<IdCol, _, _>(&self.ids, out)
+    }
+}
+
+impl<T, IdCol, Id> QueryId for BatchDelete<T, IdCol, Id> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, IdCol, Id> Query for BatchDelete<T, IdCol, Id>
+where
+    IdCol: Column,
+{
+    type SqlType = IdCol::SqlType;
+}
+
+impl<T, IdCol, Id, Conn> RunQueryDsl<Conn> for BatchDelete<T, IdCol, Id> {}
+
+/// Delete many rows by id in one statement with the ids bound as one array, postgres only
+///
+/// ```sql
+/// DELETE FROM "users" WHERE "id" = ANY($1) RETURNING "id"
+/// ```
+#[cfg(feature = "diesel-postgres")]
+pub struct BatchDeleteAny<T, IdCol, Id> {
+    table: T,
+    ids: Vec<Id>,
+    _id_column: PhantomData<IdCol>,
+}
+
+#[cfg(feature = "diesel-postgres")]
+impl<T, IdCol, Id> BatchDeleteAny<T, IdCol, Id>
+where
+    T: Table,
+    IdCol: Column<Table = T>,
+{
+    pub fn new(table: T, _id_column: IdCol, ids: Vec<Id>) -> Self {
+        Self {
+            table,
+            ids,
+            _id_column: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "diesel-postgres")]
+impl<T, IdCol, Id> QueryFragment<diesel::pg::Pg> for BatchDeleteAny<T, IdCol, Id>
+where
+    T: Table + QueryFragment<diesel::pg::Pg>,
+    IdCol: Column<Table = T>,
+    IdCol::SqlType: 'static,
+    Vec<Id>: ToSql<diesel::sql_types::Array<IdCol::SqlType>, diesel::pg::Pg>,
+    diesel::pg::Pg: diesel::sql_types::HasSqlType<diesel::sql_types::Array<IdCol::SqlType>>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, diesel::pg::Pg>) -> QueryResult<()> {
+        out.push_sql("DELETE FROM ");
+        self.table.walk_ast(out.reborrow())?;
+        out.push_sql(" WHERE ");
+        out.push_identifier(IdCol::NAME)?;
+        out.push_sql(" = ANY(");
+        out.push_bind_param::<diesel::sql_types::Array<IdCol::SqlType>, Vec<Id>>(&self.ids)?;
+        out.push_sql(") RETURNING ");
+        out.push_identifier(IdCol::NAME)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "diesel-postgres")]
+impl<T, IdCol, Id> QueryId for BatchDeleteAny<T, IdCol, Id> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+#[cfg(feature = "diesel-postgres")]
+impl<T, IdCol, Id> Query for BatchDeleteAny<T, IdCol, Id>
+where
+    IdCol: Column,
+{
+    type SqlType = IdCol::SqlType;
+}
+
+#[cfg(feature = "diesel-postgres")]
+impl<T, IdCol, Id, Conn> RunQueryDsl<Conn> for BatchDeleteAny<T, IdCol, Id> {}
+
+#[cfg(all(test, feature = "diesel-postgres"))]
+mod tests {
+    use diesel::{debug_query, pg::Pg};
+
+    use super::*;
+
+    diesel::table! {
+        users (id) {
+            id -> BigInt,
+            name -> Text,
+            age -> Nullable<Integer>,
+        }
+    }
+
+    #[test]
+    fn test_batch_update_sql() {
+        let update = BatchUpdate::<_, _, _, Pg>::new(users::table, users::id, vec![1i64, 2])
+            .set(users::name, vec!["a".to_string(), "b".to_string()])
+            .set(users::age, vec![Some(10), None]);
+        let sql = debug_query::<Pg, _>(&update).to_string();
+
+        assert_eq!(
+            sql,
+            r#"UPDATE "users" SET "name" = CASE "id" WHEN $1 THEN $2 WHEN $3 THEN $4 END, "age" = CASE "id" WHEN $5 THEN $6 WHEN $7 THEN $8 END WHERE "id" IN ($9, $10) RETURNING "id" -- binds: [1, "a", 2, "b", 1, Some(10), 2, None, 1, 2]"#
+        );
+    }
+
+    #[test]
+    fn test_batch_delete_sql() {
+        let delete = BatchDelete::new(users::table, users::id, vec![1i64, 2]);
+        let sql = debug_query::<Pg, _>(&delete).to_string();
+
+        assert_eq!(
+            sql,
+            r#"DELETE FROM "users" WHERE "id" IN ($1, $2) RETURNING "id" -- binds: [1, 2]"#
+        );
+    }
+
+    #[test]
+    fn test_batch_delete_any_sql() {
+        let ids = (0..=PG_MAX_BIND_PARAMS as i64).collect::<Vec<_>>();
+        let delete = BatchDeleteAny::new(users::table, users::id, ids);
+        let sql = debug_query::<Pg, _>(&delete).to_string();
+
+        assert!(sql.starts_with(r#"DELETE FROM "users" WHERE "id" = ANY($1) RETURNING "id""#));
+    }
+
+    #[test]
+    fn test_bind_param_limit() {
+        let rows = update_chunk_size(2);
+        assert_eq!(rows, 13107);
+
+        let update = |rows: usize| {
+            let ids = (0..rows as i64).collect::<Vec<_>>();
+            BatchUpdate::<_, _, _, Pg>::new(users::table, users::id, ids)
+                .set(users::name, vec![String::new(); rows])
+                .set(users::age, vec![None::<i32>; rows])
+        };
+        let mut sql = diesel::pg::PgQueryBuilder::default();
+        assert!(update(rows).to_sql(&mut sql, &Pg).is_ok());
+        assert!(update(rows + 1).to_sql(&mut sql, &Pg).is_err());
+
+        let ids = (0..=PG_MAX_BIND_PARAMS as i64).collect::<Vec<_>>();
+        let delete = BatchDelete::new(users::table, users::id, ids);
+        assert!(delete.to_sql(&mut sql, &Pg).is_err());
+    }
+
+    #[test]
+    fn test_effects() {
+        let effects = update_effects([1, 2, 3], [3, 1]);
+        assert!(effects[0].is_ok());
+        assert!(effects[1].is_not_found());
+        assert!(effects[2].is_ok());
+
+        assert_eq!(chunk_size(3), 21845);
+    }
+}
@@ -0,0 +1,372 @@
+//! Compile [`Criteria`] into diesel queries
+//!
+//! Columns are resolved by `FieldEnum::field_name` by default, use
+//! [`CriteriaSql::with_columns`] if the column names differ from the field names.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let query = users::table.select(UserRow::as_select()).into_boxed();
+//! let query = CriteriaSql::new(criteria).apply(query);
+//! let rows = adapter.sql_results(query).await?;
+//! ```
+
+use diesel::{
+    backend::Backend,
+    expression::{expression_types::Untyped, is_aggregate, ValidGrouping},
+    query_builder::{AstPass, BoxedSelectStatement, QueryFragment, QueryId},
+    query_dsl::methods::{FilterDsl, LimitDsl, OffsetDsl, OrderDsl},
+    serialize::ToSql,
+    sql_types::{BigInt, Bool, Double, HasSqlType, Text},
+    AppearsOnTable, Expression, QueryResult, SelectableExpression,
+};
+
+use crate::{
+    entity::FieldEnum,
+    repository::criteria::{Criteria, Predicate, SortDirection, SortOrder, Value},
+};
+
+/// Backends which can bind every [`Value`] variant
+pub trait CriteriaBackend: Backend {
+    fn push_value<'b>(value: &'b Value, out: &mut AstPass<'_, 'b, Self>) -> QueryResult<()>;
+
+    fn push_text<'b>(value: &'b str, out: &mut AstPass<'_, 'b, Self>) -> QueryResult<()>;
+}
+
+impl<DB> CriteriaBackend for DB
+where
+    DB: Backend + HasSqlType<BigInt> + HasSqlType<Bool> + HasSqlType<Double> + HasSqlType<Text>,
+    i64: ToSql<BigInt, DB>,
+    bool: ToSql<Bool, DB>,
+    f64: ToSql<Double, DB>,
+    str: ToSql<Text, DB>,
+{
+    fn push_value<'b>(value: &'b Value, out: &mut AstPass<'_, 'b, Self>) -> QueryResult<()> {
+        match value {
+            Value::Null => {
+                out.push_sql("NULL");
+                Ok(())
+            }
+            Value::Bool(v) => out.push_bind_param::<Bool, bool>(v),
+            Value::Int(v) => out.push_bind_param::<BigInt, i64>(v),
+            Value::Float(v) => out.push_bind_param::<Double, f64>(v),
+            Value::Text(v) => Self::push_text(v, out),
+        }
+    }
+
+    fn push_text<'b>(value: &'b str, out: &mut AstPass<'_, 'b, Self>) -> QueryResult<()> {
+        out.push_bind_param::<Text, str>(value)
+    }
+}
+
+type ColumnName<F> = fn(F) -> &'static str;
+
+pub struct CriteriaSql<F> {
+    criteria: Criteria<F>,
+    column: ColumnName<F>,
+}
+
+/// The `WHERE` part of a criteria
+pub struct CriteriaFilter<F> {
+    predicate: Predicate<F>,
+    column: ColumnName<F>,
+}
+
+/// The `ORDER BY` part of a criteria
+pub struct CriteriaOrder<F> {
+    order: Vec<SortOrder<F>>,
+    column: ColumnName<F>,
+}
+
+impl<F> CriteriaSql<F>
+where
+    F: FieldEnum,
+{
+    pub fn new(criteria: Criteria<F>) -> Self {
+        Self {
+            criteria,
+            column: F::field_name,
+        }
+    }
+
+    pub fn with_columns(mut self, column: fn(F) -> &'static str) -> Self {
+        self.column = column;
+        self
+    }
+
+    pub fn filter(&self) -> Option<CriteriaFilter<F>> {
+        self.criteria
+            .predicate
+            .clone()
+            .map(|predicate| CriteriaFilter {
+                predicate,
+                column: self.column,
+            })
+    }
+
+    pub fn order(&self) -> Option<CriteriaOrder<F>> {
+        if self.criteria.order.is_empty() {
+            return None;
+        }
+
+        Some(CriteriaOrder {
+            order: self.criteria.order.clone(),
+            column: self.column,
+        })
+    }
+
+    /// Apply filter, order, limit and offset to a boxed select statement
+    pub fn apply<'a, ST, QS, DB>(
+        &self,
+        query: BoxedSelectStatement<'a, ST, QS, DB>,
+    ) -> BoxedSelectStatement<'a, ST, QS, DB>
+    where
+        DB: CriteriaBackend + 'a,
+        F: Send + 'a,
+        BoxedSelectStatement<'a, ST, QS, DB>: FilterDsl<CriteriaFilter<F>, Output = BoxedSelectStatement<'a, ST, QS, DB>>
+            + OrderDsl<CriteriaOrder<F>, Output = BoxedSelectStatement<'a, ST, QS, DB>>
+            + LimitDsl<Output = BoxedSelectStatement<'a, ST, QS, DB>>
+            + OffsetDsl<Output = BoxedSelectStatement<'a, ST, QS, DB>>,
+    {
+        let mut query = query;
+        if let Some(filter) = self.filter() {
+            query = FilterDsl::filter(query, filter);
+        }
+        if let Some(order) = self.order() {
+            query = OrderDsl::order(query, order);
+        }
+        if let Some(limit) = self.criteria.limit {
+            query = LimitDsl::limit(query, limit as i64);
+        }
+        if let Some(offset) = self.criteria.offset {
+            query = OffsetDsl::offset(query, offset as i64);
+        }
+
+        query
+    }
+}
+
+impl<F> CriteriaFilter<F>
+where
+    F: Copy,
+{
+    fn walk_predicate<'b, DB>(
+        &self,
+        predicate: &'b Predicate<F>,
+        out: &mut AstPass<'_, 'b, DB>,
+    ) -> QueryResult<()>
+    where
+        DB: CriteriaBackend,
+    {
+        match predicate {
+            Predicate::Eq(f, Value::Null) => self.walk_unary(*f, " IS NULL", out),
+            Predicate::Ne(f, Value::Null) => self.walk_unary(*f, " IS NOT NULL", out),
+            Predicate::Eq(f, v) => self.walk_binary(*f, " = ", v, out),
+            Predicate::Ne(f, v) => self.walk_binary(*f, " <> ", v, out),
+            Predicate::Gt(f, v) => self.walk_binary(*f, " > ", v, out),
+            Predicate::Ge(f, v) => self.walk_binary(*f, " >= ", v, out),
+            Predicate::Lt(f, v) => self.walk_binary(*f, " < ", v, out),
+            Predicate::Le(f, v) => self.walk_binary(*f, " <= ", v, out),
+            Predicate::In(_, values) if values.is_empty() => {
+                out.push_sql("1 = 0");
+                Ok(())
+            }
+            Predicate::In(f, values) => {
+                out.push_identifier((self.column)(*f))?;
+                out.push_sql(" IN (");
+                for (idx, v) in values.iter().enumerate() {
+                    if idx != 0 {
+                        out.push_sql(", ");
+                    }
+                    DB::push_value(v, out)?;
+                }
+                out.push_sql(")");
+                Ok(())
+            }
+            Predicate::Like(f, pattern) => {
+                out.push_identifier((self.column)(*f))?;
+                out.push_sql(" LIKE ");
+                DB::push_text(pattern, out)
+            }
+            Predicate::IsNull(f) => self.walk_unary(*f, " IS NULL", out),
+            Predicate::IsNotNull(f) => self.walk_unary(*f, " IS NOT NULL", out),
+            Predicate::And(list) => self.walk_list(list, " AND ", "1 = 1", out),
+            Predicate::Or(list) => self.walk_list(list, " OR ", "1 = 0", out),
+            Predicate::Not(p) => {
+                out.push_sql("NOT (");
+                self.walk_predicate(p, out)?;
+                out.push_sql(")");
+                Ok(())
+            }
+        }
+    }
+
+    fn walk_unary<DB>(&self, f: F, op: &str, out: &mut AstPass<'_, '_, DB>) -> QueryResult<()>
+    where
+        DB: Backend,
+    {
+        out.push_identifier((self.column)(f))?;
+        out.push_sql(op);
+        Ok(())
+    }
+
+    fn walk_binary<'b, DB>(
+        &self,
+        f: F,
+        op: &str,
+        value: &'b Value,
+        out: &mut AstPass<'_, 'b, DB>,
+    ) -> QueryResult<()>
+    where
+        DB: CriteriaBackend,
+    {
+        out.push_identifier((self.column)(f))?;
+        out.push_sql(op);
+        DB::push_value(value, out)
+    }
+
+    fn walk_list<'b, DB>(
+        &self,
+        list: &'b [Predicate<F>],
+        sep: &str,
+        empty: &str,
+        out: &mut AstPass<'_, 'b, DB>,
+    ) -> QueryResult<()>
+    where
+        DB: CriteriaBackend,
+    {
+        if list.is_empty() {
+            out.push_sql(empty);
+            return Ok(());
+        }
+
+        out.push_sql("(");
+        for (idx, p) in list.iter().enumerate() {
+            if idx != 0 {
+                out.push_sql(sep);
+            }
+            self.walk_predicate(p, out)?;
+        }
+        out.push_sql(")");
+
+        Ok(())
+    }
+}
+
+impl<F, DB> QueryFragment<DB> for CriteriaFilter<F>
+where
+    F: Copy,
+    DB: CriteriaBackend,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        self.walk_predicate(&self.predicate, &mut out)
+    }
+}
+
+impl<F, DB> QueryFragment<DB> for CriteriaOrder<F>
+where
+    F: Copy,
+    DB: Backend,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        for (idx, order) in self.order.iter().enumerate() {
+            if idx != 0 {
+                out.push_sql(", ");
+            }
+            out.push_identifier((self.column)(order.field))?;
+            match order.direction {
+                SortDirection::Asc => out.push_sql(" ASC"),
+                SortDirection::Desc => out.push_sql(" DESC"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+macro_rules! impl_expression {
+    ($name:ident, $sql_type:ty) => {
+        impl<F> Expression for $name<F> {
+            type SqlType = $sql_type;
+        }
+
+        impl<F, QS> AppearsOnTable<QS> for $name<F> {}
+
+        impl<F, QS> SelectableExpression<QS> for $name<F> {}
+
+        impl<F> ValidGrouping<()> for $name<F> {
+            type IsAggregate = is_aggregate::Never;
+        }
+
+        impl<F> QueryId for $name<F> {
+            type QueryId = ();
+
+            const HAS_STATIC_QUERY_ID: bool = false;
+        }
+    };
+}
+
+impl_expression!(CriteriaFilter, Bool);
+impl_expression!(CriteriaOrder, Untyped);
+
+#[cfg(all(test, feature = "diesel-postgres"))]
+mod tests {
+    use diesel::{debug_query, pg::Pg, QueryDsl};
+
+    use super::*;
+    use crate::repository::criteria::FieldExpr;
+
+    diesel::table! {
+        users (id) {
+            id -> BigInt,
+            name -> Text,
+            age -> Nullable<Integer>,
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    enum UserField {
+        Id,
+        Name,
+        Age,
+    }
+
+    impl FieldEnum for UserField {
+        fn field_name(self) -> &'static str {
+            match self {
+                UserField::Id => "id",
+                UserField::Name => "name",
+                UserField::Age => "age",
+            }
+        }
+
+        fn sys_id() -> Self {
+            UserField::Id
+        }
+
+        fn all() -> &'static [Self] {
+            &[UserField::Id, UserField::Name, UserField::Age]
+        }
+    }
+
+    #[test]
+    fn test_criteria_to_sql() {
+        let criteria = Criteria::new()
+            .filter(UserField::Name.like("a%").or(UserField::Age.is_null()))
+            .filter(UserField::Id.eq_any([1, 2]))
+            .order_by_desc(UserField::Age)
+            .order_by_asc(UserField::Id)
+            .limit(10)
+            .offset(20);
+
+        let query = users::table.select(users::id).into_boxed::<Pg>();
+        let query = CriteriaSql::new(criteria).apply(query);
+        let sql = debug_query::<Pg, _>(&query).to_string();
+
+        assert_eq!(
+            sql,
+            r#"SELECT "users"."id" FROM "users" WHERE (("name" LIKE $1 OR "age" IS NULL) AND "id" IN ($2, $3)) ORDER BY "age" DESC, "id" ASC LIMIT $4 OFFSET $5 -- binds: ["a%", 1, 2, 10, 20]"#
+        );
+    }
+}
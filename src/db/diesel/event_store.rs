@@ -0,0 +1,266 @@
+//! Postgres event store
+//!
+//! The tables are created by [`EVENT_STORE_SQL`], or tables of the same shape with other
+//! names given to [`EventStoreDiesel::with_tables`]. The primary key on the aggregate and
+//! sequence number rejects concurrent appends at the same version. An append is made in a
+//! savepoint, so that the transaction it runs in goes on after such a conflict.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use diesel::{
+    pg::Pg,
+    sql_types::{Array, BigInt, Double, Jsonb, Text},
+    QueryableByName,
+};
+
+use crate::{
+    db::DbAdapter,
+    provider::{Provider, ProviderContext},
+    repository::event_sourced::{AppendEffect, EventStore, Snapshot, StoredEvent},
+};
+
+use super::DieselSqlRunner;
+
+pub const DEFAULT_EVENTS_TABLE: &str = "events";
+pub const DEFAULT_SNAPSHOTS_TABLE: &str = "event_snapshots";
+
+/// Schema of the default event store tables
+pub const EVENT_STORE_SQL: &str = r#"
+CREATE TABLE IF NOT EXISTS events (
+    aggregate_type TEXT NOT NULL,
+    aggregate_id TEXT NOT NULL,
+    sequence BIGINT NOT NULL,
+    payload JSONB NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (aggregate_type, aggregate_id, sequence)
+);
+CREATE TABLE IF NOT EXISTS event_snapshots (
+    aggregate_type TEXT NOT NULL,
+    aggregate_id TEXT NOT NULL,
+    version BIGINT NOT NULL,
+    state JSONB NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id)
+);
+"#;
+
+/// Event store on a diesel adapter, events are appended on the adapter's connection and
+/// hence inside its transaction
+#[derive(Clone)]
+pub struct EventStoreDiesel<A> {
+    adapter: A,
+    events_table: &'static str,
+    snapshots_table: &'static str,
+}
+
+#[derive(QueryableByName)]
+struct EventRow {
+    #[diesel(sql_type = BigInt)]
+    sequence: i64,
+    #[diesel(sql_type = Jsonb)]
+    payload: serde_json::Value,
+    #[diesel(sql_type = Double)]
+    recorded_at: f64,
+}
+
+#[derive(QueryableByName)]
+struct VersionRow {
+    #[diesel(sql_type = BigInt)]
+    version: i64,
+}
+
+#[derive(QueryableByName)]
+struct SnapshotRow {
+    #[diesel(sql_type = BigInt)]
+    version: i64,
+    #[diesel(sql_type = Jsonb)]
+    state: serde_json::Value,
+}
+
+impl<A> Provider for EventStoreDiesel<A>
+where
+    A: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(A::build(ctx)?))
+    }
+}
+
+impl<A> EventStoreDiesel<A> {
+    pub fn new(adapter: A) -> Self {
+        Self {
+            adapter,
+            events_table: DEFAULT_EVENTS_TABLE,
+            snapshots_table: DEFAULT_SNAPSHOTS_TABLE,
+        }
+    }
+
+    /// Use other tables, the names are put into the SQL as is
+    pub fn with_tables(mut self, events: &'static str, snapshots: &'static str) -> Self {
+        self.events_table = events;
+        self.snapshots_table = snapshots;
+        self
+    }
+
+    /// Inserts nothing unless the last sequence number is the expected version
+    fn append_sql(&self) -> String {
+        let table = self.events_table;
+        format!(
+            "INSERT INTO {table} (aggregate_type, aggregate_id, sequence, payload) \
+             SELECT $1, $2, $3 + e.ord, e.payload \
+             FROM unnest($4::jsonb[]) WITH ORDINALITY AS e(payload, ord) \
+             WHERE (SELECT coalesce(max(sequence), 0) FROM {table} \
+             WHERE aggregate_type = $1 AND aggregate_id = $2) = $3"
+        )
+    }
+
+    fn save_snapshot_sql(&self) -> String {
+        let table = self.snapshots_table;
+        format!(
+            "INSERT INTO {table} (aggregate_type, aggregate_id, version, state) \
+             VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (aggregate_type, aggregate_id) DO UPDATE \
+             SET version = excluded.version, state = excluded.state \
+             WHERE {table}.version < excluded.version"
+        )
+    }
+}
+
+impl<A> EventStore for EventStoreDiesel<A>
+where
+    A: DieselSqlRunner<Pg> + DbAdapter,
+{
+    async fn append(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        expected_version: u64,
+        events: Vec<serde_json::Value>,
+    ) -> anyhow::Result<AppendEffect> {
+        let count = events.len();
+        if count == 0 {
+            return Ok(AppendEffect::Ok {
+                version: expected_version,
+            });
+        }
+
+        let query = diesel::sql_query(self.append_sql())
+            .bind::<Text, _>(aggregate_type)
+            .bind::<Text, _>(aggregate_id)
+            .bind::<BigInt, _>(expected_version as i64)
+            .bind::<Array<Jsonb>, _>(events);
+        self.adapter.begin_savepoint().await?;
+        let result = self.adapter.sql_execute(query).await;
+        if result.is_ok() {
+            self.adapter.release_savepoint().await?;
+        } else {
+            // The failed insert aborted the transaction up to the savepoint
+            self.adapter.rollback_to_savepoint().await?;
+        }
+
+        match result {
+            Ok(0) => Ok(AppendEffect::Conflict),
+            Ok(_) => Ok(AppendEffect::Ok {
+                version: expected_version + count as u64,
+            }),
+            // Appended concurrently at the same version
+            Err(error) if error.is_conflict() => Ok(AppendEffect::Conflict),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn load(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        after: u64,
+    ) -> anyhow::Result<Vec<StoredEvent>> {
+        let sql = format!(
+            "SELECT sequence, payload, extract(epoch FROM recorded_at)::float8 AS recorded_at \
+             FROM {} WHERE aggregate_type = $1 AND aggregate_id = $2 AND sequence > $3 \
+             ORDER BY sequence",
+            self.events_table
+        );
+        let query = diesel::sql_query(sql)
+            .bind::<Text, _>(aggregate_type)
+            .bind::<Text, _>(aggregate_id)
+            .bind::<BigInt, _>(after as i64);
+        let rows: Vec<EventRow> = self.adapter.sql_results(query).await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| StoredEvent {
+                sequence: row.sequence as u64,
+                payload: row.payload,
+                at: UNIX_EPOCH + Duration::from_secs_f64(row.recorded_at.max(0.0)),
+            })
+            .collect())
+    }
+
+    async fn version(&mut self, aggregate_type: &str, aggregate_id: &str) -> anyhow::Result<u64> {
+        let sql = format!(
+            "SELECT coalesce(max(sequence), 0) AS version FROM {} \
+             WHERE aggregate_type = $1 AND aggregate_id = $2",
+            self.events_table
+        );
+        let query = diesel::sql_query(sql)
+            .bind::<Text, _>(aggregate_type)
+            .bind::<Text, _>(aggregate_id);
+        let row: Option<VersionRow> = self.adapter.sql_result(query).await?;
+
+        Ok(row.map_or(0, |row| row.version as u64))
+    }
+
+    async fn save_snapshot(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        snapshot: Snapshot,
+    ) -> anyhow::Result<()> {
+        let query = diesel::sql_query(self.save_snapshot_sql())
+            .bind::<Text, _>(aggregate_type)
+            .bind::<Text, _>(aggregate_id)
+            .bind::<BigInt, _>(snapshot.version as i64)
+            .bind::<Jsonb, _>(snapshot.state);
+        self.adapter.sql_execute(query).await?;
+
+        Ok(())
+    }
+
+    async fn load_snapshot(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+    ) -> anyhow::Result<Option<Snapshot>> {
+        let sql = format!(
+            "SELECT version, state FROM {} WHERE aggregate_type = $1 AND aggregate_id = $2",
+            self.snapshots_table
+        );
+        let query = diesel::sql_query(sql)
+            .bind::<Text, _>(aggregate_type)
+            .bind::<Text, _>(aggregate_id);
+        let row: Option<SnapshotRow> = self.adapter.sql_result(query).await?;
+
+        Ok(row.map(|row| Snapshot {
+            version: row.version as u64,
+            state: row.state,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sql() {
+        let store = EventStoreDiesel::new(()).with_tables("order_events", "order_snapshots");
+        let sql = store.append_sql();
+        assert!(sql.starts_with("INSERT INTO order_events"));
+        assert!(sql.contains("WITH ORDINALITY"));
+        assert!(sql.contains("FROM order_events WHERE aggregate_type = $1"));
+
+        let sql = store.save_snapshot_sql();
+        assert!(sql.starts_with("INSERT INTO order_snapshots"));
+        assert!(sql.ends_with("WHERE order_snapshots.version < excluded.version"));
+    }
+}
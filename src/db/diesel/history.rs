@@ -0,0 +1,232 @@
+//! Temporal history tables on postgres
+//!
+//! The history table of a table has the same columns, plus `valid_during`, the
+//! `tstzrange` in which the row version was current. [`HistoryTable::create_sql`] creates it
+//! together with a trigger which copies the previous version of a row into it on every
+//! `UPDATE` and `DELETE`, and records the time of every `INSERT` in `{history}_inserted`.
+//! The trigger runs inside the writing transaction, so only committed writes leave a history,
+//! whichever repository makes them.
+//!
+//! A version is valid from the end of the previous one, or from the `INSERT` of the row if
+//! that is later. Only rows inserted before the trigger was created are taken to be valid
+//! since ever. Columns are matched by position, so alter the history table together with the
+//! table.
+//!
+//! `#[entity(history)]` only marks the entity, the tables are named here and read by the
+//! repository's [`AsOfSubsetReader`](crate::repository::AsOfSubsetReader):
+//!
+//! ```rust,ignore
+//! const USERS_HISTORY: HistoryTable = HistoryTable::new("users", "users_history", "id");
+//!
+//! #[Entity]
+//! #[entity(history)]
+//! pub struct User {
+//!     id: UserId,
+//!     name: String,
+//! }
+//!
+//! impl AsOfSubsetReader<UserFull> for UserRepo {
+//!     async fn read_as_of<I>(&mut self, id: I, at: SystemTime) -> anyhow::Result<Option<UserFull>>
+//!     where
+//!         for<'a> UserIdent: From<I>,
+//!     {
+//!         let id = UserIdent::from(id);
+//!         let row: Option<UserRow> =
+//!             read_as_of::<_, _, BigInt, _>(&mut self.adapter, &USERS_HISTORY, id, at).await?;
+//!         Ok(row.map(Into::into))
+//!     }
+//! }
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use diesel::{
+    pg::Pg,
+    query_builder::QueryId,
+    serialize::ToSql,
+    sql_types::{Double, HasSqlType},
+    QueryableByName,
+};
+
+use super::DieselSqlRunner;
+
+/// A table and its history table, the names are put into the SQL as is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryTable {
+    table: &'static str,
+    history: &'static str,
+    id_column: &'static str,
+}
+
+impl HistoryTable {
+    pub const fn new(table: &'static str, history: &'static str, id_column: &'static str) -> Self {
+        Self {
+            table,
+            history,
+            id_column,
+        }
+    }
+
+    pub fn table(&self) -> &'static str {
+        self.table
+    }
+
+    pub fn history(&self) -> &'static str {
+        self.history
+    }
+
+    /// Schema of the history table and its trigger, run it after the table is created
+    pub fn create_sql(&self) -> String {
+        let Self {
+            table,
+            history,
+            id_column: id,
+        } = self;
+        let valid_from = self.valid_from_sql("OLD.");
+        format!(
+            r#"
+CREATE TABLE IF NOT EXISTS {history} (
+    LIKE {table},
+    valid_during TSTZRANGE NOT NULL
+);
+CREATE INDEX IF NOT EXISTS {history}_idx ON {history} ({id});
+CREATE TABLE IF NOT EXISTS {history}_inserted AS
+    SELECT {id}, now() AS inserted_at FROM {table} WITH NO DATA;
+CREATE INDEX IF NOT EXISTS {history}_inserted_idx ON {history}_inserted ({id});
+CREATE OR REPLACE FUNCTION {history}_record() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO {history}_inserted VALUES (NEW.{id}, now());
+        RETURN NULL;
+    END IF;
+    IF TG_OP = 'UPDATE' AND OLD IS NOT DISTINCT FROM NEW THEN
+        RETURN NULL;
+    END IF;
+    INSERT INTO {history} SELECT (OLD).*, tstzrange({valid_from}, now());
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS {history}_trigger ON {table};
+CREATE TRIGGER {history}_trigger AFTER INSERT OR UPDATE OR DELETE ON {table}
+    FOR EACH ROW EXECUTE FUNCTION {history}_record();
+"#
+        )
+    }
+
+    /// Start of the current version of the row whose id is `{id_prefix}{id_column}`: the end
+    /// of the previous version or the last insert, whichever is later.
+    ///
+    /// `now()` is the start of the transaction, so a concurrent transaction which started
+    /// later but committed first may have recorded a later time. The start is capped at
+    /// `now()`, otherwise the range would be empty or invalid.
+    fn valid_from_sql(&self, id_prefix: &str) -> String {
+        let Self {
+            history,
+            id_column: id,
+            ..
+        } = self;
+        format!(
+            "least(coalesce(greatest(\
+             (SELECT max(upper(valid_during)) FROM {history} h WHERE h.{id} = {id_prefix}{id}), \
+             (SELECT max(inserted_at) FROM {history}_inserted i WHERE i.{id} = {id_prefix}{id})\
+             ), '-infinity'), now())"
+        )
+    }
+
+    /// The version of the row `$1` valid at `$2`, either from the history or the current row
+    fn as_of_sql(&self) -> String {
+        let Self {
+            table,
+            history,
+            id_column: id,
+        } = self;
+        let valid_from = self.valid_from_sql("t.");
+        format!(
+            "SELECT * FROM (\
+             SELECT * FROM {history} WHERE {id} = $1 \
+             UNION ALL \
+             SELECT t.*, tstzrange({valid_from}, NULL) \
+             FROM {table} t WHERE {id} = $1\
+             ) v WHERE valid_during @> to_timestamp($2) LIMIT 1"
+        )
+    }
+}
+
+/// Read the row `id` as it was at `at`, `None` if it did not exist then
+///
+/// `U` is loaded by column names, the `valid_during` column may be loaded as well.
+pub async fn read_as_of<A, U, ST, Id>(
+    adapter: &mut A,
+    table: &HistoryTable,
+    id: Id,
+    at: SystemTime,
+) -> anyhow::Result<Option<U>>
+where
+    A: DieselSqlRunner<Pg>,
+    U: QueryableByName<Pg> + Send + 'static,
+    Pg: HasSqlType<ST>,
+    Id: ToSql<ST, Pg> + Send + 'static,
+    ST: QueryId + Send + 'static,
+{
+    let at = at.duration_since(UNIX_EPOCH)?.as_secs_f64();
+    let query = diesel::sql_query(table.as_of_sql())
+        .bind::<ST, _>(id)
+        .bind::<Double, _>(at);
+
+    Ok(adapter.sql_result(query).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::sql_types::{BigInt, Text};
+
+    use super::*;
+    use crate::db::{diesel::DbAdapterDiesel, ConnectionPool};
+
+    const USERS: HistoryTable = HistoryTable::new("users", "users_history", "id");
+
+    #[test]
+    fn test_history_sql() {
+        let sql = USERS.create_sql();
+        assert!(sql.contains("CREATE TABLE IF NOT EXISTS users_history (\n    LIKE users,"));
+        assert!(sql.contains("WHERE h.id = OLD.id"));
+        assert!(sql.contains("FROM users_history_inserted i WHERE i.id = OLD.id"));
+        assert!(sql.contains("INSERT INTO users_history_inserted VALUES (NEW.id, now())"));
+        assert!(sql.contains("AFTER INSERT OR UPDATE OR DELETE ON users"));
+        assert!(sql.contains("tstzrange(least(coalesce(greatest("));
+
+        let sql = USERS.as_of_sql();
+        assert!(sql.contains("SELECT * FROM users_history WHERE id = $1 UNION ALL"));
+        assert!(sql.contains("FROM users t WHERE id = $1"));
+        assert!(sql.contains("WHERE i.id = t.id"));
+        assert!(sql.ends_with("WHERE valid_during @> to_timestamp($2) LIMIT 1"));
+    }
+
+    #[derive(QueryableByName)]
+    #[allow(dead_code)]
+    struct UserRow {
+        #[diesel(sql_type = BigInt)]
+        id: i64,
+        #[diesel(sql_type = Text)]
+        name: String,
+    }
+
+    #[derive(Clone)]
+    struct Pool;
+
+    impl ConnectionPool for Pool {
+        type Connection = diesel_async::AsyncPgConnection;
+
+        async fn get_conn(&self) -> anyhow::Result<Self::Connection> {
+            unreachable!()
+        }
+    }
+
+    #[allow(dead_code)]
+    async fn read_user(
+        adapter: &mut DbAdapterDiesel<Pool>,
+        at: SystemTime,
+    ) -> anyhow::Result<Option<UserRow>> {
+        read_as_of::<_, _, BigInt, _>(adapter, &USERS, 1i64, at).await
+    }
+}
@@ -8,21 +8,18 @@ macro_rules! impl_enum_to_sql {
                 $int_type: diesel::serialize::ToSql<ST, DB>,
                 DB: diesel::backend::Backend,
                 DB: diesel::sql_types::HasSqlType<ST>,
-                for<'c> DB: diesel::backend::Backend<
-                    BindCollector<'c> = diesel::query_builder::bind_collector::RawBytesBindCollector<DB>,
-                >,
             {
                 fn to_sql<'b>(
                     &'b self,
                     out: &mut diesel::serialize::Output<'b, '_, DB>,
                 ) -> diesel::serialize::Result {
-                    let value = match self {
-                        $(Self::$variant => $value,)*
+                    // A promoted constant outlives the output, so backends which borrow bind
+                    // values such as SQLite are supported as well
+                    let value: &'static $int_type = match self {
+                        $(Self::$variant => &$value,)*
                     };
 
-                    value.to_sql(&mut out.reborrow())?;
-
-                    Ok(diesel::serialize::IsNull::No)
+                    diesel::serialize::ToSql::<ST, DB>::to_sql(value, out)
                 }
             }
 
@@ -0,0 +1,684 @@
+//! Many-to-many relations stored in a join table
+//!
+//! [`JoinTableOperator`] implements [`ForeignEntitiesOperator`] and [`ForeignEntitiesLoader`]
+//! for a join table given by its diesel table and the columns of the local and the foreign
+//! id:
+//!
+//! - `add_foreign`: multi-row `INSERT ... ON CONFLICT DO NOTHING`, `ON DUPLICATE KEY UPDATE`
+//!   on mysql, see [`InsertIgnoreBackend`]
+//! - `remove_foreign`: `DELETE ... WHERE local = $1 AND foreign IN (...)`
+//! - `clear_foreign`: `DELETE ... WHERE local = $1`
+//! - `load_foreign`/`load_foreign_batch`: `SELECT local, foreign ... WHERE local IN (...)`,
+//!   returned as [`ForeignEntities::Unchanged`]
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! diesel::table! {
+//!     user_roles (user_id, role_id) {
+//!         user_id -> BigInt,
+//!         role_id -> BigInt,
+//!     }
+//! }
+//!
+//! type UserRoles =
+//!     JoinTableOperator<Adapter, user_roles::table, user_roles::user_id, user_roles::role_id, Pg>;
+//!
+//! // in `save` and `update` of the user repository
+//! self.user_roles.save_foreign(&user.id, &user.roles).await?;
+//!
+//! // in the subset loader
+//! user.roles = self.user_roles.load_foreign(&user.id).await?;
+//! ```
+
+use std::{borrow::Borrow, collections::HashMap, hash::Hash, marker::PhantomData};
+
+use diesel::{
+    backend::Backend,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
+    serialize::ToSql,
+    sql_types::HasSqlType,
+    Column, QueryResult, RunQueryDsl, Table,
+};
+use diesel_async::methods::{ExecuteDsl, LoadQuery};
+
+use crate::{
+    entity::foreign::{ForeignContainer, ForeignEntities, ForeignEntity},
+    provider::{Provider, ProviderContext},
+    repository::{ForeignEntitiesLoader, ForeignEntitiesOperator},
+};
+
+use super::{batch::chunk_size, DieselSqlRunner};
+
+/// Reads and writes the rows of a join table on a diesel adapter
+pub struct JoinTableOperator<A, T, L, F, DB> {
+    adapter: A,
+    table: T,
+    _columns: PhantomData<(L, F, DB)>,
+}
+
+/// Backends which can insert rows skipping the ones already present
+pub trait InsertIgnoreBackend: Backend {
+    /// The clause after the values, `local` is the column of the local id
+    fn push_on_conflict(out: &mut AstPass<'_, '_, Self>, local: &'static str) -> QueryResult<()>;
+}
+
+#[cfg(feature = "diesel-postgres")]
+impl InsertIgnoreBackend for diesel::pg::Pg {
+    fn push_on_conflict(out: &mut AstPass<'_, '_, Self>, _local: &'static str) -> QueryResult<()> {
+        out.push_sql(" ON CONFLICT DO NOTHING");
+        Ok(())
+    }
+}
+
+#[cfg(feature = "diesel-sqlite")]
+impl InsertIgnoreBackend for diesel::sqlite::Sqlite {
+    fn push_on_conflict(out: &mut AstPass<'_, '_, Self>, _local: &'static str) -> QueryResult<()> {
+        out.push_sql(" ON CONFLICT DO NOTHING");
+        Ok(())
+    }
+}
+
+/// A duplicate row is updated to itself, unlike `INSERT IGNORE` which also turns other
+/// errors such as foreign key violations into warnings
+#[cfg(feature = "diesel-mysql")]
+impl InsertIgnoreBackend for diesel::mysql::Mysql {
+    fn push_on_conflict(out: &mut AstPass<'_, '_, Self>, local: &'static str) -> QueryResult<()> {
+        out.push_sql(" ON DUPLICATE KEY UPDATE ");
+        out.push_identifier(local)?;
+        out.push_sql(" = ");
+        out.push_identifier(local)
+    }
+}
+
+/// `INSERT INTO table (local, foreign) VALUES ($1, $2), ... ON CONFLICT DO NOTHING`, by the
+/// syntax of [`InsertIgnoreBackend`]
+pub struct JoinInsert<T, L, F, LId, FId> {
+    table: T,
+    local: LId,
+    foreign: Vec<FId>,
+    _columns: PhantomData<(L, F)>,
+}
+
+/// `DELETE FROM table WHERE local = $1 [AND foreign IN (...)]`
+pub struct JoinDelete<T, L, F, LId, FId> {
+    table: T,
+    local: LId,
+    foreign: Option<Vec<FId>>,
+    _columns: PhantomData<(L, F)>,
+}
+
+/// `SELECT local, foreign FROM table WHERE local IN (...)`
+pub struct JoinSelect<T, L, F, LId> {
+    table: T,
+    locals: Vec<LId>,
+    _columns: PhantomData<(L, F)>,
+}
+
+impl<A, T, L, F, DB> Clone for JoinTableOperator<A, T, L, F, DB>
+where
+    A: Clone,
+    T: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            adapter: self.adapter.clone(),
+            table: self.table.clone(),
+            _columns: PhantomData,
+        }
+    }
+}
+
+impl<A, T, L, F, DB> Provider for JoinTableOperator<A, T, L, F, DB>
+where
+    A: Provider,
+    T: Default + 'static,
+    L: Default + 'static,
+    F: Default + 'static,
+    DB: 'static,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            A::build(ctx)?,
+            T::default(),
+            L::default(),
+            F::default(),
+        ))
+    }
+}
+
+impl<A, T, L, F, DB> JoinTableOperator<A, T, L, F, DB> {
+    pub fn new(adapter: A, table: T, _local: L, _foreign: F) -> Self {
+        Self {
+            adapter,
+            table,
+            _columns: PhantomData,
+        }
+    }
+
+    pub fn adapter_mut(&mut self) -> &mut A {
+        &mut self.adapter
+    }
+}
+
+impl<A, T, L, F, DB> JoinTableOperator<A, T, L, F, DB>
+where
+    T: Table + Clone,
+    L: Column<Table = T>,
+    F: Column<Table = T>,
+{
+    pub fn insert<LId, FId>(&self, local: LId, foreign: Vec<FId>) -> JoinInsert<T, L, F, LId, FId> {
+        JoinInsert {
+            table: self.table.clone(),
+            local,
+            foreign,
+            _columns: PhantomData,
+        }
+    }
+
+    pub fn delete<LId, FId>(
+        &self,
+        local: LId,
+        foreign: Option<Vec<FId>>,
+    ) -> JoinDelete<T, L, F, LId, FId> {
+        JoinDelete {
+            table: self.table.clone(),
+            local,
+            foreign,
+            _columns: PhantomData,
+        }
+    }
+
+    pub fn select<LId>(&self, locals: Vec<LId>) -> JoinSelect<T, L, F, LId> {
+        JoinSelect {
+            table: self.table.clone(),
+            locals,
+            _columns: PhantomData,
+        }
+    }
+}
+
+impl<A, T, L, F, DB, LId, C> ForeignEntitiesOperator<LId, C> for JoinTableOperator<A, T, L, F, DB>
+where
+    A: DieselSqlRunner<DB>,
+    DB: Backend,
+    T: Table + Clone,
+    L: Column<Table = T>,
+    F: Column<Table = T>,
+    LId: Clone,
+    C: ForeignContainer,
+    <C as ForeignContainer>::Item: ForeignEntity + 'static,
+    C: IntoIterator<Item = <C as ForeignContainer>::Item>,
+    for<'a> &'a C: IntoIterator<Item = &'a <C as ForeignContainer>::Item>,
+    JoinInsert<T, L, F, LId, <<C as ForeignContainer>::Item as ForeignEntity>::Id>:
+        ExecuteDsl<A::Connection>,
+    JoinDelete<T, L, F, LId, <<C as ForeignContainer>::Item as ForeignEntity>::Id>:
+        ExecuteDsl<A::Connection>,
+{
+    async fn clear_foreign(&mut self, id: &LId) -> anyhow::Result<()> {
+        let query = self
+            .delete::<_, <<C as ForeignContainer>::Item as ForeignEntity>::Id>(id.clone(), None);
+        self.adapter.sql_execute(query).await?;
+
+        Ok(())
+    }
+
+    async fn remove_foreign(
+        &mut self,
+        id: &LId,
+        foreign_entities: &std::collections::HashSet<
+            <<C as ForeignContainer>::Item as ForeignEntity>::Id,
+        >,
+    ) -> anyhow::Result<()> {
+        let ids = foreign_entities.iter().cloned().collect::<Vec<_>>();
+        for chunk in ids.chunks(chunk_size(1) - 1) {
+            let query = self.delete(id.clone(), Some(chunk.to_vec()));
+            self.adapter.sql_execute(query).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn add_foreign<'a, I>(&mut self, id: &'a LId, foreign_entities: I) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = &'a <C as ForeignContainer>::Item>,
+        <I as IntoIterator>::Item: 'a,
+    {
+        let ids = foreign_entities
+            .into_iter()
+            .map(|entity| {
+                let id: &<<C as ForeignContainer>::Item as ForeignEntity>::Id = entity.borrow();
+                id.clone()
+            })
+            .collect::<Vec<_>>();
+        for chunk in ids.chunks(chunk_size(2)) {
+            let query = self.insert(id.clone(), chunk.to_vec());
+            self.adapter.sql_execute(query).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<A, T, L, F, DB, LId, C> ForeignEntitiesLoader<LId, C> for JoinTableOperator<A, T, L, F, DB>
+where
+    A: DieselSqlRunner<DB>,
+    DB: Backend,
+    T: Table + Clone,
+    L: Column<Table = T>,
+    F: Column<Table = T>,
+    LId: Clone + Eq + Hash + Send,
+    C: ForeignContainer,
+    <C as ForeignContainer>::Item:
+        ForeignEntity + From<<<C as ForeignContainer>::Item as ForeignEntity>::Id>,
+    <<C as ForeignContainer>::Item as ForeignEntity>::Id: Send,
+    JoinSelect<T, L, F, LId>: LoadQuery<
+            'static,
+            A::Connection,
+            (LId, <<C as ForeignContainer>::Item as ForeignEntity>::Id),
+        > + 'static,
+{
+    async fn load_foreign_batch(
+        &mut self,
+        ids: &[LId],
+    ) -> anyhow::Result<HashMap<LId, ForeignEntities<C>>> {
+        let mut containers = ids
+            .iter()
+            .map(|id| (id.clone(), C::new()))
+            .collect::<HashMap<_, _>>();
+
+        for chunk in ids.chunks(chunk_size(1)) {
+            let rows: Vec<(LId, <<C as ForeignContainer>::Item as ForeignEntity>::Id)> = self
+                .adapter
+                .sql_results(self.select(chunk.to_vec()))
+                .await?;
+            for (local, foreign) in rows {
+                if let Some(container) = containers.get_mut(&local) {
+                    container.insert(foreign.into());
+                }
+            }
+        }
+
+        Ok(containers
+            .into_iter()
+            .map(|(id, container)| (id, ForeignEntities::Unchanged(container)))
+            .collect())
+    }
+}
+
+impl<T, L, F, LId, FId, DB> QueryFragment<DB> for JoinInsert<T, L, F, LId, FId>
+where
+    T: Table + QueryFragment<DB>,
+    L: Column,
+    F: Column,
+    LId: ToSql<L::SqlType, DB>,
+    FId: ToSql<F::SqlType, DB>,
+    DB: InsertIgnoreBackend + HasSqlType<L::SqlType> + HasSqlType<F::SqlType>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        if self.foreign.is_empty() {
+            return Err(diesel::result::Error::QueryBuilderError(
+                "join table insert without any row".into(),
+            ));
+        }
+
+        out.unsafe_to_cache_prepared();
+        out.push_sql("INSERT INTO ");
+        self.table.walk_ast(out.reborrow())?;
+        out.push_sql(" (");
+        out.push_identifier(L::NAME)?;
+        out.push_sql(", ");
+        out.push_identifier(F::NAME)?;
+        out.push_sql(") VALUES ");
+        for (idx, foreign) in self.foreign.iter().enumerate() {
+            if idx != 0 {
+                out.push_sql(", ");
+            }
+            out.push_sql("(");
+            out.push_bind_param::<L::SqlType, LId>(&self.local)?;
+            out.push_sql(", ");
+            out.push_bind_param::<F::SqlType, FId>(foreign)?;
+            out.push_sql(")");
+        }
+        DB::push_on_conflict(&mut out, L::NAME)
+    }
+}
+
+impl<T, L, F, LId, FId, DB> QueryFragment<DB> for JoinDelete<T, L, F, LId, FId>
+where
+    T: Table + QueryFragment<DB>,
+    L: Column,
+    F: Column,
+    LId: ToSql<L::SqlType, DB>,
+    FId: ToSql<F::SqlType, DB>,
+    DB: Backend + HasSqlType<L::SqlType> + HasSqlType<F::SqlType>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("DELETE FROM ");
+        self.table.walk_ast(out.reborrow())?;
+        out.push_sql(" WHERE ");
+        out.push_identifier(L::NAME)?;
+        out.push_sql(" = ");
+        out.push_bind_param::<L::SqlType, LId>(&self.local)?;
+
+        if let Some(foreign) = &self.foreign {
+            out.push_sql(" AND ");
+            if foreign.is_empty() {
+                out.push_sql("1 = 0");
+                return Ok(());
+            }
+
+            out.push_identifier(F::NAME)?;
+            out.push_sql(" IN (");
+            for (idx, id) in foreign.iter().enumerate() {
+                if idx != 0 {
+                    out.push_sql(", ");
+                }
+                out.push_bind_param::<F::SqlType, FId>(id)?;
+            }
+            out.push_sql(")");
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, L, F, LId, DB> QueryFragment<DB> for JoinSelect<T, L, F, LId>
+where
+    T: Table + QueryFragment<DB>,
+    L: Column,
+    F: Column,
+    LId: ToSql<L::SqlType, DB>,
+    DB: Backend + HasSqlType<L::SqlType>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("SELECT ");
+        out.push_identifier(L::NAME)?;
+        out.push_sql(", ");
+        out.push_identifier(F::NAME)?;
+        out.push_sql(" FROM ");
+        self.table.walk_ast(out.reborrow())?;
+        out.push_sql(" WHERE ");
+        if self.locals.is_empty() {
+            out.push_sql("1 = 0");
+            return Ok(());
+        }
+
+        out.push_identifier(L::NAME)?;
+        out.push_sql(" IN (");
+        for (idx, id) in self.locals.iter().enumerate() {
+            if idx != 0 {
+                out.push_sql(", ");
+            }
+            out.push_bind_param::<L::SqlType, LId>(id)?;
+        }
+        out.push_sql(")");
+
+        Ok(())
+    }
+}
+
+impl<T, L, F, LId, FId> QueryId for JoinInsert<T, L, F, LId, FId> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, L, F, LId, FId> QueryId for JoinDelete<T, L, F, LId, FId> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, L, F, LId> QueryId for JoinSelect<T, L, F, LId> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, L, F, LId> Query for JoinSelect<T, L, F, LId>
+where
+    L: Column,
+    F: Column,
+{
+    type SqlType = (L::SqlType, F::SqlType);
+}
+
+impl<T, L, F, LId, FId, Conn> RunQueryDsl<Conn> for JoinInsert<T, L, F, LId, FId> {}
+
+impl<T, L, F, LId, FId, Conn> RunQueryDsl<Conn> for JoinDelete<T, L, F, LId, FId> {}
+
+impl<T, L, F, LId, Conn> RunQueryDsl<Conn> for JoinSelect<T, L, F, LId> {}
+
+#[cfg(all(test, feature = "diesel-postgres"))]
+mod tests {
+    use std::collections::HashSet;
+
+    use diesel::{debug_query, deserialize::FromSql, pg::Pg};
+
+    use super::*;
+    use crate::db::{diesel::DbAdapterDiesel, ConnectionPool};
+
+    diesel::table! {
+        user_roles (user_id, role_id) {
+            user_id -> BigInt,
+            role_id -> BigInt,
+        }
+    }
+
+    type UserRoles =
+        JoinTableOperator<(), user_roles::table, user_roles::user_id, user_roles::role_id, Pg>;
+
+    #[test]
+    fn test_join_table_sql() {
+        let op = UserRoles::new(
+            (),
+            user_roles::table,
+            user_roles::user_id,
+            user_roles::role_id,
+        );
+
+        let sql = debug_query::<Pg, _>(&op.insert(1i64, vec![2i64, 3])).to_string();
+        assert_eq!(
+            sql,
+            r#"INSERT INTO "user_roles" ("user_id", "role_id") VALUES ($1, $2), ($3, $4) ON CONFLICT DO NOTHING -- binds: [1, 2, 1, 3]"#
+        );
+
+        let sql = debug_query::<Pg, _>(&op.delete(1i64, Some(vec![2i64, 3]))).to_string();
+        assert_eq!(
+            sql,
+            r#"DELETE FROM "user_roles" WHERE "user_id" = $1 AND "role_id" IN ($2, $3) -- binds: [1, 2, 3]"#
+        );
+
+        let sql = debug_query::<Pg, _>(&op.delete::<_, i64>(1i64, None)).to_string();
+        assert_eq!(
+            sql,
+            r#"DELETE FROM "user_roles" WHERE "user_id" = $1 -- binds: [1]"#
+        );
+
+        let sql = debug_query::<Pg, _>(&op.select(vec![1i64, 2])).to_string();
+        assert_eq!(
+            sql,
+            r#"SELECT "user_id", "role_id" FROM "user_roles" WHERE "user_id" IN ($1, $2) -- binds: [1, 2]"#
+        );
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Hash, diesel::FromSqlRow)]
+    struct RoleId(i64);
+
+    impl crate::entity::SysId for RoleId {
+        fn generate() -> Self {
+            unreachable!()
+        }
+    }
+
+    impl ToSql<diesel::sql_types::BigInt, Pg> for RoleId {
+        fn to_sql<'b>(
+            &'b self,
+            out: &mut diesel::serialize::Output<'b, '_, Pg>,
+        ) -> diesel::serialize::Result {
+            ToSql::<diesel::sql_types::BigInt, Pg>::to_sql(&self.0, out)
+        }
+    }
+
+    impl FromSql<diesel::sql_types::BigInt, Pg> for RoleId {
+        fn from_sql(bytes: diesel::pg::PgValue<'_>) -> diesel::deserialize::Result<Self> {
+            FromSql::<diesel::sql_types::BigInt, Pg>::from_sql(bytes).map(RoleId)
+        }
+    }
+
+    #[derive(Clone)]
+    struct Pool;
+
+    impl ConnectionPool for Pool {
+        type Connection = diesel_async::AsyncPgConnection;
+
+        async fn get_conn(&self) -> anyhow::Result<Self::Connection> {
+            unreachable!()
+        }
+    }
+
+    fn assert_join_table<T>()
+    where
+        T: ForeignEntitiesOperator<i64, HashSet<RoleId>>
+            + ForeignEntitiesLoader<i64, HashSet<RoleId>>,
+    {
+    }
+
+    #[test]
+    fn test_join_table_on_adapter() {
+        assert_join_table::<
+            JoinTableOperator<
+                DbAdapterDiesel<Pool>,
+                user_roles::table,
+                user_roles::user_id,
+                user_roles::role_id,
+                Pg,
+            >,
+        >();
+    }
+}
+
+#[cfg(all(test, feature = "diesel-sqlite"))]
+mod sqlite_tests {
+    use std::collections::HashSet;
+
+    use diesel::{deserialize::FromSql, sqlite::Sqlite};
+
+    use super::*;
+    use crate::db::diesel::{
+        sqlite_pool::{build_pool, SqlitePool, SqlitePoolConfig},
+        DbAdapterDiesel,
+    };
+
+    diesel::table! {
+        user_roles (user_id, role_id) {
+            user_id -> BigInt,
+            role_id -> BigInt,
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Hash, diesel::FromSqlRow)]
+    struct RoleId(i64);
+
+    impl crate::entity::SysId for RoleId {
+        fn generate() -> Self {
+            unreachable!()
+        }
+    }
+
+    impl ToSql<diesel::sql_types::BigInt, Sqlite> for RoleId {
+        fn to_sql<'b>(
+            &'b self,
+            out: &mut diesel::serialize::Output<'b, '_, Sqlite>,
+        ) -> diesel::serialize::Result {
+            ToSql::<diesel::sql_types::BigInt, Sqlite>::to_sql(&self.0, out)
+        }
+    }
+
+    impl FromSql<diesel::sql_types::BigInt, Sqlite> for RoleId {
+        fn from_sql(
+            bytes: diesel::sqlite::SqliteValue<'_, '_, '_>,
+        ) -> diesel::deserialize::Result<Self> {
+            FromSql::<diesel::sql_types::BigInt, Sqlite>::from_sql(bytes).map(RoleId)
+        }
+    }
+
+    type UserRoles = JoinTableOperator<
+        DbAdapterDiesel<SqlitePool>,
+        user_roles::table,
+        user_roles::user_id,
+        user_roles::role_id,
+        Sqlite,
+    >;
+
+    #[tokio::test]
+    async fn test_join_table_on_sqlite() -> anyhow::Result<()> {
+        let config = SqlitePoolConfig {
+            max_conn: 1,
+            url: ":memory:".to_string(),
+            ..Default::default()
+        };
+        let mut adapter = DbAdapterDiesel::new(build_pool(&config)?);
+        let create = "CREATE TABLE user_roles (user_id BIGINT NOT NULL, role_id BIGINT NOT NULL, \
+                      PRIMARY KEY (user_id, role_id))";
+        adapter.sql_execute(diesel::sql_query(create)).await?;
+
+        let mut op = UserRoles::new(
+            adapter,
+            user_roles::table,
+            user_roles::user_id,
+            user_roles::role_id,
+        );
+        let roles = [RoleId(2), RoleId(3)];
+        ForeignEntitiesOperator::<i64, HashSet<RoleId>>::add_foreign(&mut op, &1, &roles).await?;
+        // The rows present are skipped
+        let roles = [RoleId(3), RoleId(4)];
+        ForeignEntitiesOperator::<i64, HashSet<RoleId>>::add_foreign(&mut op, &1, &roles).await?;
+        let removed = HashSet::from([RoleId(2)]);
+        ForeignEntitiesOperator::<i64, HashSet<RoleId>>::remove_foreign(&mut op, &1, &removed)
+            .await?;
+
+        let loaded: HashMap<i64, ForeignEntities<HashSet<RoleId>>> =
+            op.load_foreign_batch(&[1, 2]).await?;
+        let ForeignEntities::Unchanged(roles) = &loaded[&1] else {
+            panic!("loaded foreign entities are unchanged");
+        };
+        assert_eq!(*roles, HashSet::from([RoleId(3), RoleId(4)]));
+        assert!(matches!(&loaded[&2], ForeignEntities::Unchanged(roles) if roles.is_empty()));
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "diesel-mysql"))]
+mod mysql_tests {
+    use diesel::{debug_query, mysql::Mysql};
+
+    use super::*;
+
+    diesel::table! {
+        user_roles (user_id, role_id) {
+            user_id -> BigInt,
+            role_id -> BigInt,
+        }
+    }
+
+    #[test]
+    fn test_join_insert_sql() {
+        let op = JoinTableOperator::<(), _, _, _, Mysql>::new(
+            (),
+            user_roles::table,
+            user_roles::user_id,
+            user_roles::role_id,
+        );
+        let sql = debug_query::<Mysql, _>(&op.insert(1i64, vec![2i64])).to_string();
+        assert_eq!(
+            sql,
+            "INSERT INTO `user_roles` (`user_id`, `role_id`) VALUES (?, ?) \
+             ON DUPLICATE KEY UPDATE `user_id` = `user_id` -- binds: [1, 2]"
+        );
+    }
+}
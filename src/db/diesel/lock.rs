@@ -0,0 +1,337 @@
+//! Locking reads on postgres
+//!
+//! [`locked`] appends the lock clause of [`LockOptions`] to a select query, [`load_locked`]
+//! runs it inside the current transaction. A read which may time out runs in a savepoint, so
+//! that the transaction goes on after a [`LockEffect::Timeout`]:
+//!
+//! ```rust,ignore
+//! impl LockingSubsetLoader<AccountFull> for AccountRepo {
+//!     async fn load_locked<I>(
+//!         &mut self,
+//!         id: I,
+//!         lock: LockOptions,
+//!     ) -> anyhow::Result<LockEffect<AccountFull>>
+//!     where
+//!         for<'a> AccountIdent<'a>: From<I>,
+//!     {
+//!         let query = accounts::table
+//!             .filter(accounts::id.eq(AccountIdent::from(id)))
+//!             .select(AccountRow::as_select());
+//!         let effect = load_locked(&mut self.adapter, &self.txn, query, lock).await?;
+//!         Ok(effect.map(AccountRow::into_subset))
+//!     }
+//! }
+//! ```
+
+use diesel::{
+    pg::Pg,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
+    QueryResult, RunQueryDsl,
+};
+use diesel_async::methods::LoadQuery;
+
+use crate::{
+    db::{DbAdapter, TxnManager},
+    repository::{LockEffect, LockMode, LockOptions, LockWait},
+};
+
+use super::DieselSqlRunner;
+
+/// `{query} FOR UPDATE [NOWAIT | SKIP LOCKED]`
+pub struct Locked<Q> {
+    query: Q,
+    lock: LockOptions,
+}
+
+pub fn locked<Q>(query: Q, lock: LockOptions) -> Locked<Q> {
+    Locked { query, lock }
+}
+
+/// Run a locking read of at most one row
+///
+/// With a timeout or [`LockWait::NoWait`], the read runs in a savepoint which is rolled back
+/// if the lock is not acquired.
+///
+/// # Errors
+/// If `txn` is not in a transaction, since the lock would be released right away.
+pub async fn load_locked<'query, A, Tx, Q, U>(
+    adapter: &mut A,
+    txn: &Tx,
+    query: Q,
+    lock: LockOptions,
+) -> anyhow::Result<LockEffect<U>>
+where
+    A: DieselSqlRunner<Pg> + DbAdapter,
+    Tx: TxnManager,
+    U: Send,
+    Locked<Q>: LoadQuery<'query, A::Connection, U> + 'query,
+{
+    anyhow::ensure!(
+        txn.state().is_begun(),
+        "locking reads must be made inside a transaction"
+    );
+
+    if lock.timeout.is_none() && lock.wait != LockWait::NoWait {
+        return match adapter.sql_result(locked(query, lock)).await? {
+            Some(row) => Ok(LockEffect::Locked(row)),
+            None => Ok(LockEffect::NotFound),
+        };
+    }
+
+    adapter.begin_savepoint().await?;
+    let result = async {
+        // Rolling back to the savepoint reverts `SET LOCAL`, but releasing it keeps the value
+        // until the end of the transaction, so the transaction's own timeout is kept aside
+        // and restored after the read
+        if let Some(timeout) = lock.timeout {
+            let save =
+                "SELECT set_config('bagua.lock_timeout', current_setting('lock_timeout'), true)";
+            adapter.sql_execute(diesel::sql_query(save)).await?;
+            let sql = format!("SET LOCAL lock_timeout = {}", timeout.as_millis().max(1));
+            adapter.sql_execute(diesel::sql_query(sql)).await?;
+        }
+        let row = adapter.sql_result(locked(query, lock)).await?;
+        if lock.timeout.is_some() {
+            let restore =
+                "SELECT set_config('lock_timeout', current_setting('bagua.lock_timeout'), true)";
+            adapter.sql_execute(diesel::sql_query(restore)).await?;
+        }
+
+        Ok::<_, super::SqlErrorDiesel>(row)
+    }
+    .await;
+
+    match result {
+        Ok(row) => {
+            adapter.release_savepoint().await?;
+            Ok(row.map_or(LockEffect::NotFound, LockEffect::Locked))
+        }
+        Err(error) => {
+            adapter.rollback_to_savepoint().await?;
+            if error.is_lock_timeout() {
+                Ok(LockEffect::Timeout)
+            } else {
+                Err(error.into())
+            }
+        }
+    }
+}
+
+impl<Q> QueryFragment<Pg> for Locked<Q>
+where
+    Q: QueryFragment<Pg>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(match self.lock.mode {
+            LockMode::Update => " FOR UPDATE",
+            LockMode::NoKeyUpdate => " FOR NO KEY UPDATE",
+            LockMode::Share => " FOR SHARE",
+            LockMode::KeyShare => " FOR KEY SHARE",
+        });
+        out.push_sql(match self.lock.wait {
+            LockWait::Wait => "",
+            LockWait::NoWait => " NOWAIT",
+            LockWait::SkipLocked => " SKIP LOCKED",
+        });
+
+        Ok(())
+    }
+}
+
+impl<Q> QueryId for Locked<Q>
+where
+    Q: QueryId,
+{
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q> Query for Locked<Q>
+where
+    Q: Query,
+{
+    type SqlType = Q::SqlType;
+}
+
+impl<Q, Conn> RunQueryDsl<Conn> for Locked<Q> {}
+
+#[cfg(test)]
+mod tests {
+    use diesel::{debug_query, result::DatabaseErrorKind, ExpressionMethods, QueryDsl};
+
+    use super::*;
+    use crate::db::{
+        diesel::{DbAdapterDiesel, SqlErrorDiesel, TxnManagerDiesel},
+        ConnectionPool,
+    };
+
+    diesel::table! {
+        accounts (id) {
+            id -> BigInt,
+            balance -> BigInt,
+        }
+    }
+
+    #[test]
+    fn test_locked_sql() {
+        let query = accounts::table
+            .filter(accounts::id.eq(1i64))
+            .select(accounts::balance);
+
+        let sql = debug_query::<Pg, _>(&locked(query, LockOptions::update())).to_string();
+        assert_eq!(
+            sql,
+            r#"SELECT "accounts"."balance" FROM "accounts" WHERE ("accounts"."id" = $1) FOR UPDATE -- binds: [1]"#
+        );
+
+        let lock = LockOptions::no_key_update().skip_locked();
+        let sql = debug_query::<Pg, _>(&locked(query, lock)).to_string();
+        assert!(sql.contains(r#"= $1) FOR NO KEY UPDATE SKIP LOCKED"#));
+
+        let sql = debug_query::<Pg, _>(&locked(query, LockOptions::share().nowait())).to_string();
+        assert!(sql.contains(r#"= $1) FOR SHARE NOWAIT"#));
+    }
+
+    #[test]
+    fn test_is_lock_timeout() {
+        let error = |message: &str| {
+            SqlErrorDiesel::Diesel(diesel::result::Error::DatabaseError(
+                DatabaseErrorKind::Unknown,
+                Box::new(message.to_string()),
+            ))
+        };
+
+        assert!(error("canceling statement due to lock timeout").is_lock_timeout());
+        assert!(error("could not obtain lock on row in relation \"accounts\"").is_lock_timeout());
+        assert!(!error("duplicate key value violates unique constraint").is_lock_timeout());
+    }
+
+    #[derive(Clone)]
+    struct Pool;
+
+    impl ConnectionPool for Pool {
+        type Connection = crate::db::diesel::pg_pool::PgConn;
+
+        async fn get_conn(&self) -> anyhow::Result<Self::Connection> {
+            anyhow::bail!("no database in unit tests")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_locked_outside_transaction() {
+        let mut adapter = DbAdapterDiesel::new(Pool);
+        let txn = TxnManagerDiesel::new(adapter.clone());
+        let query = accounts::table
+            .filter(accounts::id.eq(1i64))
+            .select(accounts::balance);
+
+        let effect: anyhow::Result<LockEffect<i64>> =
+            load_locked(&mut adapter, &txn, query, LockOptions::update()).await;
+        assert!(effect.is_err());
+    }
+
+    /// Fails every locking read by a lock timeout
+    #[derive(Clone, Default)]
+    struct LockedOut {
+        calls: std::sync::Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+
+    impl LockedOut {
+        fn push(&self, call: &'static str) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push(call);
+            Ok(())
+        }
+    }
+
+    impl DbAdapter for LockedOut {
+        async fn begin_txn(&mut self) -> anyhow::Result<()> {
+            self.push("begin")
+        }
+
+        async fn commit_txn(&mut self) -> anyhow::Result<()> {
+            self.push("commit")
+        }
+
+        async fn rollback_txn(&mut self) -> anyhow::Result<()> {
+            self.push("rollback")
+        }
+
+        async fn begin_savepoint(&mut self) -> anyhow::Result<()> {
+            self.push("savepoint")
+        }
+
+        async fn rollback_to_savepoint(&mut self) -> anyhow::Result<()> {
+            self.push("rollback to savepoint")
+        }
+    }
+
+    impl DieselSqlRunner<Pg> for LockedOut {
+        type Connection = diesel_async::AsyncPgConnection;
+
+        async fn sql_execute<Sql>(&mut self, _sql: Sql) -> Result<usize, SqlErrorDiesel> {
+            self.push("set")?;
+            Ok(0)
+        }
+
+        async fn sql_result<'query, U, Sql>(
+            &mut self,
+            _sql: Sql,
+        ) -> Result<Option<U>, SqlErrorDiesel> {
+            self.push("select")?;
+            Err(SqlErrorDiesel::Diesel(
+                diesel::result::Error::DatabaseError(
+                    DatabaseErrorKind::Unknown,
+                    Box::new("canceling statement due to lock timeout".to_string()),
+                ),
+            ))
+        }
+
+        async fn sql_results<'query, U, Sql>(
+            &mut self,
+            _sql: Sql,
+        ) -> Result<Vec<U>, SqlErrorDiesel> {
+            unreachable!()
+        }
+
+        async fn sql_exists<'query, Sql>(&mut self, _sql: Sql) -> anyhow::Result<bool> {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_locked_timeout_in_savepoint() -> anyhow::Result<()> {
+        let adapter = LockedOut::default();
+        let mut txn = TxnManagerDiesel::new(adapter.clone());
+        let inner = txn.clone();
+        let query = accounts::table
+            .filter(accounts::id.eq(1i64))
+            .select(accounts::balance);
+
+        let mut reader = adapter.clone();
+        let lock = LockOptions::update().timeout(std::time::Duration::from_millis(10));
+        txn.do_transaction(async {
+            let effect: LockEffect<i64> = load_locked(&mut reader, &inner, query, lock).await?;
+            assert!(matches!(effect, LockEffect::Timeout));
+            Ok(Ok::<_, ()>(()))
+        })
+        .await?
+        .unwrap();
+
+        assert_eq!(
+            *adapter.calls.lock().unwrap(),
+            vec![
+                "begin",
+                "savepoint",
+                "set",
+                "set",
+                "select",
+                "rollback to savepoint",
+                "commit"
+            ]
+        );
+
+        Ok(())
+    }
+}
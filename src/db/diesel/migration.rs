@@ -0,0 +1,154 @@
+//! Embedded schema migrations on postgres
+//!
+//! Migrations are embedded with [`embed_migrations!`] and applied by [`run`] on a connection
+//! of their own, before the pool is initialized. [`run`] holds a postgres advisory lock while
+//! migrating, so when several replicas of a service start together one of them migrates and
+//! the others wait for it, then find nothing pending.
+//!
+//! Run it as an init function ordered before the one initializing the pool:
+//!
+//! ```rust,ignore
+//! const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+//!
+//! #[InitFunction(priority = MIGRATION_PRIORITY)]
+//! async fn migrate(settings: &Settings) -> anyhow::Result<()> {
+//!     migration::run(&settings.pg.url, MIGRATIONS, &settings.migration).await?;
+//!     Ok(())
+//! }
+//!
+//! #[InitFunction(priority = POOL_PRIORITY)]
+//! async fn init_pool(settings: &Settings) -> anyhow::Result<()> {
+//!     pg_pool::init(&settings.pg).await
+//! }
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use diesel::{pg::Pg, sql_types::BigInt, Connection, RunQueryDsl};
+use diesel_async::{async_connection_wrapper::AsyncConnectionWrapper, AsyncPgConnection};
+use diesel_migrations::MigrationHarness;
+use serde::{Deserialize, Serialize};
+
+pub use diesel::migration::MigrationSource;
+pub use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+
+/// Priority of the init function running the migrations
+pub const MIGRATION_PRIORITY: u8 = 10;
+/// Priority of the init function initializing the pool, after the migrations
+pub const POOL_PRIORITY: u8 = 20;
+
+/// Key of the advisory lock taken by default while migrating
+pub const DEFAULT_LOCK_KEY: i64 = 0x6261_6775_615f_6d69;
+
+type MigrationConnection = AsyncConnectionWrapper<AsyncPgConnection>;
+
+/// Migration config
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MigrationConfig {
+    /// List the pending migrations without applying them
+    pub dry_run: bool,
+    /// Key of the advisory lock, replicas migrating the same database must share it
+    pub lock_key: i64,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            lock_key: DEFAULT_LOCK_KEY,
+        }
+    }
+}
+
+/// Apply the pending migrations of `migrations` to the database at `url`
+///
+/// Returns the names of the applied migrations, or of the pending ones on a dry run.
+pub async fn run<S>(url: &str, migrations: S, config: &MigrationConfig) -> Result<Vec<String>>
+where
+    S: MigrationSource<Pg> + Send + 'static,
+{
+    let url = url.to_string();
+    let config = config.clone();
+    // The wrapper blocks on the async connection, keep it off the runtime's workers
+    tokio::task::spawn_blocking(move || {
+        let mut conn = MigrationConnection::establish(&url)
+            .with_context(|| format!("Failed to connect for migrations. URL = {url}"))?;
+
+        if config.dry_run {
+            let pending = pending(&mut conn, migrations)?;
+            tracing::info!(?pending, "pending migrations");
+            return Ok(pending);
+        }
+
+        let applied = with_advisory_lock(&mut conn, config.lock_key, |conn| {
+            let applied = conn
+                .run_pending_migrations(migrations)
+                .map_err(|err| anyhow!(err).context("Failed to run migrations"))?;
+            Ok(applied.iter().map(ToString::to_string).collect::<Vec<_>>())
+        })?;
+        tracing::info!(?applied, "applied migrations");
+
+        Ok(applied)
+    })
+    .await?
+}
+
+/// Names of the migrations of `migrations` not applied to the database at `url`
+pub async fn pending_migrations<S>(url: &str, migrations: S) -> Result<Vec<String>>
+where
+    S: MigrationSource<Pg> + Send + 'static,
+{
+    let config = MigrationConfig {
+        dry_run: true,
+        ..Default::default()
+    };
+    run(url, migrations, &config).await
+}
+
+fn pending<S>(conn: &mut MigrationConnection, migrations: S) -> Result<Vec<String>>
+where
+    S: MigrationSource<Pg>,
+{
+    let pending = conn
+        .pending_migrations(migrations)
+        .map_err(|err| anyhow!(err).context("Failed to list pending migrations"))?;
+
+    Ok(pending
+        .iter()
+        .map(|migration| migration.name().to_string())
+        .collect())
+}
+
+/// Run `f` holding the session advisory lock `key`, waiting for it if another session holds it
+fn with_advisory_lock<T>(
+    conn: &mut MigrationConnection,
+    key: i64,
+    f: impl FnOnce(&mut MigrationConnection) -> Result<T>,
+) -> Result<T> {
+    diesel::sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(key)
+        .execute(conn)
+        .context("Failed to take the migration lock")?;
+
+    let result = f(conn);
+    let unlocked = diesel::sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(key)
+        .execute(conn);
+
+    let value = result?;
+    unlocked.context("Failed to release the migration lock")?;
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_defaults() {
+        let config: MigrationConfig = serde_json::from_str(r#"{"dry_run": true}"#).unwrap();
+        assert!(config.dry_run);
+        assert_eq!(config.lock_key, DEFAULT_LOCK_KEY);
+    }
+}
@@ -12,41 +12,86 @@ use diesel::Expression;
 use diesel_async::methods::{ExecuteDsl, LoadQuery};
 use diesel_async::pooled_connection::PoolTransactionManager;
 use diesel_async::RunQueryDsl;
-use diesel_async::{AsyncConnection, TransactionManager};
+use diesel_async::{AsyncConnection, SimpleAsyncConnection, TransactionManager};
 use tokio::sync::Mutex;
 
+use crate::async_task::LocalTaskRunner;
 use crate::db::ConnectionPool;
+use crate::entity::BizIdFieldEnum;
 use crate::provider::{Provider, SingletonProvider};
+use crate::repository::identity_map::{IdentityMap, WithIdentityMap};
+use crate::repository::{ConflictTarget, SaveEffect};
+use crate::retry::RetryPolicy;
+use replica::{NoReplica, ReplicaRouted, Routing};
 
-use super::{DbAdapter, TxCallback, TxnManager, TxnResult, TxnState};
+use super::{
+    AsyncTxCallback, AsyncTxCallbackBoxed, BeforeCommitHook, BeforeCommitHookBoxed, DbAdapter,
+    IsolationLevel, SpawnAsyncTxCallback, TxCallback, TxnManager, TxnOptions, TxnResult, TxnState,
+};
 
+#[cfg(feature = "diesel-postgres")]
+pub mod audit;
+pub mod batch;
+pub mod criteria;
+#[cfg(feature = "diesel-postgres")]
+pub mod event_store;
+#[cfg(feature = "diesel-postgres")]
+pub mod history;
 pub mod int_enum;
+pub mod join_table;
+#[cfg(feature = "diesel-postgres")]
+pub mod lock;
+#[cfg(feature = "diesel-migrations")]
+pub mod migration;
+#[cfg(feature = "diesel-mysql")]
+pub mod mysql_pool;
 pub mod new_type;
+#[cfg(feature = "diesel-postgres")]
+pub mod outbox;
+#[cfg(feature = "diesel-postgres")]
 pub mod pg_pool;
+pub mod replica;
+#[cfg(feature = "diesel-sqlite")]
+pub mod sqlite_pool;
+pub mod testing;
+#[cfg(feature = "diesel-postgres")]
+pub mod upsert;
 
-pub struct DbAdapterDiesel<P>
+/// Diesel adapter, all statements run on one connection of the primary pool `P`.
+///
+/// Reads of [`DbAdapterDiesel::replica`] go to a replica of `R` instead, see
+/// [`replica`](self::replica).
+pub struct DbAdapterDiesel<P, R = NoReplica>
 where
     P: ConnectionPool,
 {
     conn: Arc<Mutex<Option<P::Connection>>>,
     db_pool: P,
+    replicas: R,
+    routing: Arc<SyncMutex<Routing>>,
+    identity_map: IdentityMap,
 }
 
-impl<P> Clone for DbAdapterDiesel<P>
+impl<P, R> Clone for DbAdapterDiesel<P, R>
 where
     P: ConnectionPool + Clone,
+    R: Clone,
 {
     fn clone(&self) -> Self {
         Self {
             conn: self.conn.clone(),
             db_pool: self.db_pool.clone(),
+            replicas: self.replicas.clone(),
+            routing: self.routing.clone(),
+            identity_map: self.identity_map.clone(),
         }
     }
 }
 
-impl<P> Provider for DbAdapterDiesel<P>
+impl<P, R> Provider for DbAdapterDiesel<P, R>
 where
     P: ConnectionPool + Provider + Clone,
+    R: Provider + Clone,
 {
     fn build(ctx: &mut crate::provider::ProviderContext) -> anyhow::Result<Self> {
         // DbAdapterDiesel is always singleton
@@ -54,14 +99,77 @@ where
             return Ok(this.clone());
         }
 
-        Ok(Self {
+        Ok(Self::with_replicas(P::build(ctx)?, R::build(ctx)?))
+    }
+}
+
+impl<P, R> SingletonProvider for DbAdapterDiesel<P, R>
+where
+    P: ConnectionPool + Clone + Provider,
+    R: Provider + Clone,
+{
+}
+
+impl<P> DbAdapterDiesel<P>
+where
+    P: ConnectionPool,
+{
+    pub fn new(db_pool: P) -> Self {
+        Self::with_replicas(db_pool, NoReplica)
+    }
+}
+
+impl<P, R> DbAdapterDiesel<P, R>
+where
+    P: ConnectionPool,
+{
+    pub fn with_replicas(db_pool: P, replicas: R) -> Self {
+        Self {
             conn: Arc::new(Mutex::new(None)),
-            db_pool: P::build(ctx)?,
-        })
+            db_pool,
+            replicas,
+            routing: Default::default(),
+            identity_map: IdentityMap::default(),
+        }
+    }
+
+    /// Route the reads of the returned runner to a replica, unless this adapter is in a
+    /// transaction or has committed within the sticky window. Use it in
+    /// [`SubsetReader`](crate::repository::SubsetReader) implementations only, loaders and
+    /// writes stay on the primary.
+    pub fn replica(&mut self) -> ReplicaRouted<'_, P, R> {
+        ReplicaRouted::new(self)
+    }
+
+    /// When this adapter last committed or wrote outside a transaction, see
+    /// [`replica`](self::replica)
+    pub fn last_write(&self) -> Option<std::time::SystemTime> {
+        self.routing.lock().unwrap().last_write()
+    }
+
+    /// Keep reads on the primary for the sticky window after a write, made by another adapter
+    /// of the same session or by a statement this adapter can't tell is a write
+    pub fn mark_written_at(&self, at: std::time::SystemTime) {
+        let elapsed = at.elapsed().unwrap_or_default();
+        let at = std::time::Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or_else(std::time::Instant::now);
+        self.routing.lock().unwrap().written_at(at);
+    }
+
+    pub fn mark_written(&self) {
+        self.mark_written_at(std::time::SystemTime::now());
     }
 }
 
-impl<P> SingletonProvider for DbAdapterDiesel<P> where P: ConnectionPool + Clone + Provider {}
+impl<P, R> WithIdentityMap for DbAdapterDiesel<P, R>
+where
+    P: ConnectionPool,
+{
+    fn identity_map(&self) -> &IdentityMap {
+        &self.identity_map
+    }
+}
 
 macro_rules! fetch_or_reuse_conn {
     ($this:ident, $lock:ident) => {{
@@ -75,19 +183,44 @@ macro_rules! fetch_or_reuse_conn {
     }};
 }
 
-impl<P> DbAdapter for DbAdapterDiesel<P>
+impl<P, R> DbAdapter for DbAdapterDiesel<P, R>
 where
     P: ConnectionPool,
+    R: Clone + 'static,
     <P as ConnectionPool>::Connection: DerefMut + Send,
     <<P as ConnectionPool>::Connection as Deref>::Target: AsyncConnection,
+    <<<P as ConnectionPool>::Connection as Deref>::Target as AsyncConnection>::Backend:
+        TxnOptionsBackend,
 {
     async fn begin_txn(&mut self) -> anyhow::Result<()> {
+        self.begin_txn_with(&TxnOptions::default()).await
+    }
+
+    /// The options are set by the statements of the backend, see [`TxnOptionsBackend`]
+    async fn begin_txn_with(&mut self, options: &TxnOptions) -> anyhow::Result<()> {
+        let sql = <<P::Connection as Deref>::Target as AsyncConnection>::Backend::txn_options_sql(
+            options,
+        )?;
         let mut lock = self.conn.lock().await;
         let conn = fetch_or_reuse_conn!(self, lock);
 
+        if let Some(sql) = &sql.before_begin {
+            conn.deref_mut()
+                .batch_execute(sql)
+                .await
+                .context("failed to set transaction options")?;
+        }
         PoolTransactionManager::begin_transaction(conn)
             .await
             .context("failed to begin transaction via diesel connection")?;
+        if let Some(sql) = &sql.after_begin {
+            if let Err(error) = conn.deref_mut().batch_execute(sql).await {
+                PoolTransactionManager::rollback_transaction(conn).await?;
+                return Err(error).context("failed to set transaction options");
+            }
+        }
+        self.identity_map.activate();
+        self.routing.lock().unwrap().begin();
 
         Ok(())
     }
@@ -96,9 +229,11 @@ where
         let mut lock = self.conn.lock().await;
         let conn = fetch_or_reuse_conn!(self, lock);
 
-        PoolTransactionManager::commit_transaction(conn)
-            .await
-            .context("failed to commit transaction via diesel connection")?;
+        self.identity_map.clear();
+        let result = PoolTransactionManager::commit_transaction(conn).await;
+        // Stick to the primary even if the commit failed, it may have been applied
+        self.routing.lock().unwrap().end(true);
+        result.context("failed to commit transaction via diesel connection")?;
 
         Ok(())
     }
@@ -107,12 +242,57 @@ where
         let mut lock = self.conn.lock().await;
         let conn = fetch_or_reuse_conn!(self, lock);
 
+        self.identity_map.clear();
+        self.routing.lock().unwrap().end(false);
         PoolTransactionManager::rollback_transaction(conn)
             .await
             .context("failed to rollback transaction via diesel connection")?;
 
         Ok(())
     }
+
+    // Diesel's transaction manager turns transactions begun in a transaction into savepoints,
+    // the replica routing follows the outermost transaction only
+
+    async fn begin_savepoint(&mut self) -> anyhow::Result<()> {
+        let mut lock = self.conn.lock().await;
+        let conn = fetch_or_reuse_conn!(self, lock);
+
+        PoolTransactionManager::begin_transaction(conn)
+            .await
+            .context("failed to create savepoint via diesel connection")?;
+        self.identity_map.begin_savepoint();
+
+        Ok(())
+    }
+
+    async fn release_savepoint(&mut self) -> anyhow::Result<()> {
+        let mut lock = self.conn.lock().await;
+        let conn = fetch_or_reuse_conn!(self, lock);
+
+        PoolTransactionManager::commit_transaction(conn)
+            .await
+            .context("failed to release savepoint via diesel connection")?;
+        self.identity_map.release_savepoint();
+
+        Ok(())
+    }
+
+    async fn rollback_to_savepoint(&mut self) -> anyhow::Result<()> {
+        let mut lock = self.conn.lock().await;
+        let conn = fetch_or_reuse_conn!(self, lock);
+
+        self.identity_map.rollback_to_savepoint();
+        PoolTransactionManager::rollback_transaction(conn)
+            .await
+            .context("failed to rollback to savepoint via diesel connection")?;
+
+        Ok(())
+    }
+
+    fn is_retryable(&self, error: &anyhow::Error) -> bool {
+        is_retryable_anyhow(error)
+    }
 }
 
 #[derive(Clone)]
@@ -120,6 +300,13 @@ pub struct TxnManagerDiesel<A> {
     adapter: A,
     state: Arc<SyncMutex<TxnState>>,
     callbacks: Arc<SyncMutex<Vec<Box<dyn TxCallback>>>>,
+    async_callbacks: Arc<SyncMutex<Vec<Box<dyn AsyncTxCallbackBoxed>>>>,
+    before_commit: Arc<SyncMutex<Vec<Box<dyn BeforeCommitHookBoxed>>>>,
+    callback_runner: Option<Arc<dyn SpawnAsyncTxCallback>>,
+    retry_policy: RetryPolicy,
+    options: TxnOptions,
+    /// Options the outermost transaction was begun with
+    begun_options: Arc<SyncMutex<TxnOptions>>,
 }
 
 impl<A> Provider for TxnManagerDiesel<A>
@@ -131,7 +318,10 @@ where
             return Ok(this.clone());
         }
 
-        let this = Self::new(A::build_single(ctx)?);
+        let mut this = Self::new(A::build_single(ctx)?);
+        if let Some(retry_policy) = ctx.get::<RetryPolicy>() {
+            this.retry_policy = retry_policy.clone();
+        }
         ctx.insert(this.clone());
 
         Ok(this)
@@ -146,9 +336,32 @@ impl<A> TxnManagerDiesel<A> {
             adapter,
             state: Arc::new(SyncMutex::new(TxnState::NotInTransaction)),
             callbacks: Arc::new(SyncMutex::new(Vec::new())),
+            async_callbacks: Arc::new(SyncMutex::new(Vec::new())),
+            before_commit: Arc::new(SyncMutex::new(Vec::new())),
+            callback_runner: None,
+            retry_policy: RetryPolicy::default(),
+            options: TxnOptions::default(),
+            begun_options: Arc::new(SyncMutex::new(TxnOptions::default())),
         }
     }
 
+    /// Retry policy of [`TxnManager::do_transaction_retry`], a provided manager takes the
+    /// [`RetryPolicy`] in the provider context if there is one
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Spawn the async callbacks on `runner`, by default they are awaited in order before
+    /// [`TxnManager::do_transaction`] returns
+    pub fn with_callback_runner<R>(mut self, runner: R) -> Self
+    where
+        R: LocalTaskRunner,
+    {
+        self.callback_runner = Some(Arc::new(runner));
+        self
+    }
+
     fn state(&self) -> TxnState {
         self.state.lock().unwrap().clone()
     }
@@ -170,7 +383,7 @@ impl<A> TxnManagerDiesel<A> {
             }
             TxnState::Committed => TxnResult::Committed,
             TxnState::RolledBack => TxnResult::RolledBack,
-            TxnState::Begun => TxnResult::RolledBack,
+            TxnState::Begun { .. } => TxnResult::RolledBack,
         };
 
         for cb in callbacks {
@@ -182,6 +395,127 @@ impl<A> TxnManagerDiesel<A> {
         let mut lock = self.state.lock().unwrap();
         *lock = state;
     }
+
+    fn take_async_callbacks(&self) -> Vec<Box<dyn AsyncTxCallbackBoxed>> {
+        std::mem::take(&mut *self.async_callbacks.lock().unwrap())
+    }
+
+    async fn invoke_async_callbacks(&mut self) {
+        let callbacks = self.take_async_callbacks();
+        let tx_result = match self.state() {
+            TxnState::Committed => TxnResult::Committed,
+            _ => TxnResult::RolledBack,
+        };
+
+        for callback in callbacks {
+            match &self.callback_runner {
+                Some(runner) => runner.spawn_callback(callback, tx_result),
+                None => callback.call_boxed(tx_result).await,
+            }
+        }
+    }
+
+    /// Run the before-commit hooks in order, including the ones they register
+    async fn run_before_commit(&mut self) -> anyhow::Result<()> {
+        loop {
+            let hooks = std::mem::take(&mut *self.before_commit.lock().unwrap());
+            if hooks.is_empty() {
+                return Ok(());
+            }
+
+            for hook in hooks {
+                hook.before_commit_boxed().await?;
+            }
+        }
+    }
+}
+
+impl<A> TxnManagerDiesel<A>
+where
+    A: DbAdapter,
+{
+    /// Begin the outermost transaction without a body to run, for [`testing::RollbackTxn`]
+    async fn begin_outermost(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.state().is_begun(),
+            "the transaction manager is in a transaction already"
+        );
+        self.adapter.begin_txn_with(&self.options).await?;
+        self.set_state(TxnState::Begun { depth: 1 });
+        *self.begun_options.lock().unwrap() = self.options;
+
+        Ok(())
+    }
+
+    /// Roll back the transaction begun by [`Self::begin_outermost`] and run the callbacks
+    async fn rollback_outermost(&mut self) -> anyhow::Result<()> {
+        let res = self.rollback_with(Ok(Ok::<_, ()>(()))).await;
+
+        self.before_commit.lock().unwrap().clear();
+        self.invoke_callbacks();
+        self.invoke_async_callbacks().await;
+
+        res.map(|_| ())
+    }
+
+    /// Roll back the outermost transaction, which has ended even if the rollback failed. The
+    /// error of a failed rollback is returned unless `res` is a system error already.
+    async fn rollback_with<T, E>(
+        &mut self,
+        res: crate::result::BizResult<T, E>,
+    ) -> crate::result::BizResult<T, E> {
+        let rolled_back = self.adapter.rollback_txn().await;
+        self.set_state(TxnState::RolledBack);
+
+        match (rolled_back, res) {
+            (Ok(()), res) => res,
+            (Err(rollback_err), Ok(_)) => Err(rollback_err),
+            (Err(rollback_err), Err(sys_err)) => {
+                tracing::warn!("Failed to rollback transaction: {rollback_err:#}");
+                Err(sys_err)
+            }
+        }
+    }
+
+    /// A transaction in the transaction at `depth` runs in a savepoint. A biz error or a
+    /// system error rolls back to the savepoint only, the callbacks wait for the outermost
+    /// transaction to end. The before-commit hooks registered in a savepoint rolled back are
+    /// dropped.
+    ///
+    /// A savepoint has the options of the outermost transaction, other options are an error.
+    async fn do_nested_transaction<F, T, E>(
+        &mut self,
+        depth: u32,
+        tx: F,
+    ) -> crate::result::BizResult<T, E>
+    where
+        F: std::future::Future<Output = crate::result::BizResult<T, E>>,
+    {
+        let begun_options = *self.begun_options.lock().unwrap();
+        anyhow::ensure!(
+            self.options == TxnOptions::default() || self.options == begun_options,
+            "a nested transaction can't be begun with {:?}, \
+             the outer transaction was begun with {begun_options:?}",
+            self.options
+        );
+        self.adapter.begin_savepoint().await?;
+        self.set_state(TxnState::Begun { depth: depth + 1 });
+        let hooks = self.before_commit.lock().unwrap().len();
+
+        let res = tx.await;
+        let ended = match &res {
+            Ok(Ok(_)) => self.adapter.release_savepoint().await,
+            _ => {
+                // What the hooks of the savepoint were to complete is rolled back
+                self.before_commit.lock().unwrap().truncate(hooks);
+                self.adapter.rollback_to_savepoint().await
+            }
+        };
+        self.set_state(TxnState::Begun { depth });
+        ended?;
+
+        res
+    }
 }
 
 impl<A> TxnManager for TxnManagerDiesel<A>
@@ -192,38 +526,81 @@ where
     where
         F: std::future::Future<Output = crate::result::BizResult<T, E>>,
     {
-        self.adapter.begin_txn().await?;
-        self.set_state(TxnState::Begun);
+        let depth = self.state().depth();
+        if depth > 0 {
+            return self.do_nested_transaction(depth, tx).await;
+        }
+
+        self.adapter.begin_txn_with(&self.options).await?;
+        self.set_state(TxnState::Begun { depth: 1 });
+        *self.begun_options.lock().unwrap() = self.options;
 
         let res = match tx.await {
-            Ok(Ok(value)) => {
+            Ok(Ok(value)) => match self.run_before_commit().await {
                 // Only commit when there's no system error nor biz error
-                self.adapter.commit_txn().await?;
-                self.set_state(TxnState::Committed);
+                Ok(()) => match self.adapter.commit_txn().await {
+                    Ok(()) => {
+                        self.set_state(TxnState::Committed);
 
-                Ok(Ok(value))
-            }
-            Ok(Err(user_error)) => {
-                self.adapter.rollback_txn().await?;
-                self.set_state(TxnState::RolledBack);
+                        Ok(Ok(value))
+                    }
+                    // The transaction is over either way, the callbacks see it rolled back
+                    Err(commit_err) => {
+                        self.set_state(TxnState::RolledBack);
 
-                Ok(Err(user_error))
-            }
+                        Err(commit_err)
+                    }
+                },
+                Err(hook_err) => {
+                    tracing::info!("A before-commit hook failed, rollback");
+
+                    self.rollback_with(Err(hook_err)).await
+                }
+            },
+            Ok(Err(user_error)) => self.rollback_with(Ok(Err(user_error))).await,
             Err(sys_err) => {
                 tracing::info!("A system error occurred during transaction, rollback");
 
-                self.adapter.rollback_txn().await?;
-                self.set_state(TxnState::RolledBack);
-
-                Err(sys_err)
+                self.rollback_with(Err(sys_err)).await
             }
         };
 
+        self.before_commit.lock().unwrap().clear();
         self.invoke_callbacks();
+        self.invoke_async_callbacks().await;
 
         res
     }
 
+    async fn do_transaction_retry<F, T, E>(&mut self, mut tx: F) -> crate::result::BizResult<T, E>
+    where
+        F: AsyncFnMut() -> crate::result::BizResult<T, E>,
+    {
+        // A failed statement aborts the outermost transaction, only it may be retried
+        if self.state().is_begun() {
+            return self.do_transaction(tx()).await;
+        }
+
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match self.do_transaction(tx()).await {
+                Err(error) if self.adapter.is_retryable(&error) => error,
+                res => return res,
+            };
+            let Some(delay) = self.retry_policy.next_delay(attempts) else {
+                return Err(error);
+            };
+
+            tracing::warn!(
+                attempts,
+                ?delay,
+                "Transaction failed with a retryable error, retry: {error:#}"
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
     fn register_callback<H>(&self, callback: H)
     where
         H: TxCallback,
@@ -232,15 +609,53 @@ where
         callbacks_lock.push(Box::new(callback));
     }
 
+    fn register_async_callback<H>(&self, callback: H)
+    where
+        H: AsyncTxCallback,
+    {
+        self.async_callbacks
+            .lock()
+            .unwrap()
+            .push(Box::new(callback));
+    }
+
+    fn register_before_commit<H>(&self, hook: H)
+    where
+        H: BeforeCommitHook,
+    {
+        self.before_commit.lock().unwrap().push(Box::new(hook));
+    }
+
     fn state(&self) -> TxnState {
         self.state()
     }
+
+    fn with_txn_options(mut self, options: TxnOptions) -> Self {
+        self.options = options;
+        self
+    }
 }
 
 impl<A> Drop for TxnManagerDiesel<A> {
     fn drop(&mut self) {
         if Arc::strong_count(&self.callbacks) == 1 {
             self.invoke_callbacks();
+
+            // The transaction was never ended, there is nothing to await the callbacks
+            let callbacks = self.take_async_callbacks();
+            if callbacks.is_empty() {
+                return;
+            }
+            match &self.callback_runner {
+                Some(runner) => {
+                    for callback in callbacks {
+                        runner.spawn_callback(callback, TxnResult::RolledBack);
+                    }
+                }
+                None => tracing::warn!(
+                    "Transaction manager dropped in transaction. Ignore async callbacks."
+                ),
+            }
         }
     }
 }
@@ -276,6 +691,254 @@ impl SqlErrorDiesel {
             },
         }
     }
+
+    /// Whether a row lock was not acquired, by `NOWAIT` or after `lock_timeout`
+    pub fn is_lock_timeout(&self) -> bool {
+        match self {
+            SqlErrorDiesel::Diesel(error) => is_lock_timeout_error(error),
+            SqlErrorDiesel::Anyhow(_) => false,
+        }
+    }
+
+    /// Whether the transaction was aborted by a serialization failure or a deadlock, and may
+    /// succeed if run again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SqlErrorDiesel::Diesel(error) => is_retryable_error(error),
+            SqlErrorDiesel::Anyhow(error) => is_retryable_anyhow(error),
+        }
+    }
+
+    /// The unique key violated by the statement on `table`, `None` if it is not a unique
+    /// violation.
+    ///
+    /// Constraint names are mapped by [`ConflictTarget::from_constraint`].
+    pub fn conflict_target<B>(&self, table: &str) -> Option<ConflictTarget<B>>
+    where
+        B: BizIdFieldEnum,
+    {
+        self.conflict_target_with(table, &[])
+    }
+
+    /// Same as [`SqlErrorDiesel::conflict_target`], constraint names are mapped by
+    /// [`ConflictTarget::from_constraint_map`]
+    pub fn conflict_target_with<B>(
+        &self,
+        table: &str,
+        constraints: &[(&str, B)],
+    ) -> Option<ConflictTarget<B>>
+    where
+        B: BizIdFieldEnum,
+    {
+        match self {
+            SqlErrorDiesel::Diesel(diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                info,
+            )) => Some(
+                info.constraint_name()
+                    .map(|constraint| {
+                        ConflictTarget::from_constraint_map(table, constraint, constraints)
+                    })
+                    .unwrap_or(ConflictTarget::Unknown),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Turn a unique violation into a conflicting save effect, other errors are returned
+    pub fn into_save_effect<B>(self, table: &str) -> anyhow::Result<SaveEffect<B>>
+    where
+        B: BizIdFieldEnum,
+    {
+        self.into_save_effect_with(table, &[])
+    }
+
+    /// Same as [`SqlErrorDiesel::into_save_effect`], for constraints not named by the
+    /// convention, see [`ConflictTarget::from_constraint_map`]
+    pub fn into_save_effect_with<B>(
+        self,
+        table: &str,
+        constraints: &[(&str, B)],
+    ) -> anyhow::Result<SaveEffect<B>>
+    where
+        B: BizIdFieldEnum,
+    {
+        match self.conflict_target_with(table, constraints) {
+            Some(target) => Ok(SaveEffect::Conflict(target)),
+            None => Err(self.into()),
+        }
+    }
+}
+
+// Diesel maps only some SQLSTATEs to a `DatabaseErrorKind` and doesn't expose the others:
+// postgres 40P01 deadlock_detected and 55P03 lock_not_available, mysql 1205
+// ER_LOCK_WAIT_TIMEOUT are all reported as `Unknown`, with the server's message only. These
+// are recognized by the messages of an english server, other errors of the same kind are not
+// retried.
+
+fn is_retryable_error(error: &diesel::result::Error) -> bool {
+    use diesel::result::DatabaseErrorKind;
+
+    match error {
+        // postgres 40001, mysql 1213 ER_LOCK_DEADLOCK
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => true,
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::Unknown, info) => {
+            info.message().starts_with("deadlock detected")
+        }
+        _ => false,
+    }
+}
+
+fn is_lock_timeout_error(error: &diesel::result::Error) -> bool {
+    use diesel::result::DatabaseErrorKind;
+
+    match error {
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::Unknown, info) => {
+            let message = info.message();
+            message.starts_with("could not obtain lock")
+                || message.starts_with("canceling statement due to lock timeout")
+                || message.starts_with("Lock wait timeout exceeded")
+        }
+        _ => false,
+    }
+}
+
+fn is_retryable_anyhow(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<diesel::result::Error>()
+            .is_some_and(is_retryable_error)
+    })
+}
+
+/// Statements setting [`TxnOptions`] on one transaction
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TxnOptionsSql {
+    /// Run right before the transaction begins, applying to the next transaction only
+    pub before_begin: Option<String>,
+    /// Run right after the transaction begins
+    pub after_begin: Option<String>,
+}
+
+/// Backends which can begin a transaction with [`TxnOptions`]
+pub trait TxnOptionsBackend: Backend {
+    /// The statements setting `options`, an error for options the backend can't set on a
+    /// single transaction
+    fn txn_options_sql(options: &TxnOptions) -> anyhow::Result<TxnOptionsSql>;
+}
+
+#[cfg(feature = "diesel-postgres")]
+impl TxnOptionsBackend for diesel::pg::Pg {
+    fn txn_options_sql(options: &TxnOptions) -> anyhow::Result<TxnOptionsSql> {
+        let mut modes = transaction_modes(options);
+        if options.deferrable {
+            modes.push("DEFERRABLE");
+        }
+
+        let mut statements = vec![];
+        if !modes.is_empty() {
+            statements.push(format!("SET TRANSACTION {}", modes.join(", ")));
+        }
+        if let Some(timeout) = options.statement_timeout {
+            let millis = timeout.as_millis().max(1);
+            statements.push(format!("SET LOCAL statement_timeout = {millis}"));
+        }
+        if let Some(timeout) = options.lock_timeout {
+            let millis = timeout.as_millis().max(1);
+            statements.push(format!("SET LOCAL lock_timeout = {millis}"));
+        }
+
+        Ok(TxnOptionsSql {
+            before_begin: None,
+            after_begin: (!statements.is_empty()).then(|| statements.join("; ")),
+        })
+    }
+}
+
+/// `SET TRANSACTION` is not allowed in a mysql transaction, it is run before the begin. The
+/// timeouts are session variables, they can't be set for one transaction.
+#[cfg(feature = "diesel-mysql")]
+impl TxnOptionsBackend for diesel::mysql::Mysql {
+    fn txn_options_sql(options: &TxnOptions) -> anyhow::Result<TxnOptionsSql> {
+        ensure_supported("mysql", options, &["isolation", "read_only"])?;
+        let modes = transaction_modes(options);
+
+        Ok(TxnOptionsSql {
+            before_begin: (!modes.is_empty())
+                .then(|| format!("SET TRANSACTION {}", modes.join(", "))),
+            after_begin: None,
+        })
+    }
+}
+
+/// Sqlite transactions are serializable, which satisfies any isolation level. The other
+/// options can only be set on the connection.
+#[cfg(feature = "diesel-sqlite")]
+impl TxnOptionsBackend for diesel::sqlite::Sqlite {
+    fn txn_options_sql(options: &TxnOptions) -> anyhow::Result<TxnOptionsSql> {
+        ensure_supported("sqlite", options, &["isolation"])?;
+        Ok(TxnOptionsSql::default())
+    }
+}
+
+#[cfg(any(feature = "diesel-postgres", feature = "diesel-mysql"))]
+fn transaction_modes(options: &TxnOptions) -> Vec<&'static str> {
+    let mut modes = vec![];
+    if let Some(isolation) = options.isolation {
+        modes.push(match isolation {
+            IsolationLevel::ReadCommitted => "ISOLATION LEVEL READ COMMITTED",
+            IsolationLevel::RepeatableRead => "ISOLATION LEVEL REPEATABLE READ",
+            IsolationLevel::Serializable => "ISOLATION LEVEL SERIALIZABLE",
+        });
+    }
+    if options.read_only {
+        modes.push("READ ONLY");
+    }
+
+    modes
+}
+
+/// Fail if `options` sets other options than `supported`
+#[cfg(any(feature = "diesel-mysql", feature = "diesel-sqlite"))]
+fn ensure_supported(backend: &str, options: &TxnOptions, supported: &[&str]) -> anyhow::Result<()> {
+    let set = [
+        ("isolation", options.isolation.is_some()),
+        ("read_only", options.read_only),
+        ("deferrable", options.deferrable),
+        ("statement_timeout", options.statement_timeout.is_some()),
+        ("lock_timeout", options.lock_timeout.is_some()),
+    ];
+    let unsupported = set
+        .into_iter()
+        .filter(|(name, set)| *set && !supported.contains(name))
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    anyhow::ensure!(
+        unsupported.is_empty(),
+        "{backend} can't begin a transaction with {unsupported:?}"
+    );
+
+    Ok(())
+}
+
+/// Whether the row returned by `INSERT ... ON CONFLICT DO UPDATE ... RETURNING` was inserted
+/// rather than updated, postgres only.
+///
+/// ```rust,ignore
+/// let inserted = adapter
+///     .sql_result::<bool, _>(
+///         insert_into(users::table)
+///             .values(&row)
+///             .on_conflict(users::id)
+///             .do_update()
+///             .set(&changeset)
+///             .returning(pg_inserted()),
+///     )
+///     .await?;
+/// ```
+#[cfg(feature = "diesel-postgres")]
+pub fn pg_inserted() -> diesel::expression::SqlLiteral<diesel::sql_types::Bool> {
+    diesel::dsl::sql::<diesel::sql_types::Bool>("(xmax = 0)")
 }
 
 pub trait DieselSqlRunner<DB: Backend> {
@@ -303,7 +966,7 @@ pub trait DieselSqlRunner<DB: Backend> {
         diesel::dsl::select<Exists<Sql>>: AsQuery;
 }
 
-impl<P, DB> DieselSqlRunner<DB> for DbAdapterDiesel<P>
+impl<P, R, DB> DieselSqlRunner<DB> for DbAdapterDiesel<P, R>
 where
     P: ConnectionPool,
     P::Connection: diesel_async::AsyncConnection<Backend = DB> + Send,
@@ -318,7 +981,9 @@ where
         let mut lock = self.conn.lock().await;
         let conn = fetch_or_reuse_conn!(self, lock);
 
-        Ok(sql.execute(conn).await?)
+        let rows = sql.execute(conn).await?;
+        self.routing.lock().unwrap().executed();
+        Ok(rows)
     }
 
     async fn sql_result<'query, U, Sql>(&mut self, sql: Sql) -> Result<Option<U>, SqlErrorDiesel>
@@ -361,3 +1026,533 @@ where
         Ok(exists)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Adapter {
+        calls: Arc<SyncMutex<Vec<&'static str>>>,
+        /// Commits which fail with a serialization failure before one succeeds
+        commit_failures: Arc<SyncMutex<u32>>,
+    }
+
+    impl Adapter {
+        fn push(&self, call: &'static str) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push(call);
+            Ok(())
+        }
+    }
+
+    impl DbAdapter for Adapter {
+        async fn begin_txn(&mut self) -> anyhow::Result<()> {
+            self.push("begin")
+        }
+
+        async fn begin_txn_with(&mut self, options: &TxnOptions) -> anyhow::Result<()> {
+            if *options == TxnOptions::default() {
+                self.push("begin")
+            } else {
+                self.push("begin with options")
+            }
+        }
+
+        async fn commit_txn(&mut self) -> anyhow::Result<()> {
+            let mut failures = self.commit_failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                self.push("commit failed")?;
+                let kind = diesel::result::DatabaseErrorKind::SerializationFailure;
+                return Err(database_error(kind, "could not serialize").into());
+            }
+            self.push("commit")
+        }
+
+        async fn rollback_txn(&mut self) -> anyhow::Result<()> {
+            self.push("rollback")
+        }
+
+        async fn begin_savepoint(&mut self) -> anyhow::Result<()> {
+            self.push("savepoint")
+        }
+
+        async fn release_savepoint(&mut self) -> anyhow::Result<()> {
+            self.push("release")
+        }
+
+        async fn rollback_to_savepoint(&mut self) -> anyhow::Result<()> {
+            self.push("rollback to savepoint")
+        }
+
+        fn is_retryable(&self, error: &anyhow::Error) -> bool {
+            is_retryable_anyhow(error)
+        }
+    }
+
+    impl Provider for Adapter {
+        fn build(_ctx: &mut crate::provider::ProviderContext) -> anyhow::Result<Self> {
+            Ok(Self::default())
+        }
+    }
+
+    impl SingletonProvider for Adapter {}
+
+    struct Callback(Arc<SyncMutex<Vec<TxnResult>>>);
+
+    impl TxCallback for Callback {
+        fn call(self: Box<Self>, tx_result: TxnResult) {
+            self.0.lock().unwrap().push(tx_result);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nested_transaction() -> anyhow::Result<()> {
+        let adapter = Adapter::default();
+        let mut txn = TxnManagerDiesel::new(adapter.clone());
+        let results = Arc::new(SyncMutex::new(Vec::new()));
+
+        let mut inner = txn.clone();
+        let inner2 = txn.clone();
+        let res = txn
+            .do_transaction(async {
+                assert_eq!(inner.state(), TxnState::Begun { depth: 1 });
+                inner
+                    .do_transaction(async {
+                        assert_eq!(inner2.state().depth(), 2);
+                        inner2.register_callback(Callback(results.clone()));
+                        Ok(Ok::<_, ()>(()))
+                    })
+                    .await?
+                    .unwrap();
+                // The callback waits for the outermost transaction
+                assert!(results.lock().unwrap().is_empty());
+
+                let rolled_back = inner
+                    .do_transaction(async { Ok(Err::<(), _>("inner")) })
+                    .await?;
+                assert!(rolled_back.is_err());
+                assert_eq!(inner.state().depth(), 1);
+
+                Ok(Err::<(), _>("outer"))
+            })
+            .await?;
+        assert!(res.is_err());
+        assert_eq!(txn.state(), TxnState::RolledBack);
+        assert!(matches!(
+            results.lock().unwrap()[..],
+            [TxnResult::RolledBack]
+        ));
+        assert_eq!(
+            *adapter.calls.lock().unwrap(),
+            vec![
+                "begin",
+                "savepoint",
+                "release",
+                "savepoint",
+                "rollback to savepoint",
+                "rollback"
+            ]
+        );
+
+        Ok(())
+    }
+
+    fn database_error(
+        kind: diesel::result::DatabaseErrorKind,
+        message: &str,
+    ) -> diesel::result::Error {
+        diesel::result::Error::DatabaseError(kind, Box::new(message.to_string()))
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        use diesel::result::DatabaseErrorKind;
+
+        let error = database_error(
+            DatabaseErrorKind::SerializationFailure,
+            "could not serialize",
+        );
+        assert!(SqlErrorDiesel::from(error).is_retryable());
+        let error = database_error(DatabaseErrorKind::Unknown, "deadlock detected");
+        let error = anyhow::Error::from(error).context("failed to commit");
+        assert!(SqlErrorDiesel::from(error).is_retryable());
+        let error = database_error(DatabaseErrorKind::UniqueViolation, "duplicate key");
+        assert!(!SqlErrorDiesel::from(error).is_retryable());
+        // Only the kind diesel reports deadlocks by is matched
+        let error = database_error(DatabaseErrorKind::CheckViolation, "deadlock detected");
+        assert!(!SqlErrorDiesel::from(error).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_retry() -> anyhow::Result<()> {
+        use crate::retry::Backoff;
+
+        let adapter = Adapter::default();
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(1));
+        let mut txn =
+            TxnManagerDiesel::new(adapter.clone()).with_retry_policy(RetryPolicy::new(3, backoff));
+
+        let mut attempts = 0;
+        let res = txn
+            .do_transaction_retry(|| {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt < 3 {
+                        let kind = diesel::result::DatabaseErrorKind::SerializationFailure;
+                        return Err(database_error(kind, "could not serialize").into());
+                    }
+                    Ok(Ok::<_, ()>(attempt))
+                }
+            })
+            .await?;
+        assert_eq!(res, Ok(3));
+        assert_eq!(
+            std::mem::take(&mut *adapter.calls.lock().unwrap()),
+            vec!["begin", "rollback", "begin", "rollback", "begin", "commit"]
+        );
+
+        // Exhausted
+        let res = txn
+            .do_transaction_retry(|| async {
+                let kind = diesel::result::DatabaseErrorKind::Unknown;
+                Err::<Result<(), ()>, _>(database_error(kind, "deadlock detected").into())
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(adapter.calls.lock().unwrap().len(), 6);
+        adapter.calls.lock().unwrap().clear();
+
+        // Neither biz errors nor other system errors are retried
+        let res = txn
+            .do_transaction_retry(|| async { Ok(Err::<(), _>("biz")) })
+            .await?;
+        assert!(res.is_err());
+        let res = txn
+            .do_transaction_retry(|| async { Err::<Result<(), ()>, _>(anyhow::anyhow!("sys")) })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(adapter.calls.lock().unwrap().len(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_failure_retry() -> anyhow::Result<()> {
+        use crate::{retry::Backoff, usecase::UseCase};
+
+        let adapter = Adapter::default();
+        *adapter.commit_failures.lock().unwrap() = 1;
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(1));
+        let mut txn =
+            TxnManagerDiesel::new(adapter.clone()).with_retry_policy(RetryPolicy::new(3, backoff));
+        let results = Arc::new(SyncMutex::new(Vec::new()));
+
+        let inner = txn.clone();
+        let res = txn
+            .do_transaction(async {
+                inner.register_callback(Callback(results.clone()));
+                Ok(Ok::<_, ()>(()))
+            })
+            .await;
+        // The failed commit ends the transaction
+        assert!(res.is_err());
+        assert_eq!(txn.state(), TxnState::RolledBack);
+        assert!(matches!(
+            results.lock().unwrap()[..],
+            [TxnResult::RolledBack]
+        ));
+        adapter.calls.lock().unwrap().clear();
+
+        struct Increment(u32);
+
+        impl UseCase for Increment {
+            type Input = u32;
+            type Output = u32;
+            type Error = ();
+
+            async fn execute(&mut self, input: u32) -> crate::result::BizResult<u32, ()> {
+                self.0 += input;
+                Ok(Ok(self.0))
+            }
+        }
+
+        // Without opting in, a retryable commit failure fails the use case
+        *adapter.commit_failures.lock().unwrap() = 1;
+        let mut usecase = Increment(0);
+        let res = usecase.execute_in_txn(txn.clone(), 2).await;
+        assert!(res.is_err());
+        assert_eq!(
+            *adapter.calls.lock().unwrap(),
+            vec!["begin", "commit failed"]
+        );
+        adapter.calls.lock().unwrap().clear();
+
+        // A retryable commit failure runs the use case again
+        *adapter.commit_failures.lock().unwrap() = 1;
+        let mut usecase = Increment(0);
+        let res = usecase.execute_in_txn_retry(txn.clone(), 2).await?;
+        assert_eq!(res, Ok(4));
+        assert_eq!(
+            *adapter.calls.lock().unwrap(),
+            vec!["begin", "commit failed", "begin", "commit"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provided_retry_policy() -> anyhow::Result<()> {
+        let policy = RetryPolicy::new(7, Default::default());
+        let txn = TxnManagerDiesel::<Adapter>::provide_with(|ctx| {
+            ctx.insert(policy.clone());
+        })?;
+        assert_eq!(txn.retry_policy, policy);
+
+        let txn = TxnManagerDiesel::<Adapter>::provide()?;
+        assert_eq!(txn.retry_policy, RetryPolicy::default());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "diesel-postgres")]
+    #[test]
+    fn test_pg_txn_options_sql() -> anyhow::Result<()> {
+        use diesel::pg::Pg;
+
+        assert_eq!(
+            Pg::txn_options_sql(&TxnOptions::new())?,
+            TxnOptionsSql::default()
+        );
+
+        let options = TxnOptions::new().serializable().read_only().deferrable();
+        assert_eq!(
+            Pg::txn_options_sql(&options)?.after_begin.unwrap(),
+            "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE, READ ONLY, DEFERRABLE"
+        );
+
+        let options = TxnOptions::new()
+            .with_isolation(IsolationLevel::RepeatableRead)
+            .with_statement_timeout(Duration::from_secs(5))
+            .with_lock_timeout(Duration::from_millis(100));
+        let sql = Pg::txn_options_sql(&options)?;
+        assert_eq!(sql.before_begin, None);
+        assert_eq!(
+            sql.after_begin.unwrap(),
+            "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ; \
+             SET LOCAL statement_timeout = 5000; SET LOCAL lock_timeout = 100"
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "diesel-mysql")]
+    #[test]
+    fn test_mysql_txn_options_sql() -> anyhow::Result<()> {
+        use diesel::mysql::Mysql;
+
+        let options = TxnOptions::new().serializable().read_only();
+        let sql = Mysql::txn_options_sql(&options)?;
+        assert_eq!(
+            sql.before_begin.unwrap(),
+            "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE, READ ONLY"
+        );
+        assert_eq!(sql.after_begin, None);
+
+        let options = TxnOptions::new().with_lock_timeout(Duration::from_secs(1));
+        assert!(Mysql::txn_options_sql(&options).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "diesel-sqlite")]
+    #[test]
+    fn test_sqlite_txn_options_sql() -> anyhow::Result<()> {
+        use diesel::sqlite::Sqlite;
+
+        let options = TxnOptions::new().serializable();
+        assert_eq!(Sqlite::txn_options_sql(&options)?, TxnOptionsSql::default());
+
+        let Err(error) = Sqlite::txn_options_sql(&options.read_only()) else {
+            panic!("expected an error");
+        };
+        assert!(error.to_string().contains("[\"read_only\"]"), "{error}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_txn_options() -> anyhow::Result<()> {
+        let adapter = Adapter::default();
+        let txn = TxnManagerDiesel::new(adapter.clone());
+        let mut serializable = txn
+            .clone()
+            .with_txn_options(TxnOptions::new().serializable());
+
+        let nested = txn.clone();
+        serializable
+            .do_transaction(async {
+                // A savepoint can't have other options than the outer transaction
+                let res = nested
+                    .clone()
+                    .with_txn_options(TxnOptions::new().read_only())
+                    .do_transaction(async { Ok(Ok::<_, ()>(())) })
+                    .await;
+                assert!(res.is_err());
+
+                nested
+                    .clone()
+                    .with_txn_options(TxnOptions::new().serializable())
+                    .do_transaction(async { Ok(Ok::<_, ()>(())) })
+                    .await
+            })
+            .await?
+            .unwrap();
+        txn.clone()
+            .do_transaction(async { Ok(Ok::<_, ()>(())) })
+            .await?
+            .unwrap();
+
+        assert_eq!(
+            *adapter.calls.lock().unwrap(),
+            vec![
+                "begin with options",
+                "savepoint",
+                "release",
+                "commit",
+                "begin",
+                "commit"
+            ]
+        );
+
+        Ok(())
+    }
+
+    struct Hook {
+        calls: Arc<SyncMutex<Vec<&'static str>>>,
+        name: &'static str,
+        fail: bool,
+    }
+
+    impl BeforeCommitHook for Hook {
+        async fn before_commit(self: Box<Self>) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push(self.name);
+            anyhow::ensure!(!self.fail, "hook {} failed", self.name);
+            Ok(())
+        }
+    }
+
+    struct AsyncCallback {
+        calls: Arc<SyncMutex<Vec<&'static str>>>,
+        sender: Option<tokio::sync::oneshot::Sender<TxnResult>>,
+    }
+
+    impl AsyncTxCallback for AsyncCallback {
+        async fn call(self: Box<Self>, tx_result: TxnResult) {
+            tokio::task::yield_now().await;
+            self.calls.lock().unwrap().push(match tx_result {
+                TxnResult::Committed => "async committed",
+                TxnResult::RolledBack => "async rolled back",
+            });
+            if let Some(sender) = self.sender {
+                sender.send(tx_result).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_before_commit_and_async_callbacks() -> anyhow::Result<()> {
+        let adapter = Adapter::default();
+        let calls = adapter.calls.clone();
+        let hook = |name, fail| Hook {
+            calls: calls.clone(),
+            name,
+            fail,
+        };
+        let callback = || AsyncCallback {
+            calls: calls.clone(),
+            sender: None,
+        };
+        let mut txn = TxnManagerDiesel::new(adapter.clone());
+
+        // Registered before the transaction, run before it commits
+        txn.register_before_commit(hook("before begin", false));
+        let mut inner = txn.clone();
+        let inner2 = txn.clone();
+        txn.do_transaction(async {
+            inner.register_before_commit(hook("first", false));
+            inner.register_async_callback(callback());
+            let rolled_back = inner
+                .do_transaction(async {
+                    // Dropped with the savepoint
+                    inner2.register_before_commit(hook("rolled back", false));
+                    Ok(Err::<(), _>("inner"))
+                })
+                .await?;
+            assert!(rolled_back.is_err());
+            inner.register_before_commit(hook("second", false));
+            Ok(Ok::<_, ()>(()))
+        })
+        .await?
+        .unwrap();
+        assert_eq!(
+            std::mem::take(&mut *calls.lock().unwrap()),
+            vec![
+                "begin",
+                "savepoint",
+                "rollback to savepoint",
+                "before begin",
+                "first",
+                "second",
+                "commit",
+                "async committed"
+            ]
+        );
+
+        // A failing hook rolls back
+        let inner = txn.clone();
+        let res = txn
+            .do_transaction(async {
+                inner.register_before_commit(hook("failing", true));
+                inner.register_before_commit(hook("skipped", false));
+                inner.register_async_callback(callback());
+                Ok(Ok::<_, ()>(()))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(txn.state(), TxnState::RolledBack);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["begin", "failing", "rollback", "async rolled back"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spawn_async_callbacks() -> anyhow::Result<()> {
+        use crate::async_task::tokio_impl::LocalTaskRunnerTokio;
+
+        let adapter = Adapter::default();
+        let mut txn = TxnManagerDiesel::new(adapter.clone())
+            .with_callback_runner(LocalTaskRunnerTokio::get_or_init()?);
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        let inner = txn.clone();
+        txn.do_transaction(async {
+            inner.register_async_callback(AsyncCallback {
+                calls: adapter.calls.clone(),
+                sender: Some(sender),
+            });
+            Ok(Ok::<_, ()>(()))
+        })
+        .await?
+        .unwrap();
+        assert!(matches!(receiver.await?, TxnResult::Committed));
+
+        Ok(())
+    }
+}
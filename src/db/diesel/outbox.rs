@@ -0,0 +1,231 @@
+//! Postgres outbox store
+//!
+//! The table is created by [`OUTBOX_TABLE_SQL`], or a table of the same shape with another
+//! name given to [`OutboxStoreDiesel::with_table`].
+
+use std::time::Duration;
+
+use diesel::{
+    pg::Pg,
+    sql_types::{Array, BigInt, Double, Integer, Jsonb, Nullable, Text},
+    QueryableByName,
+};
+
+use crate::{
+    outbox::{OutboxMessage, OutboxStore, StoredMessage},
+    provider::{Provider, ProviderContext},
+};
+
+use super::DieselSqlRunner;
+
+pub const DEFAULT_OUTBOX_TABLE: &str = "outbox";
+
+/// Schema of the default outbox table
+pub const OUTBOX_TABLE_SQL: &str = r#"
+CREATE TABLE IF NOT EXISTS outbox (
+    id BIGSERIAL PRIMARY KEY,
+    topic TEXT NOT NULL,
+    msg_key TEXT,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    available_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ,
+    delivered_at TIMESTAMPTZ,
+    dead BOOLEAN NOT NULL DEFAULT false,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS outbox_pending_idx ON outbox (id)
+    WHERE delivered_at IS NULL AND NOT dead;
+"#;
+
+/// Outbox store on a diesel adapter, messages are written on the adapter's connection and
+/// hence inside its transaction
+#[derive(Clone)]
+pub struct OutboxStoreDiesel<A> {
+    adapter: A,
+    table: &'static str,
+}
+
+#[derive(QueryableByName)]
+struct OutboxRow {
+    #[diesel(sql_type = BigInt)]
+    id: i64,
+    #[diesel(sql_type = Text)]
+    topic: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    msg_key: Option<String>,
+    #[diesel(sql_type = Jsonb)]
+    payload: serde_json::Value,
+    #[diesel(sql_type = Integer)]
+    attempts: i32,
+}
+
+impl<A> Provider for OutboxStoreDiesel<A>
+where
+    A: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(A::build(ctx)?))
+    }
+}
+
+impl<A> OutboxStoreDiesel<A> {
+    pub fn new(adapter: A) -> Self {
+        Self {
+            adapter,
+            table: DEFAULT_OUTBOX_TABLE,
+        }
+    }
+
+    /// Use another table, the name is put into the SQL as is
+    pub fn with_table(mut self, table: &'static str) -> Self {
+        self.table = table;
+        self
+    }
+
+    /// Lock the claimable rows, then claim the ones whose earlier pending rows of the same key
+    /// are all locked by this statement. An earlier row skipped by `SKIP LOCKED` is being
+    /// claimed by another worker, whose lease may not be visible yet, so the later rows of
+    /// its key must wait.
+    fn claim_sql(&self) -> String {
+        let table = self.table;
+        format!(
+            "WITH candidates AS (\
+             SELECT id, msg_key FROM {table} \
+             WHERE delivered_at IS NULL AND NOT dead AND available_at <= now() \
+             AND (locked_until IS NULL OR locked_until <= now()) \
+             AND (msg_key IS NULL OR NOT EXISTS (\
+             SELECT 1 FROM {table} earlier \
+             WHERE earlier.msg_key = {table}.msg_key AND earlier.id < {table}.id \
+             AND earlier.delivered_at IS NULL AND NOT earlier.dead \
+             AND (earlier.available_at > now() OR earlier.locked_until > now()))) \
+             ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED) \
+             UPDATE {table} SET locked_until = now() + make_interval(secs => $2), \
+             attempts = attempts + 1 \
+             WHERE id IN (\
+             SELECT c.id FROM candidates c \
+             WHERE c.msg_key IS NULL OR NOT EXISTS (\
+             SELECT 1 FROM {table} earlier \
+             WHERE earlier.msg_key = c.msg_key AND earlier.id < c.id \
+             AND earlier.delivered_at IS NULL AND NOT earlier.dead \
+             AND earlier.id NOT IN (SELECT id FROM candidates))) \
+             RETURNING id, topic, msg_key, payload, attempts"
+        )
+    }
+}
+
+impl<A> OutboxStore for OutboxStoreDiesel<A>
+where
+    A: DieselSqlRunner<Pg>,
+{
+    async fn enqueue(&mut self, messages: Vec<OutboxMessage>) -> anyhow::Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let mut topics = Vec::with_capacity(messages.len());
+        let mut keys = Vec::with_capacity(messages.len());
+        let mut payloads = Vec::with_capacity(messages.len());
+        for message in messages {
+            topics.push(message.topic);
+            keys.push(message.key);
+            payloads.push(message.payload);
+        }
+
+        let sql = format!(
+            "INSERT INTO {} (topic, msg_key, payload) \
+             SELECT * FROM unnest($1::text[], $2::text[], $3::jsonb[])",
+            self.table
+        );
+        let query = diesel::sql_query(sql)
+            .bind::<Array<Text>, _>(topics)
+            .bind::<Array<Nullable<Text>>, _>(keys)
+            .bind::<Array<Jsonb>, _>(payloads);
+        self.adapter.sql_execute(query).await?;
+
+        Ok(())
+    }
+
+    async fn claim(&mut self, limit: u32, lease: Duration) -> anyhow::Result<Vec<StoredMessage>> {
+        let query = diesel::sql_query(self.claim_sql())
+            .bind::<BigInt, _>(limit as i64)
+            .bind::<Double, _>(lease.as_secs_f64());
+        let mut rows: Vec<OutboxRow> = self.adapter.sql_results(query).await?;
+        // RETURNING does not keep the order of the sub-select
+        rows.sort_by_key(|row| row.id);
+
+        Ok(rows
+            .into_iter()
+            .map(|row| StoredMessage {
+                id: row.id,
+                topic: row.topic,
+                key: row.msg_key,
+                payload: row.payload,
+                attempts: row.attempts as u32,
+            })
+            .collect())
+    }
+
+    async fn mark_delivered(&mut self, ids: &[i64]) -> anyhow::Result<()> {
+        let sql = format!(
+            "UPDATE {} SET delivered_at = now(), locked_until = NULL WHERE id = ANY($1)",
+            self.table
+        );
+        let query = diesel::sql_query(sql).bind::<Array<BigInt>, _>(ids);
+        self.adapter.sql_execute(query).await?;
+
+        Ok(())
+    }
+
+    async fn release(&mut self, ids: &[i64]) -> anyhow::Result<()> {
+        let sql = format!(
+            "UPDATE {} SET locked_until = NULL, attempts = attempts - 1 WHERE id = ANY($1)",
+            self.table
+        );
+        let query = diesel::sql_query(sql).bind::<Array<BigInt>, _>(ids);
+        self.adapter.sql_execute(query).await?;
+
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &mut self,
+        id: i64,
+        error: &str,
+        retry_after: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let sql = format!(
+            "UPDATE {} SET locked_until = NULL, last_error = $2, \
+             dead = $3 IS NULL, \
+             available_at = CASE WHEN $3 IS NULL THEN available_at \
+             ELSE now() + make_interval(secs => $3) END \
+             WHERE id = $1",
+            self.table
+        );
+        let query = diesel::sql_query(sql)
+            .bind::<BigInt, _>(id)
+            .bind::<Text, _>(error)
+            .bind::<Nullable<Double>, _>(retry_after.map(|d| d.as_secs_f64()));
+        self.adapter.sql_execute(query).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_sql() {
+        let store = OutboxStoreDiesel::new(()).with_table("events_outbox");
+        let sql = store.claim_sql();
+        assert!(sql.starts_with("WITH candidates AS (SELECT id, msg_key FROM events_outbox"));
+        assert!(sql.contains("UPDATE events_outbox SET"));
+        assert!(sql.contains("FOR UPDATE SKIP LOCKED"));
+        assert!(sql.contains("earlier.msg_key = events_outbox.msg_key"));
+        // An earlier row skipped as locked holds back the later rows of its key
+        assert!(sql.contains("earlier.id NOT IN (SELECT id FROM candidates)"));
+    }
+}
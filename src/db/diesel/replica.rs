@@ -0,0 +1,384 @@
+//! Read replicas
+//!
+//! A [`DbAdapterDiesel`] with replicas sends the reads made through
+//! [`DbAdapterDiesel::replica`] to a replica connection, while loaders and writes use the
+//! primary connection as usual. Reads stay on the primary:
+//!
+//! - inside a transaction, so they see its writes
+//! - for the sticky window after a commit or a write by [`DieselSqlRunner::sql_execute`]
+//!   outside a transaction, so they see the committed writes although the replicas lag
+//!   behind
+//! - when no replica connection can be fetched
+//!
+//! The routing state is shared by the clones of an adapter, i.e. by the [`ProviderContext`]
+//! the adapter is built in, which is usually a request. To keep a user's reads on the primary
+//! across requests, store [`DbAdapterDiesel::last_write`] in the session and restore it by
+//! [`DbAdapterDiesel::mark_written_at`] in the next request. A write made by
+//! `sql_result`/`sql_results` outside a transaction, e.g. `INSERT ... RETURNING`, is not
+//! noticed, call [`DbAdapterDiesel::mark_written`] after it.
+//!
+//! ```rust,ignore
+//! static REPLICAS: OnceLock<ReplicaPools<PgReplicaPool>> = OnceLock::new();
+//!
+//! #[derive(Clone)]
+//! struct Replicas;
+//!
+//! impl ReplicaPool<PgConn> for Replicas {
+//!     async fn replica_conn(&self) -> anyhow::Result<Option<PgConn>> {
+//!         REPLICAS.get().unwrap().replica_conn().await
+//!     }
+//! }
+//!
+//! type Adapter = DbAdapterDiesel<PgPrimaryPool, Replicas>;
+//!
+//! impl SubsetReader<UserFull> for UserRepo {
+//!     async fn read<I>(&mut self, id: I) -> anyhow::Result<Option<UserFull>> {
+//!         let query = users::table.find(UserIdent::from(id)).select(UserRow::as_select());
+//!         let row = self.adapter.replica().sql_result(query).await?;
+//!         Ok(row.map(Into::into))
+//!     }
+//! }
+//! ```
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use diesel::{backend::Backend, expression::exists::Exists, query_builder::AsQuery, Expression};
+use diesel_async::{
+    methods::{ExecuteDsl, LoadQuery},
+    AsyncConnection, RunQueryDsl,
+};
+
+use crate::{
+    db::ConnectionPool,
+    provider::{Provider, ProviderContext},
+};
+
+use super::{DbAdapterDiesel, DieselSqlRunner, SqlErrorDiesel};
+
+/// How long reads stay on the primary after a commit by default
+pub const DEFAULT_STICKY_WINDOW: Duration = Duration::from_secs(5);
+
+/// Source of replica connections of type `C`
+pub trait ReplicaPool<C>: Clone + 'static {
+    /// A replica connection, `None` to read from the primary
+    async fn replica_conn(&self) -> anyhow::Result<Option<C>>;
+
+    /// How long reads stay on the primary after a commit
+    fn sticky_window(&self) -> Duration {
+        DEFAULT_STICKY_WINDOW
+    }
+}
+
+/// No replica, every read goes to the primary
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoReplica;
+
+/// Replica pools used in turn
+pub struct ReplicaPools<P> {
+    pools: Arc<[P]>,
+    next: Arc<AtomicUsize>,
+    sticky_window: Duration,
+}
+
+/// Runs the reads of an adapter on a replica, see [`DbAdapterDiesel::replica`]
+pub struct ReplicaRouted<'a, P, R>
+where
+    P: ConnectionPool,
+{
+    adapter: &'a mut DbAdapterDiesel<P, R>,
+}
+
+/// Transaction state of an adapter, shared by its clones
+#[derive(Debug, Default)]
+pub(super) struct Routing {
+    in_transaction: bool,
+    /// The last commit, or write outside a transaction
+    last_write: Option<Instant>,
+}
+
+impl<C> ReplicaPool<C> for NoReplica {
+    async fn replica_conn(&self) -> anyhow::Result<Option<C>> {
+        Ok(None)
+    }
+}
+
+impl Provider for NoReplica {
+    fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(NoReplica)
+    }
+}
+
+impl<P> Clone for ReplicaPools<P> {
+    fn clone(&self) -> Self {
+        Self {
+            pools: self.pools.clone(),
+            next: self.next.clone(),
+            sticky_window: self.sticky_window,
+        }
+    }
+}
+
+/// A single replica
+impl<P> Provider for ReplicaPools<P>
+where
+    P: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(vec![P::build(ctx)?]))
+    }
+}
+
+impl<P> ReplicaPools<P> {
+    pub fn new(pools: Vec<P>) -> Self {
+        Self {
+            pools: pools.into(),
+            next: Arc::new(AtomicUsize::new(0)),
+            sticky_window: DEFAULT_STICKY_WINDOW,
+        }
+    }
+
+    pub fn with_sticky_window(mut self, sticky_window: Duration) -> Self {
+        self.sticky_window = sticky_window;
+        self
+    }
+}
+
+impl<P> ReplicaPool<P::Connection> for ReplicaPools<P>
+where
+    P: ConnectionPool,
+{
+    /// Round robin, a replica failing to give a connection is skipped
+    async fn replica_conn(&self) -> anyhow::Result<Option<P::Connection>> {
+        let len = self.pools.len();
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..len {
+            let idx = (start + offset) % len;
+            match self.pools[idx].get_conn().await {
+                Ok(conn) => return Ok(Some(conn)),
+                Err(error) => {
+                    tracing::warn!("Failed to get a connection of replica {idx}: {error:#}");
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn sticky_window(&self) -> Duration {
+        self.sticky_window
+    }
+}
+
+impl Routing {
+    pub(super) fn begin(&mut self) {
+        self.in_transaction = true;
+    }
+
+    pub(super) fn end(&mut self, committed: bool) {
+        self.in_transaction = false;
+        if committed {
+            self.last_write = Some(Instant::now());
+        }
+    }
+
+    /// A statement was executed, it is committed at once outside a transaction
+    pub(super) fn executed(&mut self) {
+        if !self.in_transaction {
+            self.last_write = Some(Instant::now());
+        }
+    }
+
+    pub(super) fn written_at(&mut self, at: Instant) {
+        self.last_write = Some(self.last_write.map_or(at, |last| last.max(at)));
+    }
+
+    pub(super) fn last_write(&self) -> Option<SystemTime> {
+        self.last_write
+            .map(|write| SystemTime::now() - write.elapsed())
+    }
+
+    fn use_replica(&self, sticky_window: Duration) -> bool {
+        !self.in_transaction
+            && self
+                .last_write
+                .is_none_or(|write| write.elapsed() >= sticky_window)
+    }
+}
+
+impl<'a, P, R> ReplicaRouted<'a, P, R>
+where
+    P: ConnectionPool,
+{
+    pub(super) fn new(adapter: &'a mut DbAdapterDiesel<P, R>) -> Self {
+        Self { adapter }
+    }
+}
+
+impl<P, R> ReplicaRouted<'_, P, R>
+where
+    P: ConnectionPool,
+    R: ReplicaPool<P::Connection>,
+{
+    /// Whether reads go to a replica now
+    pub fn uses_replica(&self) -> bool {
+        let sticky_window = self.adapter.replicas.sticky_window();
+        self.adapter
+            .routing
+            .lock()
+            .unwrap()
+            .use_replica(sticky_window)
+    }
+
+    async fn replica_conn(&self) -> anyhow::Result<Option<P::Connection>> {
+        if !self.uses_replica() {
+            return Ok(None);
+        }
+
+        self.adapter.replicas.replica_conn().await
+    }
+}
+
+impl<P, R, DB> DieselSqlRunner<DB> for ReplicaRouted<'_, P, R>
+where
+    P: ConnectionPool,
+    P::Connection: AsyncConnection<Backend = DB> + Send,
+    R: ReplicaPool<P::Connection>,
+    DB: Backend,
+{
+    type Connection = P::Connection;
+
+    /// Always on the primary, replicas are read only
+    async fn sql_execute<Sql>(&mut self, sql: Sql) -> Result<usize, SqlErrorDiesel>
+    where
+        Sql: ExecuteDsl<Self::Connection>,
+    {
+        self.adapter.sql_execute(sql).await
+    }
+
+    async fn sql_result<'query, U, Sql>(&mut self, sql: Sql) -> Result<Option<U>, SqlErrorDiesel>
+    where
+        U: Send,
+        Sql: LoadQuery<'query, Self::Connection, U> + 'query,
+    {
+        use diesel::result::OptionalExtension;
+
+        match self.replica_conn().await? {
+            Some(mut conn) => Ok(sql.get_result(&mut conn).await.optional()?),
+            None => self.adapter.sql_result(sql).await,
+        }
+    }
+
+    async fn sql_results<'query, U, Sql>(&mut self, sql: Sql) -> Result<Vec<U>, SqlErrorDiesel>
+    where
+        U: Send,
+        Sql: LoadQuery<'query, Self::Connection, U> + 'query,
+    {
+        match self.replica_conn().await? {
+            Some(mut conn) => Ok(sql.get_results(&mut conn).await?),
+            None => self.adapter.sql_results(sql).await,
+        }
+    }
+
+    async fn sql_exists<'query, Sql>(&mut self, sql: Sql) -> anyhow::Result<bool>
+    where
+        Exists<Sql>: Expression,
+        diesel::dsl::select<diesel::dsl::exists<Sql>>:
+            LoadQuery<'query, Self::Connection, bool> + 'query + Send,
+        diesel::dsl::select<Exists<Sql>>: AsQuery,
+    {
+        match self.replica_conn().await? {
+            Some(mut conn) => Ok(diesel::select(diesel::dsl::exists(sql))
+                .get_result(&mut conn)
+                .await?),
+            None => self.adapter.sql_exists(sql).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct Pool {
+        id: usize,
+        up: bool,
+    }
+
+    impl ConnectionPool for Pool {
+        type Connection = usize;
+
+        async fn get_conn(&self) -> anyhow::Result<Self::Connection> {
+            anyhow::ensure!(self.up, "replica {} is down", self.id);
+            Ok(self.id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() -> anyhow::Result<()> {
+        let pools = ReplicaPools::new(vec![
+            Pool { id: 0, up: true },
+            Pool { id: 1, up: false },
+            Pool { id: 2, up: true },
+        ]);
+
+        let mut conns = vec![];
+        for _ in 0..4 {
+            conns.push(pools.replica_conn().await?.unwrap());
+        }
+        assert_eq!(conns, vec![0, 2, 2, 0]);
+
+        let down = ReplicaPools::new(vec![Pool { id: 0, up: false }]);
+        assert_eq!(down.replica_conn().await?, None);
+        assert_eq!(
+            ReplicaPools::<Pool>::new(vec![]).replica_conn().await?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_routing() {
+        let window = Duration::from_secs(60);
+        let mut routing = Routing::default();
+        assert!(routing.use_replica(window));
+
+        routing.begin();
+        assert!(!routing.use_replica(window));
+
+        routing.end(false);
+        assert!(routing.use_replica(window));
+
+        routing.begin();
+        routing.end(true);
+        assert!(!routing.use_replica(window));
+        assert!(routing.use_replica(Duration::ZERO));
+
+        // A write outside a transaction is committed at once
+        let mut routing = Routing::default();
+        routing.begin();
+        routing.executed();
+        routing.end(false);
+        assert!(routing.use_replica(window));
+        routing.executed();
+        assert!(!routing.use_replica(window));
+
+        // Restored from another request
+        let mut routing = Routing::default();
+        routing.written_at(Instant::now() - Duration::from_secs(30));
+        assert!(!routing.use_replica(window));
+        assert!(routing.use_replica(Duration::from_secs(10)));
+        assert!(routing.last_write().is_some());
+    }
+}
@@ -0,0 +1,364 @@
+//! SQLite connection pool for Diesel
+//!
+//! SQLite connections are synchronous, they run on tokio's blocking threads through
+//! [`SyncConnectionWrapper`]. A [`DbAdapterDiesel`](super::DbAdapterDiesel) over
+//! [`SqlitePool`] works as over a postgres pool.
+//!
+//! Every connection is set up with `foreign_keys` on and a `busy_timeout`, so that concurrent
+//! writers wait for the database lock rather than fail right away. Use a single connection for
+//! an in-memory database, each connection opens a database of its own.
+
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use diesel::{ConnectionError, SqliteConnection};
+use diesel_async::pooled_connection::deadpool::{Object, Pool};
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig};
+use diesel_async::sync_connection_wrapper::SyncConnectionWrapper;
+use diesel_async::{AsyncConnection, SimpleAsyncConnection};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::ConnectionPool,
+    provider::{Provider, ProviderContext},
+};
+
+pub type AsyncSqliteConnection = SyncConnectionWrapper<SqliteConnection>;
+
+/// sqlite pool connection
+pub type SqliteConn = Object<AsyncSqliteConnection>;
+pub type SqlitePool = Pool<AsyncSqliteConnection>;
+
+static POOL: OnceLock<SqlitePool> = OnceLock::new();
+
+/// The global sqlite pool, initialized by [`init`]
+///
+/// ```rust,ignore
+/// type Adapter = DbAdapterDiesel<GlobalSqlitePool>;
+/// type TxnManager = TxnManagerDiesel<Adapter>;
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobalSqlitePool;
+
+/// SQLite connection pool config
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SqlitePoolConfig {
+    pub max_conn: u32,
+    /// A file path, a `file:` URI or `:memory:`
+    pub url: String,
+    /// How long a connection waits for the database lock, in milliseconds
+    pub busy_timeout_ms: u32,
+}
+
+impl Default for SqlitePoolConfig {
+    fn default() -> Self {
+        Self {
+            max_conn: 4,
+            url: "bagua.db".to_string(),
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+/// Initialize a global sqlite connection pool
+pub async fn init(config: &SqlitePoolConfig) -> Result<()> {
+    init_custom_pool(&POOL, config).await
+}
+
+/// Get a sqlite connection
+///
+/// # Panics
+///
+/// Panics if the global sqlite connection pool has not been initialized
+pub async fn sqlite_conn() -> Result<SqliteConn> {
+    let conn = POOL.get().unwrap().get().await?;
+    Ok(conn)
+}
+
+/// Build a sqlite connection pool
+pub fn build_pool(config: &SqlitePoolConfig) -> Result<SqlitePool> {
+    let pragmas = format!(
+        "PRAGMA foreign_keys = ON; PRAGMA busy_timeout = {};",
+        config.busy_timeout_ms
+    );
+    let mut manager_config = ManagerConfig::default();
+    manager_config.custom_setup = Box::new(move |url| {
+        let url = url.to_string();
+        let pragmas = pragmas.clone();
+        async move {
+            let mut conn = AsyncSqliteConnection::establish(&url).await?;
+            conn.batch_execute(&pragmas)
+                .await
+                .map_err(ConnectionError::CouldntSetupConfiguration)?;
+            Ok(conn)
+        }
+        .boxed()
+    });
+
+    let manager = AsyncDieselConnectionManager::<AsyncSqliteConnection>::new_with_config(
+        &config.url,
+        manager_config,
+    );
+    let pool = Pool::builder(manager)
+        .max_size(config.max_conn as usize)
+        .build()?;
+
+    Ok(pool)
+}
+
+/// Initialize a custom sqlite connection pool
+pub async fn init_custom_pool(
+    pool: &'static OnceLock<SqlitePool>,
+    config: &SqlitePoolConfig,
+) -> Result<()> {
+    if pool.get().is_some() {
+        return Ok(());
+    }
+
+    let built_pool = build_pool(config)?;
+    let _conn = built_pool.get().await.with_context(|| {
+        format!(
+            "Failed to get a sqlite connection after building the pool. URL = {}",
+            config.url
+        )
+    })?;
+
+    pool.get_or_init(|| built_pool);
+
+    Ok(())
+}
+
+/// Get the global sqlite connection pool
+pub fn get_pool() -> &'static SqlitePool {
+    POOL.get().unwrap()
+}
+
+impl ConnectionPool for SqlitePool {
+    type Connection = SqliteConn;
+
+    async fn get_conn(&self) -> Result<Self::Connection> {
+        Ok(self.get().await?)
+    }
+}
+
+impl ConnectionPool for GlobalSqlitePool {
+    type Connection = SqliteConn;
+
+    async fn get_conn(&self) -> Result<Self::Connection> {
+        sqlite_conn().await
+    }
+}
+
+impl Provider for GlobalSqlitePool {
+    fn build(_ctx: &mut ProviderContext) -> Result<Self> {
+        Ok(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::{ExpressionMethods, QueryDsl};
+
+    use super::*;
+    use crate::{
+        db::{
+            diesel::{testing::rollback_only, DbAdapterDiesel, DieselSqlRunner, TxnManagerDiesel},
+            primitives::JsonProxy,
+            TxnManager,
+        },
+        diesel_smallint_enum,
+    };
+
+    diesel::table! {
+        notes (id) {
+            id -> BigInt,
+            kind -> SmallInt,
+            tags -> Text,
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Kind {
+        Draft,
+        Published,
+    }
+
+    diesel_smallint_enum! {
+        enum Kind {
+            Draft = 1,
+            Published = 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_adapter() -> Result<()> {
+        let config = SqlitePoolConfig {
+            max_conn: 1,
+            url: ":memory:".to_string(),
+            ..Default::default()
+        };
+        let mut adapter = DbAdapterDiesel::new(build_pool(&config)?);
+        let mut txn = TxnManagerDiesel::new(adapter.clone());
+
+        let create = "CREATE TABLE notes (id BIGINT PRIMARY KEY, kind SMALLINT NOT NULL, tags TEXT NOT NULL)";
+        adapter.sql_execute(diesel::sql_query(create)).await?;
+
+        let insert = |id: i64, kind: Kind| {
+            diesel::insert_into(notes::table).values((
+                notes::id.eq(id),
+                notes::kind.eq(kind),
+                notes::tags.eq(JsonProxy(vec!["a".to_string()])),
+            ))
+        };
+
+        let mut inner = adapter.clone();
+        txn.do_transaction(async {
+            inner.sql_execute(insert(1, Kind::Published)).await?;
+            Ok(Ok::<_, ()>(()))
+        })
+        .await?
+        .unwrap();
+
+        let mut inner = adapter.clone();
+        let rolled_back = txn
+            .do_transaction(async {
+                inner.sql_execute(insert(2, Kind::Draft)).await?;
+                Ok(Err::<(), _>("rollback"))
+            })
+            .await?;
+        assert!(rolled_back.is_err());
+
+        let rows: Vec<(i64, Kind, JsonProxy<Vec<String>>)> =
+            adapter.sql_results(notes::table.order(notes::id)).await?;
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].0, rows[0].1), (1, Kind::Published));
+        assert_eq!(rows[0].2 .0, vec!["a".to_string()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nested_transaction() -> Result<()> {
+        let config = SqlitePoolConfig {
+            max_conn: 1,
+            url: ":memory:".to_string(),
+            ..Default::default()
+        };
+        let mut adapter = DbAdapterDiesel::new(build_pool(&config)?);
+        let mut txn = TxnManagerDiesel::new(adapter.clone());
+
+        let create = "CREATE TABLE notes (id BIGINT PRIMARY KEY, kind SMALLINT NOT NULL, tags TEXT NOT NULL)";
+        adapter.sql_execute(diesel::sql_query(create)).await?;
+
+        let insert = |id: i64| {
+            diesel::insert_into(notes::table).values((
+                notes::id.eq(id),
+                notes::kind.eq(Kind::Draft),
+                notes::tags.eq(JsonProxy(Vec::<String>::new())),
+            ))
+        };
+
+        let mut inner = adapter.clone();
+        let mut nested = txn.clone();
+        txn.do_transaction(async {
+            inner.sql_execute(insert(1)).await?;
+            let mut savepoint = inner.clone();
+            let rolled_back = nested
+                .do_transaction(async {
+                    savepoint.sql_execute(insert(2)).await?;
+                    Ok(Err::<(), _>("rollback"))
+                })
+                .await?;
+            assert!(rolled_back.is_err());
+            inner.sql_execute(insert(3)).await?;
+            Ok(Ok::<_, ()>(()))
+        })
+        .await?
+        .unwrap();
+
+        let ids: Vec<i64> = adapter
+            .sql_results(notes::table.select(notes::id).order(notes::id))
+            .await?;
+        assert_eq!(ids, vec![1, 3]);
+
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct TestPool(SqlitePool);
+
+    impl ConnectionPool for TestPool {
+        type Connection = SqliteConn;
+
+        async fn get_conn(&self) -> Result<Self::Connection> {
+            self.0.get_conn().await
+        }
+    }
+
+    impl Provider for TestPool {
+        fn build(_ctx: &mut ProviderContext) -> Result<Self> {
+            unreachable!("the adapter is injected")
+        }
+    }
+
+    type Adapter = DbAdapterDiesel<TestPool>;
+
+    #[tokio::test]
+    async fn test_rollback_only() -> Result<()> {
+        let config = SqlitePoolConfig {
+            max_conn: 1,
+            url: ":memory:".to_string(),
+            ..Default::default()
+        };
+        let pool = TestPool(build_pool(&config)?);
+        let create = "CREATE TABLE notes (id BIGINT PRIMARY KEY, kind SMALLINT NOT NULL, tags TEXT NOT NULL)";
+        Adapter::new(pool.clone())
+            .sql_execute(diesel::sql_query(create))
+            .await?;
+
+        let insert = |id: i64| {
+            diesel::insert_into(notes::table).values((
+                notes::id.eq(id),
+                notes::kind.eq(Kind::Draft),
+                notes::tags.eq(JsonProxy(Vec::<String>::new())),
+            ))
+        };
+
+        rollback_only(Adapter::new(pool.clone()), |txn| async move {
+            // Transactions of use cases are savepoints in the test transaction
+            let mut txn_manager = txn.provide::<TxnManagerDiesel<Adapter>>()?;
+            let mut adapter = txn.provide::<Adapter>()?;
+            txn_manager
+                .do_transaction(async {
+                    adapter.sql_execute(insert(1)).await?;
+                    Ok(Ok::<_, ()>(()))
+                })
+                .await?
+                .unwrap();
+            let mut adapter = txn.provide::<Adapter>()?;
+            let rolled_back = txn_manager
+                .do_transaction(async {
+                    adapter.sql_execute(insert(2)).await?;
+                    Ok(Err::<(), _>("rollback"))
+                })
+                .await?;
+            assert!(rolled_back.is_err());
+
+            let ids: Vec<i64> = txn
+                .adapter()
+                .sql_results(notes::table.select(notes::id))
+                .await?;
+            assert_eq!(ids, vec![1]);
+            Ok(())
+        })
+        .await?;
+
+        let ids: Vec<i64> = Adapter::new(pool)
+            .sql_results(notes::table.select(notes::id))
+            .await?;
+        assert!(ids.is_empty());
+
+        Ok(())
+    }
+}
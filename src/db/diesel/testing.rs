@@ -0,0 +1,282 @@
+//! Rollback-only transactions for tests
+//!
+//! [`rollback_only`] begins a transaction with a [`TxnManagerDiesel`] on an adapter, runs the
+//! test body with a [`RollbackTxn`] and rolls the transaction back whatever the body returns.
+//! Repositories and use cases provided by [`RollbackTxn::provide`] share the adapter, hence
+//! its connection and transaction, so the test sees its own writes and leaves nothing behind.
+//! They share the transaction manager as well, so transactions the use cases begin are nested
+//! in it and run in savepoints. Callbacks registered in the test run once it is rolled back,
+//! with [`TxnResult::RolledBack`](crate::db::TxnResult::RolledBack).
+//!
+//! Each test gets its own connection from the pool of its adapter, tests run concurrently as
+//! long as the pool has connections for them.
+//!
+//! ```rust,ignore
+//! #[tokio::test]
+//! async fn test_register() -> anyhow::Result<()> {
+//!     rollback_only(DbAdapterDiesel::new(TestPool), |txn| async move {
+//!         let mut usecase = txn.provide::<RegisterUsecase>()?;
+//!         usecase.execute(register_cmd()).await??;
+//!
+//!         let mut repo = txn.provide::<UserRepo>()?;
+//!         assert!(repo.exists(UserId(1)).await?);
+//!         Ok(())
+//!     })
+//!     .await
+//! }
+//! ```
+
+use std::future::Future;
+
+use crate::{
+    db::{DbAdapter, TxnManager, TxnOptions},
+    provider::{Provider, ProviderContext},
+};
+
+use super::TxnManagerDiesel;
+
+/// A transaction which is never committed, cloning it shares the transaction
+#[derive(Clone)]
+pub struct RollbackTxn<A> {
+    adapter: A,
+    txn: TxnManagerDiesel<A>,
+}
+
+impl<A> RollbackTxn<A>
+where
+    A: DbAdapter,
+{
+    /// Begin the transaction on `adapter`, it must not be shared with other tests
+    pub async fn begin(adapter: A) -> anyhow::Result<Self> {
+        Self::begin_with(adapter, TxnOptions::default()).await
+    }
+
+    /// Begin the transaction on `adapter` with `options`
+    pub async fn begin_with(adapter: A, options: TxnOptions) -> anyhow::Result<Self> {
+        let mut txn = TxnManagerDiesel::new(adapter.clone()).with_txn_options(options);
+        txn.begin_outermost().await?;
+
+        Ok(Self { adapter, txn })
+    }
+
+    /// The adapter in the transaction
+    pub fn adapter(&self) -> A {
+        self.adapter.clone()
+    }
+
+    /// The transaction manager of the transaction, transactions it begins are savepoints
+    pub fn txn(&self) -> TxnManagerDiesel<A> {
+        self.txn.clone()
+    }
+
+    /// Put the adapter and the transaction manager into `ctx`, providers building an `A` or a
+    /// `TxnManagerDiesel<A>` get them
+    pub fn inject(&self, ctx: &mut ProviderContext) {
+        ctx.insert(self.adapter.clone());
+        ctx.insert(self.txn.clone());
+    }
+
+    /// A provider context holding the adapter and the transaction manager
+    pub fn context(&self) -> ProviderContext {
+        let mut ctx = ProviderContext::new();
+        self.inject(&mut ctx);
+        ctx
+    }
+
+    /// Provide a `T` built on the adapter in the transaction
+    pub fn provide<T>(&self) -> anyhow::Result<T>
+    where
+        T: Provider,
+    {
+        T::provide_with(|ctx| self.inject(ctx))
+    }
+
+    /// Provide a `T` built on the adapter in the transaction, `f` may add instances first
+    pub fn provide_with<T>(&self, f: impl FnOnce(&mut ProviderContext)) -> anyhow::Result<T>
+    where
+        T: Provider,
+    {
+        T::provide_with(|ctx| {
+            self.inject(ctx);
+            f(ctx);
+        })
+    }
+
+    /// Roll back everything written in the transaction and run the callbacks
+    pub async fn rollback(mut self) -> anyhow::Result<()> {
+        self.txn.rollback_outermost().await
+    }
+}
+
+/// Run `test` in a transaction on `adapter` and roll it back, the result of `test` is
+/// returned
+pub async fn rollback_only<A, F, Fut, T>(adapter: A, test: F) -> anyhow::Result<T>
+where
+    A: DbAdapter,
+    F: FnOnce(RollbackTxn<A>) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    rollback_only_with(adapter, TxnOptions::default(), test).await
+}
+
+/// [`rollback_only`] with a transaction begun with `options`
+pub async fn rollback_only_with<A, F, Fut, T>(
+    adapter: A,
+    options: TxnOptions,
+    test: F,
+) -> anyhow::Result<T>
+where
+    A: DbAdapter,
+    F: FnOnce(RollbackTxn<A>) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let txn = RollbackTxn::begin_with(adapter, options).await?;
+    let result = test(txn.clone()).await;
+    let rolled_back = txn.rollback().await;
+
+    let value = result?;
+    rolled_back?;
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        db::{TxCallback, TxnResult, TxnState},
+        provider::SingletonProvider,
+    };
+
+    #[derive(Clone, Default)]
+    struct Adapter {
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl DbAdapter for Adapter {
+        async fn begin_txn(&mut self) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push("begin");
+            Ok(())
+        }
+
+        async fn commit_txn(&mut self) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push("commit");
+            Ok(())
+        }
+
+        async fn rollback_txn(&mut self) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push("rollback");
+            Ok(())
+        }
+
+        async fn begin_txn_with(&mut self, options: &TxnOptions) -> anyhow::Result<()> {
+            if options.lock_timeout.is_some() {
+                self.calls.lock().unwrap().push("lock timeout");
+            }
+            self.begin_txn().await
+        }
+
+        async fn begin_savepoint(&mut self) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push("savepoint");
+            Ok(())
+        }
+
+        async fn release_savepoint(&mut self) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push("release");
+            Ok(())
+        }
+    }
+
+    impl SingletonProvider for Adapter {}
+
+    impl Provider for Adapter {
+        fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+            Ok(ctx.get::<Self>().cloned().unwrap_or_default())
+        }
+    }
+
+    struct Repo {
+        adapter: Adapter,
+    }
+
+    impl Provider for Repo {
+        fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+            Ok(Self {
+                adapter: Adapter::build(ctx)?,
+            })
+        }
+    }
+
+    struct Callback(Arc<Mutex<Vec<TxnResult>>>);
+
+    impl TxCallback for Callback {
+        fn call(self: Box<Self>, tx_result: TxnResult) {
+            self.0.lock().unwrap().push(tx_result);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rollback_only() -> anyhow::Result<()> {
+        let adapter = Adapter::default();
+        let value = rollback_only(adapter.clone(), |txn| async move {
+            let repo = txn.provide::<Repo>()?;
+            repo.adapter.calls.lock().unwrap().push("write");
+            Ok(1)
+        })
+        .await?;
+        assert_eq!(value, 1);
+        assert_eq!(
+            *adapter.calls.lock().unwrap(),
+            vec!["begin", "write", "rollback"]
+        );
+
+        let adapter = Adapter::default();
+        let result = rollback_only(adapter.clone(), |_txn| async move {
+            Err::<(), _>(anyhow::anyhow!("failed"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(*adapter.calls.lock().unwrap(), vec!["begin", "rollback"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rollback_only_nested() -> anyhow::Result<()> {
+        let adapter = Adapter::default();
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let options = TxnOptions::new().with_lock_timeout(Duration::from_secs(1));
+        let callback = results.clone();
+        rollback_only_with(adapter.clone(), options, |txn| async move {
+            let mut usecase_txn = txn.provide::<TxnManagerDiesel<Adapter>>()?;
+            assert_eq!(usecase_txn.state(), TxnState::Begun { depth: 1 });
+
+            let inner = usecase_txn.clone();
+            usecase_txn
+                .do_transaction(async {
+                    inner.register_callback(Callback(callback));
+                    Ok(Ok::<_, ()>(()))
+                })
+                .await?
+                .unwrap();
+            Ok(())
+        })
+        .await?;
+
+        assert_eq!(
+            *adapter.calls.lock().unwrap(),
+            vec!["lock timeout", "begin", "savepoint", "release", "rollback"]
+        );
+        assert!(matches!(
+            results.lock().unwrap()[..],
+            [TxnResult::RolledBack]
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! Native upsert on postgres
+//!
+//! [`Repository::upsert`](crate::repository::Repository::upsert) checks the sys id before
+//! inserting, which races with concurrent inserts of the same entity. Override it with one
+//! `INSERT ... ON CONFLICT` statement returning [`inserted`], and map the result by
+//! [`upsert_effect`]. A conflict on another unique key than the one of `ON CONFLICT` fails
+//! the statement and is mapped by [`SqlErrorDiesel::conflict_target`]:
+//!
+//! ```rust,ignore
+//! impl Repository<Account> for AccountRepo {
+//!     async fn upsert(
+//!         &mut self,
+//!         entity: &Account,
+//!         on_conflict: OnConflict,
+//!     ) -> anyhow::Result<UpsertEffect<AccountBizIdFieldEnum>> {
+//!         let row = AccountRow::from(entity);
+//!         let insert = diesel::insert_into(accounts::table).values(&row);
+//!         let result = match on_conflict {
+//!             OnConflict::Fail => self.adapter.sql_result(insert.returning(inserted())).await,
+//!             OnConflict::DoNothing => {
+//!                 let query = insert.on_conflict_do_nothing().returning(inserted());
+//!                 self.adapter.sql_result(query).await
+//!             }
+//!             OnConflict::UpdateChanged => {
+//!                 let query = insert
+//!                     .on_conflict(accounts::id)
+//!                     .do_update()
+//!                     .set(AccountChangeset::from(entity))
+//!                     .returning(inserted());
+//!                 self.adapter.sql_result(query).await
+//!             }
+//!         };
+//!
+//!         upsert_effect("accounts", result)
+//!     }
+//! }
+//! ```
+//!
+//! A failed statement aborts a postgres transaction, run it in a savepoint if the
+//! transaction should go on after a conflict.
+
+use diesel::{dsl::sql, expression::SqlLiteral, sql_types::Bool};
+
+use crate::{entity::BizIdFieldEnum, repository::UpsertEffect};
+
+use super::SqlErrorDiesel;
+
+/// `xmax = 0`, true for the row inserted by the statement and false for the updated one
+pub fn inserted() -> SqlLiteral<Bool> {
+    sql::<Bool>("xmax = 0")
+}
+
+/// Map the result of `INSERT ... [ON CONFLICT ...] RETURNING xmax = 0` on `table`.
+///
+/// No row is returned when the conflict is resolved by `DO NOTHING`, or by a `DO UPDATE`
+/// whose `WHERE` is false, the row is kept as [`UpsertEffect::Ignored`]. A unique violation
+/// is mapped by [`SqlErrorDiesel::conflict_target`], other errors are returned.
+pub fn upsert_effect<B>(
+    table: &str,
+    result: Result<Option<bool>, SqlErrorDiesel>,
+) -> anyhow::Result<UpsertEffect<B>>
+where
+    B: BizIdFieldEnum,
+{
+    match result {
+        Ok(Some(true)) => Ok(UpsertEffect::Inserted),
+        Ok(Some(false)) => Ok(UpsertEffect::Updated),
+        Ok(None) => Ok(UpsertEffect::Ignored),
+        Err(err) => match err.conflict_target(table) {
+            Some(target) => Ok(UpsertEffect::Conflict(target)),
+            None => Err(err.into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::{debug_query, pg::Pg, prelude::*, result::DatabaseErrorKind};
+
+    use crate::{entity::NoBizIdField, repository::ConflictTarget};
+
+    use super::*;
+
+    diesel::table! {
+        accounts (id) {
+            id -> BigInt,
+            age -> Integer,
+        }
+    }
+
+    #[test]
+    fn test_upsert_sql() {
+        let query = diesel::insert_into(accounts::table)
+            .values((accounts::id.eq(1), accounts::age.eq(20)))
+            .on_conflict(accounts::id)
+            .do_update()
+            .set(accounts::age.eq(20))
+            .returning(inserted());
+        let sql = debug_query::<Pg, _>(&query).to_string();
+
+        assert_eq!(
+            sql,
+            r#"INSERT INTO "accounts" ("id", "age") VALUES ($1, $2) ON CONFLICT ("id") DO UPDATE SET "age" = $3 RETURNING xmax = 0 -- binds: [1, 20, 20]"#
+        );
+    }
+
+    #[test]
+    fn test_upsert_effect() -> anyhow::Result<()> {
+        assert!(upsert_effect::<NoBizIdField>("accounts", Ok(Some(true)))?.is_inserted());
+        assert!(upsert_effect::<NoBizIdField>("accounts", Ok(Some(false)))?.is_updated());
+        assert!(matches!(
+            upsert_effect::<NoBizIdField>("accounts", Ok(None))?,
+            UpsertEffect::Ignored
+        ));
+
+        let conflict = diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            Box::new("duplicate key".to_string()),
+        );
+        assert!(matches!(
+            upsert_effect::<NoBizIdField>("accounts", Err(conflict.into()))?,
+            UpsertEffect::Conflict(ConflictTarget::Unknown)
+        ));
+
+        let error = diesel::result::Error::NotFound;
+        assert!(upsert_effect::<NoBizIdField>("accounts", Err(error.into())).is_err());
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
-use std::future::Future;
+use std::{future::Future, time::Duration};
 
-use crate::result::BizResult;
+use futures::future::LocalBoxFuture;
+
+use crate::{
+    async_task::{LocalAsyncTask, LocalTaskRunner},
+    result::BizResult,
+};
 
 #[cfg(feature = "diesel")]
 pub mod diesel;
@@ -18,6 +23,41 @@ pub trait DbAdapter: Clone + 'static {
     async fn commit_txn(&mut self) -> anyhow::Result<()>;
 
     async fn rollback_txn(&mut self) -> anyhow::Result<()>;
+
+    /// Begin a transaction with `options`
+    ///
+    /// By default only the default options are accepted, for adapters which don't support
+    /// any.
+    async fn begin_txn_with(&mut self, options: &TxnOptions) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            *options == TxnOptions::default(),
+            "the adapter can't begin a transaction with {options:?}"
+        );
+        self.begin_txn().await
+    }
+
+    /// Begin a savepoint in the transaction, for a transaction nested in it
+    ///
+    /// By default a nested transaction is begun, which diesel turns into a savepoint.
+    async fn begin_savepoint(&mut self) -> anyhow::Result<()> {
+        self.begin_txn().await
+    }
+
+    /// Release the last savepoint, keeping what was written since
+    async fn release_savepoint(&mut self) -> anyhow::Result<()> {
+        self.commit_txn().await
+    }
+
+    /// Roll back to the last savepoint, the enclosing transaction goes on
+    async fn rollback_to_savepoint(&mut self) -> anyhow::Result<()> {
+        self.rollback_txn().await
+    }
+
+    /// Whether a transaction which failed with `error` may succeed if run again, such as
+    /// after a serialization failure or a deadlock
+    fn is_retryable(&self, _error: &anyhow::Error) -> bool {
+        false
+    }
 }
 
 pub trait TxnManager: Clone + 'static {
@@ -25,17 +65,117 @@ pub trait TxnManager: Clone + 'static {
     where
         F: Future<Output = BizResult<T, E>>;
 
+    /// Same as [`TxnManager::do_transaction`], but the transaction made by `tx` is run again
+    /// while it fails with a retryable system error, as far as the retry policy of the
+    /// manager allows. By default it is run once.
+    ///
+    /// `tx` is an async closure, so that each attempt may borrow what the closure captures.
+    async fn do_transaction_retry<F, T, E>(&mut self, mut tx: F) -> BizResult<T, E>
+    where
+        F: AsyncFnMut() -> BizResult<T, E>,
+    {
+        self.do_transaction(tx()).await
+    }
+
     fn register_callback<H>(&self, callback: H)
     where
         H: TxCallback;
 
+    /// Register a callback awaited once the outermost transaction has ended
+    fn register_async_callback<H>(&self, callback: H)
+    where
+        H: AsyncTxCallback;
+
+    /// Register a hook run before the outermost transaction commits, while it is still open.
+    /// An error of the hook rolls the transaction back and is returned as a system error.
+    ///
+    /// A hook registered outside a transaction is run before the next one commits.
+    fn register_before_commit<H>(&self, hook: H)
+    where
+        H: BeforeCommitHook;
+
     fn state(&self) -> TxnState;
+
+    /// The manager beginning its transactions with `options`, by default they are ignored
+    ///
+    /// Options apply to the outermost transaction only, a nested one runs in a savepoint of it
+    /// and fails if it has other options.
+    fn with_txn_options(self, _options: TxnOptions) -> Self {
+        self
+    }
 }
 
 pub trait TxCallback: 'static {
     fn call(self: Box<Self>, tx_result: TxnResult);
 }
 
+/// A [`TxCallback`] which can await, such as to send a message after commit
+pub trait AsyncTxCallback: Send + Sync + 'static {
+    async fn call(self: Box<Self>, tx_result: TxnResult);
+}
+
+/// Runs in the transaction right before it commits
+pub trait BeforeCommitHook: Send + Sync + 'static {
+    async fn before_commit(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+pub(crate) trait AsyncTxCallbackBoxed: Send + Sync + 'static {
+    fn call_boxed(self: Box<Self>, tx_result: TxnResult) -> LocalBoxFuture<'static, ()>;
+}
+
+impl<T> AsyncTxCallbackBoxed for T
+where
+    T: AsyncTxCallback,
+{
+    fn call_boxed(self: Box<Self>, tx_result: TxnResult) -> LocalBoxFuture<'static, ()> {
+        Box::pin(AsyncTxCallback::call(self, tx_result))
+    }
+}
+
+pub(crate) trait BeforeCommitHookBoxed: Send + Sync + 'static {
+    fn before_commit_boxed(self: Box<Self>) -> LocalBoxFuture<'static, anyhow::Result<()>>;
+}
+
+impl<T> BeforeCommitHookBoxed for T
+where
+    T: BeforeCommitHook,
+{
+    fn before_commit_boxed(self: Box<Self>) -> LocalBoxFuture<'static, anyhow::Result<()>> {
+        Box::pin(BeforeCommitHook::before_commit(self))
+    }
+}
+
+/// Spawns async callbacks rather than awaiting them in the transaction, implemented by every
+/// [`LocalTaskRunner`]
+pub(crate) trait SpawnAsyncTxCallback: 'static {
+    fn spawn_callback(&self, callback: Box<dyn AsyncTxCallbackBoxed>, tx_result: TxnResult);
+}
+
+impl<R> SpawnAsyncTxCallback for R
+where
+    R: LocalTaskRunner,
+{
+    fn spawn_callback(&self, callback: Box<dyn AsyncTxCallbackBoxed>, tx_result: TxnResult) {
+        self.spawn(AsyncTxCallbackTask {
+            callback: Some(callback),
+            tx_result,
+        });
+    }
+}
+
+struct AsyncTxCallbackTask {
+    callback: Option<Box<dyn AsyncTxCallbackBoxed>>,
+    tx_result: TxnResult,
+}
+
+impl LocalAsyncTask for AsyncTxCallbackTask {
+    async fn run(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback.call_boxed(self.tx_result).await;
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum TxnResult {
     Committed,
@@ -45,7 +185,90 @@ pub enum TxnResult {
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum TxnState {
     NotInTransaction,
-    Begun,
+    /// In a transaction, `depth` is 1 in the outermost one and grows by one for each
+    /// transaction nested in it
+    Begun {
+        depth: u32,
+    },
     Committed,
     RolledBack,
 }
+
+impl TxnState {
+    pub fn is_begun(&self) -> bool {
+        matches!(self, TxnState::Begun { .. })
+    }
+
+    /// The number of transactions begun and not ended, 0 out of transactions
+    pub fn depth(&self) -> u32 {
+        match self {
+            TxnState::Begun { depth } => *depth,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// How a transaction is begun, the defaults of the database apply to what is not set
+///
+/// ```rust,ignore
+/// // Reports read a consistent snapshot without blocking writers
+/// let report = TxnOptions::new().serializable().read_only().deferrable();
+/// // Transfers fail with a serialization failure rather than lose an update
+/// let transfer = TxnOptions::new().serializable();
+///
+/// uc.execute_in_txn(txn.with_txn_options(transfer), input).await
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TxnOptions {
+    pub isolation: Option<IsolationLevel>,
+    pub read_only: bool,
+    /// A serializable read-only transaction waits for a snapshot which can't be invalidated,
+    /// then runs without serialization failures
+    pub deferrable: bool,
+    /// Statements running longer are cancelled
+    pub statement_timeout: Option<Duration>,
+    /// Waits for a lock longer fail
+    pub lock_timeout: Option<Duration>,
+}
+
+impl TxnOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = Some(isolation);
+        self
+    }
+
+    pub fn serializable(self) -> Self {
+        self.with_isolation(IsolationLevel::Serializable)
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn deferrable(mut self) -> Self {
+        self.deferrable = true;
+        self
+    }
+
+    pub fn with_statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+}
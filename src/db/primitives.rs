@@ -1,11 +1,15 @@
 use std::borrow::Cow;
 
-use ::diesel::expression::AsExpression;
+use ::diesel::{deserialize::FromSqlRow, expression::AsExpression};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, AsExpression)]
+#[derive(Serialize, Deserialize, Debug, AsExpression, FromSqlRow)]
 #[diesel(sql_type = ::diesel::sql_types::Json)]
-#[diesel(sql_type = ::diesel::sql_types::Jsonb)]
+#[cfg_attr(
+    feature = "diesel-postgres",
+    diesel(sql_type = ::diesel::sql_types::Jsonb)
+)]
+#[diesel(sql_type = ::diesel::sql_types::Text)]
 pub struct JsonProxy<T>(pub T);
 
 #[cfg(feature = "diesel")]
@@ -13,10 +17,8 @@ mod diesel_impl {
     use std::fmt::Debug;
 
     use diesel::{
-        backend::Backend,
-        query_builder::bind_collector::RawBytesBindCollector,
-        serialize::ToSql,
-        sql_types::{Json, Jsonb},
+        backend::Backend, query_builder::bind_collector::RawBytesBindCollector, serialize::ToSql,
+        sql_types::Json,
     };
     use serde::Serialize;
 
@@ -24,12 +26,13 @@ mod diesel_impl {
 
     type DieselDeserializeError = Box<dyn std::error::Error + Send + Sync>;
 
-    impl<DB, T> ToSql<Jsonb, DB> for JsonProxy<T>
+    #[cfg(feature = "diesel-postgres")]
+    impl<DB, T> ToSql<diesel::sql_types::Jsonb, DB> for JsonProxy<T>
     where
         DB: Backend,
         T: Debug,
         T: Serialize,
-        serde_json::Value: ToSql<Jsonb, DB>,
+        serde_json::Value: ToSql<diesel::sql_types::Jsonb, DB>,
         for<'c> DB: Backend<BindCollector<'c> = RawBytesBindCollector<DB>>,
     {
         fn to_sql<'b>(
@@ -113,6 +116,54 @@ mod diesel_impl {
             }
         }
     }
+
+    /// SQLite has no json type, the json is stored as TEXT
+    #[cfg(feature = "diesel-sqlite")]
+    mod sqlite_impl {
+        use diesel::{
+            deserialize::FromSql,
+            serialize::{IsNull, Output, ToSql},
+            sql_types::Text,
+            sqlite::Sqlite,
+        };
+        use serde::{de::DeserializeOwned, Serialize};
+
+        use crate::db::primitives::JsonProxy;
+
+        use super::DieselDeserializeError;
+
+        impl<T> ToSql<Text, Sqlite> for JsonProxy<T>
+        where
+            T: std::fmt::Debug,
+            T: Serialize,
+        {
+            fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> diesel::serialize::Result {
+                let value = serde_json::to_string(&self).map_err(|err| {
+                    let e = format!("failed to serialize value: {}", err);
+                    DieselDeserializeError::from(e)
+                })?;
+                out.set_value(value);
+                Ok(IsNull::No)
+            }
+        }
+
+        impl<T> FromSql<Text, Sqlite> for JsonProxy<T>
+        where
+            T: std::fmt::Debug,
+            T: DeserializeOwned,
+        {
+            fn from_sql(
+                value: <Sqlite as diesel::backend::Backend>::RawValue<'_>,
+            ) -> diesel::deserialize::Result<Self> {
+                let text = <String as FromSql<Text, Sqlite>>::from_sql(value)?;
+                let v: Self = serde_json::from_str(&text).map_err(|err| {
+                    let e = format!("corrupted db json value: {}", err);
+                    DieselDeserializeError::from(e)
+                })?;
+                Ok(v)
+            }
+        }
+    }
 }
 
 pub trait PersistentObject<'a, DO> {
@@ -81,6 +81,11 @@ impl<T> Field<T> {
         }
     }
 
+    /// Whether the field has been set since it was loaded
+    pub fn is_changed(&self) -> bool {
+        matches!(self, Field::Set(_))
+    }
+
     pub fn value_opt(self) -> Option<T> {
         match self {
             Field::Unloaded => None,
@@ -94,6 +94,14 @@ where
     C: ForeignContainer,
     <C as ForeignContainer>::Item: ForeignEntity,
 {
+    /// Whether the foreign entities have been reset or changed since they were loaded
+    pub fn is_changed(&self) -> bool {
+        matches!(
+            self,
+            ForeignEntities::Reset(_) | ForeignEntities::Changed { .. }
+        )
+    }
+
     /// Returns the original foreign entities
     ///
     /// # Panics
@@ -13,22 +13,83 @@ pub mod updater;
 pub trait Entity: FieldGroup {
     type Id<'a>: Eq;
 
-    type SysId: Eq;
+    type SysId: SysId;
 
     type BizIdFieldEnum: BizIdFieldEnum;
+
+    type FieldEnum: FieldEnum;
+
+    /// An id which borrows nothing, it can be converted into `Self::Id<'a>` of any lifetime
+    type IdOwned: Clone + Eq + Hash + Debug + 'static;
+
+    fn sys_id(&self) -> &Self::SysId;
+
+    /// Returns the sys id if the id is a sys id rather than a biz id
+    fn as_sys_id<'a>(id: &'a Self::Id<'_>) -> Option<&'a Self::SysId>;
+
+    fn id_to_owned(id: &Self::Id<'_>) -> Self::IdOwned;
+
+    /// The sys id and the loaded biz ids of the entity
+    fn ids(&self) -> Vec<Self::IdOwned>;
+
+    /// Column fields which have been set since the entity was loaded
+    fn changed_fields(&self) -> Vec<Self::FieldEnum>;
+
+    /// Foreign entity and field group fields which have been changed since the entity was
+    /// loaded, they are not in [`Entity::changed_fields`]
+    fn changed_nested_fields(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
 }
 
-pub trait BizIdFieldEnum: Copy + Clone + Eq {
+/// An entity whose previous versions are kept in a history table, so that it can be read as
+/// of a point in time, implemented by `#[entity(history)]`
+///
+/// It is only a marker for [`Repository::read_as_of`](crate::repository::Repository::read_as_of),
+/// the history table is created by the `HistoryTable` of `db::diesel::history` and read by
+/// the repository's [`AsOfSubsetReader`](crate::repository::AsOfSubsetReader).
+pub trait HistoryEntity: Entity {}
+
+pub trait BizIdFieldEnum: Copy + Clone + Eq + Debug + 'static {
     fn field_name(self) -> &'static str;
+
+    fn all() -> &'static [Self];
+
+    /// Find the biz id field guarded by a unique constraint, the constraint is expected to be
+    /// named by the postgres convention `{table}_{field}_key`
+    fn from_constraint(table: &str, constraint: &str) -> Option<Self> {
+        let field = constraint
+            .strip_prefix(table)?
+            .strip_prefix('_')?
+            .strip_suffix("_key")?;
+        Self::all()
+            .iter()
+            .copied()
+            .find(|variant| variant.field_name() == field)
+    }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum NoBizIdField {}
 
 impl BizIdFieldEnum for NoBizIdField {
     fn field_name(self) -> &'static str {
         match self {}
     }
+
+    fn all() -> &'static [Self] {
+        &[]
+    }
+}
+
+/// Enumerates the column-like fields of an entity: the sys id, the biz ids and the scalar fields.
+pub trait FieldEnum: Copy + Clone + Eq + Hash + Debug + 'static {
+    fn field_name(self) -> &'static str;
+
+    /// The variant of the sys id field
+    fn sys_id() -> Self;
+
+    fn all() -> &'static [Self];
 }
 
 pub trait SysId: Eq + Clone + Debug + Hash {
@@ -44,4 +105,7 @@ pub trait FieldGroup {
     type SubsetFull;
 
     fn update_fields(&mut self, updater: Self::Updater);
+
+    /// Whether any field has pending changes to be persisted
+    fn has_changes(&self) -> bool;
 }
@@ -3,5 +3,8 @@ use super::Entity;
 pub trait Subset {
     type Entity: Entity;
 
+    /// Names of the fields loaded by this subset
+    fn field_names() -> &'static [&'static str];
+
     fn to_entity(self) -> Self::Entity;
 }
@@ -20,6 +20,12 @@ macro_rules! flake_id {
             }
         }
 
+        impl From<$type_name> for $crate::repository::criteria::Value {
+            fn from(id: $type_name) -> Self {
+                Self::Int(id.0)
+            }
+        }
+
         impl $crate::entity::SysId for $type_name {
             fn generate() -> Self {
                 Self::generate()
@@ -97,7 +103,12 @@ macro_rules! flake_id {
             where
                 S: ::serde::Serializer,
             {
-                serializer.serialize_str(&self.0.to_string())
+                // Strings for JSON clients which can't hold an i64, integers otherwise
+                if serializer.is_human_readable() {
+                    serializer.serialize_str(&self.0.to_string())
+                } else {
+                    serializer.serialize_i64(self.0)
+                }
             }
         }
 
@@ -106,8 +117,12 @@ macro_rules! flake_id {
             where
                 D: ::serde::Deserializer<'de>,
             {
+                if !deserializer.is_human_readable() {
+                    return Ok(Self(i64::deserialize(deserializer)?));
+                }
+
                 let id = String::deserialize(deserializer)?;
-                let id = id.parse().map_err(serde::de::Error::custom)?;
+                let id = id.parse().map_err(::serde::de::Error::custom)?;
                 Ok(Self(id))
             }
         }
@@ -145,6 +160,48 @@ macro_rules! flake_id {
         );
     };
 
+    // Every diesel backend, for ids stored in more than one, e.g. postgres and sqlite in tests
+    (@impl $type_name:ident, {$($derives:tt)*}, {$($attrs:tt)*}, @diesel, $($tail:tt)*) => {
+        const _: () = {
+            use ::diesel::{
+                backend::Backend,
+                deserialize::{self, FromSql},
+                sql_types::BigInt,
+                serialize::{self, Output, ToSql},
+            };
+
+            impl<DB> ToSql<BigInt, DB> for $type_name
+            where
+                DB: Backend,
+                i64: ToSql<BigInt, DB>,
+            {
+                fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> serialize::Result {
+                    ToSql::<BigInt, DB>::to_sql(&self.0, out)
+                }
+            }
+
+            impl<DB> FromSql<BigInt, DB> for $type_name
+            where
+                DB: Backend,
+                i64: FromSql<BigInt, DB>,
+            {
+                fn from_sql(bytes: <DB as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+                    let res = FromSql::<BigInt, DB>::from_sql(bytes)?;
+                    Ok(Self(res))
+                }
+            }
+        };
+        $crate::flake_id!(@impl $type_name,
+            {$($derives)* ::diesel:: AsExpression, ::diesel::FromSqlRow,},
+            {$($attrs)* #[diesel(sql_type = ::diesel::sql_types::BigInt)] },
+             $($tail)* ,
+        );
+    };
+
+    (@impl $type_name:ident, {$($derives:tt)*}, {$($attrs:tt)*}, @diesel-sqlite, $($tail:tt)*) => {
+        $crate::flake_id!(@impl $type_name, {$($derives)*}, {$($attrs)*}, @diesel, $($tail)* ,);
+    };
+
     (@impl $type_name:ident, {$($derives:tt)*}, {$($attrs:tt)*}, @graphql, $($tail:tt)*) => {
         ::async_graphql::scalar!($type_name);
         $crate::flake_id!(@impl $type_name, {$($derives)*}, {$($attrs)*}, $($tail)* ,);
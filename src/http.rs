@@ -853,6 +853,28 @@ mod tests {
         let req: Req = unreachable!();
         all_endpoints(req);
     }
+
+    #[test]
+    fn test_page_response_body() {
+        use crate::repository::page::{Cursor, Page};
+
+        let page = Page {
+            items: vec![1, 2],
+            next_cursor: Some(Cursor::from("abc".to_string())),
+            has_more: true,
+            total: None,
+        };
+        let resp = HttpApiResponse::new_ok(page);
+        let json = serde_json::to_value(&resp.body).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": 0,
+                "data": {"items": [1, 2], "nextCursor": "abc", "hasMore": true}
+            })
+        );
+    }
 }
 
 pub trait HttpAdapter<R, U>
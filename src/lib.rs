@@ -8,13 +8,17 @@ pub mod entity;
 pub mod flake_id;
 pub mod http;
 pub mod json;
+pub mod outbox;
 pub mod provider;
 pub mod repository;
 pub mod result;
+pub mod retry;
 pub mod usecase;
 
+pub use anyhow;
 pub use futures;
 pub use macros::*;
+pub use serde_json;
 
 #[cfg(feature = "flake-id")]
 pub extern crate derive_more;
@@ -0,0 +1,705 @@
+//! Transactional outbox
+//!
+//! Messages are written by [`Outbox::publish`] to an outbox table in the same transaction as
+//! the entities, so a message is stored if and only if the transaction commits. An
+//! [`OutboxRelay`] then claims the stored messages, dispatches them to an
+//! [`OutboxPublisher`] and marks them delivered, or schedules a retry by a [`RetryPolicy`].
+//! Messages are delivered at least once, publishers should be idempotent.
+//!
+//! Messages of the same [`OutboxMessage::key`] are delivered in the order they were written:
+//! once one of them fails, the later ones wait until it is delivered or given up. Messages
+//! without a key are not ordered.
+//!
+//! The postgres store is [`OutboxStoreDiesel`], which claims rows with `FOR UPDATE SKIP
+//! LOCKED` so several relays can run side by side.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! type AppOutbox = Outbox<OutboxStoreDiesel<Adapter>, Txn>;
+//!
+//! async fn execute(&mut self) -> BizResult<(), Error> {
+//!     self.repo.save(&order).await?;
+//!     self.outbox
+//!         .publish(OutboxMessage::json("order.created", &OrderCreated { id: order.id })?)
+//!         .await?;
+//!     Ok(Ok(()))
+//! }
+//!
+//! // On startup
+//! LocalTaskRunnerTokio::get_or_init()?.spawn(OutboxRelayTask::new(RelayConfig::default(), || {
+//!     let store = OutboxStoreDiesel::<Adapter>::provide()?;
+//!     Ok((store, KafkaPublisher::provide()?))
+//! }));
+//! ```
+//!
+//! [`OutboxStoreDiesel`]: crate::db::diesel::outbox::OutboxStoreDiesel
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    db::TxnManager,
+    provider::{Provider, ProviderContext},
+    retry::RetryPolicy,
+};
+
+/// A message to be written to the outbox
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxMessage {
+    pub topic: String,
+    /// Partition or ordering key of the message broker
+    pub key: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+/// A message claimed from the outbox
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub topic: String,
+    pub key: Option<String>,
+    pub payload: serde_json::Value,
+    /// Delivery attempts, including the current one
+    pub attempts: u32,
+}
+
+/// Storage of outbox messages
+pub trait OutboxStore {
+    /// Write messages, within the current transaction if there is one
+    async fn enqueue(&mut self, messages: Vec<OutboxMessage>) -> anyhow::Result<()>;
+
+    /// Claim up to `limit` pending messages for `lease`, in the order they were written.
+    ///
+    /// Claimed messages are hidden from other relays until the lease expires, their attempts
+    /// are increased. A keyed message is not claimed while an earlier message of the same key
+    /// is pending but not claimed with it, i.e. waiting for a retry or claimed by another
+    /// relay.
+    async fn claim(&mut self, limit: u32, lease: Duration) -> anyhow::Result<Vec<StoredMessage>>;
+
+    /// Release claimed messages which were not dispatched, their attempts are restored
+    async fn release(&mut self, ids: &[i64]) -> anyhow::Result<()>;
+
+    async fn mark_delivered(&mut self, ids: &[i64]) -> anyhow::Result<()>;
+
+    /// Release a message after a failed delivery, it is retried after `retry_after`, or
+    /// given up if `None`
+    async fn mark_failed(
+        &mut self,
+        id: i64,
+        error: &str,
+        retry_after: Option<Duration>,
+    ) -> anyhow::Result<()>;
+}
+
+/// Dispatches messages to a message broker
+pub trait OutboxPublisher {
+    async fn publish(&mut self, message: &StoredMessage) -> anyhow::Result<()>;
+}
+
+/// Writes messages to the outbox inside the current transaction
+pub struct Outbox<St, Tx> {
+    store: St,
+    txn: Tx,
+}
+
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Messages claimed per round
+    pub batch_size: u32,
+    /// Wait time after a round without messages
+    pub poll_interval: Duration,
+    /// How long claimed messages are hidden from other relays
+    pub lease: Duration,
+    pub retry: RetryPolicy,
+}
+
+/// Moves messages from the outbox to a publisher
+pub struct OutboxRelay<St, P> {
+    store: St,
+    publisher: P,
+    config: RelayConfig,
+}
+
+/// Outcome of a relay round
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RelayStats {
+    pub delivered: usize,
+    pub retried: usize,
+    pub dead: usize,
+}
+
+/// Publisher which keeps messages in memory, for tests
+#[derive(Clone, Default)]
+pub struct InMemoryPublisher {
+    state: Arc<SyncMutex<InMemoryPublisherState>>,
+}
+
+#[derive(Default)]
+struct InMemoryPublisherState {
+    published: Vec<StoredMessage>,
+    failures: u32,
+}
+
+/// Outbox store which keeps messages in memory, for tests
+///
+/// Writes are not transactional.
+#[derive(Clone, Default)]
+pub struct InMemoryOutboxStore {
+    state: Arc<SyncMutex<InMemoryStoreState>>,
+}
+
+#[derive(Default)]
+struct InMemoryStoreState {
+    next_id: i64,
+    rows: Vec<InMemoryRow>,
+}
+
+struct InMemoryRow {
+    message: StoredMessage,
+    available_at: Instant,
+    locked_until: Option<Instant>,
+    delivered: bool,
+    dead: bool,
+    last_error: Option<String>,
+}
+
+impl OutboxMessage {
+    pub fn new(topic: impl Into<String>, payload: serde_json::Value) -> Self {
+        Self {
+            topic: topic.into(),
+            key: None,
+            payload,
+        }
+    }
+
+    /// A message with a JSON serialized payload
+    pub fn json<T: Serialize>(topic: impl Into<String>, payload: &T) -> anyhow::Result<Self> {
+        Ok(Self::new(topic, serde_json::to_value(payload)?))
+    }
+
+    pub fn with_key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+}
+
+impl<St, Tx> Clone for Outbox<St, Tx>
+where
+    St: Clone,
+    Tx: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            txn: self.txn.clone(),
+        }
+    }
+}
+
+impl<St, Tx> Provider for Outbox<St, Tx>
+where
+    St: Provider,
+    Tx: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(St::build(ctx)?, Tx::build(ctx)?))
+    }
+}
+
+impl<St, Tx> Outbox<St, Tx> {
+    pub fn new(store: St, txn: Tx) -> Self {
+        Self { store, txn }
+    }
+}
+
+impl<St, Tx> Outbox<St, Tx>
+where
+    St: OutboxStore,
+    Tx: TxnManager,
+{
+    /// Write a message, it is relayed once the transaction commits
+    ///
+    /// # Errors
+    /// If there is no transaction, since the message could be stored without the entities.
+    pub async fn publish(&mut self, message: OutboxMessage) -> anyhow::Result<()> {
+        self.publish_all(vec![message]).await
+    }
+
+    pub async fn publish_all(&mut self, messages: Vec<OutboxMessage>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.txn.state().is_begun(),
+            "outbox messages must be written inside a transaction"
+        );
+
+        self.store.enqueue(messages).await
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            poll_interval: Duration::from_secs(1),
+            lease: Duration::from_secs(60),
+            retry: RetryPolicy::new(10, Default::default()),
+        }
+    }
+}
+
+impl<St, P> OutboxRelay<St, P>
+where
+    St: OutboxStore,
+    P: OutboxPublisher,
+{
+    pub fn new(store: St, publisher: P, config: RelayConfig) -> Self {
+        Self {
+            store,
+            publisher,
+            config,
+        }
+    }
+
+    /// Claim a batch of messages and dispatch them.
+    ///
+    /// Once a keyed message fails, the later messages of its key are released undispatched.
+    pub async fn relay_once(&mut self) -> anyhow::Result<RelayStats> {
+        let messages = self
+            .store
+            .claim(self.config.batch_size, self.config.lease)
+            .await?;
+
+        let mut stats = RelayStats::default();
+        let mut delivered = vec![];
+        let mut failed_keys = HashSet::new();
+        let mut released = vec![];
+        for message in messages {
+            if message
+                .key
+                .as_ref()
+                .is_some_and(|key| failed_keys.contains(key))
+            {
+                released.push(message.id);
+                continue;
+            }
+            let Err(err) = self.publisher.publish(&message).await else {
+                delivered.push(message.id);
+                continue;
+            };
+
+            let retry_after = self.config.retry.next_delay(message.attempts);
+            match retry_after {
+                Some(_) => stats.retried += 1,
+                None => {
+                    tracing::error!(
+                        id = message.id,
+                        topic = message.topic,
+                        ?err,
+                        "outbox message is given up"
+                    );
+                    stats.dead += 1;
+                }
+            }
+            self.store
+                .mark_failed(message.id, &format!("{err:#}"), retry_after)
+                .await?;
+            failed_keys.extend(message.key);
+        }
+
+        stats.delivered = delivered.len();
+        if !delivered.is_empty() {
+            self.store.mark_delivered(&delivered).await?;
+        }
+        if !released.is_empty() {
+            self.store.release(&released).await?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Relay until `relay_once` finds nothing to deliver
+    pub async fn drain(&mut self) -> anyhow::Result<RelayStats> {
+        let mut total = RelayStats::default();
+        loop {
+            let stats = self.relay_once().await?;
+            total.delivered += stats.delivered;
+            total.retried += stats.retried;
+            total.dead += stats.dead;
+            if stats == RelayStats::default() {
+                return Ok(total);
+            }
+        }
+    }
+
+    pub fn store(&self) -> &St {
+        &self.store
+    }
+
+    pub fn publisher(&self) -> &P {
+        &self.publisher
+    }
+}
+
+/// Runs an [`OutboxRelay`] forever on a [`LocalTaskRunner`]
+///
+/// The relay's store and publisher are built by `build` on the runner's thread, errors of a
+/// round are logged and the round is retried after the poll interval.
+///
+/// [`LocalTaskRunner`]: crate::async_task::LocalTaskRunner
+#[cfg(feature = "tokio")]
+pub struct OutboxRelayTask<B> {
+    config: RelayConfig,
+    build: B,
+}
+
+#[cfg(feature = "tokio")]
+impl<B, St, P> OutboxRelayTask<B>
+where
+    B: FnMut() -> anyhow::Result<(St, P)> + Send + Sync + 'static,
+    St: OutboxStore,
+    P: OutboxPublisher,
+{
+    pub fn new(config: RelayConfig, build: B) -> Self {
+        Self { config, build }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<B, St, P> crate::async_task::LocalAsyncTask for OutboxRelayTask<B>
+where
+    B: FnMut() -> anyhow::Result<(St, P)> + Send + Sync + 'static,
+    St: OutboxStore,
+    P: OutboxPublisher,
+{
+    async fn run(&mut self) {
+        let mut relay = loop {
+            match (self.build)() {
+                Ok((store, publisher)) => {
+                    break OutboxRelay::new(store, publisher, self.config.clone())
+                }
+                Err(err) => {
+                    tracing::error!(?err, "failed to build outbox relay");
+                    tokio::time::sleep(self.config.poll_interval).await;
+                }
+            }
+        };
+
+        loop {
+            match relay.relay_once().await {
+                Ok(stats) if stats != RelayStats::default() => continue,
+                Ok(_) => {}
+                Err(err) => tracing::error!(?err, "failed to relay outbox messages"),
+            }
+            tokio::time::sleep(self.config.poll_interval).await;
+        }
+    }
+}
+
+impl InMemoryPublisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail the next `times` publishes
+    pub fn fail_next(&self, times: u32) {
+        self.state.lock().unwrap().failures = times;
+    }
+
+    pub fn published(&self) -> Vec<StoredMessage> {
+        self.state.lock().unwrap().published.clone()
+    }
+}
+
+impl Provider for InMemoryPublisher {
+    fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new())
+    }
+}
+
+impl OutboxPublisher for InMemoryPublisher {
+    async fn publish(&mut self, message: &StoredMessage) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            anyhow::bail!("in-memory publisher is set to fail");
+        }
+
+        state.published.push(message.clone());
+        Ok(())
+    }
+}
+
+impl InMemoryOutboxStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Messages neither delivered nor given up
+    pub fn pending(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .rows
+            .iter()
+            .filter(|r| !r.delivered && !r.dead)
+            .count()
+    }
+
+    /// Messages given up, with their last error
+    pub fn dead(&self) -> Vec<(StoredMessage, Option<String>)> {
+        let state = self.state.lock().unwrap();
+        state
+            .rows
+            .iter()
+            .filter(|r| r.dead)
+            .map(|r| (r.message.clone(), r.last_error.clone()))
+            .collect()
+    }
+}
+
+impl Provider for InMemoryOutboxStore {
+    fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new())
+    }
+}
+
+impl OutboxStore for InMemoryOutboxStore {
+    async fn enqueue(&mut self, messages: Vec<OutboxMessage>) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        for message in messages {
+            state.next_id += 1;
+            let id = state.next_id;
+            state.rows.push(InMemoryRow {
+                message: StoredMessage {
+                    id,
+                    topic: message.topic,
+                    key: message.key,
+                    payload: message.payload,
+                    attempts: 0,
+                },
+                available_at: now,
+                locked_until: None,
+                delivered: false,
+                dead: false,
+                last_error: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn claim(&mut self, limit: u32, lease: Duration) -> anyhow::Result<Vec<StoredMessage>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mut waiting_keys = HashSet::new();
+        let mut claimed = vec![];
+        for row in state.rows.iter_mut() {
+            if row.delivered || row.dead || claimed.len() >= limit as usize {
+                continue;
+            }
+            let claimable =
+                row.available_at <= now && row.locked_until.is_none_or(|until| until <= now);
+            let waiting = row
+                .message
+                .key
+                .as_ref()
+                .is_some_and(|key| waiting_keys.contains(key));
+            if claimable && !waiting {
+                row.locked_until = Some(now + lease);
+                row.message.attempts += 1;
+                claimed.push(row.message.clone());
+            } else {
+                waiting_keys.extend(row.message.key.clone());
+            }
+        }
+
+        Ok(claimed)
+    }
+
+    async fn release(&mut self, ids: &[i64]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for row in state
+            .rows
+            .iter_mut()
+            .filter(|r| ids.contains(&r.message.id))
+        {
+            row.locked_until = None;
+            row.message.attempts -= 1;
+        }
+
+        Ok(())
+    }
+
+    async fn mark_delivered(&mut self, ids: &[i64]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for row in state
+            .rows
+            .iter_mut()
+            .filter(|r| ids.contains(&r.message.id))
+        {
+            row.delivered = true;
+            row.locked_until = None;
+        }
+
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &mut self,
+        id: i64,
+        error: &str,
+        retry_after: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(row) = state.rows.iter_mut().find(|r| r.message.id == id) else {
+            anyhow::bail!("outbox message {id} not found");
+        };
+
+        row.locked_until = None;
+        row.last_error = Some(error.to_string());
+        match retry_after {
+            Some(delay) => row.available_at = Instant::now() + delay,
+            None => row.dead = true,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retry::Backoff;
+
+    use super::*;
+
+    fn relay(
+        store: &InMemoryOutboxStore,
+        publisher: &InMemoryPublisher,
+        max_attempts: u32,
+    ) -> OutboxRelay<InMemoryOutboxStore, InMemoryPublisher> {
+        let config = RelayConfig {
+            batch_size: 2,
+            retry: RetryPolicy::new(max_attempts, Backoff::new(Duration::ZERO, Duration::ZERO)),
+            ..Default::default()
+        };
+        OutboxRelay::new(store.clone(), publisher.clone(), config)
+    }
+
+    #[tokio::test]
+    async fn test_relay_in_order() -> anyhow::Result<()> {
+        let mut store = InMemoryOutboxStore::new();
+        let publisher = InMemoryPublisher::new();
+        let messages = (0..3)
+            .map(|i| OutboxMessage::new("topic", serde_json::json!(i)).with_key("k"))
+            .collect();
+        store.enqueue(messages).await?;
+
+        let stats = relay(&store, &publisher, 3).drain().await?;
+        assert_eq!(stats.delivered, 3);
+        assert_eq!(store.pending(), 0);
+
+        let payloads = publisher
+            .published()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, vec![serde_json::json!(0), 1.into(), 2.into()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_retry() -> anyhow::Result<()> {
+        let mut store = InMemoryOutboxStore::new();
+        let publisher = InMemoryPublisher::new();
+        store
+            .enqueue(vec![OutboxMessage::new("topic", serde_json::Value::Null)])
+            .await?;
+
+        publisher.fail_next(1);
+        let mut relay = relay(&store, &publisher, 2);
+        let stats = relay.relay_once().await?;
+        assert_eq!(stats.retried, 1);
+        assert_eq!(store.pending(), 1);
+
+        let stats = relay.relay_once().await?;
+        assert_eq!(stats.delivered, 1);
+        assert_eq!(publisher.published()[0].attempts, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_give_up() -> anyhow::Result<()> {
+        let mut store = InMemoryOutboxStore::new();
+        let publisher = InMemoryPublisher::new();
+        store
+            .enqueue(vec![OutboxMessage::new("topic", serde_json::Value::Null)])
+            .await?;
+
+        publisher.fail_next(2);
+        let stats = relay(&store, &publisher, 2).drain().await?;
+        assert_eq!(stats.retried, 1);
+        assert_eq!(stats.dead, 1);
+        assert_eq!(store.pending(), 0);
+
+        let dead = store.dead();
+        assert_eq!(dead[0].0.attempts, 2);
+        assert!(dead[0].1.as_deref().unwrap().contains("set to fail"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_keeps_key_order() -> anyhow::Result<()> {
+        let mut store = InMemoryOutboxStore::new();
+        let publisher = InMemoryPublisher::new();
+        let messages = [("a", 0), ("a", 1), ("b", 2), ("a", 3)]
+            .into_iter()
+            .map(|(key, i)| OutboxMessage::new("topic", serde_json::json!(i)).with_key(key))
+            .collect();
+        store.enqueue(messages).await?;
+
+        // The first message of "a" is retried later, the second one waits for it
+        publisher.fail_next(1);
+        let config = RelayConfig {
+            batch_size: 10,
+            retry: RetryPolicy::new(
+                3,
+                Backoff::new(Duration::from_secs(60), Duration::from_secs(60)),
+            ),
+            ..Default::default()
+        };
+        let mut relay = OutboxRelay::new(store.clone(), publisher.clone(), config);
+        let stats = relay.relay_once().await?;
+        assert_eq!((stats.retried, stats.delivered), (1, 1));
+        assert_eq!(publisher.published()[0].payload, serde_json::json!(2));
+
+        // Later rounds don't pass the message waiting for a retry either
+        let stats = relay.relay_once().await?;
+        assert_eq!(stats, RelayStats::default());
+        assert_eq!(store.pending(), 3);
+
+        store.state.lock().unwrap().rows[0].available_at = Instant::now();
+        relay.drain().await?;
+
+        let payloads = publisher
+            .published()
+            .into_iter()
+            .map(|m| m.payload)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            payloads,
+            vec![serde_json::json!(2), 0.into(), 1.into(), 3.into()]
+        );
+        // The released message was not counted as an attempt
+        assert_eq!(publisher.published()[2].attempts, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,485 @@
+//! Audit log
+//!
+//! [`AuditedRepository`] wraps a repository and writes an [`AuditRecord`] to an
+//! [`AuditSink`] after every successful `save`, `update` and `delete`: the entity type, the
+//! sys id, the actor, the time and the values of the written fields. All fields are recorded
+//! on save, the changed fields on update and none on delete. Field values are serialized by
+//! [`FieldAccess::field_json`], so arrays and structs are recorded as JSON values. Only the written values are recorded, not the values they replaced, so
+//! the log is not a before/after trail: the prior value of a field is found in the previous
+//! record which wrote it.
+//!
+//! The sys id is recorded by its JSON serialization, a string id as is, so records of the same
+//! entity share the same `entity_id`. An entity deleted by a biz id is loaded first to resolve
+//! its sys id.
+//!
+//! A sink writing on the same connection as the repository, such as
+//! [`AuditSinkDiesel`], records inside the transaction, so a write and its audit record are
+//! committed or rolled back together. An error of the sink fails the write.
+//!
+//! The actor is taken from the [`AuditActor`] in the [`ProviderContext`] when the
+//! repository is built, put it there from the request's [`HttpCredential`], or put
+//! [`AuditActor::system`] for system jobs. Building the repository fails without it:
+//!
+//! ```rust,ignore
+//! let actor = AuditActor::from_credential(&req);
+//! let uc = UpdateProfileUseCase::provide_with(|ctx| {
+//!     ctx.insert(actor);
+//! })?;
+//!
+//! type AuditedUserRepo = AuditedRepository<User, UserRepo, AuditSinkDiesel<Adapter>>;
+//! ```
+//!
+//! [`AuditSinkDiesel`]: crate::db::diesel::audit::AuditSinkDiesel
+
+use std::{
+    marker::PhantomData,
+    sync::{Arc, Mutex as SyncMutex},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entity::{subset::Subset, Entity, FieldEnum},
+    http::{HttpCredential, IdCredential},
+    provider::{Provider, ProviderContext},
+};
+
+use super::{
+    criteria::FieldAccess, entity_name, AsOfSubsetReader, BatchSubsetLoader, BatchSubsetReader,
+    DeleteEffect, LockEffect, LockOptions, LockingSubsetLoader, OnConflict, Repository,
+    SaveEffectOf, SubsetLoader, SubsetReader, UpdateEffect, UpsertEffect,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    /// Name of the entity type
+    pub entity: &'static str,
+    /// JSON serialization of the sys id, a string id as is
+    pub entity_id: String,
+    pub action: AuditAction,
+    pub actor: Option<String>,
+    pub at: SystemTime,
+    /// Field name => written value
+    pub changes: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Who makes the writes, `None` for system jobs
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AuditActor(pub Option<String>);
+
+/// Destination of audit records
+pub trait AuditSink {
+    async fn record(&mut self, record: AuditRecord) -> anyhow::Result<()>;
+}
+
+pub struct AuditedRepository<E, R, Sk> {
+    inner: R,
+    sink: Sk,
+    actor: AuditActor,
+    _entity: PhantomData<E>,
+}
+
+/// Sink which keeps records in memory, for tests
+#[derive(Clone, Default)]
+pub struct InMemoryAuditSink {
+    records: Arc<SyncMutex<Vec<AuditRecord>>>,
+}
+
+impl<T> From<IdCredential<T>> for AuditActor
+where
+    T: ToString,
+{
+    fn from(value: IdCredential<T>) -> Self {
+        Self(Some(value.id.to_string()))
+    }
+}
+
+impl AuditActor {
+    /// The id of the request's credential
+    pub fn from_credential<R, T>(request: &R) -> Self
+    where
+        R: HttpCredential<IdCredential<T>>,
+        T: ToString,
+    {
+        Self::from(request.credential())
+    }
+
+    pub fn system() -> Self {
+        Self(None)
+    }
+}
+
+impl<E, R, Sk> Clone for AuditedRepository<E, R, Sk>
+where
+    R: Clone,
+    Sk: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            sink: self.sink.clone(),
+            actor: self.actor.clone(),
+            _entity: PhantomData,
+        }
+    }
+}
+
+impl<E, R, Sk> Provider for AuditedRepository<E, R, Sk>
+where
+    E: 'static,
+    R: Provider,
+    Sk: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        let actor = ctx.get::<AuditActor>().cloned().ok_or_else(|| {
+            anyhow::anyhow!(
+                "no AuditActor in the provider context, insert AuditActor::system() for system jobs"
+            )
+        })?;
+        Ok(Self::new(R::build(ctx)?, Sk::build(ctx)?, actor))
+    }
+}
+
+impl<E, R, Sk> AuditedRepository<E, R, Sk> {
+    pub fn new(inner: R, sink: Sk, actor: AuditActor) -> Self {
+        Self {
+            inner,
+            sink,
+            actor,
+            _entity: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn sink(&self) -> &Sk {
+        &self.sink
+    }
+
+    pub fn actor(&self) -> &AuditActor {
+        &self.actor
+    }
+}
+
+impl<E, R, Sk> AuditedRepository<E, R, Sk>
+where
+    E: Entity + FieldAccess<E::FieldEnum>,
+    Sk: AuditSink,
+{
+    async fn record(
+        &mut self,
+        action: AuditAction,
+        sys_id: &E::SysId,
+        fields: &[E::FieldEnum],
+        entity: Option<&E>,
+    ) -> anyhow::Result<()>
+    where
+        E::SysId: Serialize,
+    {
+        let mut changes = serde_json::Map::new();
+        if let Some(entity) = entity {
+            for field in fields {
+                changes.insert(field.field_name().to_string(), entity.field_json(*field)?);
+            }
+        }
+
+        self.sink
+            .record(AuditRecord {
+                entity: entity_name::<E>(),
+                entity_id: audit_id(sys_id)?,
+                action,
+                actor: self.actor.0.clone(),
+                at: SystemTime::now(),
+                changes,
+            })
+            .await
+    }
+}
+
+/// A stable serialization of the id, unlike its debug representation
+fn audit_id<T: Serialize>(id: &T) -> anyhow::Result<String> {
+    Ok(match serde_json::to_value(id)? {
+        serde_json::Value::String(id) => id,
+        id => id.to_string(),
+    })
+}
+
+impl<E, R, Sk> AuditedRepository<E, R, Sk>
+where
+    E: Entity,
+    E::SubsetFull: Subset<Entity = E>,
+    R: SubsetLoader<E::SubsetFull>,
+    for<'a> E::Id<'a>: From<E::IdOwned>,
+{
+    /// The sys id of an entity about to be deleted, a biz id is resolved by loading it
+    async fn resolve_sys_id(&mut self, id: &E::IdOwned) -> anyhow::Result<Option<E::SysId>> {
+        let ident = E::Id::from(id.clone());
+        if let Some(sys_id) = E::as_sys_id(&ident) {
+            return Ok(Some(sys_id.clone()));
+        }
+
+        let entity = self.inner.load(id.clone()).await?;
+        Ok(entity.map(|subset| subset.to_entity().sys_id().clone()))
+    }
+}
+
+fn deleted_sys_id<Id>(sys_id: Option<Id>) -> anyhow::Result<Id> {
+    sys_id.ok_or_else(|| anyhow::anyhow!("the deleted entity was not found by its biz id"))
+}
+
+impl<E, R, Sk, S> SubsetReader<S> for AuditedRepository<E, R, Sk>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: SubsetReader<S>,
+{
+    async fn read<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.read(id).await
+    }
+}
+
+impl<E, R, Sk, S> SubsetLoader<S> for AuditedRepository<E, R, Sk>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: SubsetLoader<S>,
+{
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.load(id).await
+    }
+}
+
+impl<E, R, Sk, S> LockingSubsetLoader<S> for AuditedRepository<E, R, Sk>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: LockingSubsetLoader<S>,
+{
+    async fn load_locked<I>(&mut self, id: I, lock: LockOptions) -> anyhow::Result<LockEffect<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.load_locked(id, lock).await
+    }
+}
+
+impl<E, R, Sk, S> AsOfSubsetReader<S> for AuditedRepository<E, R, Sk>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: AsOfSubsetReader<S>,
+{
+    async fn read_as_of<I>(&mut self, id: I, at: SystemTime) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.read_as_of(id, at).await
+    }
+}
+
+impl<E, R, Sk, S, C> BatchSubsetReader<C, S> for AuditedRepository<E, R, Sk>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: BatchSubsetReader<C, S>,
+{
+    async fn read_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>> {
+        self.inner.read_batch(condition).await
+    }
+}
+
+impl<E, R, Sk, S, C> BatchSubsetLoader<C, S> for AuditedRepository<E, R, Sk>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: BatchSubsetLoader<C, S>,
+{
+    async fn load_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>> {
+        self.inner.load_batch(condition).await
+    }
+}
+
+impl<E, R, Sk> Repository<E> for AuditedRepository<E, R, Sk>
+where
+    E: Entity + FieldAccess<E::FieldEnum>,
+    E::SysId: Serialize,
+    E::SubsetFull: Subset<Entity = E>,
+    R: Repository<E> + SubsetLoader<E::SubsetFull>,
+    Sk: AuditSink,
+    for<'a> E::Id<'a>: From<E::IdOwned>,
+{
+    async fn save(&mut self, entity: &E) -> anyhow::Result<SaveEffectOf<E>> {
+        let effect = self.inner.save(entity).await?;
+        if effect.is_ok() {
+            self.record(
+                AuditAction::Create,
+                entity.sys_id(),
+                E::FieldEnum::all(),
+                Some(entity),
+            )
+            .await?;
+        }
+
+        Ok(effect)
+    }
+
+    async fn update(&mut self, entity: &E) -> anyhow::Result<UpdateEffect> {
+        let changed = entity.changed_fields();
+        let effect = self.inner.update(entity).await?;
+        if effect.is_ok() {
+            self.record(AuditAction::Update, entity.sys_id(), &changed, Some(entity))
+                .await?;
+        }
+
+        Ok(effect)
+    }
+
+    async fn delete<I>(&mut self, id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = E::id_to_owned(&E::Id::from(id));
+        let sys_id = self.resolve_sys_id(&id).await?;
+        let effect = self.inner.delete(id).await?;
+        if effect.is_ok() {
+            let sys_id = deleted_sys_id(sys_id)?;
+            self.record(AuditAction::Delete, &sys_id, &[], None).await?;
+        }
+
+        Ok(effect)
+    }
+
+    async fn exists<I>(&mut self, id: I) -> anyhow::Result<bool>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        self.inner.exists(id).await
+    }
+
+    async fn save_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<SaveEffectOf<E>>> {
+        let effects = self.inner.save_batch(entities).await?;
+        for (entity, effect) in entities.iter().zip(&effects) {
+            if effect.is_ok() {
+                self.record(
+                    AuditAction::Create,
+                    entity.sys_id(),
+                    E::FieldEnum::all(),
+                    Some(entity),
+                )
+                .await?;
+            }
+        }
+
+        Ok(effects)
+    }
+
+    async fn update_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<UpdateEffect>> {
+        let changed = entities
+            .iter()
+            .map(|entity| entity.changed_fields())
+            .collect::<Vec<_>>();
+        let effects = self.inner.update_batch(entities).await?;
+        for ((entity, changed), effect) in entities.iter().zip(&changed).zip(&effects) {
+            if effect.is_ok() {
+                self.record(AuditAction::Update, entity.sys_id(), changed, Some(entity))
+                    .await?;
+            }
+        }
+
+        Ok(effects)
+    }
+
+    async fn delete_batch<I>(&mut self, ids: Vec<I>) -> anyhow::Result<Vec<DeleteEffect>>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let ids = ids
+            .into_iter()
+            .map(|id| E::id_to_owned(&E::Id::from(id)))
+            .collect::<Vec<_>>();
+        let mut sys_ids = Vec::with_capacity(ids.len());
+        for id in &ids {
+            sys_ids.push(self.resolve_sys_id(id).await?);
+        }
+        let effects = self.inner.delete_batch(ids).await?;
+        for (sys_id, effect) in sys_ids.into_iter().zip(&effects) {
+            if effect.is_ok() {
+                let sys_id = deleted_sys_id(sys_id)?;
+                self.record(AuditAction::Delete, &sys_id, &[], None).await?;
+            }
+        }
+
+        Ok(effects)
+    }
+
+    async fn upsert(
+        &mut self,
+        entity: &E,
+        on_conflict: OnConflict,
+    ) -> anyhow::Result<UpsertEffect<E::BizIdFieldEnum>>
+    where
+        for<'a> E::Id<'a>: From<E::SysId>,
+    {
+        let changed = entity.changed_fields();
+        let effect = self.inner.upsert(entity, on_conflict).await?;
+        let id = entity.sys_id();
+        match effect {
+            UpsertEffect::Inserted => {
+                self.record(AuditAction::Create, id, E::FieldEnum::all(), Some(entity))
+                    .await?
+            }
+            UpsertEffect::Updated => {
+                self.record(AuditAction::Update, id, &changed, Some(entity))
+                    .await?
+            }
+            UpsertEffect::Ignored | UpsertEffect::Conflict(_) => {}
+        }
+
+        Ok(effect)
+    }
+}
+
+impl InMemoryAuditSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> Vec<AuditRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl Provider for InMemoryAuditSink {
+    fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new())
+    }
+}
+
+impl AuditSink for InMemoryAuditSink {
+    async fn record(&mut self, record: AuditRecord) -> anyhow::Result<()> {
+        self.records.lock().unwrap().push(record);
+        Ok(())
+    }
+}
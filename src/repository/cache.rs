@@ -0,0 +1,697 @@
+//! Read-through cache for subset readers
+//!
+//! [`CachedRepository`] wraps a repository. Reads by sys id through [`SubsetReader`] and reads
+//! through [`BatchSubsetReader`] are served from a [`CacheBackend`], and misses are read from
+//! the inner repository. Loaders are never cached since loaded entities are meant to be
+//! modified.
+//!
+//! Writes made through the decorator invalidate the affected entries once the transaction
+//! commits, by a [`TxCallback`]. A rolled back write leaves the cache untouched. Until the
+//! transaction ends, the written ids bypass the cache, for every decorator of the entity
+//! built from the same [`ProviderContext`]. Outside a transaction entries are invalidated
+//! immediately.
+//!
+//! Reads in a transaction are served from the cache but never populate it, the rows they see
+//! may be written by the transaction and rolled back later.
+//!
+//! Subsets must be `Clone` to be cached, use `#[subset_attr(derive(Clone))]` on the entity.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! static USER_CACHE: OnceLock<UserCache> = OnceLock::new();
+//!
+//! #[derive(Clone)]
+//! pub struct UserCache(LruTtlCache<CacheKey<UserId>, CacheValue>);
+//!
+//! impl Provider for UserCache {
+//!     fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+//!         Ok(USER_CACHE
+//!             .get_or_init(|| UserCache(LruTtlCache::new(10_000, Duration::from_secs(60))))
+//!             .clone())
+//!     }
+//! }
+//!
+//! type CachedUserRepo = CachedRepository<User, UserRepo, Txn, UserCache>;
+//! ```
+
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    marker::PhantomData,
+    rc::Rc,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    db::{TxCallback, TxnManager, TxnResult},
+    entity::{subset::Subset, Entity},
+    provider::{Provider, ProviderContext},
+};
+
+use super::{
+    AsOfSubsetReader, BatchSubsetLoader, BatchSubsetReader, DeleteEffect, LockEffect, LockOptions,
+    LockingSubsetLoader, OnConflict, Repository, SaveEffectOf, SubsetLoader, SubsetReader,
+    UpdateEffect, UpsertEffect,
+};
+
+/// A key-value cache shared by all requests
+pub trait CacheBackend<K, V>: Clone + 'static {
+    fn get(&self, key: &K) -> Option<V>;
+
+    fn put(&self, key: K, value: V);
+
+    fn invalidate(&self, key: &K);
+
+    /// Drop all entries whose key matches the predicate
+    fn invalidate_if(&self, predicate: &dyn Fn(&K) -> bool);
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKey<Id> {
+    /// A subset read by sys id
+    Entity { subset: TypeId, id: Id },
+    /// A batch read, keyed by the hash of the condition
+    Batch { subset: TypeId, condition: u64 },
+}
+
+pub type CacheValue = Arc<dyn Any + Send + Sync>;
+
+/// In-process cache with LRU eviction and a time to live
+pub struct LruTtlCache<K, V> {
+    state: Arc<SyncMutex<LruState<K, V>>>,
+}
+
+struct LruState<K, V> {
+    capacity: usize,
+    ttl: Duration,
+    tick: u64,
+    entries: HashMap<K, LruSlot<V>>,
+    /// Last access tick => key
+    recency: BTreeMap<u64, K>,
+}
+
+struct LruSlot<V> {
+    value: V,
+    expires_at: Instant,
+    tick: u64,
+}
+
+pub struct CachedRepository<E, R, Tx, B>
+where
+    E: Entity,
+{
+    inner: R,
+    txn: Tx,
+    backend: B,
+    dirty: Rc<RefCell<DirtyKeys<E::SysId>>>,
+    _entity: PhantomData<E>,
+}
+
+/// Ids written by the current transaction
+struct DirtyKeys<Id> {
+    all: bool,
+    ids: HashSet<Id>,
+}
+
+/// The dirty keys of `E` shared by the decorators of a [`ProviderContext`]
+struct SharedDirtyKeys<E: Entity>(Rc<RefCell<DirtyKeys<E::SysId>>>);
+
+enum Invalidation<Id> {
+    /// An entity changed, batch reads are invalidated as well
+    Entity(Id),
+    /// Entities of unknown ids changed
+    All,
+    /// Entities were added, only batch reads are invalidated
+    Batches,
+}
+
+struct InvalidateOnCommit<Id, B> {
+    backend: B,
+    invalidation: Invalidation<Id>,
+    dirty: Rc<RefCell<DirtyKeys<Id>>>,
+}
+
+impl<K, V> Clone for LruTtlCache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<K, V> LruTtlCache<K, V>
+where
+    K: Hash + Eq + Clone + Send,
+    V: Clone + Send,
+{
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            state: Arc::new(SyncMutex::new(LruState {
+                capacity,
+                ttl,
+                tick: 0,
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V> LruState<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(slot) = self.entries.remove(key) {
+            self.recency.remove(&slot.tick);
+        }
+    }
+}
+
+impl<K, V> CacheBackend<K, V> for LruTtlCache<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        let mut state = self.state.lock().unwrap();
+        let tick = state.next_tick();
+        let slot = state.entries.get_mut(key)?;
+        if slot.expires_at <= Instant::now() {
+            state.remove(key);
+            return None;
+        }
+
+        let old_tick = std::mem::replace(&mut slot.tick, tick);
+        let value = slot.value.clone();
+        state.recency.remove(&old_tick);
+        state.recency.insert(tick, key.clone());
+
+        Some(value)
+    }
+
+    fn put(&self, key: K, value: V) {
+        let mut state = self.state.lock().unwrap();
+        if state.capacity == 0 {
+            return;
+        }
+
+        state.remove(&key);
+        let tick = state.next_tick();
+        let slot = LruSlot {
+            value,
+            expires_at: Instant::now() + state.ttl,
+            tick,
+        };
+        state.entries.insert(key.clone(), slot);
+        state.recency.insert(tick, key);
+
+        while state.entries.len() > state.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+    }
+
+    fn invalidate(&self, key: &K) {
+        self.state.lock().unwrap().remove(key);
+    }
+
+    fn invalidate_if(&self, predicate: &dyn Fn(&K) -> bool) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.entries.retain(|k, _| !predicate(k));
+        state.recency.retain(|_, k| !predicate(k));
+    }
+}
+
+impl<Id> CacheKey<Id> {
+    fn entity<S: 'static>(id: Id) -> Self {
+        CacheKey::Entity {
+            subset: TypeId::of::<S>(),
+            id,
+        }
+    }
+
+    fn batch<S: 'static, C: Hash + 'static>(condition: &C) -> Self {
+        let mut hasher = DefaultHasher::new();
+        TypeId::of::<C>().hash(&mut hasher);
+        condition.hash(&mut hasher);
+
+        CacheKey::Batch {
+            subset: TypeId::of::<S>(),
+            condition: hasher.finish(),
+        }
+    }
+}
+
+impl<Id> Invalidation<Id>
+where
+    Id: PartialEq,
+{
+    fn apply<B>(&self, backend: &B)
+    where
+        B: CacheBackend<CacheKey<Id>, CacheValue>,
+    {
+        match self {
+            Invalidation::Entity(id) => backend.invalidate_if(&|key| match key {
+                CacheKey::Entity { id: key_id, .. } => key_id == id,
+                CacheKey::Batch { .. } => true,
+            }),
+            Invalidation::All => backend.invalidate_if(&|_| true),
+            Invalidation::Batches => {
+                backend.invalidate_if(&|key| matches!(key, CacheKey::Batch { .. }))
+            }
+        }
+    }
+}
+
+impl<Id> Default for DirtyKeys<Id> {
+    fn default() -> Self {
+        Self {
+            all: false,
+            ids: HashSet::new(),
+        }
+    }
+}
+
+impl<Id, B> TxCallback for InvalidateOnCommit<Id, B>
+where
+    Id: PartialEq + 'static,
+    B: CacheBackend<CacheKey<Id>, CacheValue>,
+{
+    fn call(self: Box<Self>, tx_result: TxnResult) {
+        if let TxnResult::Committed = tx_result {
+            self.invalidation.apply(&self.backend);
+        }
+
+        let mut dirty = self.dirty.borrow_mut();
+        dirty.all = false;
+        dirty.ids.clear();
+    }
+}
+
+impl<E, R, Tx, B> Clone for CachedRepository<E, R, Tx, B>
+where
+    E: Entity,
+    R: Clone,
+    Tx: Clone,
+    B: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            txn: self.txn.clone(),
+            backend: self.backend.clone(),
+            dirty: self.dirty.clone(),
+            _entity: PhantomData,
+        }
+    }
+}
+
+impl<E, R, Tx, B> Provider for CachedRepository<E, R, Tx, B>
+where
+    E: Entity + 'static,
+    R: Provider,
+    Tx: Provider,
+    B: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        let mut this = Self::new(R::build(ctx)?, Tx::build(ctx)?, B::build(ctx)?);
+        match ctx.get::<SharedDirtyKeys<E>>() {
+            Some(shared) => this.dirty = shared.0.clone(),
+            None => {
+                ctx.insert(SharedDirtyKeys::<E>(this.dirty.clone()));
+            }
+        }
+
+        Ok(this)
+    }
+}
+
+impl<E, R, Tx, B> CachedRepository<E, R, Tx, B>
+where
+    E: Entity,
+{
+    /// A decorator tracking its own dirty keys, the ones built by [`Provider`] share them
+    pub fn new(inner: R, txn: Tx, backend: B) -> Self {
+        Self {
+            inner,
+            txn,
+            backend,
+            dirty: Rc::new(RefCell::new(DirtyKeys::default())),
+            _entity: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<E, R, Tx, B> CachedRepository<E, R, Tx, B>
+where
+    E: Entity + 'static,
+    Tx: TxnManager,
+    B: CacheBackend<CacheKey<E::SysId>, CacheValue>,
+{
+    fn is_dirty(&self, id: Option<&E::SysId>) -> bool {
+        let dirty = self.dirty.borrow();
+        dirty.all || id.is_some_and(|id| dirty.ids.contains(id))
+    }
+
+    fn is_batch_dirty(&self) -> bool {
+        let dirty = self.dirty.borrow();
+        dirty.all || !dirty.ids.is_empty()
+    }
+
+    /// Rows read in a transaction may be rolled back
+    fn can_populate(&self) -> bool {
+        !self.txn.state().is_begun()
+    }
+
+    /// Invalidate after commit if in a transaction, otherwise invalidate immediately
+    fn invalidate(&self, invalidation: Invalidation<E::SysId>) {
+        if !self.txn.state().is_begun() {
+            invalidation.apply(&self.backend);
+            return;
+        }
+
+        {
+            let mut dirty = self.dirty.borrow_mut();
+            match &invalidation {
+                Invalidation::Entity(id) => {
+                    dirty.ids.insert(id.clone());
+                }
+                Invalidation::All | Invalidation::Batches => dirty.all = true,
+            }
+        }
+
+        self.txn.register_callback(InvalidateOnCommit {
+            backend: self.backend.clone(),
+            invalidation,
+            dirty: self.dirty.clone(),
+        });
+    }
+}
+
+impl<E, R, Tx, B, S> SubsetReader<S> for CachedRepository<E, R, Tx, B>
+where
+    E: Entity + 'static,
+    S: Subset<Entity = E> + Clone + Send + Sync + 'static,
+    R: SubsetReader<S>,
+    Tx: TxnManager,
+    B: CacheBackend<CacheKey<E::SysId>, CacheValue>,
+    for<'a> E::Id<'a>: From<E::IdOwned>,
+{
+    async fn read<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        let id = E::Id::from(id);
+        let owned_id = E::id_to_owned(&id);
+        let Some(sys_id) = E::as_sys_id(&id).cloned() else {
+            // Only reads by sys id are cached
+            return self.inner.read(owned_id).await;
+        };
+
+        let cacheable = !self.is_dirty(Some(&sys_id));
+        let key = CacheKey::entity::<S>(sys_id);
+        if cacheable {
+            if let Some(subset) = self.backend.get(&key) {
+                if let Some(subset) = subset.downcast_ref::<S>() {
+                    return Ok(Some(subset.clone()));
+                }
+            }
+        }
+
+        let subset = self.inner.read(owned_id).await?;
+        if let (true, Some(subset)) = (cacheable && self.can_populate(), &subset) {
+            self.backend.put(key, Arc::new(subset.clone()));
+        }
+
+        Ok(subset)
+    }
+}
+
+impl<E, R, Tx, B, S, C> BatchSubsetReader<C, S> for CachedRepository<E, R, Tx, B>
+where
+    E: Entity + 'static,
+    S: Subset<Entity = E> + Clone + Send + Sync + 'static,
+    C: Hash + Eq + Clone + Send + Sync + 'static,
+    R: BatchSubsetReader<C, S>,
+    Tx: TxnManager,
+    B: CacheBackend<CacheKey<E::SysId>, CacheValue>,
+{
+    async fn read_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>> {
+        let cacheable = !self.is_batch_dirty();
+        let key = CacheKey::batch::<S, C>(&condition);
+        if cacheable {
+            let cached = self.backend.get(&key);
+            // The condition is stored along with the subsets in case of hash collisions
+            if let Some((cached_condition, subsets)) = cached
+                .as_deref()
+                .and_then(|v| v.downcast_ref::<(C, Vec<S>)>())
+            {
+                if *cached_condition == condition {
+                    return Ok(subsets.clone());
+                }
+            }
+        }
+
+        let subsets = self.inner.read_batch(condition.clone()).await?;
+        if cacheable && self.can_populate() {
+            self.backend
+                .put(key, Arc::new((condition, subsets.clone())));
+        }
+
+        Ok(subsets)
+    }
+}
+
+impl<E, R, Tx, B, S> SubsetLoader<S> for CachedRepository<E, R, Tx, B>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: SubsetLoader<S>,
+{
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.load(id).await
+    }
+}
+
+impl<E, R, Tx, B, S> LockingSubsetLoader<S> for CachedRepository<E, R, Tx, B>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: LockingSubsetLoader<S>,
+{
+    async fn load_locked<I>(&mut self, id: I, lock: LockOptions) -> anyhow::Result<LockEffect<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.load_locked(id, lock).await
+    }
+}
+
+/// Not cached, versions of the past are read rarely
+impl<E, R, Tx, B, S> AsOfSubsetReader<S> for CachedRepository<E, R, Tx, B>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: AsOfSubsetReader<S>,
+{
+    async fn read_as_of<I>(&mut self, id: I, at: SystemTime) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.read_as_of(id, at).await
+    }
+}
+
+impl<E, R, Tx, B, S, C> BatchSubsetLoader<C, S> for CachedRepository<E, R, Tx, B>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: BatchSubsetLoader<C, S>,
+{
+    async fn load_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>> {
+        self.inner.load_batch(condition).await
+    }
+}
+
+impl<E, R, Tx, B> Repository<E> for CachedRepository<E, R, Tx, B>
+where
+    E: Entity + 'static,
+    R: Repository<E>,
+    Tx: TxnManager,
+    B: CacheBackend<CacheKey<E::SysId>, CacheValue>,
+    for<'a> E::Id<'a>: From<E::IdOwned>,
+{
+    async fn save(&mut self, entity: &E) -> anyhow::Result<SaveEffectOf<E>> {
+        let effect = self.inner.save(entity).await?;
+        if effect.is_ok() {
+            self.invalidate(Invalidation::Batches);
+        }
+
+        Ok(effect)
+    }
+
+    async fn update(&mut self, entity: &E) -> anyhow::Result<UpdateEffect> {
+        let effect = self.inner.update(entity).await?;
+        if effect.is_ok() {
+            self.invalidate(Invalidation::Entity(entity.sys_id().clone()));
+        }
+
+        Ok(effect)
+    }
+
+    async fn delete<I>(&mut self, id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = E::Id::from(id);
+        let invalidation = match E::as_sys_id(&id) {
+            Some(sys_id) => Invalidation::Entity(sys_id.clone()),
+            None => Invalidation::All,
+        };
+
+        let effect = self.inner.delete(E::id_to_owned(&id)).await?;
+        if effect.is_ok() {
+            self.invalidate(invalidation);
+        }
+
+        Ok(effect)
+    }
+
+    async fn exists<I>(&mut self, id: I) -> anyhow::Result<bool>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        self.inner.exists(id).await
+    }
+
+    async fn save_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<SaveEffectOf<E>>> {
+        let effects = self.inner.save_batch(entities).await?;
+        if effects.iter().any(|effect| effect.is_ok()) {
+            self.invalidate(Invalidation::Batches);
+        }
+
+        Ok(effects)
+    }
+
+    async fn update_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<UpdateEffect>> {
+        let effects = self.inner.update_batch(entities).await?;
+        for (entity, effect) in entities.iter().zip(&effects) {
+            if effect.is_ok() {
+                self.invalidate(Invalidation::Entity(entity.sys_id().clone()));
+            }
+        }
+
+        Ok(effects)
+    }
+
+    async fn delete_batch<I>(&mut self, ids: Vec<I>) -> anyhow::Result<Vec<DeleteEffect>>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let mut owned_ids = Vec::with_capacity(ids.len());
+        let mut invalidations = Vec::with_capacity(ids.len());
+        for id in ids {
+            let id = E::Id::from(id);
+            invalidations.push(match E::as_sys_id(&id) {
+                Some(sys_id) => Invalidation::Entity(sys_id.clone()),
+                None => Invalidation::All,
+            });
+            owned_ids.push(E::id_to_owned(&id));
+        }
+
+        let effects = self.inner.delete_batch(owned_ids).await?;
+        for (invalidation, effect) in invalidations.into_iter().zip(&effects) {
+            if effect.is_ok() {
+                self.invalidate(invalidation);
+            }
+        }
+
+        Ok(effects)
+    }
+
+    async fn upsert(
+        &mut self,
+        entity: &E,
+        on_conflict: OnConflict,
+    ) -> anyhow::Result<UpsertEffect<E::BizIdFieldEnum>>
+    where
+        for<'a> E::Id<'a>: From<E::SysId>,
+    {
+        let effect = self.inner.upsert(entity, on_conflict).await?;
+        match effect {
+            UpsertEffect::Inserted => self.invalidate(Invalidation::Batches),
+            UpsertEffect::Updated => self.invalidate(Invalidation::Entity(entity.sys_id().clone())),
+            UpsertEffect::Ignored | UpsertEffect::Conflict(_) => {}
+        }
+
+        Ok(effect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = LruTtlCache::<u32, u32>::new(2, Duration::from_secs(60));
+        cache.put(1, 1);
+        cache.put(2, 2);
+        assert_eq!(cache.get(&1), Some(1));
+
+        // 2 is the least recently used
+        cache.put(3, 3);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.get(&3), Some(3));
+        assert_eq!(cache.len(), 2);
+
+        cache.invalidate_if(&|k| *k == 1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_lru_ttl() {
+        let cache = LruTtlCache::<u32, u32>::new(2, Duration::ZERO);
+        cache.put(1, 1);
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,743 @@
+//! Typed query criteria for batch loaders and readers
+//!
+//! A [`Criteria`] is built from the generated `XxxFieldEnum` of an entity and can be used as
+//! the condition type of [`BatchSubsetLoader`](super::BatchSubsetLoader) and
+//! [`BatchSubsetReader`](super::BatchSubsetReader). The diesel translator lives in
+//! `db::diesel::criteria`, in-memory repositories can evaluate it with [`FieldAccess`].
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use bagua::repository::criteria::{Criteria, FieldExpr};
+//!
+//! let criteria = Criteria::new()
+//!     .filter(UserFieldEnum::Age.ge(18).and(UserFieldEnum::Name.like("A%")))
+//!     .order_by_desc(UserFieldEnum::Age)
+//!     .limit(20);
+//! ```
+
+use std::cmp::Ordering;
+
+use crate::entity::FieldEnum;
+
+/// A dynamically typed value compared against a field
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate<F> {
+    Eq(F, Value),
+    Ne(F, Value),
+    In(F, Vec<Value>),
+    Gt(F, Value),
+    Ge(F, Value),
+    Lt(F, Value),
+    Le(F, Value),
+    Like(F, String),
+    IsNull(F),
+    IsNotNull(F),
+    And(Vec<Predicate<F>>),
+    Or(Vec<Predicate<F>>),
+    Not(Box<Predicate<F>>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SortOrder<F> {
+    pub field: F,
+    pub direction: SortDirection,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Criteria<F> {
+    pub predicate: Option<Predicate<F>>,
+    pub order: Vec<SortOrder<F>>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+/// Read the value of a field from an in-memory record
+///
+/// `#[Entity]` implements it for the entity and its `XxxFull` subset, an unloaded field
+/// reads as `NULL`.
+pub trait FieldAccess<F> {
+    fn field_value(&self, field: F) -> Value;
+
+    /// The JSON serialization of the field, `null` if it is not loaded.
+    ///
+    /// The default is the JSON of [`FieldAccess::field_value`], in which arrays and maps are
+    /// text, `#[Entity]` serializes the field itself.
+    fn field_json(&self, field: F) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.field_value(field))?)
+    }
+}
+
+/// The JSON serialization of a field, used by the generated [`FieldAccess::field_json`]
+pub fn to_json<T>(value: &T) -> anyhow::Result<serde_json::Value>
+where
+    T: serde::Serialize + ?Sized,
+{
+    Ok(serde_json::to_value(value)?)
+}
+
+/// The value of a serializable field, arrays and maps are compared as their JSON text.
+///
+/// Scalars are serialized as a non human readable format, so that ids which are strings in
+/// JSON but integers in the database (e.g. `flake_id!` ids) compare as integers.
+pub fn to_value<T>(value: &T) -> Value
+where
+    T: serde::Serialize + ?Sized,
+{
+    match value.serialize(ValueSerializer) {
+        Ok(value) => value,
+        // e.g. uuids are bytes when not human readable, but strings in JSON
+        Err(NotScalar) => match serde_json::to_value(value) {
+            Ok(serde_json::Value::Null) | Err(_) => Value::Null,
+            Ok(serde_json::Value::Bool(b)) => Value::Bool(b),
+            Ok(serde_json::Value::Number(n)) => match n.as_i64() {
+                Some(i) => Value::Int(i),
+                None => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            Ok(serde_json::Value::String(s)) => Value::Text(s),
+            Ok(json) => Value::Text(json.to_string()),
+        },
+    }
+}
+
+/// Serialize a scalar into a [`Value`], compound values are rejected with [`NotScalar`]
+struct ValueSerializer;
+
+#[derive(Debug)]
+struct NotScalar;
+
+impl std::fmt::Display for NotScalar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("not a scalar value")
+    }
+}
+
+impl std::error::Error for NotScalar {}
+
+impl serde::ser::Error for NotScalar {
+    fn custom<T: std::fmt::Display>(_msg: T) -> Self {
+        NotScalar
+    }
+}
+
+macro_rules! serialize_as {
+    ($($method:ident: $ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Value, NotScalar> {
+                Ok(Value::$variant(v.into()))
+            }
+        )*
+    };
+}
+
+impl serde::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = NotScalar;
+    type SerializeSeq = serde::ser::Impossible<Value, NotScalar>;
+    type SerializeTuple = serde::ser::Impossible<Value, NotScalar>;
+    type SerializeTupleStruct = serde::ser::Impossible<Value, NotScalar>;
+    type SerializeTupleVariant = serde::ser::Impossible<Value, NotScalar>;
+    type SerializeMap = serde::ser::Impossible<Value, NotScalar>;
+    type SerializeStruct = serde::ser::Impossible<Value, NotScalar>;
+    type SerializeStructVariant = serde::ser::Impossible<Value, NotScalar>;
+
+    serialize_as! {
+        serialize_bool: bool => Bool,
+        serialize_i8: i8 => Int,
+        serialize_i16: i16 => Int,
+        serialize_i32: i32 => Int,
+        serialize_i64: i64 => Int,
+        serialize_u8: u8 => Int,
+        serialize_u16: u16 => Int,
+        serialize_u32: u32 => Int,
+        serialize_f32: f32 => Float,
+        serialize_f64: f64 => Float,
+        serialize_str: &str => Text,
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, NotScalar> {
+        Ok(match i64::try_from(v) {
+            Ok(v) => Value::Int(v),
+            Err(_) => Value::Float(v as f64),
+        })
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, NotScalar> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Value, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_none(self) -> Result<Value, NotScalar> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: serde::Serialize + ?Sized>(self, value: &T) -> Result<Value, NotScalar> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, NotScalar> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, NotScalar> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, NotScalar> {
+        Ok(Value::Text(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: serde::Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, NotScalar> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: serde::Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Value, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, NotScalar> {
+        Err(NotScalar)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Predicate constructors on the generated field enums
+pub trait FieldExpr: FieldEnum {
+    fn eq(self, value: impl Into<Value>) -> Predicate<Self> {
+        Predicate::Eq(self, value.into())
+    }
+
+    fn ne(self, value: impl Into<Value>) -> Predicate<Self> {
+        Predicate::Ne(self, value.into())
+    }
+
+    fn eq_any<I>(self, values: I) -> Predicate<Self>
+    where
+        I: IntoIterator,
+        I::Item: Into<Value>,
+    {
+        Predicate::In(self, values.into_iter().map(Into::into).collect())
+    }
+
+    fn gt(self, value: impl Into<Value>) -> Predicate<Self> {
+        Predicate::Gt(self, value.into())
+    }
+
+    fn ge(self, value: impl Into<Value>) -> Predicate<Self> {
+        Predicate::Ge(self, value.into())
+    }
+
+    fn lt(self, value: impl Into<Value>) -> Predicate<Self> {
+        Predicate::Lt(self, value.into())
+    }
+
+    fn le(self, value: impl Into<Value>) -> Predicate<Self> {
+        Predicate::Le(self, value.into())
+    }
+
+    /// `lower <= field AND field <= upper`
+    fn between(self, lower: impl Into<Value>, upper: impl Into<Value>) -> Predicate<Self> {
+        Predicate::And(vec![self.ge(lower), self.le(upper)])
+    }
+
+    /// SQL `LIKE` pattern, `%` matches any sequence and `_` matches one character
+    fn like(self, pattern: impl Into<String>) -> Predicate<Self> {
+        Predicate::Like(self, pattern.into())
+    }
+
+    fn is_null(self) -> Predicate<Self> {
+        Predicate::IsNull(self)
+    }
+
+    fn is_not_null(self) -> Predicate<Self> {
+        Predicate::IsNotNull(self)
+    }
+}
+
+impl<F: FieldEnum> FieldExpr for F {}
+
+impl<F> Predicate<F> {
+    pub fn and(self, other: Predicate<F>) -> Predicate<F> {
+        match self {
+            Predicate::And(mut list) => {
+                list.push(other);
+                Predicate::And(list)
+            }
+            this => Predicate::And(vec![this, other]),
+        }
+    }
+
+    pub fn or(self, other: Predicate<F>) -> Predicate<F> {
+        match self {
+            Predicate::Or(mut list) => {
+                list.push(other);
+                Predicate::Or(list)
+            }
+            this => Predicate::Or(vec![this, other]),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Predicate<F> {
+        Predicate::Not(Box::new(self))
+    }
+}
+
+impl<F: Copy> Predicate<F> {
+    /// Evaluate the predicate against an in-memory record.
+    ///
+    /// Comparisons with `NULL` are never satisfied, like in SQL.
+    pub fn matches<R>(&self, record: &R) -> bool
+    where
+        R: FieldAccess<F> + ?Sized,
+    {
+        self.eval(record) == Some(true)
+    }
+
+    /// Three-valued evaluation like SQL, `None` is `UNKNOWN`, so that `NOT` of an unknown
+    /// comparison is still unknown
+    fn eval<R>(&self, record: &R) -> Option<bool>
+    where
+        R: FieldAccess<F> + ?Sized,
+    {
+        match self {
+            Predicate::Eq(f, Value::Null) => Some(record.field_value(*f).is_null()),
+            Predicate::Ne(f, Value::Null) => Some(!record.field_value(*f).is_null()),
+            Predicate::Eq(f, v) => record.field_value(*f).sql_cmp(v).map(Ordering::is_eq),
+            Predicate::Ne(f, v) => record.field_value(*f).sql_cmp(v).map(Ordering::is_ne),
+            Predicate::In(f, values) => {
+                let value = record.field_value(*f);
+                any(values.iter().map(|v| value.sql_cmp(v).map(Ordering::is_eq)))
+            }
+            Predicate::Gt(f, v) => record.field_value(*f).sql_cmp(v).map(Ordering::is_gt),
+            Predicate::Ge(f, v) => record.field_value(*f).sql_cmp(v).map(Ordering::is_ge),
+            Predicate::Lt(f, v) => record.field_value(*f).sql_cmp(v).map(Ordering::is_lt),
+            Predicate::Le(f, v) => record.field_value(*f).sql_cmp(v).map(Ordering::is_le),
+            Predicate::Like(f, pattern) => match record.field_value(*f) {
+                Value::Text(text) => Some(like_matches(&text, pattern)),
+                Value::Null => None,
+                _ => Some(false),
+            },
+            Predicate::IsNull(f) => Some(record.field_value(*f).is_null()),
+            Predicate::IsNotNull(f) => Some(!record.field_value(*f).is_null()),
+            Predicate::And(list) => {
+                any(list.iter().map(|p| p.eval(record).map(|b| !b))).map(|b| !b)
+            }
+            Predicate::Or(list) => any(list.iter().map(|p| p.eval(record))),
+            Predicate::Not(p) => p.eval(record).map(|b| !b),
+        }
+    }
+}
+
+/// SQL `OR` of three-valued results, `TRUE` wins over `UNKNOWN` which wins over `FALSE`
+fn any(results: impl Iterator<Item = Option<bool>>) -> Option<bool> {
+    let mut result = Some(false);
+    for r in results {
+        match r {
+            Some(true) => return Some(true),
+            None => result = None,
+            Some(false) => {}
+        }
+    }
+
+    result
+}
+
+impl<F> Default for Criteria<F> {
+    fn default() -> Self {
+        Self {
+            predicate: None,
+            order: vec![],
+            limit: None,
+            offset: None,
+        }
+    }
+}
+
+impl<F> Criteria<F> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a predicate. Multiple calls are combined with `AND`
+    pub fn filter(mut self, predicate: Predicate<F>) -> Self {
+        self.predicate = match self.predicate.take() {
+            Some(p) => Some(p.and(predicate)),
+            None => Some(predicate),
+        };
+        self
+    }
+
+    pub fn order_by(mut self, field: F, direction: SortDirection) -> Self {
+        self.order.push(SortOrder { field, direction });
+        self
+    }
+
+    pub fn order_by_asc(self, field: F) -> Self {
+        self.order_by(field, SortDirection::Asc)
+    }
+
+    pub fn order_by_desc(self, field: F) -> Self {
+        self.order_by(field, SortDirection::Desc)
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+}
+
+impl<F: Copy> Criteria<F> {
+    pub fn matches<R>(&self, record: &R) -> bool
+    where
+        R: FieldAccess<F> + ?Sized,
+    {
+        match &self.predicate {
+            Some(p) => p.matches(record),
+            None => true,
+        }
+    }
+
+    /// Filter, sort and slice in-memory records like the database would
+    pub fn apply<R>(&self, records: impl IntoIterator<Item = R>) -> Vec<R>
+    where
+        R: FieldAccess<F>,
+    {
+        let mut records = records
+            .into_iter()
+            .filter(|r| self.matches(r))
+            .collect::<Vec<_>>();
+
+        if !self.order.is_empty() {
+            records.sort_by(|a, b| self.compare(a, b));
+        }
+
+        let offset = self.offset.unwrap_or(0) as usize;
+        let limit = self.limit.map(|l| l as usize).unwrap_or(usize::MAX);
+
+        records.into_iter().skip(offset).take(limit).collect()
+    }
+
+    fn compare<R>(&self, a: &R, b: &R) -> Ordering
+    where
+        R: FieldAccess<F>,
+    {
+        for order in &self.order {
+            let a = a.field_value(order.field);
+            let b = b.field_value(order.field);
+            let ordering = a.sort_cmp(&b);
+            let ordering = match order.direction {
+                SortDirection::Asc => ordering,
+                SortDirection::Desc => ordering.reverse(),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    }
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Compare two values. `None` if any of them is `NULL` or they are not comparable
+    fn sql_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+            (Value::Float(a), Value::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::Text(a), Value::Text(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+
+    /// Total order used for sorting. `NULL`s sort last in ascending order, like Postgres
+    fn sort_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) => Ordering::Greater,
+            (_, Value::Null) => Ordering::Less,
+            (a, b) => a.sql_cmp(b).unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+fn like_matches(text: &str, pattern: &str) -> bool {
+    let text = text.chars().collect::<Vec<_>>();
+    let pattern = pattern.chars().collect::<Vec<_>>();
+
+    // dp[j]: whether text[..i] matches pattern[..j]
+    let mut dp = vec![false; pattern.len() + 1];
+    dp[0] = true;
+    for j in 1..=pattern.len() {
+        dp[j] = dp[j - 1] && pattern[j - 1] == '%';
+    }
+
+    for c in text {
+        let mut prev = dp[0];
+        dp[0] = false;
+        for j in 1..=pattern.len() {
+            let current = dp[j];
+            dp[j] = match pattern[j - 1] {
+                '%' => dp[j - 1] || dp[j],
+                '_' => prev,
+                p => prev && p == c,
+            };
+            prev = current;
+        }
+    }
+
+    dp[pattern.len()]
+}
+
+macro_rules! impl_from_for_value {
+    ($variant:ident: $($ty:ty),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    Value::$variant(value.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_for_value!(Bool: bool);
+impl_from_for_value!(Int: i8, i16, i32, i64, u8, u16, u32);
+impl_from_for_value!(Float: f32, f64);
+impl_from_for_value!(Text: String, &str);
+
+impl From<&String> for Value {
+    fn from(value: &String) -> Self {
+        Value::Text(value.clone())
+    }
+}
+
+impl<T> From<Option<T>> for Value
+where
+    T: Into<Value>,
+{
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(v) => v.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    enum UserField {
+        Id,
+        Name,
+        Age,
+    }
+
+    impl FieldEnum for UserField {
+        fn field_name(self) -> &'static str {
+            match self {
+                UserField::Id => "id",
+                UserField::Name => "name",
+                UserField::Age => "age",
+            }
+        }
+
+        fn sys_id() -> Self {
+            UserField::Id
+        }
+
+        fn all() -> &'static [Self] {
+            &[UserField::Id, UserField::Name, UserField::Age]
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct User {
+        id: i64,
+        name: &'static str,
+        age: Option<u8>,
+    }
+
+    impl FieldAccess<UserField> for User {
+        fn field_value(&self, field: UserField) -> Value {
+            match field {
+                UserField::Id => self.id.into(),
+                UserField::Name => self.name.into(),
+                UserField::Age => self.age.into(),
+            }
+        }
+    }
+
+    fn users() -> Vec<User> {
+        vec![
+            User {
+                id: 1,
+                name: "alice",
+                age: Some(30),
+            },
+            User {
+                id: 2,
+                name: "bob",
+                age: None,
+            },
+            User {
+                id: 3,
+                name: "anna",
+                age: Some(18),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_filter_and_sort() {
+        let criteria = Criteria::new()
+            .filter(UserField::Name.like("a%"))
+            .filter(UserField::Age.between(10, 40))
+            .order_by_asc(UserField::Age);
+        let ids = criteria
+            .apply(users())
+            .into_iter()
+            .map(|u| u.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![3, 1]);
+
+        let criteria = Criteria::new()
+            .filter(UserField::Age.is_null().or(UserField::Id.eq_any([3])))
+            .order_by_desc(UserField::Id)
+            .offset(1)
+            .limit(5);
+        let ids = criteria
+            .apply(users())
+            .into_iter()
+            .map(|u| u.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![2]);
+    }
+
+    #[test]
+    fn test_null_comparison() {
+        let bob = &users()[1];
+        assert!(!UserField::Age.gt(1).matches(bob));
+        assert!(!UserField::Age.ne(1).matches(bob));
+        assert!(UserField::Age.eq(Value::Null).matches(bob));
+        assert!(!UserField::Age.gt(1).not().matches(bob));
+        assert!(!UserField::Age.eq_any([1, 2]).not().matches(bob));
+        assert!(!UserField::Age
+            .gt(1)
+            .or(UserField::Id.eq(1))
+            .not()
+            .matches(bob));
+        assert!(UserField::Age
+            .gt(1)
+            .and(UserField::Id.eq(1))
+            .not()
+            .matches(bob));
+        assert!(UserField::Age.is_null().not().not().matches(bob));
+    }
+
+    #[test]
+    fn test_like() {
+        assert!(like_matches("alice", "a%"));
+        assert!(like_matches("alice", "_lic_"));
+        assert!(like_matches("alice", "%ic%"));
+        assert!(!like_matches("alice", "b%"));
+        assert!(!like_matches("alice", "_lic"));
+        assert!(like_matches("", "%"));
+    }
+}
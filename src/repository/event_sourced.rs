@@ -0,0 +1,658 @@
+//! Event-sourced repositories
+//!
+//! [`EventSourcedRepository`] persists an aggregate as the sequence of its events in an
+//! [`EventStore`] instead of a row:
+//!
+//! - `save` appends [`AggregateEvent::Created`] with the values of all fields
+//! - `update` appends [`AggregateEvent::FieldsChanged`] with the values of the changed fields
+//! - `delete` appends [`AggregateEvent::Deleted`]
+//! - [`EventSourcedRepository::append_events`] appends domain events, which
+//!   [`EventSourced::apply`] folds into the state
+//!
+//! Field values are serialized by [`FieldAccess::field_json`] and merged into the serialized
+//! [`EventSourced::State`] by field name, so the state must serialize each field under its
+//! field name: a field renamed by serde fails the fold instead of being dropped. Changes of
+//! foreign entities and field groups are not field values, they are recorded as domain events,
+//! `save` and `update` fail if an entity has any.
+//!
+//! Every event has the next sequence number of its aggregate. Appends are optimistic: the
+//! version an aggregate was loaded at is expected to be the last sequence number in the store,
+//! otherwise the append is a conflict. `update` fails for an aggregate which was not loaded by
+//! the repository, as there is no version to check against. Loading folds the events after the latest snapshot,
+//! which is taken every [`EventSourcedRepository::with_snapshot_every`] events.
+//!
+//! ```rust,ignore
+//! #[Entity]
+//! #[subset_attr(derive(serde::Serialize, serde::Deserialize))]
+//! pub struct Account {
+//!     id: AccountId,
+//!     balance: i64,
+//! }
+//!
+//! impl EventSourced for Account {
+//!     type State = AccountFull;
+//!     type Event = AccountEvent;
+//!
+//!     fn apply(state: Option<AccountFull>, event: &AccountEvent) -> anyhow::Result<Option<AccountFull>> {
+//!         let mut account = state.context("account does not exist")?;
+//!         match event {
+//!             AccountEvent::Deposited(amount) => account.balance += amount,
+//!         }
+//!         Ok(Some(account))
+//!     }
+//! }
+//!
+//! type AccountRepo = EventSourcedRepository<Account, EventStoreDiesel<Adapter>>;
+//! ```
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex as SyncMutex},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    entity::{subset::Subset, Entity, FieldEnum},
+    provider::{Provider, ProviderContext},
+};
+
+use super::{
+    criteria::FieldAccess, entity_name, ConflictTarget, DeleteEffect, Repository, SaveEffect,
+    SaveEffectOf, SubsetLoader, SubsetReader, UpdateEffect,
+};
+
+/// An entity persisted by its events
+pub trait EventSourced: Entity + FieldAccess<Self::FieldEnum> {
+    /// The subset the aggregate is rebuilt as, it is also the content of snapshots
+    type State: Subset<Entity = Self> + Serialize + DeserializeOwned;
+
+    /// Domain events, [`NoDomainEvent`] if only field changes are recorded
+    type Event: Serialize + DeserializeOwned;
+
+    /// Name of the aggregate type in the event store
+    fn aggregate_type() -> &'static str {
+        entity_name::<Self>()
+    }
+
+    /// Fold a domain event into the state, `None` if the aggregate does not exist
+    fn apply(
+        state: Option<Self::State>,
+        event: &Self::Event,
+    ) -> anyhow::Result<Option<Self::State>>;
+}
+
+/// Domain event type of aggregates without domain events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoDomainEvent {}
+
+/// An event of an aggregate, as stored in the event store
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum AggregateEvent<D> {
+    /// Field name => value of all fields
+    Created(serde_json::Map<String, serde_json::Value>),
+    /// Field name => value of the changed fields
+    FieldsChanged(serde_json::Map<String, serde_json::Value>),
+    Deleted,
+    Domain(D),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredEvent {
+    pub sequence: u64,
+    pub payload: serde_json::Value,
+    pub at: SystemTime,
+}
+
+/// The state of an aggregate after the event `version`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub version: u64,
+    pub state: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use = "Append effect should be checked"]
+pub enum AppendEffect {
+    /// Appended, the aggregate is at `version` now
+    Ok { version: u64 },
+    /// The aggregate is not at the expected version
+    Conflict,
+}
+
+/// Event streams keyed by the aggregate type and id
+pub trait EventStore {
+    /// Append events with the sequence numbers following `expected_version`, a conflict if the
+    /// last sequence number of the aggregate is not `expected_version`
+    async fn append(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        expected_version: u64,
+        events: Vec<serde_json::Value>,
+    ) -> anyhow::Result<AppendEffect>;
+
+    /// Events with sequence numbers greater than `after`, in order
+    async fn load(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        after: u64,
+    ) -> anyhow::Result<Vec<StoredEvent>>;
+
+    /// The last sequence number of the aggregate, 0 if it has no event
+    async fn version(&mut self, aggregate_type: &str, aggregate_id: &str) -> anyhow::Result<u64>;
+
+    /// Keep the snapshot if it is newer than the stored one
+    async fn save_snapshot(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        snapshot: Snapshot,
+    ) -> anyhow::Result<()>;
+
+    async fn load_snapshot(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+    ) -> anyhow::Result<Option<Snapshot>>;
+}
+
+pub struct EventSourcedRepository<E, St>
+where
+    E: Entity,
+{
+    store: St,
+    snapshot_every: Option<u64>,
+    /// Versions the aggregates were loaded at
+    versions: HashMap<E::SysId, u64>,
+    _entity: PhantomData<E>,
+}
+
+/// Event store which keeps events in memory, for tests
+#[derive(Clone, Default)]
+pub struct InMemoryEventStore {
+    inner: Arc<SyncMutex<InMemoryEvents>>,
+}
+
+#[derive(Default)]
+struct InMemoryEvents {
+    events: HashMap<(String, String), Vec<StoredEvent>>,
+    snapshots: HashMap<(String, String), Snapshot>,
+}
+
+impl<E, St> Clone for EventSourcedRepository<E, St>
+where
+    E: Entity,
+    St: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            snapshot_every: self.snapshot_every,
+            versions: self.versions.clone(),
+            _entity: PhantomData,
+        }
+    }
+}
+
+impl<E, St> Provider for EventSourcedRepository<E, St>
+where
+    E: Entity + 'static,
+    St: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(St::build(ctx)?))
+    }
+}
+
+impl<E, St> EventSourcedRepository<E, St>
+where
+    E: Entity,
+{
+    pub fn new(store: St) -> Self {
+        Self {
+            store,
+            snapshot_every: None,
+            versions: HashMap::new(),
+            _entity: PhantomData,
+        }
+    }
+
+    /// Take a snapshot whenever an append crosses a multiple of `events` events
+    pub fn with_snapshot_every(mut self, events: u64) -> Self {
+        self.snapshot_every = Some(events.max(1));
+        self
+    }
+
+    pub fn store_mut(&mut self) -> &mut St {
+        &mut self.store
+    }
+
+    /// The version an aggregate was loaded at by this repository
+    pub fn loaded_version(&self, id: &E::SysId) -> Option<u64> {
+        self.versions.get(id).copied()
+    }
+}
+
+impl<E, St> EventSourcedRepository<E, St>
+where
+    E: EventSourced,
+    E::SysId: Serialize,
+    St: EventStore,
+{
+    /// Append domain events to an aggregate, at the version it was loaded at if it was
+    pub async fn append_events(
+        &mut self,
+        id: &E::SysId,
+        events: Vec<E::Event>,
+    ) -> anyhow::Result<AppendEffect> {
+        let events = events.into_iter().map(AggregateEvent::Domain).collect();
+        let expected = match self.versions.get(id) {
+            Some(version) => *version,
+            None => self.store.version(E::aggregate_type(), &key(id)?).await?,
+        };
+        self.append(id, expected, events).await
+    }
+
+    /// All events of an aggregate with their sequence numbers, for auditing and replay
+    pub async fn history(
+        &mut self,
+        id: &E::SysId,
+    ) -> anyhow::Result<Vec<(u64, AggregateEvent<E::Event>)>> {
+        let events = self.store.load(E::aggregate_type(), &key(id)?, 0).await?;
+        events
+            .into_iter()
+            .map(|event| Ok((event.sequence, serde_json::from_value(event.payload)?)))
+            .collect()
+    }
+
+    /// The state of an aggregate right after the event `version`, ignoring snapshots
+    pub async fn replay(
+        &mut self,
+        id: &E::SysId,
+        version: u64,
+    ) -> anyhow::Result<Option<E::State>> {
+        let mut state = None;
+        for (sequence, event) in self.history(id).await? {
+            if sequence > version {
+                break;
+            }
+            state = fold::<E>(state, event)?;
+        }
+
+        Ok(state)
+    }
+
+    /// The current state and version of an aggregate
+    async fn rebuild(&mut self, id: &E::SysId) -> anyhow::Result<(Option<E::State>, u64)> {
+        let key = key(id)?;
+        let (mut state, mut version) =
+            match self.store.load_snapshot(E::aggregate_type(), &key).await? {
+                Some(snapshot) => (serde_json::from_value(snapshot.state)?, snapshot.version),
+                None => (None, 0),
+            };
+
+        for event in self.store.load(E::aggregate_type(), &key, version).await? {
+            state = fold::<E>(state, serde_json::from_value(event.payload)?)?;
+            version = event.sequence;
+        }
+
+        Ok((state, version))
+    }
+
+    async fn append(
+        &mut self,
+        id: &E::SysId,
+        expected: u64,
+        events: Vec<AggregateEvent<E::Event>>,
+    ) -> anyhow::Result<AppendEffect> {
+        let key = key(id)?;
+        let events = events
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let effect = self
+            .store
+            .append(E::aggregate_type(), &key, expected, events)
+            .await?;
+
+        let AppendEffect::Ok { version } = effect else {
+            return Ok(effect);
+        };
+        self.versions.insert(id.clone(), version);
+
+        if let Some(every) = self.snapshot_every {
+            if version / every > expected / every {
+                let (state, version) = self.rebuild(id).await?;
+                let snapshot = Snapshot {
+                    version,
+                    state: serde_json::to_value(state)?,
+                };
+                self.store
+                    .save_snapshot(E::aggregate_type(), &key, snapshot)
+                    .await?;
+            }
+        }
+
+        Ok(effect)
+    }
+
+    fn sys_id<I>(id: I) -> anyhow::Result<E::SysId>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = E::Id::from(id);
+        let sys_id = E::as_sys_id(&id)
+            .context("event-sourced aggregates are loaded by their sys id")?
+            .clone();
+        Ok(sys_id)
+    }
+}
+
+/// The aggregate id in the store, a JSON string is stored without its quotes
+fn key<T: Serialize>(id: &T) -> anyhow::Result<String> {
+    Ok(match serde_json::to_value(id)? {
+        serde_json::Value::String(id) => id,
+        id => id.to_string(),
+    })
+}
+
+fn field_values<E>(
+    entity: &E,
+    fields: &[E::FieldEnum],
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>>
+where
+    E: Entity + FieldAccess<E::FieldEnum>,
+{
+    let mut values = serde_json::Map::new();
+    for field in fields {
+        values.insert(field.field_name().to_string(), entity.field_json(*field)?);
+    }
+
+    Ok(values)
+}
+
+fn ensure_no_nested_changes<E: Entity>(entity: &E) -> anyhow::Result<()> {
+    let nested = entity.changed_nested_fields();
+    anyhow::ensure!(
+        nested.is_empty(),
+        "changes of {nested:?} of {} are not field values, record them as domain events",
+        entity_name::<E>()
+    );
+    Ok(())
+}
+
+fn state_fields<E>(state: &E::State) -> anyhow::Result<serde_json::Map<String, serde_json::Value>>
+where
+    E: EventSourced,
+{
+    let serde_json::Value::Object(fields) = serde_json::to_value(state)? else {
+        anyhow::bail!("the state of an aggregate must be serialized as a map");
+    };
+    Ok(fields)
+}
+
+/// Fail on a field which is not in the serialized state, e.g. one renamed by serde
+fn ensure_known_fields(
+    state: &serde_json::Map<String, serde_json::Value>,
+    values: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<()> {
+    if let Some(unknown) = values.keys().find(|field| !state.contains_key(*field)) {
+        anyhow::bail!("field {unknown} is not in the serialized state of the aggregate");
+    }
+    Ok(())
+}
+
+fn fold<E>(
+    state: Option<E::State>,
+    event: AggregateEvent<E::Event>,
+) -> anyhow::Result<Option<E::State>>
+where
+    E: EventSourced,
+{
+    match event {
+        AggregateEvent::Created(values) => {
+            let state = serde_json::from_value(serde_json::Value::Object(values.clone()))?;
+            ensure_known_fields(&state_fields::<E>(&state)?, &values)?;
+            Ok(Some(state))
+        }
+        AggregateEvent::FieldsChanged(values) => {
+            let state = state.context("fields changed on an aggregate which does not exist")?;
+            let mut fields = state_fields::<E>(&state)?;
+            ensure_known_fields(&fields, &values)?;
+            fields.extend(values);
+            Ok(Some(serde_json::from_value(serde_json::Value::Object(
+                fields,
+            ))?))
+        }
+        AggregateEvent::Deleted => Ok(None),
+        AggregateEvent::Domain(event) => E::apply(state, &event),
+    }
+}
+
+impl<E, St> SubsetLoader<E::State> for EventSourcedRepository<E, St>
+where
+    E: EventSourced,
+    E::SysId: Serialize,
+    St: EventStore,
+{
+    /// Rebuild the aggregate and remember its version for the next append
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<E::State>>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = Self::sys_id(id)?;
+        let (state, version) = self.rebuild(&id).await?;
+        if state.is_some() {
+            self.versions.insert(id, version);
+        }
+
+        Ok(state)
+    }
+}
+
+impl<E, St> SubsetReader<E::State> for EventSourcedRepository<E, St>
+where
+    E: EventSourced,
+    E::SysId: Serialize,
+    St: EventStore,
+{
+    async fn read<I>(&mut self, id: I) -> anyhow::Result<Option<E::State>>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = Self::sys_id(id)?;
+        Ok(self.rebuild(&id).await?.0)
+    }
+}
+
+impl<E, St> Repository<E> for EventSourcedRepository<E, St>
+where
+    E: EventSourced,
+    E::SysId: Serialize,
+    St: EventStore,
+{
+    async fn save(&mut self, entity: &E) -> anyhow::Result<SaveEffectOf<E>> {
+        ensure_no_nested_changes(entity)?;
+        let created = AggregateEvent::Created(field_values(entity, E::FieldEnum::all())?);
+        match self.append(entity.sys_id(), 0, vec![created]).await? {
+            AppendEffect::Ok { .. } => Ok(SaveEffect::Ok),
+            AppendEffect::Conflict => Ok(SaveEffect::Conflict(ConflictTarget::SysId)),
+        }
+    }
+
+    /// A conflict if the aggregate has changed since it was loaded, an error if it was not
+    /// loaded by this repository
+    async fn update(&mut self, entity: &E) -> anyhow::Result<UpdateEffect> {
+        ensure_no_nested_changes(entity)?;
+        let id = entity.sys_id();
+        let expected = match self.versions.get(id) {
+            Some(version) => *version,
+            None => match self.rebuild(id).await? {
+                (Some(_), _) => anyhow::bail!(
+                    "aggregate {id:?} was not loaded by this repository, \
+                     there is no version to check the update against"
+                ),
+                (None, _) => return Ok(UpdateEffect::NotFound),
+            },
+        };
+
+        let fields = entity.changed_fields();
+        if fields.is_empty() {
+            return Ok(UpdateEffect::Ok);
+        }
+
+        let changed = AggregateEvent::FieldsChanged(field_values(entity, &fields)?);
+        match self.append(id, expected, vec![changed]).await? {
+            AppendEffect::Ok { .. } => Ok(UpdateEffect::Ok),
+            AppendEffect::Conflict => Ok(UpdateEffect::Conflict),
+        }
+    }
+
+    async fn delete<I>(&mut self, id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = Self::sys_id(id)?;
+        let (state, version) = self.rebuild(&id).await?;
+        if state.is_none() {
+            return Ok(DeleteEffect::NotFound);
+        }
+
+        // Deleted by id, at the current version rather than the loaded one
+        match self
+            .append(&id, version, vec![AggregateEvent::Deleted])
+            .await?
+        {
+            AppendEffect::Ok { .. } => {
+                self.versions.remove(&id);
+                Ok(DeleteEffect::Ok)
+            }
+            AppendEffect::Conflict => {
+                anyhow::bail!("aggregate {id:?} was appended to while being deleted")
+            }
+        }
+    }
+
+    async fn exists<I>(&mut self, id: I) -> anyhow::Result<bool>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = Self::sys_id(id)?;
+        Ok(self.rebuild(&id).await?.0.is_some())
+    }
+}
+
+impl AppendEffect {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, AppendEffect::Ok { .. })
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, AppendEffect::Conflict)
+    }
+
+    pub fn ignore_effect(self) {}
+}
+
+impl InMemoryEventStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Provider for InMemoryEventStore {
+    fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new())
+    }
+}
+
+impl EventStore for InMemoryEventStore {
+    async fn append(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        expected_version: u64,
+        events: Vec<serde_json::Value>,
+    ) -> anyhow::Result<AppendEffect> {
+        let mut inner = self.inner.lock().unwrap();
+        let stream = inner
+            .events
+            .entry((aggregate_type.to_string(), aggregate_id.to_string()))
+            .or_default();
+        if stream.len() as u64 != expected_version {
+            return Ok(AppendEffect::Conflict);
+        }
+
+        let at = SystemTime::now();
+        for payload in events {
+            stream.push(StoredEvent {
+                sequence: stream.len() as u64 + 1,
+                payload,
+                at,
+            });
+        }
+
+        Ok(AppendEffect::Ok {
+            version: stream.len() as u64,
+        })
+    }
+
+    async fn load(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        after: u64,
+    ) -> anyhow::Result<Vec<StoredEvent>> {
+        let inner = self.inner.lock().unwrap();
+        let key = (aggregate_type.to_string(), aggregate_id.to_string());
+        Ok(inner
+            .events
+            .get(&key)
+            .map(|stream| stream.iter().skip(after as usize).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn version(&mut self, aggregate_type: &str, aggregate_id: &str) -> anyhow::Result<u64> {
+        let inner = self.inner.lock().unwrap();
+        let key = (aggregate_type.to_string(), aggregate_id.to_string());
+        Ok(inner
+            .events
+            .get(&key)
+            .map_or(0, |stream| stream.len() as u64))
+    }
+
+    async fn save_snapshot(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        snapshot: Snapshot,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (aggregate_type.to_string(), aggregate_id.to_string());
+        if inner
+            .snapshots
+            .get(&key)
+            .is_none_or(|stored| stored.version < snapshot.version)
+        {
+            inner.snapshots.insert(key, snapshot);
+        }
+
+        Ok(())
+    }
+
+    async fn load_snapshot(
+        &mut self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+    ) -> anyhow::Result<Option<Snapshot>> {
+        let inner = self.inner.lock().unwrap();
+        let key = (aggregate_type.to_string(), aggregate_id.to_string());
+        Ok(inner.snapshots.get(&key).cloned())
+    }
+}
@@ -0,0 +1,241 @@
+//! Identity map
+//!
+//! Within a transaction, every entity loaded by [`Repository::find_tracked`] is kept in the
+//! identity map of the db adapter, keyed by its sys id. Loading the same entity again returns
+//! the same [`Tracked`] instance instead of an independent copy, so changes made through one
+//! handle are seen by all of them.
+//!
+//! The map is only active between `begin_txn` and `commit_txn`/`rollback_txn`. Outside a
+//! transaction entities are not cached. Rolling back to a savepoint evicts the entities loaded
+//! since the savepoint began, the ones loaded before it stay shared.
+//!
+//! [`Repository::find`] is not routed through the map. It returns an owned entity, which could
+//! not be the shared instance anyway, and it would require every repository to implement
+//! [`WithIdentityMap`]. Use `find_tracked` where the shared instance matters.
+//!
+//! The map does not flush changes. Load by [`UnitOfWork::find_tracked`] so that the shared
+//! instance is also tracked by the unit of work and flushed before commit.
+//!
+//! [`Repository::find`]: super::Repository::find
+//! [`Repository::find_tracked`]: super::Repository::find_tracked
+//! [`UnitOfWork::find_tracked`]: super::unit_of_work::UnitOfWork::find_tracked
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, Mutex as SyncMutex},
+};
+
+use crate::entity::{subset::Subset, Entity};
+
+use super::unit_of_work::Tracked;
+
+#[derive(Clone, Default)]
+pub struct IdentityMap {
+    state: Arc<SyncMutex<IdentityMapState>>,
+}
+
+#[derive(Default)]
+struct IdentityMapState {
+    active: bool,
+    /// Entity type => `HashMap<E::SysId, IdentityEntry<E>>`
+    entities: HashMap<TypeId, Box<dyn AnyEntityMap>>,
+    /// Sequence number of the next inserted entry
+    next_seq: u64,
+    /// The `next_seq` at the beginning of each open savepoint, innermost last
+    savepoints: Vec<u64>,
+}
+
+struct IdentityEntry<E> {
+    entity: Tracked<E>,
+    subset: &'static str,
+    fields: &'static [&'static str],
+    seq: u64,
+}
+
+type EntityMap<E> = HashMap<<E as Entity>::SysId, IdentityEntry<E>>;
+
+/// An [`EntityMap`] of any entity type
+trait AnyEntityMap: Any + Send {
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// Drop the entries inserted at or after `seq`
+    fn evict_since(&mut self, seq: u64);
+}
+
+impl<K, E> AnyEntityMap for HashMap<K, IdentityEntry<E>>
+where
+    K: Send + 'static,
+    E: 'static,
+    IdentityEntry<E>: Send,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn evict_since(&mut self, seq: u64) {
+        self.retain(|_, entry| entry.seq < seq);
+    }
+}
+
+/// Repositories which share the identity map of their db adapter
+pub trait WithIdentityMap {
+    fn identity_map(&self) -> &IdentityMap;
+}
+
+impl IdentityMap {
+    /// Start caching entities, called when a transaction begins
+    pub fn activate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.active = true;
+        state.entities.clear();
+        state.savepoints.clear();
+    }
+
+    /// Stop caching entities and drop all of them, called when a transaction ends
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.active = false;
+        state.entities.clear();
+        state.savepoints.clear();
+    }
+
+    /// Remember which entities are cached, called when a savepoint begins
+    pub fn begin_savepoint(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.active {
+            let seq = state.next_seq;
+            state.savepoints.push(seq);
+        }
+    }
+
+    /// Keep the entities cached since the savepoint began, called when it is released
+    pub fn release_savepoint(&self) {
+        self.state.lock().unwrap().savepoints.pop();
+    }
+
+    /// Drop the entities cached since the savepoint began, they may hold rolled back values,
+    /// called when the transaction is rolled back to it
+    pub fn rollback_to_savepoint(&self) {
+        let mut state = self.state.lock().unwrap();
+        let Some(seq) = state.savepoints.pop() else {
+            return;
+        };
+        for map in state.entities.values_mut() {
+            map.evict_since(seq);
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.lock().unwrap().active
+    }
+
+    /// Returns the tracked instance of the entity.
+    ///
+    /// # Errors
+    /// If the instance was loaded by a subset which lacks some fields of `S`.
+    pub fn get<S>(
+        &self,
+        sys_id: &<S::Entity as Entity>::SysId,
+    ) -> anyhow::Result<Option<Tracked<S::Entity>>>
+    where
+        S: Subset,
+        S::Entity: Send + 'static,
+        <S::Entity as Entity>::SysId: Send + 'static,
+    {
+        let state = self.state.lock().unwrap();
+        let Some(entry) = state
+            .entities
+            .get(&TypeId::of::<S::Entity>())
+            .and_then(|map| map.as_any().downcast_ref::<EntityMap<S::Entity>>())
+            .and_then(|map| map.get(sys_id))
+        else {
+            return Ok(None);
+        };
+
+        entry.ensure_covers::<S>(sys_id)?;
+        Ok(Some(entry.entity.clone()))
+    }
+
+    /// Put a freshly loaded entity into the map.
+    ///
+    /// If the entity is already tracked, the tracked instance is returned and the fresh copy
+    /// is dropped. Nothing is cached if the map is not active.
+    ///
+    /// # Errors
+    /// If the tracked instance was loaded by a subset which lacks some fields of `S`.
+    pub fn insert<S>(&self, entity: S::Entity) -> anyhow::Result<Tracked<S::Entity>>
+    where
+        S: Subset,
+        S::Entity: Send + 'static,
+        <S::Entity as Entity>::SysId: Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        if !state.active {
+            return Ok(Tracked::new(entity));
+        }
+
+        let seq = state.next_seq;
+        let map = state
+            .entities
+            .entry(TypeId::of::<S::Entity>())
+            .or_insert_with(|| Box::new(EntityMap::<S::Entity>::new()))
+            .as_any_mut()
+            .downcast_mut::<EntityMap<S::Entity>>()
+            .expect("identity map is keyed by entity type");
+
+        let sys_id = entity.sys_id().clone();
+        if let Some(entry) = map.get(&sys_id) {
+            entry.ensure_covers::<S>(&sys_id)?;
+            return Ok(entry.entity.clone());
+        }
+
+        let tracked = Tracked::new(entity);
+        map.insert(
+            sys_id,
+            IdentityEntry {
+                entity: tracked.clone(),
+                subset: std::any::type_name::<S>(),
+                fields: S::field_names(),
+                seq,
+            },
+        );
+        state.next_seq += 1;
+
+        Ok(tracked)
+    }
+}
+
+impl<E> IdentityEntry<E>
+where
+    E: Entity,
+{
+    fn ensure_covers<S>(&self, sys_id: &E::SysId) -> anyhow::Result<()>
+    where
+        S: Subset<Entity = E>,
+    {
+        let missing = S::field_names()
+            .iter()
+            .filter(|f| !self.fields.contains(f))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "entity `{}` ({:?}) is already loaded by subset `{}` in this transaction, \
+                 which lacks fields {:?} required by subset `{}`",
+                std::any::type_name::<E>(),
+                sys_id,
+                self.subset,
+                missing,
+                std::any::type_name::<S>(),
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,668 @@
+//! Probabilistic `fast_exists`
+//!
+//! [`FilteredRepository`] wraps a repository and answers [`Repository::fast_exists`] from an
+//! in-process [`MembershipFilter`] instead of the database. A key which is not in the filter
+//! is answered by [`FastExists::NoButNotSure`], since another process may have inserted it,
+//! and a key in the filter by [`FastExists::YesButNotSure`], since the filter has false
+//! positives and keeps deleted keys until it is rebuilt. Both ids and biz ids are kept, so
+//! signup-uniqueness checks on an email can skip the database for most new emails.
+//!
+//! The filter is seeded on startup by [`BloomFilter::rebuild`] from a stream of entities,
+//! e.g. [`Repository::read_stream`], and kept up to date by writes made through the
+//! decorator once their transaction commits. [`RebuildFilterTask`] rebuilds it periodically
+//! to drop deleted keys and the keys written by other processes. Until seeded, the filter is
+//! not consulted.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! static USER_FILTER: OnceLock<UserFilter> = OnceLock::new();
+//!
+//! #[derive(Clone)]
+//! pub struct UserFilter(BloomFilter<UserIdentOwned>);
+//!
+//! impl Provider for UserFilter {
+//!     fn build(_ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+//!         Ok(USER_FILTER
+//!             .get_or_init(|| UserFilter(BloomFilter::new(1_000_000, 0.01)))
+//!             .clone())
+//!     }
+//! }
+//!
+//! // On startup
+//! let filter = UserFilter::build(&mut ctx)?;
+//! let mut repo = UserRepo::build(&mut ctx)?;
+//! filter.rebuild(repo.read_stream::<UserIds>(Criteria::new(), None)).await?;
+//!
+//! type FilteredUserRepo = FilteredRepository<User, UserRepo, Txn, UserFilter>;
+//! ```
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    marker::PhantomData,
+    sync::{Arc, Mutex as SyncMutex},
+    time::SystemTime,
+};
+
+use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{TxCallback, TxnManager, TxnResult},
+    entity::{subset::Subset, Entity},
+    provider::{Provider, ProviderContext},
+};
+
+use super::{
+    AsOfSubsetReader, BatchSubsetLoader, BatchSubsetReader, DeleteEffect, FastExists, LockEffect,
+    LockOptions, LockingSubsetLoader, OnConflict, Repository, SaveEffectOf, SubsetLoader,
+    SubsetReader, UpdateEffect, UpsertEffect,
+};
+
+/// A set which may answer false positives, shared by all requests
+pub trait MembershipFilter<K>: Clone + 'static {
+    fn insert(&self, key: &K);
+
+    /// Remove a key, filters which can't delete keep it until they are rebuilt
+    fn remove(&self, key: &K);
+
+    fn might_contain(&self, key: &K) -> bool;
+
+    /// Whether the filter has been seeded
+    fn is_ready(&self) -> bool;
+}
+
+/// Bloom filter with double hashing
+pub struct BloomFilter<K> {
+    state: Arc<SyncMutex<BloomState>>,
+    _key: PhantomData<fn(&K)>,
+}
+
+/// Serializable content of a [`BloomFilter`]
+///
+/// Keys are hashed by [`DefaultHasher`], whose algorithm may change between Rust releases,
+/// so a snapshot should only be restored by a binary built with the same toolchain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BloomSnapshot {
+    pub bits: Vec<u64>,
+    pub hashes: u32,
+    pub inserted: u64,
+}
+
+struct BloomState {
+    bits: Vec<u64>,
+    hashes: u32,
+    inserted: u64,
+    /// Keys removed since the last rebuild, they are still in the filter
+    stale: u64,
+    ready: bool,
+    /// Keys inserted during a rebuild, to be replayed into the rebuilt content
+    buffered: Option<Vec<(u64, u64)>>,
+}
+
+/// Stop buffering the inserted keys when a rebuild ends, fails or is cancelled
+struct Rebuilding<'a>(&'a SyncMutex<BloomState>);
+
+impl Drop for Rebuilding<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().buffered = None;
+    }
+}
+
+impl<K> Clone for BloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K> BloomFilter<K>
+where
+    K: Hash,
+{
+    /// A filter sized for `expected_items` keys with the given false positive rate
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let (bits, hashes) = Self::dimensions(expected_items, false_positive_rate);
+        Self {
+            state: Arc::new(SyncMutex::new(BloomState::new(bits, hashes))),
+            _key: PhantomData,
+        }
+    }
+
+    /// Number of bits and hash functions
+    fn dimensions(expected_items: usize, false_positive_rate: f64) -> (usize, u32) {
+        let n = expected_items.max(1) as f64;
+        let p = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (bits / n * ln2).round().clamp(1.0, 32.0);
+
+        (bits as usize, hashes as u32)
+    }
+
+    /// Replace the content by the ids of the entities.
+    ///
+    /// The new content is built aside, the filter keeps answering from the old content until
+    /// the stream is drained. Keys inserted meanwhile are buffered and replayed into the new
+    /// content, since the stream may have been read before they were written. On error the
+    /// filter is left untouched. Only one rebuild may run at a time.
+    pub async fn rebuild<E, St>(&self, entities: St) -> anyhow::Result<()>
+    where
+        E: Entity<IdOwned = K>,
+        St: Stream<Item = anyhow::Result<E>>,
+    {
+        let (len, hashes) = {
+            let mut state = self.state.lock().unwrap();
+            anyhow::ensure!(
+                state.buffered.is_none(),
+                "the bloom filter is being rebuilt"
+            );
+            state.buffered = Some(Vec::new());
+            (state.bits.len(), state.hashes)
+        };
+        let _rebuilding = Rebuilding(&self.state);
+        let mut fresh = BloomState::new(len * 64, hashes);
+        fresh.ready = true;
+
+        let mut entities = std::pin::pin!(entities);
+        while let Some(entity) = entities.try_next().await? {
+            for id in entity.ids() {
+                fresh.insert(hash_pair(&id));
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for hash in state.buffered.take().unwrap_or_default() {
+            fresh.insert(hash);
+        }
+        *state = fresh;
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> BloomSnapshot {
+        let state = self.state.lock().unwrap();
+        BloomSnapshot {
+            bits: state.bits.clone(),
+            hashes: state.hashes,
+            inserted: state.inserted,
+        }
+    }
+
+    /// Restore a persisted snapshot, the filter is ready afterwards
+    pub fn restore(&self, snapshot: BloomSnapshot) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !snapshot.bits.is_empty() && snapshot.hashes > 0,
+            "empty bloom filter snapshot"
+        );
+
+        let mut state = self.state.lock().unwrap();
+        *state = BloomState {
+            bits: snapshot.bits,
+            hashes: snapshot.hashes,
+            inserted: snapshot.inserted,
+            stale: 0,
+            ready: true,
+            buffered: state.buffered.take().map(|_| Vec::new()),
+        };
+
+        Ok(())
+    }
+
+    /// Number of keys inserted since the last rebuild
+    pub fn inserted(&self) -> u64 {
+        self.state.lock().unwrap().inserted
+    }
+
+    /// Number of keys removed since the last rebuild
+    pub fn stale(&self) -> u64 {
+        self.state.lock().unwrap().stale
+    }
+}
+
+impl<K> MembershipFilter<K> for BloomFilter<K>
+where
+    K: Hash + 'static,
+{
+    fn insert(&self, key: &K) {
+        self.state.lock().unwrap().insert(hash_pair(key));
+    }
+
+    fn remove(&self, _key: &K) {
+        self.state.lock().unwrap().stale += 1;
+    }
+
+    fn might_contain(&self, key: &K) -> bool {
+        self.state.lock().unwrap().contains(hash_pair(key))
+    }
+
+    fn is_ready(&self) -> bool {
+        self.state.lock().unwrap().ready
+    }
+}
+
+impl BloomState {
+    fn new(bits: usize, hashes: u32) -> Self {
+        Self {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes,
+            inserted: 0,
+            stale: 0,
+            ready: false,
+            buffered: None,
+        }
+    }
+
+    fn positions(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 64) as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        for pos in self.positions(hash).collect::<Vec<_>>() {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+        self.inserted += 1;
+        if let Some(buffered) = &mut self.buffered {
+            buffered.push(hash);
+        }
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        self.positions(hash)
+            .all(|pos| self.bits[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+}
+
+fn hash_pair<K: Hash>(key: &K) -> (u64, u64) {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let h1 = hasher.finish();
+    // A second, independent hash derived from the first state
+    0x9e37_79b9_7f4a_7c15u64.hash(&mut hasher);
+    let h2 = hasher.finish() | 1;
+
+    (h1, h2)
+}
+
+pub struct FilteredRepository<E, R, Tx, F> {
+    inner: R,
+    txn: Tx,
+    filter: F,
+    _entity: PhantomData<E>,
+}
+
+enum FilterUpdate<K> {
+    Insert(Vec<K>),
+    Remove(K),
+}
+
+struct UpdateOnCommit<K, F> {
+    filter: F,
+    update: FilterUpdate<K>,
+}
+
+impl<K> FilterUpdate<K> {
+    fn apply<F>(&self, filter: &F)
+    where
+        F: MembershipFilter<K>,
+    {
+        match self {
+            FilterUpdate::Insert(keys) => keys.iter().for_each(|key| filter.insert(key)),
+            FilterUpdate::Remove(key) => filter.remove(key),
+        }
+    }
+}
+
+impl<K, F> TxCallback for UpdateOnCommit<K, F>
+where
+    K: 'static,
+    F: MembershipFilter<K>,
+{
+    fn call(self: Box<Self>, tx_result: TxnResult) {
+        if let TxnResult::Committed = tx_result {
+            self.update.apply(&self.filter);
+        }
+    }
+}
+
+impl<E, R, Tx, F> Clone for FilteredRepository<E, R, Tx, F>
+where
+    R: Clone,
+    Tx: Clone,
+    F: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            txn: self.txn.clone(),
+            filter: self.filter.clone(),
+            _entity: PhantomData,
+        }
+    }
+}
+
+impl<E, R, Tx, F> Provider for FilteredRepository<E, R, Tx, F>
+where
+    E: 'static,
+    R: Provider,
+    Tx: Provider,
+    F: Provider,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        Ok(Self::new(R::build(ctx)?, Tx::build(ctx)?, F::build(ctx)?))
+    }
+}
+
+impl<E, R, Tx, F> FilteredRepository<E, R, Tx, F> {
+    pub fn new(inner: R, txn: Tx, filter: F) -> Self {
+        Self {
+            inner,
+            txn,
+            filter,
+            _entity: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn filter(&self) -> &F {
+        &self.filter
+    }
+}
+
+impl<E, R, Tx, F> FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    Tx: TxnManager,
+    F: MembershipFilter<E::IdOwned>,
+{
+    /// Insert the ids of the entities written successfully
+    fn insert_ok(&self, entities: &[E], ok: impl Iterator<Item = bool>) {
+        let ids = entities
+            .iter()
+            .zip(ok)
+            .filter(|(_, ok)| *ok)
+            .flat_map(|(entity, _)| entity.ids())
+            .collect::<Vec<_>>();
+        if !ids.is_empty() {
+            self.update_filter(FilterUpdate::Insert(ids));
+        }
+    }
+
+    /// Update after commit if in a transaction, otherwise update immediately
+    fn update_filter(&self, update: FilterUpdate<E::IdOwned>) {
+        if !self.txn.state().is_begun() {
+            update.apply(&self.filter);
+            return;
+        }
+
+        self.txn.register_callback(UpdateOnCommit {
+            filter: self.filter.clone(),
+            update,
+        });
+    }
+}
+
+impl<E, R, Tx, F, S> SubsetReader<S> for FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: SubsetReader<S>,
+{
+    async fn read<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.read(id).await
+    }
+}
+
+impl<E, R, Tx, F, S> SubsetLoader<S> for FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: SubsetLoader<S>,
+{
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.load(id).await
+    }
+}
+
+impl<E, R, Tx, F, S> LockingSubsetLoader<S> for FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: LockingSubsetLoader<S>,
+{
+    async fn load_locked<I>(&mut self, id: I, lock: LockOptions) -> anyhow::Result<LockEffect<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.load_locked(id, lock).await
+    }
+}
+
+/// Not filtered, a deleted entity may have existed at that time
+impl<E, R, Tx, F, S> AsOfSubsetReader<S> for FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: AsOfSubsetReader<S>,
+{
+    async fn read_as_of<I>(&mut self, id: I, at: SystemTime) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>,
+    {
+        self.inner.read_as_of(id, at).await
+    }
+}
+
+impl<E, R, Tx, F, S, C> BatchSubsetReader<C, S> for FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: BatchSubsetReader<C, S>,
+{
+    async fn read_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>> {
+        self.inner.read_batch(condition).await
+    }
+}
+
+impl<E, R, Tx, F, S, C> BatchSubsetLoader<C, S> for FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    S: Subset<Entity = E>,
+    R: BatchSubsetLoader<C, S>,
+{
+    async fn load_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>> {
+        self.inner.load_batch(condition).await
+    }
+}
+
+impl<E, R, Tx, F> Repository<E> for FilteredRepository<E, R, Tx, F>
+where
+    E: Entity,
+    R: Repository<E>,
+    Tx: TxnManager,
+    F: MembershipFilter<E::IdOwned>,
+    for<'a> E::Id<'a>: From<E::IdOwned>,
+{
+    async fn save(&mut self, entity: &E) -> anyhow::Result<SaveEffectOf<E>> {
+        let effect = self.inner.save(entity).await?;
+        if effect.is_ok() {
+            self.update_filter(FilterUpdate::Insert(entity.ids()));
+        }
+
+        Ok(effect)
+    }
+
+    async fn update(&mut self, entity: &E) -> anyhow::Result<UpdateEffect> {
+        let effect = self.inner.update(entity).await?;
+        // Biz ids may have changed
+        if effect.is_ok() {
+            self.update_filter(FilterUpdate::Insert(entity.ids()));
+        }
+
+        Ok(effect)
+    }
+
+    async fn delete<I>(&mut self, id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let id = E::id_to_owned(&E::Id::from(id));
+        let effect = self.inner.delete(id.clone()).await?;
+        if effect.is_ok() {
+            self.update_filter(FilterUpdate::Remove(id));
+        }
+
+        Ok(effect)
+    }
+
+    async fn exists<I>(&mut self, id: I) -> anyhow::Result<bool>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        self.inner.exists(id).await
+    }
+
+    async fn save_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<SaveEffectOf<E>>> {
+        let effects = self.inner.save_batch(entities).await?;
+        self.insert_ok(entities, effects.iter().map(|effect| effect.is_ok()));
+
+        Ok(effects)
+    }
+
+    async fn update_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<UpdateEffect>> {
+        let effects = self.inner.update_batch(entities).await?;
+        self.insert_ok(entities, effects.iter().map(|effect| effect.is_ok()));
+
+        Ok(effects)
+    }
+
+    async fn delete_batch<I>(&mut self, ids: Vec<I>) -> anyhow::Result<Vec<DeleteEffect>>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let ids = ids
+            .into_iter()
+            .map(|id| E::id_to_owned(&E::Id::from(id)))
+            .collect::<Vec<_>>();
+        let effects = self.inner.delete_batch(ids.clone()).await?;
+        for (id, effect) in ids.into_iter().zip(&effects) {
+            if effect.is_ok() {
+                self.update_filter(FilterUpdate::Remove(id));
+            }
+        }
+
+        Ok(effects)
+    }
+
+    async fn upsert(
+        &mut self,
+        entity: &E,
+        on_conflict: OnConflict,
+    ) -> anyhow::Result<UpsertEffect<E::BizIdFieldEnum>>
+    where
+        for<'a> E::Id<'a>: From<E::SysId>,
+    {
+        let effect = self.inner.upsert(entity, on_conflict).await?;
+        if matches!(effect, UpsertEffect::Inserted | UpsertEffect::Updated) {
+            self.update_filter(FilterUpdate::Insert(entity.ids()));
+        }
+
+        Ok(effect)
+    }
+
+    async fn fast_exists<I>(&mut self, id: I) -> anyhow::Result<FastExists>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        if !self.filter.is_ready() {
+            return self.inner.fast_exists(id).await;
+        }
+
+        let id = E::id_to_owned(&E::Id::from(id));
+        if self.filter.might_contain(&id) {
+            Ok(FastExists::YesButNotSure)
+        } else {
+            Ok(FastExists::NoButNotSure)
+        }
+    }
+}
+
+/// Rebuild a filter periodically
+///
+/// `rebuild` is called every `interval`, typically it builds a repository and calls
+/// [`BloomFilter::rebuild`] with its stream. Errors are logged and the filter keeps its
+/// content until the next round.
+#[cfg(feature = "tokio")]
+pub struct RebuildFilterTask<F> {
+    interval: std::time::Duration,
+    rebuild: F,
+}
+
+#[cfg(feature = "tokio")]
+impl<F, Fut> RebuildFilterTask<F>
+where
+    F: FnMut() -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = anyhow::Result<()>>,
+{
+    pub fn new(interval: std::time::Duration, rebuild: F) -> Self {
+        Self { interval, rebuild }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<F, Fut> crate::async_task::LocalAsyncTask for RebuildFilterTask<F>
+where
+    F: FnMut() -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = anyhow::Result<()>>,
+{
+    async fn run(&mut self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            if let Err(err) = (self.rebuild)().await {
+                tracing::error!(?err, "failed to rebuild membership filter");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let filter = BloomFilter::<u64>::new(1000, 0.01);
+        assert!(!filter.is_ready());
+        for key in 0..1000 {
+            filter.insert(&key);
+        }
+
+        assert!((0..1000).all(|key| filter.might_contain(&key)));
+        let false_positives = (1000..11000)
+            .filter(|key| filter.might_contain(key))
+            .count();
+        assert!(false_positives < 300, "{false_positives}");
+
+        let restored = BloomFilter::<u64>::new(1, 0.5);
+        restored.restore(filter.snapshot()).unwrap();
+        assert!(restored.is_ready());
+        assert!((0..1000).all(|key| restored.might_contain(&key)));
+        assert_eq!(restored.inserted(), 1000);
+    }
+}
@@ -0,0 +1,737 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use criteria::{Criteria, FieldAccess};
+use futures::Stream;
+use identity_map::WithIdentityMap;
+use page::{Keyset, Page, PageRequest};
+use unit_of_work::Tracked;
+
+use crate::entity::{
+    foreign::{ForeignContainer, ForeignEntities, ForeignEntity},
+    subset::Subset,
+    BizIdFieldEnum, Entity, HistoryEntity, NoBizIdField,
+};
+
+pub mod audit;
+pub mod cache;
+pub mod criteria;
+pub mod event_sourced;
+pub mod identity_map;
+pub mod membership;
+pub mod page;
+pub mod seed;
+pub mod stream;
+pub mod unit_of_work;
+
+/// Check UpdateEffect and return if not ok
+#[macro_export]
+macro_rules! check_update_effect {
+    ($effect:expr) => {
+        let effect: ::bagua::repository::UpdateEffect = $effect;
+        if !effect.is_ok() {
+            return Ok(effect);
+        }
+    };
+}
+
+/// Check SaveEffect and return if not ok
+#[macro_export]
+macro_rules! check_save_effect {
+    ($effect:expr) => {
+        let effect: ::bagua::repository::SaveEffect<_> = $effect;
+        if !effect.is_ok() {
+            return Ok(effect);
+        }
+    };
+}
+
+/// Check DeleteEffect and return if not ok
+#[macro_export]
+macro_rules! check_delete_effect {
+    ($effect:expr) => {
+        let effect: ::bagua::repository::DeleteEffect = $effect;
+        if !effect.is_ok() {
+            return Ok(effect);
+        }
+    };
+}
+
+pub trait Repository<E: Entity> {
+    /// Load an independent copy of the entity, bypassing the identity map, see
+    /// [`Repository::find_tracked`]
+    async fn find<S, I>(&mut self, id: I) -> anyhow::Result<Option<E>>
+    where
+        S: Subset<Entity = E>,
+        Self: SubsetLoader<S>,
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let subset = self.load(id).await?;
+        Ok(subset.map(|s| s.to_entity()))
+    }
+
+    /// Same as [`Repository::find`], but locks the row until the end of the transaction
+    ///
+    /// # Errors
+    /// If there is no transaction, since the lock would be released right away.
+    async fn find_for_update<S, I>(
+        &mut self,
+        id: I,
+        lock: LockOptions,
+    ) -> anyhow::Result<LockEffect<E>>
+    where
+        S: Subset<Entity = E>,
+        Self: LockingSubsetLoader<S>,
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let effect = self.load_locked(id, lock).await?;
+        Ok(effect.map(|s| s.to_entity()))
+    }
+
+    /// Same as [`Repository::find`], but returns the instance kept in the identity map if the
+    /// entity has been loaded in the current transaction
+    ///
+    /// Changes to the instance are not flushed, unless it is loaded by
+    /// [`UnitOfWork::find_tracked`](unit_of_work::UnitOfWork::find_tracked).
+    async fn find_tracked<S, I>(&mut self, id: I) -> anyhow::Result<Option<Tracked<E>>>
+    where
+        S: Subset<Entity = E>,
+        Self: SubsetLoader<S> + WithIdentityMap,
+        I: Clone,
+        E: Send + 'static,
+        E::SysId: Send + 'static,
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let identity_map = self.identity_map().clone();
+        let key = E::Id::from(id.clone());
+        if let Some(sys_id) = E::as_sys_id(&key) {
+            if let Some(tracked) = identity_map.get::<S>(sys_id)? {
+                return Ok(Some(tracked));
+            }
+        }
+
+        let Some(subset) = self.load(id).await? else {
+            return Ok(None);
+        };
+        let tracked = identity_map.insert::<S>(subset.to_entity())?;
+
+        Ok(Some(tracked))
+    }
+
+    async fn find_batch<S, C>(&mut self, condition: C) -> anyhow::Result<Vec<E>>
+    where
+        S: Subset<Entity = E>,
+        Self: BatchSubsetLoader<C, S>,
+    {
+        let subset = self.load_batch(condition).await?;
+        Ok(subset.into_iter().map(|s| s.to_entity()).collect())
+    }
+
+    async fn read<S, I>(&mut self, id: I) -> anyhow::Result<Option<E>>
+    where
+        S: Subset<Entity = E>,
+        Self: SubsetReader<S>,
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let subset = SubsetReader::read(self, id).await?;
+        Ok(subset.map(|s| s.to_entity()))
+    }
+
+    /// The entity as it was at `at`, read from its history table, `None` if it did not
+    /// exist then
+    async fn read_as_of<S, I>(&mut self, id: I, at: SystemTime) -> anyhow::Result<Option<E>>
+    where
+        S: Subset<Entity = E>,
+        E: HistoryEntity,
+        Self: AsOfSubsetReader<S>,
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let subset = AsOfSubsetReader::read_as_of(self, id, at).await?;
+        Ok(subset.map(|s| s.to_entity()))
+    }
+
+    async fn read_batch<S, C>(&mut self, condition: C) -> anyhow::Result<Vec<E>>
+    where
+        S: Subset<Entity = E>,
+        Self: BatchSubsetReader<C, S>,
+    {
+        let subset = BatchSubsetReader::read_batch(self, condition).await?;
+        Ok(subset.into_iter().map(|s| s.to_entity()).collect())
+    }
+
+    /// Read a page of entities using keyset pagination on the criteria's sort keys and the sys id
+    async fn read_page<S>(
+        &mut self,
+        criteria: Criteria<E::FieldEnum>,
+        page: &PageRequest,
+    ) -> anyhow::Result<Page<E>>
+    where
+        S: Subset<Entity = E>,
+        E: FieldAccess<E::FieldEnum>,
+        Self: BatchSubsetReader<Criteria<E::FieldEnum>, S>,
+    {
+        let keyset = Keyset::new(criteria, page)?;
+        let entities = Repository::read_batch::<S, _>(self, keyset.criteria().clone()).await?;
+        keyset.page(entities)
+    }
+
+    /// Load a page of entities using keyset pagination on the criteria's sort keys and the sys id
+    async fn find_page<S>(
+        &mut self,
+        criteria: Criteria<E::FieldEnum>,
+        page: &PageRequest,
+    ) -> anyhow::Result<Page<E>>
+    where
+        S: Subset<Entity = E>,
+        E: FieldAccess<E::FieldEnum>,
+        Self: BatchSubsetLoader<Criteria<E::FieldEnum>, S>,
+    {
+        let keyset = Keyset::new(criteria, page)?;
+        let entities = Repository::find_batch::<S, _>(self, keyset.criteria().clone()).await?;
+        keyset.page(entities)
+    }
+
+    /// Read all entities matched by the criteria as a stream, in keyset chunks of `chunk_size`.
+    ///
+    /// See [`stream::read_batch_stream`].
+    fn read_stream<'r, S>(
+        &'r mut self,
+        criteria: Criteria<E::FieldEnum>,
+        chunk_size: Option<u32>,
+    ) -> impl Stream<Item = anyhow::Result<E>> + 'r
+    where
+        S: Subset<Entity = E> + 'r,
+        E: 'r,
+        E: FieldAccess<E::FieldEnum>,
+        Self: BatchSubsetReader<Criteria<E::FieldEnum>, S> + Sized,
+    {
+        stream::keyset_stream(self, criteria, chunk_size, S::to_entity)
+    }
+
+    /// Same as [`Repository::read_page`], the total count is filled if `page.with_total` is set
+    async fn read_page_counted<S>(
+        &mut self,
+        criteria: Criteria<E::FieldEnum>,
+        page: &PageRequest,
+    ) -> anyhow::Result<Page<E>>
+    where
+        S: Subset<Entity = E>,
+        E: FieldAccess<E::FieldEnum>,
+        Self: BatchSubsetReader<Criteria<E::FieldEnum>, S> + BatchCounter<Criteria<E::FieldEnum>>,
+    {
+        let total = if page.with_total {
+            let mut condition = criteria.clone();
+            condition.limit = None;
+            condition.offset = None;
+            Some(self.count(condition).await?)
+        } else {
+            None
+        };
+
+        let mut result = self.read_page(criteria, page).await?;
+        result.total = total;
+
+        Ok(result)
+    }
+
+    async fn save(&mut self, entity: &E) -> anyhow::Result<SaveEffectOf<E>>;
+
+    async fn update(&mut self, entity: &E) -> anyhow::Result<UpdateEffect>;
+
+    async fn delete<I>(&mut self, id: I) -> anyhow::Result<DeleteEffect>
+    where
+        for<'a> E::Id<'a>: From<I>;
+
+    async fn exists<I>(&mut self, id: I) -> anyhow::Result<bool>
+    where
+        for<'a> E::Id<'a>: From<I>;
+
+    /// Save entities, the effects are in the same order as the entities
+    async fn save_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<SaveEffectOf<E>>> {
+        let mut effects = Vec::with_capacity(entities.len());
+        for entity in entities {
+            effects.push(self.save(entity).await?);
+        }
+
+        Ok(effects)
+    }
+
+    /// Update entities, the effects are in the same order as the entities
+    async fn update_batch(&mut self, entities: &[E]) -> anyhow::Result<Vec<UpdateEffect>> {
+        let mut effects = Vec::with_capacity(entities.len());
+        for entity in entities {
+            effects.push(self.update(entity).await?);
+        }
+
+        Ok(effects)
+    }
+
+    /// Delete entities, the effects are in the same order as the ids
+    async fn delete_batch<I>(&mut self, ids: Vec<I>) -> anyhow::Result<Vec<DeleteEffect>>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let mut effects = Vec::with_capacity(ids.len());
+        for id in ids {
+            effects.push(self.delete(id).await?);
+        }
+
+        Ok(effects)
+    }
+
+    /// Insert the entity, or resolve a conflict with an existing row by `on_conflict`.
+    ///
+    /// The default implementation checks whether the sys id exists before
+    /// [`Repository::save`], so that a conflict on the sys id never fails an insert, and
+    /// updates the existing row by [`Repository::update`]. It is not atomic, and a conflict on
+    /// a biz id still fails the insert, which aborts a postgres transaction. Repositories
+    /// backed by a database should override it with a native upsert, e.g.
+    /// `INSERT ... ON CONFLICT` as in `db::diesel::upsert`.
+    async fn upsert(
+        &mut self,
+        entity: &E,
+        on_conflict: OnConflict,
+    ) -> anyhow::Result<UpsertEffect<E::BizIdFieldEnum>>
+    where
+        for<'a> E::Id<'a>: From<E::SysId>,
+    {
+        if !self.exists(entity.sys_id().clone()).await? {
+            return match self.save(entity).await? {
+                SaveEffect::Ok => Ok(UpsertEffect::Inserted),
+                // The row with the same biz id is another entity
+                SaveEffect::Conflict(target) => match on_conflict {
+                    OnConflict::DoNothing => Ok(UpsertEffect::Ignored),
+                    OnConflict::Fail | OnConflict::UpdateChanged => {
+                        Ok(UpsertEffect::Conflict(target))
+                    }
+                },
+            };
+        }
+
+        match on_conflict {
+            OnConflict::DoNothing => Ok(UpsertEffect::Ignored),
+            OnConflict::Fail => Ok(UpsertEffect::Conflict(ConflictTarget::SysId)),
+            OnConflict::UpdateChanged => match self.update(entity).await? {
+                UpdateEffect::Ok => Ok(UpsertEffect::Updated),
+                UpdateEffect::Conflict | UpdateEffect::NotFound => {
+                    Ok(UpsertEffect::Conflict(ConflictTarget::SysId))
+                }
+            },
+        }
+    }
+
+    async fn fast_exists<I>(&mut self, id: I) -> anyhow::Result<FastExists>
+    where
+        for<'a> E::Id<'a>: From<I>,
+    {
+        let exists = self.exists(id).await?;
+        if exists {
+            Ok(FastExists::Yes)
+        } else {
+            Ok(FastExists::No)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FastExists {
+    Yes,
+    No,
+    YesButNotSure,
+    NoButNotSure,
+}
+
+pub trait SubsetLoader<S: Subset> {
+    async fn load<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>;
+}
+
+/// Loads a subset and takes a row lock on it, see [`LockOptions`]
+pub trait LockingSubsetLoader<S: Subset> {
+    async fn load_locked<I>(&mut self, id: I, lock: LockOptions) -> anyhow::Result<LockEffect<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>;
+}
+
+pub trait SubsetReader<S: Subset> {
+    async fn read<I>(&mut self, id: I) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>;
+}
+
+/// Reads a subset as it was at a point in time, see [`HistoryEntity`]
+pub trait AsOfSubsetReader<S: Subset> {
+    async fn read_as_of<I>(&mut self, id: I, at: SystemTime) -> anyhow::Result<Option<S>>
+    where
+        for<'a> <<S as Subset>::Entity as Entity>::Id<'a>: From<I>;
+}
+
+pub trait BatchSubsetLoader<C, S: Subset> {
+    async fn load_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>>;
+}
+
+pub trait BatchSubsetReader<C, S: Subset> {
+    async fn read_batch(&mut self, condition: C) -> anyhow::Result<Vec<S>>;
+}
+
+pub trait BatchCounter<C> {
+    async fn count(&mut self, condition: C) -> anyhow::Result<u64>;
+}
+
+#[derive(Debug)]
+#[must_use = "Save effect should be checked"]
+pub enum SaveEffect<B = NoBizIdField> {
+    Ok,
+    Conflict(ConflictTarget<B>),
+}
+
+/// The save effect of entity `E`
+pub type SaveEffectOf<E> = SaveEffect<<E as Entity>::BizIdFieldEnum>;
+
+/// The unique key a conflicting row collided on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictTarget<B = NoBizIdField> {
+    SysId,
+    BizId(B),
+    /// The database did not tell which unique key collided
+    Unknown,
+}
+
+/// How [`Repository::upsert`] resolves a conflict with an existing row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Keep the existing row
+    DoNothing,
+    /// Update the changed fields of the existing row with the same sys id
+    UpdateChanged,
+    /// Return the conflict
+    Fail,
+}
+
+#[derive(Debug)]
+#[must_use = "Upsert effect should be checked"]
+pub enum UpsertEffect<B = NoBizIdField> {
+    Inserted,
+    Updated,
+    /// The row exists and is kept by [`OnConflict::DoNothing`]
+    Ignored,
+    Conflict(ConflictTarget<B>),
+}
+
+/// Strength of a row lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockMode {
+    /// `FOR UPDATE`
+    #[default]
+    Update,
+    /// `FOR NO KEY UPDATE`, does not block inserts referencing the row
+    NoKeyUpdate,
+    /// `FOR SHARE`
+    Share,
+    /// `FOR KEY SHARE`
+    KeyShare,
+}
+
+/// What to do if the row is locked by another transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockWait {
+    #[default]
+    Wait,
+    /// `NOWAIT`, fail with [`LockEffect::Timeout`] at once
+    NoWait,
+    /// `SKIP LOCKED`, a locked row is read as [`LockEffect::NotFound`]
+    SkipLocked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockOptions {
+    pub mode: LockMode,
+    pub wait: LockWait,
+    /// Maximum time to wait for the lock, the database default if `None`
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug)]
+#[must_use = "Lock effect should be checked"]
+pub enum LockEffect<T> {
+    Locked(T),
+    NotFound,
+    /// The lock was not acquired in time, or at once with [`LockWait::NoWait`]. Loaders which
+    /// read in a savepoint, such as the diesel ones, leave the transaction usable, otherwise
+    /// the database may have aborted it and it should be rolled back.
+    Timeout,
+}
+
+#[derive(Debug)]
+#[must_use = "Delete effect should be checked"]
+pub enum DeleteEffect {
+    Ok,
+    NotFound,
+}
+
+#[derive(Debug)]
+#[must_use = "Update effect should be checked"]
+pub enum UpdateEffect {
+    Ok,
+    Conflict,
+    NotFound,
+}
+
+impl UpdateEffect {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, UpdateEffect::NotFound)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self, UpdateEffect::Ok)
+    }
+
+    pub fn is_effected(&self) -> bool {
+        self.is_ok()
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, UpdateEffect::Conflict)
+    }
+
+    pub fn ignore_effect(self) {}
+}
+
+impl<B> SaveEffect<B> {
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, SaveEffect::Conflict(_))
+    }
+
+    pub fn conflict_target(&self) -> Option<&ConflictTarget<B>> {
+        match self {
+            SaveEffect::Ok => None,
+            SaveEffect::Conflict(target) => Some(target),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self, SaveEffect::Ok)
+    }
+
+    pub fn is_effected(&self) -> bool {
+        self.is_ok()
+    }
+
+    pub fn ignore_effect(self) {}
+}
+
+impl<B> UpsertEffect<B> {
+    pub fn is_inserted(&self) -> bool {
+        matches!(self, UpsertEffect::Inserted)
+    }
+
+    pub fn is_updated(&self) -> bool {
+        matches!(self, UpsertEffect::Updated)
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, UpsertEffect::Conflict(_))
+    }
+
+    pub fn is_effected(&self) -> bool {
+        self.is_inserted() || self.is_updated()
+    }
+
+    pub fn ignore_effect(self) {}
+}
+
+impl<B: BizIdFieldEnum> ConflictTarget<B> {
+    /// Map the name of the violated unique constraint, by the postgres naming convention:
+    /// `{table}_pkey` for the sys id and `{table}_{field}_key` for biz ids
+    pub fn from_constraint(table: &str, constraint: &str) -> Self {
+        if constraint
+            .strip_prefix(table)
+            .is_some_and(|rest| rest == "_pkey")
+        {
+            return ConflictTarget::SysId;
+        }
+
+        B::from_constraint(table, constraint)
+            .map(ConflictTarget::BizId)
+            .unwrap_or(ConflictTarget::Unknown)
+    }
+
+    /// Same as [`ConflictTarget::from_constraint`], the constraints named in `constraints`
+    /// are mapped to their biz id field first, for the ones not named by the convention
+    pub fn from_constraint_map(table: &str, constraint: &str, constraints: &[(&str, B)]) -> Self {
+        constraints
+            .iter()
+            .find(|(name, _)| *name == constraint)
+            .map(|(_, field)| ConflictTarget::BizId(*field))
+            .unwrap_or_else(|| Self::from_constraint(table, constraint))
+    }
+
+    pub fn biz_id(&self) -> Option<B> {
+        match self {
+            ConflictTarget::BizId(field) => Some(*field),
+            _ => None,
+        }
+    }
+}
+
+impl LockOptions {
+    /// `FOR UPDATE`
+    pub fn update() -> Self {
+        Self::default()
+    }
+
+    /// `FOR NO KEY UPDATE`
+    pub fn no_key_update() -> Self {
+        Self::default().with_mode(LockMode::NoKeyUpdate)
+    }
+
+    /// `FOR SHARE`
+    pub fn share() -> Self {
+        Self::default().with_mode(LockMode::Share)
+    }
+
+    pub fn with_mode(mut self, mode: LockMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn nowait(mut self) -> Self {
+        self.wait = LockWait::NoWait;
+        self
+    }
+
+    pub fn skip_locked(mut self) -> Self {
+        self.wait = LockWait::SkipLocked;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<T> LockEffect<T> {
+    pub fn is_locked(&self) -> bool {
+        matches!(self, LockEffect::Locked(_))
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, LockEffect::NotFound)
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, LockEffect::Timeout)
+    }
+
+    pub fn locked(self) -> Option<T> {
+        match self {
+            LockEffect::Locked(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> LockEffect<U> {
+        match self {
+            LockEffect::Locked(value) => LockEffect::Locked(f(value)),
+            LockEffect::NotFound => LockEffect::NotFound,
+            LockEffect::Timeout => LockEffect::Timeout,
+        }
+    }
+}
+
+impl DeleteEffect {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, DeleteEffect::NotFound)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self, DeleteEffect::Ok)
+    }
+
+    pub fn is_effected(&self) -> bool {
+        self.is_ok()
+    }
+
+    pub fn ignore_effect(self) {}
+}
+
+pub trait ForeignEntitiesOperator<LocalId, C>
+where
+    C: ForeignContainer,
+    <C as ForeignContainer>::Item: ForeignEntity,
+    C: IntoIterator<Item = <C as ForeignContainer>::Item>,
+    for<'a> &'a C: IntoIterator<Item = &'a <C as ForeignContainer>::Item>,
+{
+    async fn save_foreign(
+        &mut self,
+        id: &LocalId,
+        foreign_entities: &ForeignEntities<C>,
+    ) -> anyhow::Result<()> {
+        match foreign_entities {
+            ForeignEntities::Unloaded => {}
+            ForeignEntities::Unchanged(_) => {}
+            ForeignEntities::Reset(reset) => {
+                self.clear_foreign(id).await?;
+                self.add_foreign(id, reset).await?;
+            }
+            ForeignEntities::Changed {
+                original: _,
+                add,
+                remove,
+            } => {
+                self.add_foreign(&id, add).await?;
+                self.remove_foreign(&id, remove).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn clear_foreign(&mut self, id: &LocalId) -> anyhow::Result<()>;
+
+    async fn remove_foreign(
+        &mut self,
+        id: &LocalId,
+        foreign_entities: &HashSet<<<C as ForeignContainer>::Item as ForeignEntity>::Id>,
+    ) -> anyhow::Result<()>;
+
+    async fn add_foreign<'a, F>(
+        &mut self,
+        id: &'a LocalId,
+        foreign_entities: F,
+    ) -> anyhow::Result<()>
+    where
+        F: IntoIterator<Item = &'a <C as ForeignContainer>::Item>,
+        <F as IntoIterator>::Item: 'a;
+}
+
+/// The type name of an entity without its module path
+pub(crate) fn entity_name<E: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<E>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Loads the foreign entities of local entities, as [`ForeignEntities::Unchanged`]
+pub trait ForeignEntitiesLoader<LocalId, C>
+where
+    C: ForeignContainer,
+    <C as ForeignContainer>::Item: ForeignEntity,
+{
+    async fn load_foreign(&mut self, id: &LocalId) -> anyhow::Result<ForeignEntities<C>>
+    where
+        LocalId: Clone + Eq + std::hash::Hash,
+    {
+        let mut loaded = self.load_foreign_batch(std::slice::from_ref(id)).await?;
+        Ok(loaded
+            .remove(id)
+            .unwrap_or(ForeignEntities::Unchanged(C::new())))
+    }
+
+    /// Every id is in the result, with an empty container if it has no foreign entities
+    async fn load_foreign_batch(
+        &mut self,
+        ids: &[LocalId],
+    ) -> anyhow::Result<HashMap<LocalId, ForeignEntities<C>>>;
+}
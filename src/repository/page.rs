@@ -0,0 +1,400 @@
+//! Keyset (cursor) pagination
+//!
+//! A page is read by ordering on the criteria's sort keys followed by the sys id, and by
+//! filtering on the keys of the last item of the previous page, which is carried in an opaque
+//! [`Cursor`]. Sort keys are expected to be non-null.
+//!
+//! A size deserialized from a request is clamped to [`max_page_size`], a size of zero is
+//! rejected.
+
+use std::{fmt::Display, str::FromStr, sync::OnceLock};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::entity::FieldEnum;
+
+use super::criteria::{Criteria, FieldAccess, FieldExpr, Predicate, SortDirection, Value};
+
+pub const DEFAULT_PAGE_SIZE: u32 = 20;
+pub const DEFAULT_MAX_PAGE_SIZE: u32 = 100;
+
+static MAX_PAGE_SIZE: OnceLock<u32> = OnceLock::new();
+
+/// Set the largest page size a client can request, once at startup
+pub fn init_max_page_size(max: u32) -> anyhow::Result<()> {
+    anyhow::ensure!(max > 0, "max page size must be positive");
+    MAX_PAGE_SIZE
+        .set(max)
+        .map_err(|_| anyhow::anyhow!("max page size is already set"))
+}
+
+/// The largest page size, [`DEFAULT_MAX_PAGE_SIZE`] unless set by [`init_max_page_size`]
+pub fn max_page_size() -> u32 {
+    MAX_PAGE_SIZE
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_PAGE_SIZE)
+}
+
+/// Opaque position of the last item of a page
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Cursor(String);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageRequest {
+    #[serde(default)]
+    pub cursor: Option<Cursor>,
+    #[serde(default = "default_page_size", deserialize_with = "de_page_size")]
+    pub size: u32,
+    #[serde(default, deserialize_with = "de_str_or_value")]
+    pub with_total: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+    pub has_more: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// Query parameters of a paginated endpoint
+///
+/// Both parts are flattened, so `?name=foo&size=10&cursor=xx` can be extracted by
+/// `HttpJsonQuery<PagedQuery<NameQuery>>`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PagedQuery<Q> {
+    #[serde(flatten)]
+    pub page: PageRequest,
+    #[serde(flatten)]
+    pub query: Q,
+}
+
+fn default_page_size() -> u32 {
+    DEFAULT_PAGE_SIZE
+}
+
+/// A positive size, clamped to [`max_page_size`]
+fn de_page_size<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let size: u32 = de_str_or_value(deserializer)?;
+    if size == 0 {
+        return Err(serde::de::Error::custom("page size must be positive"));
+    }
+
+    Ok(size.min(max_page_size()))
+}
+
+/// Query strings deliver every value as a string when the struct is flattened
+fn de_str_or_value<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    <T as FromStr>::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StrOrValue<T> {
+        Str(String),
+        Value(T),
+    }
+
+    match StrOrValue::<T>::deserialize(deserializer)? {
+        StrOrValue::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        StrOrValue::Value(v) => Ok(v),
+    }
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        Self {
+            cursor: None,
+            size: DEFAULT_PAGE_SIZE,
+            with_total: false,
+        }
+    }
+}
+
+impl PageRequest {
+    pub fn first(size: u32) -> Self {
+        Self {
+            cursor: None,
+            size,
+            with_total: false,
+        }
+    }
+
+    pub fn after(cursor: Cursor, size: u32) -> Self {
+        Self {
+            cursor: Some(cursor),
+            size,
+            with_total: false,
+        }
+    }
+
+    pub fn with_total(mut self) -> Self {
+        self.with_total = true;
+        self
+    }
+}
+
+impl Cursor {
+    fn encode(keys: &[Value]) -> anyhow::Result<Self> {
+        let json = serde_json::to_vec(keys).context("failed to encode cursor")?;
+        Ok(Self(URL_SAFE_NO_PAD.encode(json)))
+    }
+
+    fn decode(&self) -> anyhow::Result<Vec<Value>> {
+        let json = URL_SAFE_NO_PAD
+            .decode(&self.0)
+            .context("invalid cursor encoding")?;
+        let keys = serde_json::from_slice(&json).context("invalid cursor content")?;
+        Ok(keys)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Cursor {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Page<T> {
+    pub fn empty() -> Self {
+        Self {
+            items: vec![],
+            next_cursor: None,
+            has_more: false,
+            total: None,
+        }
+    }
+
+    pub fn map<U, F>(self, f: F) -> Page<U>
+    where
+        F: FnMut(T) -> U,
+    {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+            has_more: self.has_more,
+            total: self.total,
+        }
+    }
+
+    pub fn with_total(mut self, total: u64) -> Self {
+        self.total = Some(total);
+        self
+    }
+}
+
+/// Keyset pagination over a criteria
+pub struct Keyset<F> {
+    criteria: Criteria<F>,
+    size: u32,
+}
+
+impl<F> Keyset<F>
+where
+    F: FieldEnum,
+{
+    /// Build the criteria of the requested page.
+    ///
+    /// The sys id is appended as the last sort key so the order is total, offset is dropped
+    /// and one extra row is requested to tell whether there are more pages.
+    pub fn new(mut criteria: Criteria<F>, page: &PageRequest) -> anyhow::Result<Self> {
+        anyhow::ensure!(page.size > 0, "page size must be positive");
+        let size = page.size;
+
+        let sys_id = F::sys_id();
+        if !criteria.order.iter().any(|o| o.field == sys_id) {
+            criteria = criteria.order_by_asc(sys_id);
+        }
+        criteria.offset = None;
+        criteria.limit = Some(size as u64 + 1);
+
+        if let Some(cursor) = &page.cursor {
+            let keys = cursor.decode()?;
+            anyhow::ensure!(
+                keys.len() == criteria.order.len(),
+                "cursor does not match the sort order"
+            );
+            let after = Self::after_predicate(&criteria, keys);
+            criteria = criteria.filter(after);
+        }
+
+        Ok(Self { criteria, size })
+    }
+
+    /// `(k1 > v1) OR (k1 = v1 AND k2 > v2) OR ...`, with `<` for descending keys
+    fn after_predicate(criteria: &Criteria<F>, keys: Vec<Value>) -> Predicate<F> {
+        let mut alternatives = vec![];
+        for (idx, (order, key)) in criteria.order.iter().zip(keys.iter()).enumerate() {
+            let mut all = criteria.order[..idx]
+                .iter()
+                .zip(keys.iter())
+                .map(|(o, k)| o.field.eq(k.clone()))
+                .collect::<Vec<_>>();
+            let next = match order.direction {
+                SortDirection::Asc => order.field.gt(key.clone()),
+                SortDirection::Desc => order.field.lt(key.clone()),
+            };
+            all.push(next);
+            alternatives.push(Predicate::And(all));
+        }
+
+        Predicate::Or(alternatives)
+    }
+
+    pub fn criteria(&self) -> &Criteria<F> {
+        &self.criteria
+    }
+
+    pub fn into_criteria(self) -> Criteria<F> {
+        self.criteria
+    }
+
+    /// Turn the fetched rows into a page
+    pub fn page<T>(&self, mut items: Vec<T>) -> anyhow::Result<Page<T>>
+    where
+        T: FieldAccess<F>,
+    {
+        let has_more = items.len() > self.size as usize;
+        items.truncate(self.size as usize);
+
+        let next_cursor = match items.last() {
+            Some(last) if has_more => {
+                let keys = self
+                    .criteria
+                    .order
+                    .iter()
+                    .map(|o| last.field_value(o.field))
+                    .collect::<Vec<_>>();
+                Some(Cursor::encode(&keys)?)
+            }
+            _ => None,
+        };
+
+        Ok(Page {
+            items,
+            next_cursor,
+            has_more,
+            total: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    enum PostField {
+        Id,
+        Score,
+    }
+
+    impl FieldEnum for PostField {
+        fn field_name(self) -> &'static str {
+            match self {
+                PostField::Id => "id",
+                PostField::Score => "score",
+            }
+        }
+
+        fn sys_id() -> Self {
+            PostField::Id
+        }
+
+        fn all() -> &'static [Self] {
+            &[PostField::Id, PostField::Score]
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Post {
+        id: i64,
+        score: i64,
+    }
+
+    impl FieldAccess<PostField> for Post {
+        fn field_value(&self, field: PostField) -> Value {
+            match field {
+                PostField::Id => self.id.into(),
+                PostField::Score => self.score.into(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_keyset_pages() -> anyhow::Result<()> {
+        let posts = (1..=7)
+            .map(|id| Post { id, score: id % 3 })
+            .collect::<Vec<_>>();
+        let criteria = Criteria::new().order_by_desc(PostField::Score);
+
+        let mut page = PageRequest::first(3);
+        let mut seen = vec![];
+        loop {
+            let keyset = Keyset::new(criteria.clone(), &page)?;
+            let rows = keyset.criteria().apply(posts.clone());
+            let result = keyset.page(rows)?;
+            seen.extend(result.items.iter().map(|p| p.id));
+
+            match result.next_cursor {
+                Some(cursor) => page = PageRequest::after(cursor, 3),
+                None => {
+                    assert!(!result.has_more);
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(seen, vec![2, 5, 1, 4, 7, 3, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_request_from_query() {
+        #[derive(Deserialize)]
+        struct NameQuery {
+            name: String,
+        }
+
+        let query: PagedQuery<NameQuery> =
+            serde_urlencoded::from_str("name=a&size=5&withTotal=true").unwrap();
+        assert_eq!(query.query.name, "a");
+        assert_eq!(query.page.size, 5);
+        assert!(query.page.with_total);
+        assert_eq!(query.page.cursor, None);
+
+        let query: PagedQuery<NameQuery> =
+            serde_urlencoded::from_str("name=a&cursor=WzFd").unwrap();
+        assert_eq!(query.page.size, DEFAULT_PAGE_SIZE);
+        assert_eq!(query.page.cursor, Some(Cursor::from("WzFd".to_string())));
+
+        let query: PagedQuery<NameQuery> =
+            serde_urlencoded::from_str("name=a&size=100000").unwrap();
+        assert_eq!(query.page.size, max_page_size());
+
+        assert!(serde_urlencoded::from_str::<PagedQuery<NameQuery>>("name=a&size=0").is_err());
+        assert!(serde_urlencoded::from_str::<PagedQuery<NameQuery>>("name=a&size=-1").is_err());
+    }
+
+    #[test]
+    fn test_zero_page_size() {
+        let criteria = Criteria::<PostField>::new();
+        assert!(Keyset::new(criteria, &PageRequest::first(0)).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Seed data from JSON or YAML documents
+//!
+//! A document maps a kind of entity to its named records, a record is the `Model` of the
+//! entity. A string `"@kind.name"` anywhere in a record refers to another record and is
+//! replaced by its sys id, `"@@"` at the start of a string escapes a literal `@`.
+//!
+//! ```yaml
+//! user:
+//!   alice: { name: Alice }
+//! post:
+//!   hello: { title: Hello, author: "@user.alice" }
+//! ```
+//!
+//! [`Seeder::seed`] builds each record into an entity, which generates its sys id, and saves
+//! it by the repository registered for its kind. A record is saved after the records it
+//! refers to, whatever their order in the document.
+//!
+//! ```rust,ignore
+//! let mut seeder = Seeder::new()
+//!     .register::<UserModel, _>("user", txn.provide::<UserRepo>()?)
+//!     .register::<PostModel, _>("post", txn.provide::<PostRepo>()?);
+//! let refs = seeder.seed(&SeedDocument::from_yaml(include_str!("demo.yaml"))?).await?;
+//! let alice: UserId = refs.get("user.alice")?;
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+};
+
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::entity::{model::Model, Entity};
+
+use super::{Repository, SaveEffect};
+
+const REF_PREFIX: char = '@';
+
+/// Records by name by kind, in the order of the document
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SeedDocument(IndexMap<String, IndexMap<String, Value>>);
+
+impl SeedDocument {
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).context("Failed to parse the seed document")
+    }
+
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        serde_yaml::from_str(yaml).context("Failed to parse the seed document")
+    }
+
+    /// Add the records of `other`, a record of the same kind and name is replaced
+    pub fn merge(&mut self, other: SeedDocument) {
+        for (kind, records) in other.0 {
+            self.0.entry(kind).or_default().extend(records);
+        }
+    }
+
+    fn records(&self) -> impl Iterator<Item = (RecordRef, &Value)> {
+        self.0.iter().flat_map(|(kind, records)| {
+            records.iter().map(move |(name, record)| {
+                let record_ref = RecordRef {
+                    kind: kind.clone(),
+                    name: name.clone(),
+                };
+                (record_ref, record)
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RecordRef {
+    kind: String,
+    name: String,
+}
+
+impl RecordRef {
+    fn parse(reference: &str) -> Result<Self> {
+        let Some((kind, name)) = reference.split_once('.') else {
+            bail!("Invalid seed reference `@{reference}`, expected `@kind.name`");
+        };
+
+        Ok(Self {
+            kind: kind.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for RecordRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.kind, self.name)
+    }
+}
+
+/// Sys ids of the seeded records
+#[derive(Debug, Clone, Default)]
+pub struct SeedRefs(HashMap<RecordRef, Value>);
+
+impl SeedRefs {
+    /// The sys id of the record `kind.name`
+    pub fn get<I>(&self, reference: &str) -> Result<I>
+    where
+        I: DeserializeOwned,
+    {
+        let record_ref = RecordRef::parse(reference)?;
+        let id = self
+            .0
+            .get(&record_ref)
+            .with_context(|| format!("Record {record_ref} has not been seeded"))?;
+
+        Ok(serde_json::from_value(id.clone())?)
+    }
+}
+
+type SaveFuture<'a> = Pin<Box<dyn Future<Output = Result<Value>> + 'a>>;
+
+/// Saves records of a kind, returning the sys id
+trait SeedKind {
+    fn save(&mut self, record: Value) -> SaveFuture<'_>;
+}
+
+struct Registered<M, R> {
+    repo: R,
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M, R> SeedKind for Registered<M, R>
+where
+    M: Model + DeserializeOwned,
+    <M::Entity as Entity>::SysId: Serialize,
+    R: Repository<M::Entity>,
+{
+    fn save(&mut self, record: Value) -> SaveFuture<'_> {
+        Box::pin(async move {
+            let model: M = serde_json::from_value(record)?;
+            let entity = model.build_entity();
+            match self.repo.save(&entity).await? {
+                SaveEffect::Ok => Ok(serde_json::to_value(entity.sys_id())?),
+                SaveEffect::Conflict(target) => bail!("Conflicts on {target:?}"),
+            }
+        })
+    }
+}
+
+/// Saves seed documents by the repositories registered for their kinds
+#[derive(Default)]
+pub struct Seeder {
+    kinds: HashMap<String, Box<dyn SeedKind>>,
+    refs: SeedRefs,
+}
+
+impl Seeder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Save the records of `kind` as `M`, by `repo`
+    pub fn register<M, R>(mut self, kind: &str, repo: R) -> Self
+    where
+        M: Model + DeserializeOwned + 'static,
+        <M::Entity as Entity>::SysId: Serialize,
+        R: Repository<M::Entity> + 'static,
+    {
+        let registered = Registered::<M, R> {
+            repo,
+            _model: PhantomData,
+        };
+        self.kinds.insert(kind.to_string(), Box::new(registered));
+        self
+    }
+
+    /// The records seeded so far, references to them resolve in later documents
+    pub fn refs(&self) -> &SeedRefs {
+        &self.refs
+    }
+
+    /// Save the records of `document` in dependency order
+    pub async fn seed(&mut self, document: &SeedDocument) -> Result<SeedRefs> {
+        let mut pending = Vec::new();
+        for (record_ref, record) in document.records() {
+            if !self.kinds.contains_key(&record_ref.kind) {
+                bail!(
+                    "No repository is registered for seed kind `{}`",
+                    record_ref.kind
+                );
+            }
+            let mut deps = HashSet::new();
+            collect_refs(record, &mut deps)
+                .with_context(|| format!("Invalid seed record {record_ref}"))?;
+            pending.push((record_ref, record, deps));
+        }
+
+        let in_document: HashSet<_> = pending.iter().map(|(r, ..)| r.clone()).collect();
+        for (record_ref, _, deps) in &pending {
+            if let Some(missing) = deps
+                .iter()
+                .find(|dep| !in_document.contains(dep) && !self.refs.0.contains_key(dep))
+            {
+                bail!("Seed record {record_ref} refers to unknown record {missing}");
+            }
+        }
+
+        let mut seeded = SeedRefs::default();
+        while !pending.is_empty() {
+            let ready = pending
+                .iter()
+                .position(|(_, _, deps)| deps.iter().all(|dep| self.refs.0.contains_key(dep)));
+            let Some(ready) = ready else {
+                let cycle = pending
+                    .iter()
+                    .map(|(r, ..)| r.to_string())
+                    .collect::<Vec<_>>();
+                bail!("Seed records refer to each other: {}", cycle.join(", "));
+            };
+
+            let (record_ref, record, _) = pending.remove(ready);
+            let record = resolve_refs(record, &self.refs)?;
+            let kind = self.kinds.get_mut(&record_ref.kind).unwrap();
+            let id = kind
+                .save(record)
+                .await
+                .with_context(|| format!("Failed to seed record {record_ref}"))?;
+
+            self.refs.0.insert(record_ref.clone(), id.clone());
+            seeded.0.insert(record_ref, id);
+        }
+
+        Ok(seeded)
+    }
+}
+
+fn parse_ref(s: &str) -> Option<&str> {
+    s.strip_prefix(REF_PREFIX)
+        .filter(|rest| !rest.starts_with(REF_PREFIX))
+}
+
+fn collect_refs(value: &Value, refs: &mut HashSet<RecordRef>) -> Result<()> {
+    match value {
+        Value::String(s) => {
+            if let Some(reference) = parse_ref(s) {
+                refs.insert(RecordRef::parse(reference)?);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_refs(value, refs)?;
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values() {
+                collect_refs(value, refs)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn resolve_refs(value: &Value, refs: &SeedRefs) -> Result<Value> {
+    Ok(match value {
+        Value::String(s) => match parse_ref(s) {
+            Some(reference) => {
+                let record_ref = RecordRef::parse(reference)?;
+                refs.0.get(&record_ref).cloned().unwrap()
+            }
+            // An escaped `@`
+            None if s.starts_with(REF_PREFIX) => Value::String(s[1..].to_string()),
+            None => value.clone(),
+        },
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| resolve_refs(value, refs))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| Ok((key.clone(), resolve_refs(value, refs)?)))
+                .collect::<Result<_>>()?,
+        ),
+        _ => value.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_refs() {
+        let record = json!({
+            "title": "@@home",
+            "author": "@user.alice",
+            "tags": ["a", "@tag.rust"],
+        });
+        let mut deps = HashSet::new();
+        collect_refs(&record, &mut deps).unwrap();
+        assert_eq!(deps.len(), 2);
+        assert!(deps.contains(&RecordRef::parse("user.alice").unwrap()));
+
+        let refs = SeedRefs(
+            [
+                (RecordRef::parse("user.alice").unwrap(), json!(1)),
+                (RecordRef::parse("tag.rust").unwrap(), json!(7)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let record = resolve_refs(&record, &refs).unwrap();
+        assert_eq!(
+            record,
+            json!({ "title": "@home", "author": 1, "tags": ["a", 7] })
+        );
+        assert_eq!(refs.get::<i64>("tag.rust").unwrap(), 7);
+
+        assert!(collect_refs(&json!("@alice"), &mut deps).is_err());
+    }
+}
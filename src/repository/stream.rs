@@ -0,0 +1,143 @@
+//! Streaming batch reads
+//!
+//! [`read_batch_stream`] reads the rows matched by a criteria in keyset chunks (see
+//! [`Keyset`]), so a scan over millions of rows keeps at most one chunk in memory. The next
+//! chunk is only read when the consumer has drained the previous one, which gives
+//! back-pressure for free.
+//!
+//! The stream borrows the reader, so inside a transaction every chunk is read on the
+//! transaction's connection.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let criteria = Criteria::new().filter(UserFieldEnum::Age.ge(18));
+//! let mut users = pin!(repo.read_stream::<UserFull>(criteria, Some(500)));
+//! while let Some(user) = users.try_next().await? {
+//!     export(user)?;
+//! }
+//! ```
+
+use std::collections::VecDeque;
+
+use futures::Stream;
+
+use crate::entity::{subset::Subset, FieldEnum};
+
+use super::{
+    criteria::{Criteria, FieldAccess},
+    page::{Cursor, Keyset, PageRequest},
+    BatchSubsetReader,
+};
+
+pub const DEFAULT_CHUNK_SIZE: u32 = 1000;
+
+/// Streaming variant of [`BatchSubsetReader::read_batch`] over a criteria.
+///
+/// The criteria's limit caps the number of items of the stream, offset is not supported.
+/// Chunks have `chunk_size` rows, [`DEFAULT_CHUNK_SIZE`] if not given.
+pub fn read_batch_stream<'r, R, S, F>(
+    reader: &'r mut R,
+    criteria: Criteria<F>,
+    chunk_size: Option<u32>,
+) -> impl Stream<Item = anyhow::Result<S>> + 'r
+where
+    R: BatchSubsetReader<Criteria<F>, S>,
+    S: Subset + FieldAccess<F> + 'r,
+    F: FieldEnum,
+{
+    keyset_stream(reader, criteria, chunk_size, |subset| subset)
+}
+
+/// Read chunks by `reader`, mapping each subset before it is used as a keyset
+pub(crate) fn keyset_stream<'r, R, S, T, F>(
+    reader: &'r mut R,
+    criteria: Criteria<F>,
+    chunk_size: Option<u32>,
+    map: fn(S) -> T,
+) -> impl Stream<Item = anyhow::Result<T>> + 'r
+where
+    R: BatchSubsetReader<Criteria<F>, S>,
+    S: Subset + 'r,
+    T: FieldAccess<F> + 'r,
+    F: FieldEnum,
+{
+    let state = KeysetState {
+        reader,
+        remaining: criteria.limit,
+        criteria,
+        chunk_size: chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1),
+        cursor: None,
+        buffer: VecDeque::new(),
+        done: false,
+        map,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if state.remaining == Some(0) {
+                return None;
+            }
+
+            if let Some(item) = state.buffer.pop_front() {
+                state.remaining = state.remaining.map(|remaining| remaining - 1);
+                return Some((Ok(item), state));
+            }
+
+            if state.done {
+                return None;
+            }
+
+            if let Err(err) = state.next_chunk().await {
+                state.done = true;
+                return Some((Err(err), state));
+            }
+        }
+    })
+}
+
+struct KeysetState<'r, R, S, T, F> {
+    reader: &'r mut R,
+    criteria: Criteria<F>,
+    chunk_size: u32,
+    /// Items left to yield if the criteria has a limit
+    remaining: Option<u64>,
+    cursor: Option<Cursor>,
+    buffer: VecDeque<T>,
+    done: bool,
+    map: fn(S) -> T,
+}
+
+impl<R, S, T, F> KeysetState<'_, R, S, T, F>
+where
+    R: BatchSubsetReader<Criteria<F>, S>,
+    S: Subset,
+    T: FieldAccess<F>,
+    F: FieldEnum,
+{
+    async fn next_chunk(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.criteria.offset.is_none(),
+            "offset is not supported by streaming reads"
+        );
+
+        let size = match self.remaining {
+            Some(remaining) => remaining.min(self.chunk_size as u64) as u32,
+            None => self.chunk_size,
+        };
+        let page = PageRequest {
+            cursor: self.cursor.take(),
+            size,
+            with_total: false,
+        };
+        let keyset = Keyset::new(self.criteria.clone(), &page)?;
+        let rows = self.reader.read_batch(keyset.criteria().clone()).await?;
+        let page = keyset.page(rows.into_iter().map(self.map).collect())?;
+
+        self.done = page.next_cursor.is_none();
+        self.cursor = page.next_cursor;
+        self.buffer.extend(page.items);
+
+        Ok(())
+    }
+}
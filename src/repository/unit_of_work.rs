@@ -0,0 +1,491 @@
+//! Unit of Work
+//!
+//! Entities loaded through a [`UnitOfWork`] are tracked until the end of the transaction.
+//! When the transaction is about to commit, every tracked entity with pending changes is
+//! flushed by [`Repository::update`]. Flushing follows the declared dependencies between
+//! entity types, and any effect other than `Ok` aborts the transaction.
+//!
+//! `UnitOfWork<Tx>` is a [`TxnManager`] itself, so it can replace the transaction manager of a
+//! [`TxnUseCase`](crate::usecase::TxnUseCase). It is a singleton within a [`ProviderContext`],
+//! so the use case can ask for the same instance to load its entities.
+//!
+//! Only the outermost transaction flushes. A transaction nested in it, such as the one of a
+//! use case called by another use case, keeps its entities tracked for the outermost commit,
+//! and drops the ones it tracked if it is rolled back.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! type Txn = UnitOfWork<TxnManagerDiesel<DbAdapter>>;
+//!
+//! #[derive(Provider)]
+//! struct RenameUser {
+//!     uow: Txn,
+//!     repo: UserRepo,
+//! }
+//!
+//! impl UseCase for RenameUser {
+//!     async fn execute(&mut self, input: Self::Input) -> BizResult<Self::Output, Self::Error> {
+//!         let user = ensure_exist!(
+//!             self.uow.find::<UserSubset, _, _>(&mut self.repo, input.id).await?,
+//!             Error::NotFound
+//!         );
+//!         user.lock().await.name.set(input.name);
+//!
+//!         // No need to call `update`, the user is flushed before commit
+//!         biz_ok!(())
+//!     }
+//! }
+//!
+//! let uc: TxnUseCase<Txn, RenameUser> = TxnUseCase::provide()?;
+//! ```
+
+use std::{any::TypeId, cell::RefCell, collections::HashMap, fmt::Debug, rc::Rc, sync::Arc};
+
+use futures::{
+    future::LocalBoxFuture,
+    lock::{Mutex, MutexGuard},
+};
+
+use crate::{
+    db::{AsyncTxCallback, BeforeCommitHook, TxCallback, TxnManager, TxnOptions, TxnState},
+    entity::{subset::Subset, ChildEntity, Entity},
+    provider::{Provider, ProviderContext, SingletonProvider},
+    result::BizResult,
+};
+
+use super::{identity_map::WithIdentityMap, BatchSubsetLoader, Repository, SubsetLoader};
+
+pub struct UnitOfWork<Tx> {
+    txn: Tx,
+    state: Rc<RefCell<UowState>>,
+}
+
+/// Shared handle of an entity tracked by a [`UnitOfWork`]
+pub struct Tracked<E> {
+    entity: Arc<Mutex<E>>,
+}
+
+#[derive(Default)]
+struct UowState {
+    entries: Vec<Box<dyn PendingFlush>>,
+    /// Entity type => types which must be flushed before it
+    dependencies: HashMap<TypeId, Vec<TypeId>>,
+}
+
+trait PendingFlush {
+    fn entity_type(&self) -> TypeId;
+
+    /// Address of the tracked instance
+    fn entity_ptr(&self) -> *const ();
+
+    fn flush(self: Box<Self>) -> LocalBoxFuture<'static, anyhow::Result<()>>;
+}
+
+struct TrackedEntry<E, R> {
+    entity: Tracked<E>,
+    repo: R,
+}
+
+impl<Tx> Clone for UnitOfWork<Tx>
+where
+    Tx: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            txn: self.txn.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<Tx> Provider for UnitOfWork<Tx>
+where
+    Tx: Provider + Clone,
+{
+    fn build(ctx: &mut ProviderContext) -> anyhow::Result<Self> {
+        if let Some(this) = ctx.get::<Self>() {
+            return Ok(this.clone());
+        }
+
+        let this = Self::new(Tx::build(ctx)?);
+        ctx.insert(this.clone());
+
+        Ok(this)
+    }
+}
+
+impl<Tx> SingletonProvider for UnitOfWork<Tx> where Tx: Provider + Clone {}
+
+impl<E> Clone for Tracked<E> {
+    fn clone(&self) -> Self {
+        Self {
+            entity: self.entity.clone(),
+        }
+    }
+}
+
+impl<E> Tracked<E>
+where
+    E: Send,
+{
+    pub(crate) fn new(entity: E) -> Self {
+        Self {
+            entity: Arc::new(Mutex::new(entity)),
+        }
+    }
+}
+
+impl<E> Tracked<E> {
+    pub async fn lock(&self) -> MutexGuard<'_, E> {
+        self.entity.lock().await
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, E>> {
+        self.entity.try_lock()
+    }
+
+    /// Whether both handles point to the same instance
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.entity, &other.entity)
+    }
+}
+
+impl<Tx> UnitOfWork<Tx> {
+    pub fn new(txn: Tx) -> Self {
+        Self {
+            txn,
+            state: Rc::new(RefCell::new(UowState::default())),
+        }
+    }
+
+    /// Track an entity, it is flushed by `repo` before commit if it has pending changes
+    pub fn track<E, R>(&self, repo: &R, entity: E) -> Tracked<E>
+    where
+        E: Entity + Send + 'static,
+        R: Repository<E> + Clone + 'static,
+    {
+        let tracked = Tracked::new(entity);
+        self.track_handle(repo, &tracked);
+
+        tracked
+    }
+
+    /// Track a shared handle, such as one from the identity map. A handle already tracked is
+    /// not tracked twice.
+    pub fn track_handle<E, R>(&self, repo: &R, tracked: &Tracked<E>)
+    where
+        E: Entity + 'static,
+        R: Repository<E> + Clone + 'static,
+    {
+        let mut state = self.state.borrow_mut();
+        let ptr = Arc::as_ptr(&tracked.entity) as *const ();
+        if state.entries.iter().any(|entry| entry.entity_ptr() == ptr) {
+            return;
+        }
+
+        let entry = TrackedEntry {
+            entity: tracked.clone(),
+            repo: repo.clone(),
+        };
+        state.entries.push(Box::new(entry));
+    }
+
+    /// Load an entity by [`Repository::find_tracked`], which returns the instance of the
+    /// identity map, and track it
+    pub async fn find_tracked<S, I, R>(
+        &self,
+        repo: &mut R,
+        id: I,
+    ) -> anyhow::Result<Option<Tracked<S::Entity>>>
+    where
+        S: Subset,
+        S::Entity: Send + 'static,
+        <S::Entity as Entity>::SysId: Send + 'static,
+        R: Repository<S::Entity> + SubsetLoader<S> + WithIdentityMap + Clone + 'static,
+        I: Clone,
+        for<'a> <S::Entity as Entity>::Id<'a>: From<I>,
+    {
+        let tracked = repo.find_tracked::<S, I>(id).await?;
+        if let Some(tracked) = &tracked {
+            self.track_handle(repo, tracked);
+        }
+
+        Ok(tracked)
+    }
+
+    /// Load an entity by [`Repository::find`] and track it
+    pub async fn find<S, I, R>(
+        &self,
+        repo: &mut R,
+        id: I,
+    ) -> anyhow::Result<Option<Tracked<S::Entity>>>
+    where
+        S: Subset,
+        S::Entity: Send + 'static,
+        R: Repository<S::Entity> + SubsetLoader<S> + Clone + 'static,
+        for<'a> <S::Entity as Entity>::Id<'a>: From<I>,
+    {
+        let entity = repo.find::<S, I>(id).await?;
+        Ok(entity.map(|e| self.track(repo, e)))
+    }
+
+    /// Load entities by [`Repository::find_batch`] and track them
+    pub async fn find_batch<S, C, R>(
+        &self,
+        repo: &mut R,
+        condition: C,
+    ) -> anyhow::Result<Vec<Tracked<S::Entity>>>
+    where
+        S: Subset,
+        S::Entity: Send + 'static,
+        R: Repository<S::Entity> + BatchSubsetLoader<C, S> + Clone + 'static,
+    {
+        let entities = repo.find_batch::<S, C>(condition).await?;
+        Ok(entities.into_iter().map(|e| self.track(repo, e)).collect())
+    }
+
+    /// Declare that entities of type `E` must be flushed after entities of type `Dep`
+    pub fn depends_on<E, Dep>(&self)
+    where
+        E: Entity + 'static,
+        Dep: Entity + 'static,
+    {
+        let mut state = self.state.borrow_mut();
+        let deps = state.dependencies.entry(TypeId::of::<E>()).or_default();
+        if !deps.contains(&TypeId::of::<Dep>()) {
+            deps.push(TypeId::of::<Dep>());
+        }
+    }
+
+    /// Declare that child entities must be flushed after their parent
+    pub fn depends_on_parent<E>(&self)
+    where
+        E: ChildEntity + 'static,
+        E::Parent: 'static,
+    {
+        self.depends_on::<E, E::Parent>();
+    }
+
+    /// Number of tracked entities
+    pub fn len(&self) -> usize {
+        self.state.borrow().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stop tracking all entities without flushing them
+    pub fn clear(&self) {
+        self.state.borrow_mut().entries.clear();
+    }
+
+    /// Stop tracking the entities tracked by a nested transaction which was rolled back
+    fn drop_rolled_back<T, E>(&self, res: &BizResult<T, E>, tracked: usize) {
+        if !matches!(res, Ok(Ok(_))) {
+            self.state.borrow_mut().entries.truncate(tracked);
+        }
+    }
+
+    /// Flush all tracked entities with pending changes in dependency order
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let entries = {
+            let mut state = self.state.borrow_mut();
+            let entries = std::mem::take(&mut state.entries);
+            flush_order(entries, &state.dependencies)?
+        };
+
+        for entry in entries {
+            entry.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sort entries by their entity types so that dependencies come first.
+///
+/// Entries of the same type keep their tracking order.
+fn flush_order(
+    entries: Vec<Box<dyn PendingFlush>>,
+    dependencies: &HashMap<TypeId, Vec<TypeId>>,
+) -> anyhow::Result<Vec<Box<dyn PendingFlush>>> {
+    let mut types = vec![];
+    let mut groups: HashMap<TypeId, Vec<Box<dyn PendingFlush>>> = HashMap::new();
+    for entry in entries {
+        let ty = entry.entity_type();
+        if !groups.contains_key(&ty) {
+            types.push(ty);
+        }
+        groups.entry(ty).or_default().push(entry);
+    }
+
+    let mut ordered = Vec::with_capacity(groups.len());
+    while !types.is_empty() {
+        let ready = types.iter().position(|ty| {
+            dependencies
+                .get(ty)
+                .map(|deps| deps.iter().all(|dep| !types.contains(dep) || dep == ty))
+                .unwrap_or(true)
+        });
+        let Some(ready) = ready else {
+            anyhow::bail!("cyclic dependencies between tracked entity types");
+        };
+
+        let ty = types.remove(ready);
+        ordered.extend(groups.remove(&ty).unwrap_or_default());
+    }
+
+    Ok(ordered)
+}
+
+impl<E, R> PendingFlush for TrackedEntry<E, R>
+where
+    E: Entity + 'static,
+    R: Repository<E> + 'static,
+{
+    fn entity_type(&self) -> TypeId {
+        TypeId::of::<E>()
+    }
+
+    fn entity_ptr(&self) -> *const () {
+        Arc::as_ptr(&self.entity.entity) as *const ()
+    }
+
+    fn flush(self: Box<Self>) -> LocalBoxFuture<'static, anyhow::Result<()>> {
+        Box::pin(async move {
+            let TrackedEntry { entity, mut repo } = *self;
+            let entity = entity.lock().await;
+            if !entity.has_changes() {
+                return Ok(());
+            }
+
+            let effect = repo.update(&entity).await?;
+            if !effect.is_ok() {
+                anyhow::bail!(
+                    "failed to flush entity `{}`: {:?}",
+                    std::any::type_name::<E>(),
+                    effect
+                );
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl<Tx> TxnManager for UnitOfWork<Tx>
+where
+    Tx: TxnManager,
+{
+    async fn do_transaction<F, T, E>(&mut self, tx: F) -> BizResult<T, E>
+    where
+        F: std::future::Future<Output = BizResult<T, E>>,
+    {
+        if self.txn.state().depth() > 0 {
+            let tracked = self.len();
+            let res = self.txn.do_transaction(tx).await;
+            self.drop_rolled_back(&res, tracked);
+            return res;
+        }
+
+        let this = self.clone();
+        let res = self
+            .txn
+            .do_transaction(async move {
+                let value = match tx.await {
+                    Ok(Ok(value)) => value,
+                    other => return other,
+                };
+
+                // A flush error is a system error, which rolls back the transaction
+                this.flush().await?;
+                Ok(Ok(value))
+            })
+            .await;
+
+        self.clear();
+
+        res
+    }
+
+    async fn do_transaction_retry<F, T, E>(&mut self, mut tx: F) -> BizResult<T, E>
+    where
+        F: AsyncFnMut() -> BizResult<T, E>,
+    {
+        if self.txn.state().depth() > 0 {
+            let tracked = self.len();
+            let res = self.txn.do_transaction_retry(tx).await;
+            self.drop_rolled_back(&res, tracked);
+            return res;
+        }
+
+        let this = self.clone();
+        let mut retry = false;
+        let res = self
+            .txn
+            .do_transaction_retry(async || {
+                // Entities tracked by a failed attempt were rolled back with it
+                if retry {
+                    this.clear();
+                }
+                retry = true;
+
+                let value = match tx().await {
+                    Ok(Ok(value)) => value,
+                    other => return other,
+                };
+
+                this.flush().await?;
+                Ok(Ok(value))
+            })
+            .await;
+
+        self.clear();
+
+        res
+    }
+
+    fn register_callback<H>(&self, callback: H)
+    where
+        H: TxCallback,
+    {
+        self.txn.register_callback(callback);
+    }
+
+    fn register_async_callback<H>(&self, callback: H)
+    where
+        H: AsyncTxCallback,
+    {
+        self.txn.register_async_callback(callback);
+    }
+
+    fn register_before_commit<H>(&self, hook: H)
+    where
+        H: BeforeCommitHook,
+    {
+        self.txn.register_before_commit(hook);
+    }
+
+    fn state(&self) -> TxnState {
+        self.txn.state()
+    }
+
+    fn with_txn_options(mut self, options: TxnOptions) -> Self {
+        self.txn = self.txn.with_txn_options(options);
+        self
+    }
+}
+
+impl<E> Debug for Tracked<E>
+where
+    E: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.entity.try_lock() {
+            Some(entity) => f.debug_tuple("Tracked").field(&*entity).finish(),
+            None => f.debug_tuple("Tracked").field(&"<locked>").finish(),
+        }
+    }
+}
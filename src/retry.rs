@@ -0,0 +1,140 @@
+//! Retry policies with exponential backoff
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::Duration,
+};
+
+/// Exponential backoff: `initial * multiplier ^ (attempt - 1)`, capped by `max`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    /// Randomize each delay between half and the whole of it, so retries of many callers
+    /// don't happen in lockstep
+    pub jitter: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn without_jitter(mut self) -> Self {
+        self.jitter = false;
+        self
+    }
+
+    /// Delay before the retry following the `attempt`th attempt, starting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial.as_secs_f64() * self.multiplier.max(1.0).powi(exp);
+        let delay = delay.min(self.max.as_secs_f64());
+        let delay = if self.jitter {
+            delay * (0.5 + random_unit() * 0.5)
+        } else {
+            delay
+        };
+
+        Duration::from_secs_f64(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, backoff: Backoff) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+        }
+    }
+
+    /// Never retry
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// Delay before the next attempt, `None` if `attempts` attempts have exhausted the policy
+    pub fn next_delay(&self, attempts: u32) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+
+        Some(self.backoff.delay(attempts))
+    }
+}
+
+/// A random number in `[0, 1)`, good enough for jitter
+fn random_unit() -> f64 {
+    let random = RandomState::new().hash_one(std::time::Instant::now());
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).without_jitter();
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(400));
+        assert_eq!(backoff.delay(10), Duration::from_secs(1));
+
+        let backoff = backoff.with_multiplier(3.0);
+        assert_eq!(backoff.delay(2), Duration::from_millis(300));
+
+        let jittered = Backoff::new(Duration::from_secs(1), Duration::from_secs(1));
+        let delay = jittered.delay(1);
+        assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new(3, Backoff::default().without_jitter());
+        assert_eq!(policy.next_delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.next_delay(2), Some(Duration::from_millis(200)));
+        assert_eq!(policy.next_delay(3), None);
+        assert_eq!(RetryPolicy::none().next_delay(1), None);
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{db::TxnManager, provider::Provider, result::BizResult};
+use crate::{
+    db::{TxnManager, TxnOptions},
+    provider::Provider,
+    result::BizResult,
+};
 
 pub trait UseCase {
     type Input;
@@ -7,6 +11,8 @@ pub trait UseCase {
 
     async fn execute(&mut self, input: Self::Input) -> BizResult<Self::Output, Self::Error>;
 
+    /// Run in a transaction of `txn`, which may carry options set by
+    /// [`TxnManager::with_txn_options`]
     async fn execute_in_txn<Txn>(
         &mut self,
         mut txn: Txn,
@@ -17,11 +23,69 @@ pub trait UseCase {
     {
         txn.do_transaction(self.execute(input)).await
     }
+
+    /// Same as [`UseCase::execute_in_txn`], but the use case is executed again with a clone
+    /// of `input` while the transaction fails with a retryable error, as far as the retry
+    /// policy of `txn` allows, see [`TxnManager::do_transaction_retry`]
+    async fn execute_in_txn_retry<Txn>(
+        &mut self,
+        mut txn: Txn,
+        input: Self::Input,
+    ) -> BizResult<Self::Output, Self::Error>
+    where
+        Txn: TxnManager,
+        Self::Input: Clone,
+    {
+        txn.do_transaction_retry(async || self.execute(input.clone()).await)
+            .await
+    }
 }
 
+/// Runs the use case `UC` in a transaction of `Tx`, or in a savepoint when a transaction
+/// has already begun
 pub struct TxnUseCase<Tx, UC> {
     tx: Tx,
     uc: UC,
+    options: Option<TxnOptions>,
+}
+
+impl<Tx, UC> TxnUseCase<Tx, UC> {
+    /// Begin the transactions of the use case with `options`
+    pub fn with_txn_options(mut self, options: TxnOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    fn txn(&self) -> Tx
+    where
+        Tx: TxnManager,
+    {
+        match self.options {
+            Some(options) => self.tx.clone().with_txn_options(options),
+            None => self.tx.clone(),
+        }
+    }
+
+    fn with_options<Txn: TxnManager>(&self, txn: Txn) -> Txn {
+        match self.options {
+            Some(options) => txn.with_txn_options(options),
+            None => txn,
+        }
+    }
+}
+
+impl<Tx, UC> TxnUseCase<Tx, UC>
+where
+    Tx: TxnManager,
+    UC: UseCase,
+    UC::Input: Clone,
+{
+    /// Same as [`UseCase::execute`], but the transaction is run again while it fails with a
+    /// retryable error, see [`UseCase::execute_in_txn_retry`]
+    pub async fn execute_retry(&mut self, params: UC::Input) -> BizResult<UC::Output, UC::Error> {
+        let tx = self.txn();
+        self.uc.execute_in_txn_retry(tx, params).await
+    }
 }
 
 impl<Tx, UC> Provider for TxnUseCase<Tx, UC>
@@ -33,6 +97,7 @@ where
         Ok(Self {
             tx: Tx::build(ctx)?,
             uc: UC::build(ctx)?,
+            options: None,
         })
     }
 }
@@ -49,7 +114,8 @@ where
     type Error = UC::Error;
 
     async fn execute(&mut self, params: Self::Input) -> BizResult<Self::Output, Self::Error> {
-        self.uc.execute_in_txn(self.tx.clone(), params).await
+        let tx = self.txn();
+        self.uc.execute_in_txn(tx, params).await
     }
 
     async fn execute_in_txn<Txn>(
@@ -60,8 +126,22 @@ where
     where
         Txn: TxnManager,
     {
+        let txn = self.with_options(txn);
         self.uc.execute_in_txn(txn, params).await
     }
+
+    async fn execute_in_txn_retry<Txn>(
+        &mut self,
+        txn: Txn,
+        params: Self::Input,
+    ) -> BizResult<Self::Output, Self::Error>
+    where
+        Txn: TxnManager,
+        Self::Input: Clone,
+    {
+        let txn = self.with_options(txn);
+        self.uc.execute_in_txn_retry(txn, params).await
+    }
 }
 
 #[macro_export]
@@ -78,5 +158,17 @@ macro_rules! no_transaction {
             // There's no need to use transaction in this UseCase
             self.execute(params).await
         }
+
+        async fn execute_in_txn_retry<Txn>(
+            &mut self,
+            _txn: Txn,
+            params: Self::Input,
+        ) -> bagua::result::BizResult<Self::Output, Self::Error>
+        where
+            Txn: bagua::db::TxnManager,
+            Self::Input: Clone,
+        {
+            self.execute(params).await
+        }
     };
 }